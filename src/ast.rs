@@ -1,44 +1,824 @@
 use crate::token::Token;
-use std::fmt::{Debug, Formatter, Result};
+use std::fmt::{Debug, Display, Formatter, Result};
 
 pub trait Node {
     fn token_literal(&self) -> String;
 }
 
-pub trait Expression: Node {
-    fn expression_node(&self);
+/// How much native stack has to be left to go one level further down a
+/// tree, and how much more to get when there isn't.
+const RED_ZONE: usize = 128 * 1024;
+const STACK_SEGMENT: usize = 4 * 1024 * 1024;
+
+/// Runs `f`, on more native stack if there isn't much left. Whatever
+/// recurses over expressions goes through this, since they can nest up to
+/// `parser::MAX_NESTING` deep.
+pub(crate) fn deeper<R>(f: impl FnOnce() -> R) -> R {
+    stacker::maybe_grow(RED_ZONE, STACK_SEGMENT, f)
+}
+
+#[derive(Debug, Clone)]
+pub struct Identifier<'a> {
+    token: Token<'a>,
+    value: String,
+}
+
+impl<'a> Identifier<'a> {
+    pub fn new(token: Token<'a>, value: String) -> Identifier<'a> {
+        Identifier { token, value }
+    }
+
+    pub fn token(&self) -> &Token<'a> {
+        &self.token
+    }
+
+    pub fn value(&self) -> &String {
+        &self.value
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IntegerInternal<'a> {
+    token: Token<'a>,
+    value: i128,
+    original: Option<Box<Expression<'a>>>,
+}
+
+impl<'a> IntegerInternal<'a> {
+    pub fn new(token: Token<'a>, value: i128) -> Self {
+        Self {
+            token,
+            value,
+            original: None,
+        }
+    }
+
+    pub fn token(&self) -> &Token<'a> {
+        &self.token
+    }
+
+    pub fn value(&self) -> i128 {
+        self.value
+    }
+
+    /// The expression the optimizer folded into this literal, if it did.
+    pub fn original(&self) -> Option<&Expression<'a>> {
+        self.original.as_deref()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BooleanInternal<'a> {
+    token: Token<'a>,
+    value: bool,
+    original: Option<Box<Expression<'a>>>,
+}
+
+impl<'a> BooleanInternal<'a> {
+    pub fn new(token: Token<'a>, value: bool) -> Self {
+        Self {
+            token,
+            value,
+            original: None,
+        }
+    }
+
+    pub fn token(&self) -> &Token<'a> {
+        &self.token
+    }
+
+    pub fn value(&self) -> bool {
+        self.value
+    }
+
+    /// The expression the optimizer folded into this literal, if it did.
+    pub fn original(&self) -> Option<&Expression<'a>> {
+        self.original.as_deref()
+    }
+}
+
+/// A string literal. Strings can't be taken apart yet; they're compared,
+/// printed, and what caught errors describe themselves with.
+#[derive(Debug, Clone)]
+pub struct StringInternal<'a> {
+    token: Token<'a>,
+}
+
+impl<'a> StringInternal<'a> {
+    pub fn new(token: Token<'a>) -> Self {
+        Self { token }
+    }
+
+    pub fn token(&self) -> &Token<'a> {
+        &self.token
+    }
+
+    pub fn value(&self) -> &str {
+        &self.token.literal
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PrefixInternal<'a> {
+    token: Token<'a>,
+    operator: String,
+    right: Box<Expression<'a>>,
+}
+
+impl<'a> PrefixInternal<'a> {
+    pub fn new(token: Token<'a>, operator: String, right: Expression<'a>) -> Self {
+        Self {
+            token,
+            operator,
+            right: Box::new(right),
+        }
+    }
+
+    pub fn token(&self) -> &Token<'a> {
+        &self.token
+    }
+
+    pub fn operator(&self) -> &str {
+        &self.operator
+    }
+
+    pub fn right(&self) -> &Expression<'a> {
+        &self.right
+    }
+
+    pub fn right_mut(&mut self) -> &mut Expression<'a> {
+        &mut self.right
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct InfixInternal<'a> {
+    token: Token<'a>,
+    left: Box<Expression<'a>>,
+    operator: String,
+    right: Box<Expression<'a>>,
+}
+
+impl<'a> InfixInternal<'a> {
+    pub fn new(
+        token: Token<'a>,
+        left: Expression<'a>,
+        operator: String,
+        right: Expression<'a>,
+    ) -> Self {
+        Self {
+            token,
+            left: Box::new(left),
+            operator,
+            right: Box::new(right),
+        }
+    }
+
+    pub fn token(&self) -> &Token<'a> {
+        &self.token
+    }
+
+    pub fn left(&self) -> &Expression<'a> {
+        &self.left
+    }
+
+    pub fn operator(&self) -> &str {
+        &self.operator
+    }
+
+    pub fn right(&self) -> &Expression<'a> {
+        &self.right
+    }
+
+    pub fn left_mut(&mut self) -> &mut Expression<'a> {
+        &mut self.left
+    }
+
+    pub fn right_mut(&mut self) -> &mut Expression<'a> {
+        &mut self.right
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Block<'a> {
+    token: Token<'a>,
+    pub statements: Vec<Statement<'a>>,
+}
+
+impl<'a> Block<'a> {
+    pub fn new(token: Token<'a>, statements: Vec<Statement<'a>>) -> Self {
+        Self { token, statements }
+    }
+
+    pub fn token(&self) -> &Token<'a> {
+        &self.token
+    }
+
+    /// The outermost expressions of the block's statements, in order.
+    pub fn expressions(&self) -> Vec<&Expression<'a>> {
+        self.statements
+            .iter()
+            .flat_map(Statement::expressions)
+            .collect()
+    }
+
+    pub fn expressions_mut(&mut self) -> Vec<&mut Expression<'a>> {
+        self.statements
+            .iter_mut()
+            .flat_map(Statement::expressions_mut)
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IfInternal<'a> {
+    token: Token<'a>,
+    condition: Box<Expression<'a>>,
+    consequence: Block<'a>,
+    alternative: Option<Block<'a>>,
+}
+
+impl<'a> IfInternal<'a> {
+    pub fn new(
+        token: Token<'a>,
+        condition: Expression<'a>,
+        consequence: Block<'a>,
+        alternative: Option<Block<'a>>,
+    ) -> Self {
+        Self {
+            token,
+            condition: Box::new(condition),
+            consequence,
+            alternative,
+        }
+    }
+
+    pub fn token(&self) -> &Token<'a> {
+        &self.token
+    }
+
+    pub fn condition(&self) -> &Expression<'a> {
+        &self.condition
+    }
+
+    pub fn consequence(&self) -> &Block<'a> {
+        &self.consequence
+    }
+
+    pub fn alternative(&self) -> Option<&Block<'a>> {
+        self.alternative.as_ref()
+    }
+
+    pub fn condition_mut(&mut self) -> &mut Expression<'a> {
+        &mut self.condition
+    }
+
+    pub fn consequence_mut(&mut self) -> &mut Block<'a> {
+        &mut self.consequence
+    }
+
+    pub fn alternative_mut(&mut self) -> Option<&mut Block<'a>> {
+        self.alternative.as_mut()
+    }
+
+    pub fn into_branches(self) -> (Block<'a>, Option<Block<'a>>) {
+        (self.consequence, self.alternative)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FunctionInternal<'a> {
+    token: Token<'a>,
+    parameters: Vec<Identifier<'a>>,
+    body: Block<'a>,
+}
+
+impl<'a> FunctionInternal<'a> {
+    pub fn new(token: Token<'a>, parameters: Vec<Identifier<'a>>, body: Block<'a>) -> Self {
+        Self {
+            token,
+            parameters,
+            body,
+        }
+    }
+
+    pub fn token(&self) -> &Token<'a> {
+        &self.token
+    }
+
+    pub fn parameters(&self) -> &[Identifier<'a>] {
+        &self.parameters
+    }
+
+    pub fn body(&self) -> &Block<'a> {
+        &self.body
+    }
+
+    pub fn body_mut(&mut self) -> &mut Block<'a> {
+        &mut self.body
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CallInternal<'a> {
+    token: Token<'a>,
+    function: Box<Expression<'a>>,
+    arguments: Vec<Expression<'a>>,
+}
+
+impl<'a> CallInternal<'a> {
+    pub fn new(token: Token<'a>, function: Expression<'a>, arguments: Vec<Expression<'a>>) -> Self {
+        Self {
+            token,
+            function: Box::new(function),
+            arguments,
+        }
+    }
+
+    pub fn token(&self) -> &Token<'a> {
+        &self.token
+    }
+
+    pub fn function(&self) -> &Expression<'a> {
+        &self.function
+    }
+
+    pub fn arguments(&self) -> &[Expression<'a>] {
+        &self.arguments
+    }
+
+    pub fn function_mut(&mut self) -> &mut Expression<'a> {
+        &mut self.function
+    }
+
+    pub fn arguments_mut(&mut self) -> &mut [Expression<'a>] {
+        &mut self.arguments
+    }
+}
+
+/// `object.name`, which reads a binding out of a module, or a field of a
+/// caught error.
+#[derive(Debug, Clone)]
+pub struct MemberInternal<'a> {
+    token: Token<'a>,
+    object: Box<Expression<'a>>,
+    name: Identifier<'a>,
+}
+
+impl<'a> MemberInternal<'a> {
+    pub fn new(token: Token<'a>, object: Expression<'a>, name: Identifier<'a>) -> Self {
+        Self {
+            token,
+            object: Box::new(object),
+            name,
+        }
+    }
+
+    pub fn token(&self) -> &Token<'a> {
+        &self.token
+    }
+
+    pub fn object(&self) -> &Expression<'a> {
+        &self.object
+    }
+
+    pub fn name(&self) -> &Identifier<'a> {
+        &self.name
+    }
+
+    pub fn object_mut(&mut self) -> &mut Expression<'a> {
+        &mut self.object
+    }
+}
+
+/// `[a, b, c]`, which makes a new array each time it's evaluated.
+#[derive(Debug, Clone)]
+pub struct ArrayInternal<'a> {
+    token: Token<'a>,
+    elements: Vec<Expression<'a>>,
+}
+
+impl<'a> ArrayInternal<'a> {
+    pub fn new(token: Token<'a>, elements: Vec<Expression<'a>>) -> Self {
+        Self { token, elements }
+    }
+
+    pub fn token(&self) -> &Token<'a> {
+        &self.token
+    }
+
+    pub fn elements(&self) -> &[Expression<'a>] {
+        &self.elements
+    }
+
+    pub fn elements_mut(&mut self) -> &mut [Expression<'a>] {
+        &mut self.elements
+    }
+}
+
+/// `{key: value, ...}`, which makes a new hash each time it's evaluated.
+/// Its keys keep the order they're written in.
+#[derive(Debug, Clone)]
+pub struct HashInternal<'a> {
+    token: Token<'a>,
+    pairs: Vec<(Expression<'a>, Expression<'a>)>,
+}
+
+impl<'a> HashInternal<'a> {
+    pub fn new(token: Token<'a>, pairs: Vec<(Expression<'a>, Expression<'a>)>) -> Self {
+        Self { token, pairs }
+    }
+
+    pub fn token(&self) -> &Token<'a> {
+        &self.token
+    }
+
+    pub fn pairs(&self) -> &[(Expression<'a>, Expression<'a>)] {
+        &self.pairs
+    }
+
+    pub fn pairs_mut(&mut self) -> &mut [(Expression<'a>, Expression<'a>)] {
+        &mut self.pairs
+    }
+}
+
+/// `left[index]`, an element of an array or the value of a key in a hash.
+#[derive(Debug, Clone)]
+pub struct IndexInternal<'a> {
+    token: Token<'a>,
+    left: Box<Expression<'a>>,
+    index: Box<Expression<'a>>,
+}
+
+impl<'a> IndexInternal<'a> {
+    pub fn new(token: Token<'a>, left: Expression<'a>, index: Expression<'a>) -> Self {
+        Self {
+            token,
+            left: Box::new(left),
+            index: Box::new(index),
+        }
+    }
+
+    pub fn token(&self) -> &Token<'a> {
+        &self.token
+    }
+
+    pub fn left(&self) -> &Expression<'a> {
+        &self.left
+    }
+
+    pub fn index(&self) -> &Expression<'a> {
+        &self.index
+    }
+
+    pub fn left_mut(&mut self) -> &mut Expression<'a> {
+        &mut self.left
+    }
+
+    pub fn index_mut(&mut self) -> &mut Expression<'a> {
+        &mut self.index
+    }
+}
+
+/// `name = value`, or a compound assignment like `name += value`, which
+/// rebinds a name that's already in scope.
+#[derive(Debug, Clone)]
+pub struct AssignInternal<'a> {
+    token: Token<'a>,
+    name: Identifier<'a>,
+    value: Box<Expression<'a>>,
+}
+
+impl<'a> AssignInternal<'a> {
+    pub fn new(token: Token<'a>, name: Identifier<'a>, value: Expression<'a>) -> Self {
+        Self {
+            token,
+            name,
+            value: Box::new(value),
+        }
+    }
+
+    pub fn token(&self) -> &Token<'a> {
+        &self.token
+    }
+
+    pub fn name(&self) -> &Identifier<'a> {
+        &self.name
+    }
+
+    /// `=`, `+=` and so on.
+    pub fn operator(&self) -> &str {
+        &self.token.literal
+    }
+
+    /// The infix operator a compound assignment applies, like `+` for `+=`.
+    pub fn infix_operator(&self) -> Option<&str> {
+        infix_operator(self.operator())
+    }
+
+    pub fn value(&self) -> &Expression<'a> {
+        &self.value
+    }
+
+    pub fn value_mut(&mut self) -> &mut Expression<'a> {
+        &mut self.value
+    }
 }
 
-pub struct Identifier<'a> {
+fn infix_operator(assign: &str) -> Option<&str> {
+    match assign {
+        "=" => None,
+        op => op.strip_suffix('='),
+    }
+}
+
+/// `left[index] = value`, or a compound assignment like `left[index] +=
+/// value`, which changes an array or hash in place.
+#[derive(Debug, Clone)]
+pub struct IndexAssignInternal<'a> {
     token: Token<'a>,
-    value: String,
+    target: IndexInternal<'a>,
+    value: Box<Expression<'a>>,
 }
 
-impl Identifier<'_> {
-    pub fn new(token: Token, value: String) -> Identifier {
-        Identifier { token, value }
+impl<'a> IndexAssignInternal<'a> {
+    pub fn new(token: Token<'a>, target: IndexInternal<'a>, value: Expression<'a>) -> Self {
+        Self {
+            token,
+            target,
+            value: Box::new(value),
+        }
     }
 
-    pub fn token(&self) -> &Token {
+    pub fn token(&self) -> &Token<'a> {
         &self.token
     }
 
-    pub fn value(&self) -> &String {
+    /// The `left[index]` being assigned to.
+    pub fn target(&self) -> &IndexInternal<'a> {
+        &self.target
+    }
+
+    pub fn target_mut(&mut self) -> &mut IndexInternal<'a> {
+        &mut self.target
+    }
+
+    /// `=`, `+=` and so on.
+    pub fn operator(&self) -> &str {
+        &self.token.literal
+    }
+
+    /// The infix operator a compound assignment applies, like `+` for `+=`.
+    pub fn infix_operator(&self) -> Option<&str> {
+        infix_operator(self.operator())
+    }
+
+    pub fn value(&self) -> &Expression<'a> {
         &self.value
     }
+
+    pub fn value_mut(&mut self) -> &mut Expression<'a> {
+        &mut self.value
+    }
+}
+
+/// `try { } catch (name) { } finally { }`, whose value is that of the block
+/// that ran to the end, `try` or `catch`. `name` is bound to the error like
+/// a `let` in the enclosing scope. The `finally` block is optional and runs
+/// however the others are left, its value thrown away.
+#[derive(Debug, Clone)]
+pub struct TryInternal<'a> {
+    token: Token<'a>,
+    body: Block<'a>,
+    name: Identifier<'a>,
+    handler: Block<'a>,
+    finally: Option<Block<'a>>,
+}
+
+impl<'a> TryInternal<'a> {
+    pub fn new(
+        token: Token<'a>,
+        body: Block<'a>,
+        name: Identifier<'a>,
+        handler: Block<'a>,
+        finally: Option<Block<'a>>,
+    ) -> Self {
+        Self {
+            token,
+            body,
+            name,
+            handler,
+            finally,
+        }
+    }
+
+    pub fn token(&self) -> &Token<'a> {
+        &self.token
+    }
+
+    pub fn body(&self) -> &Block<'a> {
+        &self.body
+    }
+
+    pub fn name(&self) -> &Identifier<'a> {
+        &self.name
+    }
+
+    /// The `catch` block.
+    pub fn handler(&self) -> &Block<'a> {
+        &self.handler
+    }
+
+    pub fn finally(&self) -> Option<&Block<'a>> {
+        self.finally.as_ref()
+    }
+
+    pub fn body_mut(&mut self) -> &mut Block<'a> {
+        &mut self.body
+    }
+
+    pub fn handler_mut(&mut self) -> &mut Block<'a> {
+        &mut self.handler
+    }
+
+    pub fn finally_mut(&mut self) -> Option<&mut Block<'a>> {
+        self.finally.as_mut()
+    }
+
+    /// The blocks in the order they're written.
+    pub fn blocks(&self) -> impl Iterator<Item = &Block<'a>> {
+        [&self.body, &self.handler].into_iter().chain(&self.finally)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Expression<'a> {
+    Identifier(Identifier<'a>),
+    Integer(IntegerInternal<'a>),
+    Boolean(BooleanInternal<'a>),
+    String(StringInternal<'a>),
+    Prefix(PrefixInternal<'a>),
+    Infix(InfixInternal<'a>),
+    If(IfInternal<'a>),
+    Function(FunctionInternal<'a>),
+    Call(CallInternal<'a>),
+    Member(MemberInternal<'a>),
+    Array(ArrayInternal<'a>),
+    Hash(HashInternal<'a>),
+    Index(IndexInternal<'a>),
+    Assign(AssignInternal<'a>),
+    IndexAssign(IndexAssignInternal<'a>),
+    Try(Box<TryInternal<'a>>),
+    /// `macro(params) { body }`, which only means something as the value
+    /// of a top-level `let`, and is gone once macros have been expanded.
+    Macro(FunctionInternal<'a>),
+}
+
+impl<'a> Expression<'a> {
+    /// The expression as it was written, before the optimizer folded it
+    /// into a literal, so that errors can quote the source.
+    pub fn unfolded(&self) -> &Expression<'a> {
+        let original = match self {
+            Expression::Integer(i) => i.original(),
+            Expression::Boolean(i) => i.original(),
+            _ => None,
+        };
+        original.map_or(self, Expression::unfolded)
+    }
+
+    /// Makes this literal stand in for `original`, which the optimizer
+    /// folded into it.
+    pub fn fold(&mut self, original: Expression<'a>) {
+        let slot = match self {
+            Expression::Integer(i) => &mut i.original,
+            Expression::Boolean(i) => &mut i.original,
+            _ => unreachable!("only literals are folded"),
+        };
+        *slot = Some(Box::new(original));
+    }
+
+    pub fn token(&self) -> &Token<'a> {
+        match self {
+            Expression::Identifier(i) => i.token(),
+            Expression::Integer(i) => i.token(),
+            Expression::Boolean(i) => i.token(),
+            Expression::String(i) => i.token(),
+            Expression::Prefix(i) => i.token(),
+            Expression::Infix(i) => i.token(),
+            Expression::If(i) => i.token(),
+            Expression::Function(i) => i.token(),
+            Expression::Call(i) => i.token(),
+            Expression::Member(i) => i.token(),
+            Expression::Array(i) => i.token(),
+            Expression::Hash(i) => i.token(),
+            Expression::Index(i) => i.token(),
+            Expression::Assign(i) => i.token(),
+            Expression::IndexAssign(i) => i.token(),
+            Expression::Try(i) => i.token(),
+            Expression::Macro(i) => i.token(),
+        }
+    }
+
+    /// The expressions directly inside this one, in the order they're
+    /// written, including the outermost ones of its blocks.
+    pub fn children(&self) -> Vec<&Expression<'a>> {
+        match self {
+            Expression::Identifier(_)
+            | Expression::Integer(_)
+            | Expression::Boolean(_)
+            | Expression::String(_) => Vec::new(),
+            Expression::Prefix(i) => vec![&*i.right],
+            Expression::Infix(i) => vec![&*i.left, &*i.right],
+            Expression::If(i) => {
+                let mut out = vec![&*i.condition];
+                out.extend(i.consequence.expressions());
+                if let Some(alt) = &i.alternative {
+                    out.extend(alt.expressions());
+                }
+                out
+            }
+            Expression::Function(i) | Expression::Macro(i) => i.body.expressions(),
+            Expression::Call(i) => {
+                let mut out = vec![&*i.function];
+                out.extend(&i.arguments);
+                out
+            }
+            Expression::Member(i) => vec![&*i.object],
+            Expression::Array(i) => i.elements.iter().collect(),
+            Expression::Hash(i) => i.pairs.iter().flat_map(|(k, v)| [k, v]).collect(),
+            Expression::Index(i) => vec![&*i.left, &*i.index],
+            Expression::Assign(i) => vec![&*i.value],
+            Expression::IndexAssign(i) => vec![&*i.target.left, &*i.target.index, &*i.value],
+            Expression::Try(i) => i.blocks().flat_map(Block::expressions).collect(),
+        }
+    }
+
+    pub fn children_mut(&mut self) -> Vec<&mut Expression<'a>> {
+        match self {
+            Expression::Identifier(_)
+            | Expression::Integer(_)
+            | Expression::Boolean(_)
+            | Expression::String(_) => Vec::new(),
+            Expression::Prefix(i) => vec![&mut *i.right],
+            Expression::Infix(i) => vec![&mut *i.left, &mut *i.right],
+            Expression::If(i) => {
+                let mut out = vec![&mut *i.condition];
+                out.extend(i.consequence.expressions_mut());
+                if let Some(alt) = &mut i.alternative {
+                    out.extend(alt.expressions_mut());
+                }
+                out
+            }
+            Expression::Function(i) | Expression::Macro(i) => i.body.expressions_mut(),
+            Expression::Call(i) => {
+                let mut out = vec![&mut *i.function];
+                out.extend(&mut i.arguments);
+                out
+            }
+            Expression::Member(i) => vec![&mut *i.object],
+            Expression::Array(i) => i.elements.iter_mut().collect(),
+            Expression::Hash(i) => i.pairs.iter_mut().flat_map(|(k, v)| [k, v]).collect(),
+            Expression::Index(i) => vec![&mut *i.left, &mut *i.index],
+            Expression::Assign(i) => vec![&mut *i.value],
+            Expression::IndexAssign(i) => {
+                vec![&mut *i.target.left, &mut *i.target.index, &mut *i.value]
+            }
+            Expression::Try(i) => {
+                let TryInternal {
+                    body,
+                    handler,
+                    finally,
+                    ..
+                } = &mut **i;
+                let mut out = body.expressions_mut();
+                out.extend(handler.expressions_mut());
+                if let Some(finally) = finally {
+                    out.extend(finally.expressions_mut());
+                }
+                out
+            }
+        }
+    }
+}
+
+impl Node for Expression<'_> {
+    fn token_literal(&self) -> String {
+        self.token().literal.clone()
+    }
 }
 
+#[derive(Debug, Clone)]
 pub struct LetInternal<'a> {
     token: Token<'a>,
     name: Option<Identifier<'a>>,
-    value: Option<Box<dyn Expression>>,
+    value: Option<Expression<'a>>,
 }
 
 impl<'a> LetInternal<'a> {
     pub fn new(
         token: Token<'a>,
         name: Option<Identifier<'a>>,
-        value: Option<Box<dyn Expression>>,
+        value: Option<Expression<'a>>,
     ) -> LetInternal<'a> {
         LetInternal { token, name, value }
     }
@@ -47,6 +827,10 @@ impl<'a> LetInternal<'a> {
         self.name = Some(name);
     }
 
+    pub fn change_value(&mut self, value: Expression<'a>) {
+        self.value = Some(value);
+    }
+
     pub fn token(&self) -> &Token<'a> {
         &self.token
     }
@@ -55,51 +839,312 @@ impl<'a> LetInternal<'a> {
         self.name.as_ref()
     }
 
-    pub fn value(&self) -> Option<&Box<dyn Expression>> {
+    pub fn value(&self) -> Option<&Expression<'a>> {
         self.value.as_ref()
     }
+
+    pub fn value_mut(&mut self) -> Option<&mut Expression<'a>> {
+        self.value.as_mut()
+    }
 }
 
+#[derive(Debug, Clone)]
 pub struct ReturnInternal<'a> {
     token: Token<'a>,
-    return_value: Option<Box<dyn Expression>>,
+    return_value: Option<Expression<'a>>,
 }
 
 impl<'a> ReturnInternal<'a> {
-    pub fn init(token: Token<'a>, return_value: Option<Box<dyn Expression>>) -> Self {
+    pub fn init(token: Token<'a>, return_value: Option<Expression<'a>>) -> Self {
         Self {
             token,
             return_value,
         }
     }
 
+    pub fn token(&self) -> &Token<'a> {
+        &self.token
+    }
+
     pub fn token_literal(&self) -> &str {
         &self.token.literal
     }
+
+    pub fn return_value(&self) -> Option<&Expression<'a>> {
+        self.return_value.as_ref()
+    }
+
+    pub fn return_value_mut(&mut self) -> Option<&mut Expression<'a>> {
+        self.return_value.as_mut()
+    }
 }
 
+#[derive(Debug, Clone)]
 pub struct ExpressionInternal<'a> {
     token: Token<'a>,
-    expression: Option<Box<dyn Expression>>,
+    expression: Option<Expression<'a>>,
 }
 
 impl<'a> ExpressionInternal<'a> {
-    pub fn init(token: Token<'a>, expression: Option<Box<dyn Expression>>) -> Self {
+    pub fn init(token: Token<'a>, expression: Option<Expression<'a>>) -> Self {
         Self { token, expression }
     }
 
+    pub fn token(&self) -> &Token<'a> {
+        &self.token
+    }
+
     pub fn token_literal(&self) -> &str {
         &self.token.literal
     }
+
+    pub fn expression(&self) -> Option<&Expression<'a>> {
+        self.expression.as_ref()
+    }
+
+    pub fn expression_mut(&mut self) -> Option<&mut Expression<'a>> {
+        self.expression.as_mut()
+    }
+
+    pub fn into_expression(self) -> Option<Expression<'a>> {
+        self.expression
+    }
+}
+
+/// `import "path/to/lib.my";`, which binds the module to the file's name
+/// without the extension.
+#[derive(Debug, Clone)]
+pub struct ImportInternal<'a> {
+    token: Token<'a>,
+    path: String,
+    name: Identifier<'a>,
+}
+
+impl<'a> ImportInternal<'a> {
+    pub fn new(token: Token<'a>, path: String, name: Identifier<'a>) -> Self {
+        Self { token, path, name }
+    }
+
+    pub fn token(&self) -> &Token<'a> {
+        &self.token
+    }
+
+    /// The path as written, relative to the importing file.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn name(&self) -> &Identifier<'a> {
+        &self.name
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WhileInternal<'a> {
+    token: Token<'a>,
+    condition: Box<Expression<'a>>,
+    body: Block<'a>,
+}
+
+impl<'a> WhileInternal<'a> {
+    pub fn new(token: Token<'a>, condition: Expression<'a>, body: Block<'a>) -> Self {
+        Self {
+            token,
+            condition: Box::new(condition),
+            body,
+        }
+    }
+
+    pub fn token(&self) -> &Token<'a> {
+        &self.token
+    }
+
+    pub fn condition(&self) -> &Expression<'a> {
+        &self.condition
+    }
+
+    pub fn body(&self) -> &Block<'a> {
+        &self.body
+    }
+
+    pub fn condition_mut(&mut self) -> &mut Expression<'a> {
+        &mut self.condition
+    }
+
+    pub fn body_mut(&mut self) -> &mut Block<'a> {
+        &mut self.body
+    }
+}
+
+/// `for (x in start..end) { }`, which binds `x` to each integer from `start`
+/// up to but not including `end`, like a `let` in the enclosing scope.
+/// Without `..end`, `start` is an array to bind `x` to each element of, or
+/// a hash to bind it to each key of, in order.
+#[derive(Debug, Clone)]
+pub struct ForInternal<'a> {
+    token: Token<'a>,
+    variable: Identifier<'a>,
+    start: Box<Expression<'a>>,
+    end: Option<Box<Expression<'a>>>,
+    body: Block<'a>,
+}
+
+impl<'a> ForInternal<'a> {
+    pub fn new(
+        token: Token<'a>,
+        variable: Identifier<'a>,
+        start: Expression<'a>,
+        end: Option<Expression<'a>>,
+        body: Block<'a>,
+    ) -> Self {
+        Self {
+            token,
+            variable,
+            start: Box::new(start),
+            end: end.map(Box::new),
+            body,
+        }
+    }
+
+    pub fn token(&self) -> &Token<'a> {
+        &self.token
+    }
+
+    pub fn variable(&self) -> &Identifier<'a> {
+        &self.variable
+    }
+
+    pub fn start(&self) -> &Expression<'a> {
+        &self.start
+    }
+
+    pub fn end(&self) -> Option<&Expression<'a>> {
+        self.end.as_deref()
+    }
+
+    pub fn body(&self) -> &Block<'a> {
+        &self.body
+    }
+
+    pub fn start_mut(&mut self) -> &mut Expression<'a> {
+        &mut self.start
+    }
+
+    pub fn end_mut(&mut self) -> Option<&mut Expression<'a>> {
+        self.end.as_deref_mut()
+    }
+
+    pub fn body_mut(&mut self) -> &mut Block<'a> {
+        &mut self.body
+    }
+}
+
+/// `throw value;`, which fails with `value` as the error, or with the
+/// error `value` is if it's one that was caught.
+#[derive(Debug, Clone)]
+pub struct ThrowInternal<'a> {
+    token: Token<'a>,
+    value: Expression<'a>,
+}
+
+impl<'a> ThrowInternal<'a> {
+    pub fn new(token: Token<'a>, value: Expression<'a>) -> Self {
+        Self { token, value }
+    }
+
+    pub fn token(&self) -> &Token<'a> {
+        &self.token
+    }
+
+    pub fn value(&self) -> &Expression<'a> {
+        &self.value
+    }
+
+    pub fn value_mut(&mut self) -> &mut Expression<'a> {
+        &mut self.value
+    }
 }
 
+#[derive(Debug, Clone)]
 pub enum Statement<'a> {
     Let(LetInternal<'a>),
     Return(ReturnInternal<'a>),
     Expression(ExpressionInternal<'a>),
+    Import(ImportInternal<'a>),
+    While(WhileInternal<'a>),
+    For(ForInternal<'a>),
+    /// Only parsed inside a loop, and not in a function within it.
+    Break(Token<'a>),
+    Continue(Token<'a>),
+    Throw(ThrowInternal<'a>),
+}
+
+impl<'a> Statement<'a> {
+    pub fn token(&self) -> &Token<'a> {
+        match self {
+            Statement::Let(i) => i.token(),
+            Statement::Return(i) => i.token(),
+            Statement::Expression(i) => i.token(),
+            Statement::Import(i) => i.token(),
+            Statement::While(i) => i.token(),
+            Statement::For(i) => i.token(),
+            Statement::Break(t) | Statement::Continue(t) => t,
+            Statement::Throw(i) => i.token(),
+        }
+    }
+
+    /// The outermost expressions in the statement, in the order they're
+    /// written, including those of a loop's body.
+    pub fn expressions(&self) -> Vec<&Expression<'a>> {
+        deeper(|| match self {
+            Statement::Let(i) => i.value.iter().collect(),
+            Statement::Return(i) => i.return_value.iter().collect(),
+            Statement::Expression(i) => i.expression.iter().collect(),
+            Statement::While(i) => {
+                let mut out = vec![&*i.condition];
+                out.extend(i.body.expressions());
+                out
+            }
+            Statement::For(i) => {
+                let mut out = vec![&*i.start];
+                out.extend(i.end.as_deref());
+                out.extend(i.body.expressions());
+                out
+            }
+            Statement::Throw(i) => vec![&i.value],
+            Statement::Import(_) | Statement::Break(_) | Statement::Continue(_) => Vec::new(),
+        })
+    }
+
+    pub fn expressions_mut(&mut self) -> Vec<&mut Expression<'a>> {
+        deeper(|| match self {
+            Statement::Let(i) => i.value.iter_mut().collect(),
+            Statement::Return(i) => i.return_value.iter_mut().collect(),
+            Statement::Expression(i) => i.expression.iter_mut().collect(),
+            Statement::While(i) => {
+                let mut out = vec![&mut *i.condition];
+                out.extend(i.body.expressions_mut());
+                out
+            }
+            Statement::For(i) => {
+                let mut out = vec![&mut *i.start];
+                out.extend(i.end.as_deref_mut());
+                out.extend(i.body.expressions_mut());
+                out
+            }
+            Statement::Throw(i) => vec![&mut i.value],
+            Statement::Import(_) | Statement::Break(_) | Statement::Continue(_) => Vec::new(),
+        })
+    }
 }
 
-#[derive(Default)]
+impl Node for Statement<'_> {
+    fn token_literal(&self) -> String {
+        self.token().literal.clone()
+    }
+}
+
+#[derive(Default, Clone)]
 pub struct Program<'a> {
     pub statements: Vec<Statement<'a>>,
 }
@@ -110,18 +1155,149 @@ impl Program<'_> {
     }
 }
 
+impl Node for Program<'_> {
+    fn token_literal(&self) -> String {
+        match self.statements.first() {
+            Some(s) => s.token_literal(),
+            None => String::new(),
+        }
+    }
+}
+
+impl Display for Identifier<'_> {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "{}", self.value)
+    }
+}
 
-impl Debug for Program<'_> {
+impl Display for Block<'_> {
     fn fmt(&self, f: &mut Formatter) -> Result {
-        let mut s = String::new();
         for stmt in &self.statements {
-            if let Statement::Let(i) = stmt {
-                s.push_str("let ");
-                s.push_str(&i.name().unwrap().value);
-                s.push_str(" = ");
+            write!(f, "{}", stmt)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for Expression<'_> {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        deeper(|| match self {
+            Expression::Identifier(i) => write!(f, "{}", i),
+            Expression::Integer(i) => write!(f, "{}", i.value),
+            Expression::Boolean(i) => write!(f, "{}", i.value),
+            Expression::String(i) => write!(f, "\"{}\"", i.value()),
+            Expression::Prefix(i) => write!(f, "({}{})", i.operator, i.right),
+            Expression::Infix(i) => write!(f, "({} {} {})", i.left, i.operator, i.right),
+            Expression::If(i) => {
+                write!(f, "if {} {{ {} }}", i.condition, i.consequence)?;
+                if let Some(alt) = &i.alternative {
+                    write!(f, " else {{ {} }}", alt)?;
+                }
+                Ok(())
+            }
+            Expression::Function(i) | Expression::Macro(i) => {
+                let keyword = match self {
+                    Expression::Macro(_) => "macro",
+                    _ => "fn",
+                };
+                let params: Vec<String> = i.parameters.iter().map(|p| p.to_string()).collect();
+                write!(f, "{}({}) {{ {} }}", keyword, params.join(", "), i.body)
+            }
+            Expression::Call(i) => {
+                let args: Vec<String> = i.arguments.iter().map(|a| a.to_string()).collect();
+                write!(f, "{}({})", i.function, args.join(", "))
+            }
+            Expression::Member(i) => write!(f, "{}.{}", i.object, i.name),
+            Expression::Array(i) => {
+                let elements: Vec<String> = i.elements.iter().map(|e| e.to_string()).collect();
+                write!(f, "[{}]", elements.join(", "))
+            }
+            Expression::Hash(i) => {
+                let pairs: Vec<String> = i
+                    .pairs
+                    .iter()
+                    .map(|(k, v)| format!("{}: {}", k, v))
+                    .collect();
+                write!(f, "{{{}}}", pairs.join(", "))
+            }
+            Expression::Index(i) => write!(f, "({}[{}])", i.left, i.index),
+            Expression::Assign(i) => write!(f, "({} {} {})", i.name, i.operator(), i.value),
+            Expression::IndexAssign(i) => write!(
+                f,
+                "({}[{}] {} {})",
+                i.target.left,
+                i.target.index,
+                i.operator(),
+                i.value
+            ),
+            Expression::Try(i) => {
+                write!(
+                    f,
+                    "try {{ {} }} catch ({}) {{ {} }}",
+                    i.body, i.name, i.handler
+                )?;
+                if let Some(finally) = &i.finally {
+                    write!(f, " finally {{ {} }}", finally)?;
+                }
+                Ok(())
+            }
+        })
+    }
+}
+
+impl Display for Statement<'_> {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        deeper(|| match self {
+            Statement::Let(i) => {
+                write!(f, "let ")?;
+                if let Some(name) = &i.name {
+                    write!(f, "{}", name)?;
+                }
+                write!(f, " = ")?;
+                if let Some(value) = &i.value {
+                    write!(f, "{}", value)?;
+                }
+                write!(f, ";")
+            }
+            Statement::Return(i) => {
+                write!(f, "return")?;
+                if let Some(value) = &i.return_value {
+                    write!(f, " {}", value)?;
+                }
+                write!(f, ";")
             }
+            Statement::Expression(i) => match &i.expression {
+                Some(e) => write!(f, "{}", e),
+                None => Ok(()),
+            },
+            Statement::Import(i) => write!(f, "import \"{}\";", i.path),
+            Statement::While(i) => write!(f, "while {} {{ {} }}", i.condition, i.body),
+            Statement::For(i) => {
+                write!(f, "for {} in {}", i.variable, i.start)?;
+                if let Some(end) = &i.end {
+                    write!(f, "..{}", end)?;
+                }
+                write!(f, " {{ {} }}", i.body)
+            }
+            Statement::Break(_) => write!(f, "break;"),
+            Statement::Continue(_) => write!(f, "continue;"),
+            Statement::Throw(i) => write!(f, "throw {};", i.value),
+        })
+    }
+}
+
+impl Display for Program<'_> {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        for stmt in &self.statements {
+            write!(f, "{}", stmt)?;
         }
-        write!(f, "{}", s)
+        Ok(())
+    }
+}
+
+impl Debug for Program<'_> {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "{}", self)
     }
 }
 
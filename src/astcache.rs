@@ -0,0 +1,950 @@
+use crate::ast::{
+    self, ArrayInternal, AssignInternal, Block, BooleanInternal, CallInternal, Expression,
+    ExpressionInternal, ForInternal, FunctionInternal, HashInternal, Identifier, IfInternal,
+    ImportInternal, IndexAssignInternal, IndexInternal, InfixInternal, IntegerInternal,
+    LetInternal, MemberInternal, PrefixInternal, Program, ReturnInternal, Statement,
+    StringInternal, ThrowInternal, TryInternal, WhileInternal,
+};
+use crate::token::{Location, Token, TokenKind};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+// A cache file is laid out as
+//
+//     magic | version: u16 | source hash: u64 | string table | node stream | checksum: u64
+//
+// Integers other than the fixed width ones above are LEB128 varints, and the
+// checksum is FNV-1a over everything before it. Strings (identifiers and
+// operators) are stored once in the table and referred to by index.
+
+const MAGIC: &[u8; 4] = b"PMKA";
+
+/// Bump whenever the encoding of any node or token changes, or the parser
+/// stops accepting something it used to, so old cache files are ignored
+/// rather than misread.
+pub const FORMAT_VERSION: u16 = 11;
+
+const HEADER_LEN: usize = MAGIC.len() + 2 + 8;
+
+/// FNV-1a, which is stable across builds unlike `DefaultHasher`.
+pub fn hash(bytes: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for b in bytes {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h
+}
+
+/// What a parse of `input` is cached under. Another version of the parser
+/// can read the same source differently, so the version is hashed too.
+pub fn source_hash(input: &str) -> u64 {
+    let mut bytes = env!("CARGO_PKG_VERSION").as_bytes().to_vec();
+    bytes.push(0);
+    bytes.extend_from_slice(input.as_bytes());
+    hash(&bytes)
+}
+
+/// Whether `dir` is a directory that only the current user can write to.
+/// Anyone else who could would get to choose the programs it runs.
+#[cfg(unix)]
+fn private(dir: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    // not following links, so one can't point somewhere else
+    match std::fs::symlink_metadata(dir) {
+        // SAFETY: geteuid can't fail and has no preconditions
+        Ok(m) => m.is_dir() && m.uid() == unsafe { libc::geteuid() } && m.mode() & 0o022 == 0,
+        Err(_) => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn private(dir: &Path) -> bool {
+    dir.is_dir()
+}
+
+/// Cached parses of source files, one file per source hash.
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    pub fn new(dir: PathBuf) -> Cache {
+        Cache { dir }
+    }
+
+    /// `$PLMMKY_CACHE_DIR`, or `plmmky` in the user's cache directory,
+    /// which is `$XDG_CACHE_HOME` or else `~/.cache`. There's none when
+    /// neither is set.
+    pub fn default_dir() -> Option<PathBuf> {
+        if let Some(dir) = std::env::var_os("PLMMKY_CACHE_DIR") {
+            return Some(PathBuf::from(dir));
+        }
+        // relative paths in these are to be ignored
+        let absolute = |p: &PathBuf| p.is_absolute();
+        let base = std::env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .filter(absolute)
+            .or_else(|| {
+                std::env::var_os("HOME")
+                    .map(|home| PathBuf::from(home).join(".cache"))
+                    .filter(absolute)
+            })?;
+        Some(base.join("plmmky"))
+    }
+
+    fn entry(&self, source_hash: u64) -> PathBuf {
+        self.dir.join(format!("{:016x}.ast", source_hash))
+    }
+
+    /// The cached parse of `input`, if there is a valid one. Locations in it
+    /// point into `path`. A directory other users can write to is ignored.
+    pub fn load<'a>(&self, input: &str, path: &'a Path) -> Option<Program<'a>> {
+        if !private(&self.dir) {
+            return None;
+        }
+        let source_hash = source_hash(input);
+        let bytes = std::fs::read(self.entry(source_hash)).ok()?;
+        decode(&bytes, source_hash, path).ok()
+    }
+
+    pub fn store(&self, input: &str, program: &Program) -> std::io::Result<()> {
+        let source_hash = source_hash(input);
+        let mut builder = std::fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        builder.create(&self.dir)?;
+        if !private(&self.dir) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!("{} isn't private to this user", self.dir.display()),
+            ));
+        }
+
+        // write then rename, so a reader never sees half a file
+        let entry = self.entry(source_hash);
+        let tmp = entry.with_extension(format!("tmp{}", std::process::id()));
+        std::fs::write(&tmp, encode(program, source_hash))?;
+        std::fs::rename(tmp, entry)
+    }
+}
+
+pub fn encode(program: &Program, source_hash: u64) -> Vec<u8> {
+    let mut e = Encoder::default();
+    e.varint(program.statements.len());
+    for stmt in &program.statements {
+        e.statement(stmt);
+    }
+
+    let mut out = Vec::with_capacity(HEADER_LEN + e.out.len() + 8);
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    out.extend_from_slice(&source_hash.to_le_bytes());
+
+    write_varint(&mut out, e.strings.len());
+    for s in &e.strings {
+        write_varint(&mut out, s.len());
+        out.extend_from_slice(s.as_bytes());
+    }
+    out.extend_from_slice(&e.out);
+
+    let checksum = hash(&out);
+    out.extend_from_slice(&checksum.to_le_bytes());
+    out
+}
+
+/// Decodes a cache file, checking that it's intact and was made from the
+/// source with `source_hash`.
+pub fn decode<'a>(bytes: &[u8], source_hash: u64, path: &'a Path) -> Result<Program<'a>, String> {
+    if bytes.len() < HEADER_LEN + 8 {
+        return Err(String::from("truncated cache file"));
+    }
+
+    let (body, checksum) = bytes.split_at(bytes.len() - 8);
+    if hash(body).to_le_bytes() != checksum {
+        return Err(String::from("cache checksum mismatch"));
+    }
+
+    if &body[..MAGIC.len()] != MAGIC {
+        return Err(String::from("not a cache file"));
+    }
+
+    let mut d = Decoder {
+        bytes: body,
+        pos: MAGIC.len(),
+        strings: Vec::new(),
+        path,
+    };
+
+    let version = u16::from_le_bytes([d.u8()?, d.u8()?]);
+    if version != FORMAT_VERSION {
+        return Err(format!("unsupported cache format version {}", version));
+    }
+
+    let mut hash_bytes = [0; 8];
+    for b in hash_bytes.iter_mut() {
+        *b = d.u8()?;
+    }
+    if u64::from_le_bytes(hash_bytes) != source_hash {
+        return Err(String::from("cache is for different source"));
+    }
+
+    for _ in 0..d.varint()? {
+        let len = d.varint()?;
+        let s = d.take(len)?;
+        let s = std::str::from_utf8(s).map_err(|_| String::from("invalid string in cache"))?;
+        d.strings.push(s.to_string());
+    }
+
+    let mut program = Program::new();
+    for _ in 0..d.varint()? {
+        program.statements.push(d.statement()?);
+    }
+
+    if d.pos != body.len() {
+        return Err(String::from("trailing bytes in cache file"));
+    }
+
+    Ok(program)
+}
+
+fn write_varint(out: &mut Vec<u8>, mut v: usize) {
+    loop {
+        let byte = (v & 0x7f) as u8;
+        v >>= 7;
+        if v == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+// Tags for the node stream. Only ever add new ones.
+const STMT_LET: u8 = 0;
+const STMT_RETURN: u8 = 1;
+const STMT_EXPRESSION: u8 = 2;
+const STMT_IMPORT: u8 = 3;
+const STMT_WHILE: u8 = 4;
+const STMT_FOR: u8 = 5;
+const STMT_BREAK: u8 = 6;
+const STMT_CONTINUE: u8 = 7;
+const STMT_THROW: u8 = 8;
+
+const EXPR_IDENTIFIER: u8 = 0;
+const EXPR_INTEGER: u8 = 1;
+const EXPR_BOOLEAN: u8 = 2;
+const EXPR_PREFIX: u8 = 3;
+const EXPR_INFIX: u8 = 4;
+const EXPR_IF: u8 = 5;
+const EXPR_FUNCTION: u8 = 6;
+const EXPR_CALL: u8 = 7;
+const EXPR_MEMBER: u8 = 8;
+const EXPR_ASSIGN: u8 = 9;
+const EXPR_STRING: u8 = 10;
+const EXPR_TRY: u8 = 11;
+const EXPR_MACRO: u8 = 12;
+const EXPR_ARRAY: u8 = 13;
+const EXPR_HASH: u8 = 14;
+const EXPR_INDEX: u8 = 15;
+const EXPR_INDEX_ASSIGN: u8 = 16;
+
+const TOKEN_IDENT: u8 = 0;
+const TOKEN_INT: u8 = 1;
+/// Well clear of the tags `TOKEN_KINDS` uses.
+const TOKEN_STRING: u8 = 255;
+
+/// The rest of the token kinds, tagged by their index plus two.
+const TOKEN_KINDS: [TokenKind; 56] = [
+    TokenKind::ILLEGAL,
+    TokenKind::EOF,
+    TokenKind::ASSIGN,
+    TokenKind::PLUS,
+    TokenKind::MINUS,
+    TokenKind::BANG,
+    TokenKind::ASTERISK,
+    TokenKind::SLASH,
+    TokenKind::LT,
+    TokenKind::GT,
+    TokenKind::EQ,
+    TokenKind::NEQ,
+    TokenKind::COMMA,
+    TokenKind::SEMICOLON,
+    TokenKind::LPAREN,
+    TokenKind::RPAREN,
+    TokenKind::LBRACE,
+    TokenKind::RBRACE,
+    TokenKind::FUNCTION,
+    TokenKind::LET,
+    TokenKind::TRUE,
+    TokenKind::FALSE,
+    TokenKind::IF,
+    TokenKind::ELSE,
+    TokenKind::RETURN,
+    TokenKind::DOT,
+    TokenKind::IMPORT,
+    TokenKind::DOTDOT,
+    TokenKind::WHILE,
+    TokenKind::FOR,
+    TokenKind::IN,
+    TokenKind::BREAK,
+    TokenKind::CONTINUE,
+    TokenKind::PLUSEQ,
+    TokenKind::MINUSEQ,
+    TokenKind::ASTERISKEQ,
+    TokenKind::SLASHEQ,
+    TokenKind::PERCENT,
+    TokenKind::LTE,
+    TokenKind::GTE,
+    TokenKind::AND,
+    TokenKind::OR,
+    TokenKind::AMPERSAND,
+    TokenKind::PIPE,
+    TokenKind::CARET,
+    TokenKind::TILDE,
+    TokenKind::SHL,
+    TokenKind::SHR,
+    TokenKind::TRY,
+    TokenKind::CATCH,
+    TokenKind::FINALLY,
+    TokenKind::THROW,
+    TokenKind::MACRO,
+    TokenKind::LBRACKET,
+    TokenKind::RBRACKET,
+    TokenKind::COLON,
+];
+
+#[derive(Default)]
+struct Encoder {
+    out: Vec<u8>,
+    strings: Vec<String>,
+    interned: HashMap<String, usize>,
+}
+
+impl Encoder {
+    fn varint(&mut self, v: usize) {
+        write_varint(&mut self.out, v);
+    }
+
+    fn string(&mut self, s: &str) {
+        let idx = match self.interned.get(s) {
+            Some(idx) => *idx,
+            None => {
+                self.strings.push(s.to_string());
+                self.interned.insert(s.to_string(), self.strings.len() - 1);
+                self.strings.len() - 1
+            }
+        };
+        self.varint(idx);
+    }
+
+    fn token(&mut self, t: &Token) {
+        match &t.ttype {
+            TokenKind::IDENT(s) => {
+                self.out.push(TOKEN_IDENT);
+                self.string(s);
+            }
+            TokenKind::INT(i) => {
+                self.out.push(TOKEN_INT);
+                self.out.extend_from_slice(&i.to_le_bytes());
+            }
+            TokenKind::STRING(s) => {
+                self.out.push(TOKEN_STRING);
+                self.string(s);
+            }
+            kind => {
+                let tag = TOKEN_KINDS
+                    .iter()
+                    .position(|k| k == kind)
+                    .expect("every other kind is listed");
+                self.out.push(tag as u8 + 2);
+            }
+        }
+
+        // spans are stored one higher so that 0 can mean there isn't one
+        match t.local() {
+            Some(l) => {
+                let (row, col, _) = l.get();
+                self.varint(row + 1);
+                self.varint(*col);
+            }
+            None => self.varint(0),
+        }
+    }
+
+    fn identifier(&mut self, i: &Identifier) {
+        self.token(i.token());
+        self.string(i.value());
+    }
+
+    fn flag(&mut self, present: bool) {
+        self.out.push(present as u8);
+    }
+
+    fn statement(&mut self, stmt: &Statement) {
+        ast::deeper(|| match stmt {
+            Statement::Let(i) => {
+                self.out.push(STMT_LET);
+                self.token(i.token());
+                self.flag(i.name().is_some());
+                if let Some(name) = i.name() {
+                    self.identifier(name);
+                }
+                self.optional(i.value());
+            }
+            Statement::Return(i) => {
+                self.out.push(STMT_RETURN);
+                self.token(i.token());
+                self.optional(i.return_value());
+            }
+            Statement::Expression(i) => {
+                self.out.push(STMT_EXPRESSION);
+                self.token(i.token());
+                self.optional(i.expression());
+            }
+            Statement::Import(i) => {
+                self.out.push(STMT_IMPORT);
+                self.token(i.token());
+                self.string(i.path());
+                self.identifier(i.name());
+            }
+            Statement::While(i) => {
+                self.out.push(STMT_WHILE);
+                self.token(i.token());
+                self.expression(i.condition());
+                self.block(i.body());
+            }
+            Statement::For(i) => {
+                self.out.push(STMT_FOR);
+                self.token(i.token());
+                self.identifier(i.variable());
+                self.expression(i.start());
+                self.optional(i.end());
+                self.block(i.body());
+            }
+            Statement::Break(t) => {
+                self.out.push(STMT_BREAK);
+                self.token(t);
+            }
+            Statement::Continue(t) => {
+                self.out.push(STMT_CONTINUE);
+                self.token(t);
+            }
+            Statement::Throw(i) => {
+                self.out.push(STMT_THROW);
+                self.token(i.token());
+                self.expression(i.value());
+            }
+        })
+    }
+
+    fn optional(&mut self, e: Option<&Expression>) {
+        self.flag(e.is_some());
+        if let Some(e) = e {
+            self.expression(e);
+        }
+    }
+
+    fn block(&mut self, block: &Block) {
+        self.token(block.token());
+        self.varint(block.statements.len());
+        for stmt in &block.statements {
+            self.statement(stmt);
+        }
+    }
+
+    fn expression(&mut self, e: &Expression) {
+        ast::deeper(|| match e {
+            Expression::Identifier(i) => {
+                self.out.push(EXPR_IDENTIFIER);
+                self.identifier(i);
+            }
+            Expression::Integer(i) => {
+                self.out.push(EXPR_INTEGER);
+                self.token(i.token());
+                self.out.extend_from_slice(&i.value().to_le_bytes());
+            }
+            Expression::Boolean(i) => {
+                self.out.push(EXPR_BOOLEAN);
+                self.token(i.token());
+                self.flag(i.value());
+            }
+            Expression::Prefix(i) => {
+                self.out.push(EXPR_PREFIX);
+                self.token(i.token());
+                self.string(i.operator());
+                self.expression(i.right());
+            }
+            Expression::Infix(i) => {
+                self.out.push(EXPR_INFIX);
+                self.token(i.token());
+                self.expression(i.left());
+                self.string(i.operator());
+                self.expression(i.right());
+            }
+            Expression::If(i) => {
+                self.out.push(EXPR_IF);
+                self.token(i.token());
+                self.expression(i.condition());
+                self.block(i.consequence());
+                self.flag(i.alternative().is_some());
+                if let Some(alt) = i.alternative() {
+                    self.block(alt);
+                }
+            }
+            Expression::Function(i) | Expression::Macro(i) => {
+                self.out.push(match e {
+                    Expression::Macro(_) => EXPR_MACRO,
+                    _ => EXPR_FUNCTION,
+                });
+                self.token(i.token());
+                self.varint(i.parameters().len());
+                for p in i.parameters() {
+                    self.identifier(p);
+                }
+                self.block(i.body());
+            }
+            Expression::Call(i) => {
+                self.out.push(EXPR_CALL);
+                self.token(i.token());
+                self.expression(i.function());
+                self.varint(i.arguments().len());
+                for arg in i.arguments() {
+                    self.expression(arg);
+                }
+            }
+            Expression::Member(i) => {
+                self.out.push(EXPR_MEMBER);
+                self.token(i.token());
+                self.expression(i.object());
+                self.identifier(i.name());
+            }
+            Expression::Array(i) => {
+                self.out.push(EXPR_ARRAY);
+                self.token(i.token());
+                self.varint(i.elements().len());
+                for element in i.elements() {
+                    self.expression(element);
+                }
+            }
+            Expression::Hash(i) => {
+                self.out.push(EXPR_HASH);
+                self.token(i.token());
+                self.varint(i.pairs().len());
+                for (key, value) in i.pairs() {
+                    self.expression(key);
+                    self.expression(value);
+                }
+            }
+            Expression::Index(i) => {
+                self.out.push(EXPR_INDEX);
+                self.token(i.token());
+                self.expression(i.left());
+                self.expression(i.index());
+            }
+            Expression::IndexAssign(i) => {
+                self.out.push(EXPR_INDEX_ASSIGN);
+                self.token(i.token());
+                self.token(i.target().token());
+                self.expression(i.target().left());
+                self.expression(i.target().index());
+                self.expression(i.value());
+            }
+            Expression::Assign(i) => {
+                self.out.push(EXPR_ASSIGN);
+                self.token(i.token());
+                self.identifier(i.name());
+                self.expression(i.value());
+            }
+            Expression::String(i) => {
+                self.out.push(EXPR_STRING);
+                self.token(i.token());
+            }
+            Expression::Try(i) => {
+                self.out.push(EXPR_TRY);
+                self.token(i.token());
+                self.block(i.body());
+                self.identifier(i.name());
+                self.block(i.handler());
+                self.flag(i.finally().is_some());
+                if let Some(finally) = i.finally() {
+                    self.block(finally);
+                }
+            }
+        })
+    }
+}
+
+struct Decoder<'b, 'a> {
+    bytes: &'b [u8],
+    pos: usize,
+    strings: Vec<String>,
+    path: &'a Path,
+}
+
+impl<'b, 'a> Decoder<'b, 'a> {
+    fn take(&mut self, n: usize) -> Result<&'b [u8], String> {
+        if self.bytes.len() - self.pos < n {
+            return Err(String::from("truncated cache file"));
+        }
+        let s = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(s)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn varint(&mut self) -> Result<usize, String> {
+        let mut v: usize = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift >= usize::BITS {
+                return Err(String::from("varint too long in cache file"));
+            }
+            v |= ((byte & 0x7f) as usize) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(v);
+            }
+            shift += 7;
+        }
+    }
+
+    fn i128(&mut self) -> Result<i128, String> {
+        let bytes = self.take(16)?;
+        let mut buf = [0; 16];
+        buf.copy_from_slice(bytes);
+        Ok(i128::from_le_bytes(buf))
+    }
+
+    fn flag(&mut self) -> Result<bool, String> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            b => Err(format!("invalid flag {} in cache file", b)),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let idx = self.varint()?;
+        match self.strings.get(idx) {
+            Some(s) => Ok(s.clone()),
+            None => Err(format!("string index {} out of range in cache file", idx)),
+        }
+    }
+
+    fn token(&mut self) -> Result<Token<'a>, String> {
+        let tag = self.u8()?;
+        let kind = match tag {
+            TOKEN_IDENT => TokenKind::IDENT(self.string()?),
+            TOKEN_INT => TokenKind::INT(self.i128()?),
+            TOKEN_STRING => TokenKind::STRING(self.string()?),
+            _ => match TOKEN_KINDS.get(tag as usize - 2) {
+                Some(k) => k.clone(),
+                None => return Err(format!("invalid token tag {} in cache file", tag)),
+            },
+        };
+
+        let local = match self.varint()? {
+            0 => None,
+            row => Some(Location::new(row - 1, self.varint()?, self.path)),
+        };
+
+        Ok(Token::new(kind, local))
+    }
+
+    fn identifier(&mut self) -> Result<Identifier<'a>, String> {
+        let token = self.token()?;
+        Ok(Identifier::new(token, self.string()?))
+    }
+
+    fn statement(&mut self) -> Result<Statement<'a>, String> {
+        ast::deeper(|| {
+            let tag = self.u8()?;
+            let token = self.token()?;
+
+            match tag {
+                STMT_LET => {
+                    let name = match self.flag()? {
+                        true => Some(self.identifier()?),
+                        false => None,
+                    };
+                    let value = self.optional()?;
+                    Ok(Statement::Let(LetInternal::new(token, name, value)))
+                }
+                STMT_RETURN => Ok(Statement::Return(ReturnInternal::init(
+                    token,
+                    self.optional()?,
+                ))),
+                STMT_EXPRESSION => Ok(Statement::Expression(ExpressionInternal::init(
+                    token,
+                    self.optional()?,
+                ))),
+                STMT_IMPORT => {
+                    let path = self.string()?;
+                    let name = self.identifier()?;
+                    Ok(Statement::Import(ImportInternal::new(token, path, name)))
+                }
+                STMT_WHILE => {
+                    let condition = self.expression()?;
+                    let body = self.block()?;
+                    Ok(Statement::While(WhileInternal::new(token, condition, body)))
+                }
+                STMT_FOR => {
+                    let variable = self.identifier()?;
+                    let start = self.expression()?;
+                    let end = self.optional()?;
+                    let body = self.block()?;
+                    Ok(Statement::For(ForInternal::new(
+                        token, variable, start, end, body,
+                    )))
+                }
+                STMT_BREAK => Ok(Statement::Break(token)),
+                STMT_CONTINUE => Ok(Statement::Continue(token)),
+                STMT_THROW => Ok(Statement::Throw(ThrowInternal::new(
+                    token,
+                    self.expression()?,
+                ))),
+                _ => Err(format!("invalid statement tag {} in cache file", tag)),
+            }
+        })
+    }
+
+    fn optional(&mut self) -> Result<Option<Expression<'a>>, String> {
+        match self.flag()? {
+            true => Ok(Some(self.expression()?)),
+            false => Ok(None),
+        }
+    }
+
+    fn block(&mut self) -> Result<Block<'a>, String> {
+        let token = self.token()?;
+        let mut statements = Vec::new();
+        for _ in 0..self.varint()? {
+            statements.push(self.statement()?);
+        }
+        Ok(Block::new(token, statements))
+    }
+
+    fn expression(&mut self) -> Result<Expression<'a>, String> {
+        ast::deeper(|| {
+            let tag = self.u8()?;
+
+            if tag == EXPR_IDENTIFIER {
+                return Ok(Expression::Identifier(self.identifier()?));
+            }
+
+            let token = self.token()?;
+            let e = match tag {
+                EXPR_INTEGER => Expression::Integer(IntegerInternal::new(token, self.i128()?)),
+                EXPR_BOOLEAN => Expression::Boolean(BooleanInternal::new(token, self.flag()?)),
+                EXPR_PREFIX => {
+                    let operator = self.string()?;
+                    Expression::Prefix(PrefixInternal::new(token, operator, self.expression()?))
+                }
+                EXPR_INFIX => {
+                    let left = self.expression()?;
+                    let operator = self.string()?;
+                    let right = self.expression()?;
+                    Expression::Infix(InfixInternal::new(token, left, operator, right))
+                }
+                EXPR_IF => {
+                    let condition = self.expression()?;
+                    let consequence = self.block()?;
+                    let alternative = match self.flag()? {
+                        true => Some(self.block()?),
+                        false => None,
+                    };
+                    Expression::If(IfInternal::new(token, condition, consequence, alternative))
+                }
+                EXPR_FUNCTION | EXPR_MACRO => {
+                    let mut parameters = Vec::new();
+                    for _ in 0..self.varint()? {
+                        parameters.push(self.identifier()?);
+                    }
+                    let function = FunctionInternal::new(token, parameters, self.block()?);
+                    match tag {
+                        EXPR_MACRO => Expression::Macro(function),
+                        _ => Expression::Function(function),
+                    }
+                }
+                EXPR_CALL => {
+                    let function = self.expression()?;
+                    let mut arguments = Vec::new();
+                    for _ in 0..self.varint()? {
+                        arguments.push(self.expression()?);
+                    }
+                    Expression::Call(CallInternal::new(token, function, arguments))
+                }
+                EXPR_MEMBER => {
+                    let object = self.expression()?;
+                    Expression::Member(MemberInternal::new(token, object, self.identifier()?))
+                }
+                EXPR_ARRAY => {
+                    let mut elements = Vec::new();
+                    for _ in 0..self.varint()? {
+                        elements.push(self.expression()?);
+                    }
+                    Expression::Array(ArrayInternal::new(token, elements))
+                }
+                EXPR_HASH => {
+                    let mut pairs = Vec::new();
+                    for _ in 0..self.varint()? {
+                        pairs.push((self.expression()?, self.expression()?));
+                    }
+                    Expression::Hash(HashInternal::new(token, pairs))
+                }
+                EXPR_INDEX => {
+                    let left = self.expression()?;
+                    Expression::Index(IndexInternal::new(token, left, self.expression()?))
+                }
+                EXPR_INDEX_ASSIGN => {
+                    let bracket = self.token()?;
+                    let left = self.expression()?;
+                    let target = IndexInternal::new(bracket, left, self.expression()?);
+                    Expression::IndexAssign(IndexAssignInternal::new(
+                        token,
+                        target,
+                        self.expression()?,
+                    ))
+                }
+                EXPR_ASSIGN => {
+                    let name = self.identifier()?;
+                    Expression::Assign(AssignInternal::new(token, name, self.expression()?))
+                }
+                EXPR_STRING => Expression::String(StringInternal::new(token)),
+                EXPR_TRY => {
+                    let body = self.block()?;
+                    let name = self.identifier()?;
+                    let handler = self.block()?;
+                    let finally = match self.flag()? {
+                        true => Some(self.block()?),
+                        false => None,
+                    };
+                    Expression::Try(Box::new(TryInternal::new(
+                        token, body, name, handler, finally,
+                    )))
+                }
+                _ => return Err(format!("invalid expression tag {} in cache file", tag)),
+            };
+
+            Ok(e)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    const INPUT: &str = "let fib = fn(n) {
+    if (n < 2) { return n; } else { fib(n - 1) + fib(-(-n) - 2) }
+};
+puts(fib(10) != 55, !true, 170141183460469231731687303715884105727 * 1);
+import \"lib/math.my\";
+math.max(1, 2);
+for (i in 0..3) { while (true) { if (i > 1) { break; } continue; } }
+let total = 0; total = 1; total += 2; total -= 1; total *= 3; total /= 2;
+total % 2 <= 1 && total >= 0 || false;
+~total & 255 | 1 ^ 2 << 3 >> 1;
+let n = try { throw \"bad\"; } catch (e) { e.message } finally { puts(n); };
+let unless = macro(c, a, b) { quote(if (!(unquote(c))) { unquote(a) } else { unquote(b) }) };
+let xs = [1, [2], {\"a\": 3, true: {}}][0]; for (x in xs) { push(xs, x); xs[0] += 1; }";
+
+    fn parse<'a>(input: &str, path: &'a Path) -> Program<'a> {
+        let l = Lexer::new(input, false, Some(path));
+        let mut p = Parser::new(l);
+        let program = p.parse_program().expect("Program should be Some here");
+        assert_eq!(p.errors().len(), 0);
+        program
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let path = Path::new("cache.my");
+        let program = parse(INPUT, path);
+        let source_hash = hash(INPUT.as_bytes());
+
+        let bytes = encode(&program, source_hash);
+        let decoded = decode(&bytes, source_hash, path).expect("should decode");
+
+        // Debug shows every token, so this compares the spans too
+        assert_eq!(
+            format!("{:?}", decoded.statements),
+            format!("{:?}", program.statements)
+        );
+
+        assert_eq!(
+            decode(&bytes, source_hash + 1, path).err(),
+            Some(String::from("cache is for different source"))
+        );
+    }
+
+    #[test]
+    fn test_damaged_files_are_rejected() {
+        let path = Path::new("cache.my");
+        let program = parse(INPUT, path);
+        let source_hash = hash(INPUT.as_bytes());
+        let bytes = encode(&program, source_hash);
+
+        for len in 0..bytes.len() {
+            assert!(decode(&bytes[..len], source_hash, path).is_err(), "{}", len);
+        }
+
+        for idx in 0..bytes.len() {
+            let mut tampered = bytes.clone();
+            tampered[idx] ^= 0x10;
+            assert!(decode(&tampered, source_hash, path).is_err(), "{}", idx);
+        }
+
+        // a well formed file from a future version
+        let mut future = bytes[..bytes.len() - 8].to_vec();
+        future[MAGIC.len()] = 99;
+        let checksum = hash(&future);
+        future.extend_from_slice(&checksum.to_le_bytes());
+        assert_eq!(
+            decode(&future, source_hash, path).err(),
+            Some(String::from("unsupported cache format version 99"))
+        );
+    }
+
+    #[test]
+    fn test_cache_dir() {
+        let dir = std::env::temp_dir().join(format!("plmmky-cache-test-{}", std::process::id()));
+        let cache = Cache::new(dir.clone());
+        let path = Path::new("cache.my");
+
+        assert!(cache.load(INPUT, path).is_none());
+
+        cache
+            .store(INPUT, &parse(INPUT, path))
+            .expect("should store");
+        let loaded = cache.load(INPUT, path).expect("should be cached");
+        assert_eq!(format!("{:?}", loaded), format!("{:?}", parse(INPUT, path)));
+
+        // a directory anyone can write to is as good as no cache
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let shared = std::fs::Permissions::from_mode(0o777);
+            std::fs::set_permissions(&dir, shared).expect("should change permissions");
+            assert!(cache.load(INPUT, path).is_none());
+            assert!(cache.store(INPUT, &parse(INPUT, path)).is_err());
+            let private = std::fs::Permissions::from_mode(0o700);
+            std::fs::set_permissions(&dir, private).expect("should change permissions");
+        }
+
+        // clobber the entry, which must then be ignored
+        let entry = cache.entry(source_hash(INPUT));
+        std::fs::write(&entry, b"PMKA garbage").expect("should write");
+        assert!(cache.load(INPUT, path).is_none());
+
+        std::fs::remove_dir_all(dir).expect("should clean up");
+    }
+}
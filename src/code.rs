@@ -0,0 +1,348 @@
+use std::fmt::Write;
+
+pub type Instructions = Vec<u8>;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Opcode {
+    Constant,
+    Pop,
+
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    BitAnd,
+    BitOr,
+    BitXor,
+    ShiftLeft,
+    ShiftRight,
+
+    True,
+    False,
+    Null,
+
+    Equal,
+    NotEqual,
+    GreaterThan,
+    GreaterEqual,
+
+    Minus,
+    Bang,
+    BitNot,
+
+    JumpNotTruthy,
+    Jump,
+
+    GetGlobal,
+    SetGlobal,
+    GetLocal,
+    SetLocal,
+    GetBuiltin,
+    GetFree,
+    MakeCell,
+    GetCell,
+    SetCell,
+
+    Call,
+    TailCall,
+    ReturnValue,
+    Return,
+
+    Closure,
+    CurrentClosure,
+
+    GetMember,
+
+    CheckRange,
+
+    SetHandler,
+    PopHandler,
+    Throw,
+
+    Array,
+    Hash,
+    Index,
+    Next,
+    DupPair,
+    SetIndex,
+}
+
+/// Every opcode, indexed by its byte.
+const OPCODES: [Opcode; 50] = [
+    Opcode::Constant,
+    Opcode::Pop,
+    Opcode::Add,
+    Opcode::Sub,
+    Opcode::Mul,
+    Opcode::Div,
+    Opcode::Mod,
+    Opcode::BitAnd,
+    Opcode::BitOr,
+    Opcode::BitXor,
+    Opcode::ShiftLeft,
+    Opcode::ShiftRight,
+    Opcode::True,
+    Opcode::False,
+    Opcode::Null,
+    Opcode::Equal,
+    Opcode::NotEqual,
+    Opcode::GreaterThan,
+    Opcode::GreaterEqual,
+    Opcode::Minus,
+    Opcode::Bang,
+    Opcode::BitNot,
+    Opcode::JumpNotTruthy,
+    Opcode::Jump,
+    Opcode::GetGlobal,
+    Opcode::SetGlobal,
+    Opcode::GetLocal,
+    Opcode::SetLocal,
+    Opcode::GetBuiltin,
+    Opcode::GetFree,
+    Opcode::MakeCell,
+    Opcode::GetCell,
+    Opcode::SetCell,
+    Opcode::Call,
+    Opcode::TailCall,
+    Opcode::ReturnValue,
+    Opcode::Return,
+    Opcode::Closure,
+    Opcode::CurrentClosure,
+    Opcode::GetMember,
+    Opcode::CheckRange,
+    Opcode::SetHandler,
+    Opcode::PopHandler,
+    Opcode::Throw,
+    Opcode::Array,
+    Opcode::Hash,
+    Opcode::Index,
+    Opcode::Next,
+    Opcode::DupPair,
+    Opcode::SetIndex,
+];
+
+pub struct Definition {
+    pub name: &'static str,
+    /// Width in bytes of each operand.
+    pub operand_widths: &'static [usize],
+}
+
+impl Opcode {
+    pub fn from_byte(b: u8) -> Option<Opcode> {
+        OPCODES.get(b as usize).copied()
+    }
+
+    pub fn definition(&self) -> Definition {
+        let (name, operand_widths): (&'static str, &'static [usize]) = match self {
+            Opcode::Constant => ("OpConstant", &[2]),
+            Opcode::Pop => ("OpPop", &[]),
+            Opcode::Add => ("OpAdd", &[]),
+            Opcode::Sub => ("OpSub", &[]),
+            Opcode::Mul => ("OpMul", &[]),
+            Opcode::Div => ("OpDiv", &[]),
+            Opcode::Mod => ("OpMod", &[]),
+            Opcode::BitAnd => ("OpBitAnd", &[]),
+            Opcode::BitOr => ("OpBitOr", &[]),
+            Opcode::BitXor => ("OpBitXor", &[]),
+            Opcode::ShiftLeft => ("OpShiftLeft", &[]),
+            Opcode::ShiftRight => ("OpShiftRight", &[]),
+            Opcode::True => ("OpTrue", &[]),
+            Opcode::False => ("OpFalse", &[]),
+            Opcode::Null => ("OpNull", &[]),
+            Opcode::Equal => ("OpEqual", &[]),
+            Opcode::NotEqual => ("OpNotEqual", &[]),
+            Opcode::GreaterThan => ("OpGreaterThan", &[]),
+            Opcode::GreaterEqual => ("OpGreaterEqual", &[]),
+            Opcode::Minus => ("OpMinus", &[]),
+            Opcode::Bang => ("OpBang", &[]),
+            Opcode::BitNot => ("OpBitNot", &[]),
+            Opcode::JumpNotTruthy => ("OpJumpNotTruthy", &[2]),
+            Opcode::Jump => ("OpJump", &[2]),
+            Opcode::GetGlobal => ("OpGetGlobal", &[2]),
+            Opcode::SetGlobal => ("OpSetGlobal", &[2]),
+            Opcode::GetLocal => ("OpGetLocal", &[1]),
+            Opcode::SetLocal => ("OpSetLocal", &[1]),
+            Opcode::GetBuiltin => ("OpGetBuiltin", &[1]),
+            Opcode::GetFree => ("OpGetFree", &[1]),
+            // puts the local in a cell, which closures share with the
+            // function instead of copying the value
+            Opcode::MakeCell => ("OpMakeCell", &[1]),
+            // replaces the cell on top of the stack with what's in it
+            Opcode::GetCell => ("OpGetCell", &[]),
+            // pops a cell, then stores the value under it in the cell
+            Opcode::SetCell => ("OpSetCell", &[]),
+            Opcode::Call => ("OpCall", &[1]),
+            // a call whose result is returned straight away, which takes
+            // over the caller's frame
+            Opcode::TailCall => ("OpTailCall", &[1]),
+            Opcode::ReturnValue => ("OpReturnValue", &[]),
+            Opcode::Return => ("OpReturn", &[]),
+            // constant index of the function, number of free variables
+            Opcode::Closure => ("OpClosure", &[2, 1]),
+            Opcode::CurrentClosure => ("OpCurrentClosure", &[]),
+            // index of the name in `Bytecode::members`
+            Opcode::GetMember => ("OpGetMember", &[2]),
+            // fails unless the two values on top of the stack, the bounds
+            // of a `for` loop, are integers, and leaves them there
+            Opcode::CheckRange => ("OpCheckRange", &[]),
+            // where to go with the error when one happens before the
+            // matching `OpPopHandler`
+            Opcode::SetHandler => ("OpSetHandler", &[2]),
+            Opcode::PopHandler => ("OpPopHandler", &[]),
+            // fails with the value on top of the stack
+            Opcode::Throw => ("OpThrow", &[]),
+            // number of elements, which are on top of the stack in order
+            Opcode::Array => ("OpArray", &[2]),
+            // number of pairs, each a key then its value
+            Opcode::Hash => ("OpHash", &[2]),
+            // pops the index, then what's indexed
+            Opcode::Index => ("OpIndex", &[]),
+            // pops a position, then the array or hash a `for` goes over,
+            // and pushes what's there, or jumps to the operand past the end
+            Opcode::Next => ("OpNext", &[2]),
+            // pushes the top two values again, in the same order
+            Opcode::DupPair => ("OpDupPair", &[]),
+            // pops the value, the index, then what's indexed, and pushes
+            // the value back once it's stored
+            Opcode::SetIndex => ("OpSetIndex", &[]),
+        };
+
+        Definition {
+            name,
+            operand_widths,
+        }
+    }
+}
+
+/// Encodes one instruction. Operands are big endian.
+pub fn make(op: Opcode, operands: &[usize]) -> Instructions {
+    let def = op.definition();
+    let len = 1 + def.operand_widths.iter().sum::<usize>();
+
+    let mut ins = Vec::with_capacity(len);
+    ins.push(op as u8);
+
+    for (operand, width) in operands.iter().zip(def.operand_widths) {
+        match width {
+            2 => ins.extend_from_slice(&(*operand as u16).to_be_bytes()),
+            1 => ins.push(*operand as u8),
+            _ => unreachable!("no operand is {} bytes wide", width),
+        }
+    }
+
+    ins
+}
+
+pub fn read_u16(ins: &[u8]) -> u16 {
+    u16::from_be_bytes([ins[0], ins[1]])
+}
+
+pub fn read_u8(ins: &[u8]) -> u8 {
+    ins[0]
+}
+
+/// Decodes the operands following an opcode, returning them with the
+/// number of bytes they took up.
+pub fn read_operands(def: &Definition, ins: &[u8]) -> (Vec<usize>, usize) {
+    let mut operands = Vec::with_capacity(def.operand_widths.len());
+    let mut offset = 0;
+
+    for width in def.operand_widths {
+        match width {
+            2 => operands.push(read_u16(&ins[offset..]) as usize),
+            1 => operands.push(read_u8(&ins[offset..]) as usize),
+            _ => unreachable!("no operand is {} bytes wide", width),
+        }
+        offset += width;
+    }
+
+    (operands, offset)
+}
+
+/// One instruction per line, prefixed with its offset.
+pub fn disassemble(ins: &[u8]) -> String {
+    let mut out = String::new();
+    let mut i = 0;
+
+    while i < ins.len() {
+        let op = match Opcode::from_byte(ins[i]) {
+            Some(op) => op,
+            None => {
+                let _ = writeln!(out, "ERROR: unknown opcode {}", ins[i]);
+                i += 1;
+                continue;
+            }
+        };
+
+        let def = op.definition();
+        let (operands, read) = read_operands(&def, &ins[i + 1..]);
+
+        let _ = write!(out, "{:04} {}", i, def.name);
+        for operand in operands {
+            let _ = write!(out, " {}", operand);
+        }
+        out.push('\n');
+
+        i += 1 + read;
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_make_and_read_operands() {
+        let tests: [(Opcode, &[usize], &[u8]); 4] = [
+            (
+                Opcode::Constant,
+                &[65534],
+                &[Opcode::Constant as u8, 255, 254],
+            ),
+            (Opcode::Add, &[], &[Opcode::Add as u8]),
+            (Opcode::GetLocal, &[255], &[Opcode::GetLocal as u8, 255]),
+            (
+                Opcode::Closure,
+                &[65534, 255],
+                &[Opcode::Closure as u8, 255, 254, 255],
+            ),
+        ];
+
+        for (op, operands, expected) in tests {
+            let ins = make(op, operands);
+            assert_eq!(ins, expected);
+
+            let (read, n) = read_operands(&op.definition(), &ins[1..]);
+            assert_eq!(n, ins.len() - 1);
+            assert_eq!(read, operands);
+        }
+
+        for (idx, op) in OPCODES.iter().enumerate() {
+            assert_eq!(*op as usize, idx);
+        }
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let ins = [
+            make(Opcode::Add, &[]),
+            make(Opcode::GetLocal, &[1]),
+            make(Opcode::Constant, &[2]),
+            make(Opcode::Constant, &[65535]),
+            make(Opcode::Closure, &[65535, 255]),
+        ]
+        .concat();
+
+        let expected = "0000 OpAdd\n\
+            0001 OpGetLocal 1\n\
+            0003 OpConstant 2\n\
+            0006 OpConstant 65535\n\
+            0009 OpClosure 65535 255\n";
+
+        assert_eq!(disassemble(&ins), expected);
+    }
+}
@@ -0,0 +1,1324 @@
+use crate::ast::{
+    self, Block, CallInternal, Expression, ForInternal, FunctionInternal, IfInternal,
+    ImportInternal, Program, Statement, TryInternal,
+};
+use crate::code::{self, Instructions, Opcode};
+use crate::error::Site;
+use crate::macros;
+use crate::module::Modules;
+use crate::object::{self, CompiledFunction, Object, BUILTINS};
+use crate::symbol_table::{self, Symbol, SymbolScope, SymbolTable};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+pub struct Bytecode {
+    pub instructions: Instructions,
+    pub constants: Vec<Object>,
+    /// Names of the globals by slot, for error messages.
+    pub globals: Vec<String>,
+    /// The names `OpGetMember` looks up, by its operand.
+    pub members: Vec<String>,
+    /// Where the main instructions that can fail came from, like
+    /// `CompiledFunction::sites`.
+    pub sites: Vec<(usize, Site)>,
+}
+
+#[derive(Clone, Copy)]
+struct EmittedInstruction {
+    opcode: Opcode,
+    position: usize,
+}
+
+#[derive(Default)]
+struct CompilationScope {
+    instructions: Instructions,
+    last: Option<EmittedInstruction>,
+    previous: Option<EmittedInstruction>,
+    sites: Vec<(usize, Site)>,
+    /// The `try`s being compiled in this function, innermost last.
+    tries: Vec<Try>,
+}
+
+/// A loop being compiled, with the jumps its `break` and `continue`
+/// statements leave to be patched.
+struct Loop {
+    /// Values on the stack when the loop started. A `break` in the middle of
+    /// an expression pops back down to this.
+    temporaries: usize,
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
+}
+
+/// The ways out of a block other than reaching its end or failing.
+#[derive(Clone, Copy, PartialEq)]
+enum Exit {
+    Return,
+    Break,
+    Continue,
+}
+
+/// How a `finally` block was reached, which is kept in a hidden slot so
+/// that the code after it can carry on the same way.
+const REACHED_END: i128 = 0;
+const RETHROWING: i128 = 1;
+
+impl Exit {
+    fn code(self) -> i128 {
+        match self {
+            Exit::Return => 2,
+            Exit::Break => 3,
+            Exit::Continue => 4,
+        }
+    }
+}
+
+/// Which block of a `try` is being compiled. A handler is active in the
+/// body, and in the `catch` block when there's a `finally`.
+#[derive(Clone, Copy, PartialEq)]
+enum TryPart {
+    Body,
+    Catch,
+    Finally,
+}
+
+/// A `try` being compiled, which a `return`, `break` or `continue` inside
+/// it has to leave properly.
+struct Try {
+    part: TryPart,
+    /// Loops already being compiled when it started, which a `break` in it
+    /// jumps out of it to.
+    loops: usize,
+    /// Values on the stack when it started.
+    temporaries: usize,
+    /// For a `try` with a `finally`, the hidden slots that keep the value
+    /// it's leaving with and how it's leaving while the `finally` runs.
+    slots: Option<(Symbol, Symbol)>,
+    /// The jumps to the `finally` block, and the exits they were taking.
+    finally_jumps: Vec<usize>,
+    exits: Vec<Exit>,
+}
+
+pub struct Compiler {
+    constants: Vec<Object>,
+    symbols: SymbolTable,
+    scopes: Vec<CompilationScope>,
+    members: Vec<String>,
+    loops: Vec<Loop>,
+    /// Operands on the stack waiting for the rest of their expression.
+    temporaries: usize,
+
+    modules: Modules,
+    /// The file being compiled, which imports are relative to.
+    file: Option<PathBuf>,
+    /// Modules whose code has been compiled, by `module::Module::id`.
+    loaded: HashMap<usize, Rc<object::Module>>,
+    /// Globals the host defined, which modules can see too.
+    shared: Vec<String>,
+}
+
+impl Default for Compiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Compiler {
+    pub fn new() -> Compiler {
+        let mut symbols = SymbolTable::new();
+        for (idx, builtin) in BUILTINS.iter().enumerate() {
+            symbols.define_builtin(idx, builtin.name);
+        }
+
+        Compiler {
+            constants: Vec::new(),
+            symbols,
+            scopes: vec![CompilationScope::default()],
+            members: Vec::new(),
+            loops: Vec::new(),
+            temporaries: 0,
+            modules: Modules::new(),
+            file: None,
+            loaded: HashMap::new(),
+            shared: Vec::new(),
+        }
+    }
+
+    /// Sets the file the next `compile` is of, which its imports are
+    /// relative to. Without one they're relative to the current directory.
+    pub fn set_file(&mut self, file: Option<&Path>) {
+        self.file = file.map(Path::to_path_buf);
+    }
+
+    /// Compiles `program` onto the end of the main instructions. The symbol
+    /// table and constants are kept between calls, which is what lets the
+    /// repl compile one line at a time.
+    ///
+    /// An imported module is compiled inline where it's first imported, so
+    /// it runs there, once.
+    pub fn compile(&mut self, program: &Program) -> Result<(), String> {
+        self.modules.load(program, self.file.as_deref())?;
+
+        // a failed compile throws its instructions away, modules included
+        let loaded = self.loaded.clone();
+        let compiled = self.statements(&program.statements);
+        if compiled.is_err() {
+            self.loaded = loaded;
+        }
+        compiled
+    }
+
+    fn statements(&mut self, statements: &[Statement]) -> Result<(), String> {
+        // top level functions can call each other regardless of order, so
+        // every global gets its slot before anything refers to it
+        for stmt in statements {
+            let name = match stmt {
+                Statement::Let(i) => i.name(),
+                Statement::Import(i) => Some(i.name()),
+                _ => None,
+            };
+            if let Some(name) = name {
+                self.symbols.define(name.value());
+            }
+        }
+
+        for stmt in statements {
+            if let Err(e) = self.statement(stmt) {
+                // leave any function we were in, so the next compile starts
+                // from the top level again
+                self.scopes.truncate(1);
+                self.scope().tries.clear();
+                while !self.symbols.is_global() {
+                    self.symbols.pop();
+                }
+                self.loops.clear();
+                self.temporaries = 0;
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Defines the global `name`, or finds it if it exists, returning its
+    /// slot. Hosts use this to set globals before compiling the code that
+    /// reads them.
+    pub fn define_global(&mut self, name: &str) -> usize {
+        if !self.shared.iter().any(|s| s == name) {
+            self.shared.push(name.to_string());
+        }
+        self.symbols.define(name).index
+    }
+
+    /// The slot of the global `name`, if it has been defined.
+    pub fn global(&self, name: &str) -> Option<usize> {
+        self.symbols.global(name)
+    }
+
+    pub fn bytecode(&self) -> Bytecode {
+        Bytecode {
+            instructions: self.scopes[0].instructions.clone(),
+            constants: self.constants.clone(),
+            globals: self.symbols.global_names(),
+            members: self.members.clone(),
+            sites: self.scopes[0].sites.clone(),
+        }
+    }
+
+    /// Like `bytecode`, but hands over the main instructions so the next
+    /// `compile` starts from scratch.
+    pub fn take_bytecode(&mut self) -> Bytecode {
+        let scope = std::mem::take(&mut self.scopes[0]);
+        Bytecode {
+            instructions: scope.instructions,
+            constants: self.constants.clone(),
+            globals: self.symbols.global_names(),
+            members: self.members.clone(),
+            sites: scope.sites,
+        }
+    }
+
+    fn scope(&mut self) -> &mut CompilationScope {
+        self.scopes
+            .last_mut()
+            .expect("there is always a main scope")
+    }
+
+    fn add_constant(&mut self, obj: Object) -> usize {
+        self.constants.push(obj);
+        self.constants.len() - 1
+    }
+
+    fn emit(&mut self, op: Opcode, operands: &[usize]) -> usize {
+        let ins = code::make(op, operands);
+        let scope = self.scope();
+        let position = scope.instructions.len();
+        scope.instructions.extend(ins);
+
+        scope.previous = scope.last;
+        scope.last = Some(EmittedInstruction {
+            opcode: op,
+            position,
+        });
+        position
+    }
+
+    /// Notes that the instruction just emitted can fail, and came from
+    /// `site`.
+    fn mark(&mut self, site: Site) {
+        let scope = self.scope();
+        scope.sites.push((scope.instructions.len(), site));
+    }
+
+    fn last_is(&mut self, op: Opcode) -> bool {
+        self.scope().last.is_some_and(|l| l.opcode == op)
+    }
+
+    fn remove_last_pop(&mut self) {
+        let scope = self.scope();
+        if let Some(last) = scope.last {
+            scope.instructions.truncate(last.position);
+            scope.last = scope.previous;
+        }
+    }
+
+    fn replace_last_pop_with_return(&mut self) {
+        let scope = self.scope();
+        if let Some(last) = scope.last.as_mut() {
+            scope.instructions[last.position] = Opcode::ReturnValue as u8;
+            last.opcode = Opcode::ReturnValue;
+        }
+    }
+
+    /// Points the jump at `position` to `target`.
+    fn change_operand(&mut self, position: usize, target: usize) {
+        let scope = self.scope();
+        let op = Opcode::from_byte(scope.instructions[position]).expect("we emitted this");
+        let ins = code::make(op, &[target]);
+        scope.instructions[position..position + ins.len()].copy_from_slice(&ins);
+    }
+
+    fn load_symbol(&mut self, s: &Symbol) {
+        self.load_slot(s);
+        if s.cell {
+            self.emit(Opcode::GetCell, &[]);
+        }
+    }
+
+    /// Loads what's in `s`'s slot, which is the cell for a cell.
+    fn load_slot(&mut self, s: &Symbol) {
+        match s.scope {
+            SymbolScope::Global => self.emit(Opcode::GetGlobal, &[s.index]),
+            SymbolScope::Local => self.emit(Opcode::GetLocal, &[s.index]),
+            SymbolScope::Builtin => self.emit(Opcode::GetBuiltin, &[s.index]),
+            SymbolScope::Free => self.emit(Opcode::GetFree, &[s.index]),
+            SymbolScope::Function => self.emit(Opcode::CurrentClosure, &[]),
+        };
+    }
+
+    fn store_symbol(&mut self, s: &Symbol) {
+        if s.scope == SymbolScope::Global {
+            self.emit(Opcode::SetGlobal, &[s.index]);
+        } else if s.cell {
+            self.load_slot(s);
+            self.emit(Opcode::SetCell, &[]);
+        } else {
+            self.emit(Opcode::SetLocal, &[s.index]);
+        }
+    }
+
+    fn statement(&mut self, stmt: &Statement) -> Result<(), String> {
+        ast::deeper(|| {
+            match stmt {
+                Statement::Expression(i) => {
+                    if let Some(e) = i.expression() {
+                        self.expression(e)?;
+                        self.emit(Opcode::Pop, &[]);
+                    }
+                }
+                Statement::Let(i) => {
+                    let name = match i.name() {
+                        Some(n) => n.value(),
+                        None => return Ok(()),
+                    };
+
+                    match i.value() {
+                        Some(Expression::Function(f)) => self.function(f, Some(name))?,
+                        Some(value) => self.expression(value)?,
+                        None => {
+                            self.emit(Opcode::Null, &[]);
+                        }
+                    }
+
+                    let symbol = self.symbols.define(name);
+                    self.store_symbol(&symbol);
+                }
+                Statement::Return(i) => {
+                    // a `try` has to see what the call does, so a call in one
+                    // isn't the last thing the function does
+                    let tail = self.scopes.len() > 1 && self.scope().tries.is_empty();
+                    match i.return_value() {
+                        Some(value) if tail => self.tail_expression(value)?,
+                        Some(value) => self.expression(value)?,
+                        None => {
+                            self.emit(Opcode::Null, &[]);
+                        }
+                    }
+                    self.exit(Exit::Return);
+                }
+                Statement::Import(i) => {
+                    let module = self.import(i)?;
+                    let idx = self.add_constant(Object::Module(module));
+                    self.emit(Opcode::Constant, &[idx]);
+
+                    let symbol = self.symbols.define(i.name().value());
+                    self.emit(Opcode::SetGlobal, &[symbol.index]);
+                }
+                Statement::While(i) => {
+                    let start = self.scope().instructions.len();
+                    self.expression(i.condition())?;
+                    let exit = self.emit(Opcode::JumpNotTruthy, &[9999]);
+
+                    let body = self.loop_body(i.body())?;
+                    self.emit(Opcode::Jump, &[start]);
+                    self.end_loop(body, exit, start);
+                }
+                Statement::For(i) => {
+                    let end = match i.end() {
+                        Some(end) => end,
+                        None => return self.for_each(i),
+                    };
+                    self.expression(i.start())?;
+                    self.temporaries += 1;
+                    self.expression(end)?;
+                    self.temporaries -= 1;
+                    self.emit(Opcode::CheckRange, &[]);
+                    self.mark(Site::range(i.token(), i.start(), end));
+
+                    // the bounds go in slots of their own, so that rebinding the
+                    // variable doesn't change how often the loop runs
+                    let depth = self.loops.len();
+                    let end = self.symbols.define(&format!("for.{}.end", depth));
+                    let counter = self.symbols.define(&format!("for.{}", depth));
+                    self.store_symbol(&end);
+                    self.store_symbol(&counter);
+
+                    let start = self.scope().instructions.len();
+                    self.load_symbol(&end);
+                    self.load_symbol(&counter);
+                    self.emit(Opcode::GreaterThan, &[]);
+                    let exit = self.emit(Opcode::JumpNotTruthy, &[9999]);
+
+                    self.load_symbol(&counter);
+                    let variable = self.symbols.define(i.variable().value());
+                    self.store_symbol(&variable);
+
+                    let body = self.loop_body(i.body())?;
+                    let next = self.step(&counter, start);
+                    self.end_loop(body, exit, next);
+                }
+                Statement::Break(_) | Statement::Continue(_) => {
+                    if self.loops.is_empty() {
+                        return Err(format!(
+                            "{} outside of a loop",
+                            stmt.token().literal.to_lowercase()
+                        ));
+                    }
+                    match stmt {
+                        Statement::Break(_) => self.exit(Exit::Break),
+                        _ => self.exit(Exit::Continue),
+                    }
+                }
+                Statement::Throw(i) => {
+                    self.expression(i.value())?;
+                    self.emit(Opcode::Throw, &[]);
+                    self.mark(Site::throw(i.token(), i.value()));
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    /// `for (x in items)`, which counts through the positions of the
+    /// array or hash, looking it up afresh each time.
+    fn for_each(&mut self, i: &ForInternal) -> Result<(), String> {
+        self.expression(i.start())?;
+
+        let depth = self.loops.len();
+        let items = self.symbols.define(&format!("for.{}.items", depth));
+        let counter = self.symbols.define(&format!("for.{}", depth));
+        self.store_symbol(&items);
+        let zero = self.add_constant(Object::Integer(0));
+        self.emit(Opcode::Constant, &[zero]);
+        self.store_symbol(&counter);
+
+        let start = self.scope().instructions.len();
+        self.load_symbol(&items);
+        self.load_symbol(&counter);
+        let exit = self.emit(Opcode::Next, &[9999]);
+        self.mark(Site::of(i.start()));
+
+        let variable = self.symbols.define(i.variable().value());
+        self.store_symbol(&variable);
+
+        let body = self.loop_body(i.body())?;
+        let next = self.step(&counter, start);
+        self.end_loop(body, exit, next);
+        Ok(())
+    }
+
+    /// Adds one to a `for` loop's counter and goes back to the `start` of
+    /// the loop, returning where that begins, which is where `continue`
+    /// goes.
+    fn step(&mut self, counter: &Symbol, start: usize) -> usize {
+        let next = self.scope().instructions.len();
+        self.load_symbol(counter);
+        let one = self.add_constant(Object::Integer(1));
+        self.emit(Opcode::Constant, &[one]);
+        self.emit(Opcode::Add, &[]);
+        self.store_symbol(counter);
+        self.emit(Opcode::Jump, &[start]);
+        next
+    }
+
+    /// Leaves by `exit`, with the value a `return` returns on top of the
+    /// stack.
+    fn exit(&mut self, exit: Exit) {
+        if self.leave_tries(exit) {
+            return;
+        }
+
+        if exit == Exit::Return {
+            self.emit(Opcode::ReturnValue, &[]);
+            return;
+        }
+
+        let l = self.loops.last().expect("checked by the caller");
+        for _ in l.temporaries..self.temporaries {
+            self.emit(Opcode::Pop, &[]);
+        }
+
+        let jump = self.emit(Opcode::Jump, &[9999]);
+        let l = self.loops.last_mut().expect("checked by the caller");
+        match exit {
+            Exit::Break => l.breaks.push(jump),
+            _ => l.continues.push(jump),
+        }
+    }
+
+    /// Pops the handlers of the `try`s that `exit` jumps out of, innermost
+    /// first, up to one with a `finally`. That one is jumped to instead,
+    /// and carries on with the exit once it's done, which is when this
+    /// returns true.
+    fn leave_tries(&mut self, exit: Exit) -> bool {
+        let loops = self.loops.len();
+
+        for idx in (0..self.scope().tries.len()).rev() {
+            let t = &self.scope().tries[idx];
+            if exit != Exit::Return && t.loops < loops {
+                break;
+            }
+            let (part, slots, temporaries) = (t.part, t.slots.clone(), t.temporaries);
+
+            if part == TryPart::Body || (part == TryPart::Catch && slots.is_some()) {
+                self.emit(Opcode::PopHandler, &[]);
+            }
+            let (value, how) = match slots {
+                Some(slots) if part != TryPart::Finally => slots,
+                _ => continue,
+            };
+
+            if exit == Exit::Return {
+                self.store_symbol(&value);
+            }
+            for _ in temporaries..self.temporaries {
+                self.emit(Opcode::Pop, &[]);
+            }
+            self.set_exit_code(&how, exit.code());
+            let jump = self.emit(Opcode::Jump, &[9999]);
+
+            let t = &mut self.scope().tries[idx];
+            t.finally_jumps.push(jump);
+            if !t.exits.contains(&exit) {
+                t.exits.push(exit);
+            }
+            return true;
+        }
+
+        false
+    }
+
+    fn set_exit_code(&mut self, how: &Symbol, code: i128) {
+        let idx = self.add_constant(Object::Integer(code));
+        self.emit(Opcode::Constant, &[idx]);
+        self.store_symbol(how);
+    }
+
+    /// Jumps past what follows unless the hidden slot `how` says the
+    /// `finally` block was reached with `code`. Returns the jump to patch.
+    fn unless_exit_code(&mut self, how: &Symbol, code: i128) -> usize {
+        self.load_symbol(how);
+        let idx = self.add_constant(Object::Integer(code));
+        self.emit(Opcode::Constant, &[idx]);
+        self.emit(Opcode::Equal, &[]);
+        self.emit(Opcode::JumpNotTruthy, &[9999])
+    }
+
+    /// Compiles a `try`, `e`, leaving its value on the stack. The VM jumps
+    /// to the `catch` block with the error on the stack when something
+    /// fails while its handler is active.
+    ///
+    /// A `finally` block is reached with the value the `try` is leaving
+    /// with, and how it's leaving, in hidden slots; the `catch` block has
+    /// a handler of its own that gets there with its error. After the
+    /// `finally` block the `try` carries on leaving that way.
+    fn try_catch(&mut self, i: &TryInternal, e: &Expression) -> Result<(), String> {
+        let slots = match i.finally() {
+            Some(_) => {
+                let depth = self.scope().tries.len();
+                let value = self.symbols.define(&format!("try.{}.value", depth));
+                let how = self.symbols.define(&format!("try.{}.exit", depth));
+                Some((value, how))
+            }
+            None => None,
+        };
+
+        let set_handler = self.emit(Opcode::SetHandler, &[9999]);
+        let t = Try {
+            part: TryPart::Body,
+            loops: self.loops.len(),
+            temporaries: self.temporaries,
+            slots: slots.clone(),
+            finally_jumps: Vec::new(),
+            exits: Vec::new(),
+        };
+        self.scope().tries.push(t);
+
+        self.block_value(i.body())?;
+        self.emit(Opcode::PopHandler, &[]);
+        let done = self.emit(Opcode::Jump, &[9999]);
+
+        let catch = self.scope().instructions.len();
+        self.change_operand(set_handler, catch);
+        let name = self.symbols.define(i.name().value());
+        self.store_symbol(&name);
+        self.current_try().part = TryPart::Catch;
+
+        let rethrow = slots
+            .as_ref()
+            .map(|_| self.emit(Opcode::SetHandler, &[9999]));
+        self.block_value(i.handler())?;
+        if rethrow.is_some() {
+            self.emit(Opcode::PopHandler, &[]);
+        }
+
+        let after = self.scope().instructions.len();
+        self.change_operand(done, after);
+
+        let (finally, (value, how), rethrow) = match (i.finally(), slots, rethrow) {
+            (Some(finally), Some(slots), Some(rethrow)) => (finally, slots, rethrow),
+            _ => {
+                self.scope().tries.pop();
+                return Ok(());
+            }
+        };
+
+        self.store_symbol(&value);
+        self.set_exit_code(&how, REACHED_END);
+        let reached_end = self.emit(Opcode::Jump, &[9999]);
+
+        let rethrowing = self.scope().instructions.len();
+        self.change_operand(rethrow, rethrowing);
+        self.store_symbol(&value);
+        self.set_exit_code(&how, RETHROWING);
+
+        let start = self.scope().instructions.len();
+        self.change_operand(reached_end, start);
+        let jumps = std::mem::take(&mut self.current_try().finally_jumps);
+        for jump in jumps {
+            self.change_operand(jump, start);
+        }
+        self.current_try().part = TryPart::Finally;
+        self.block(finally)?;
+
+        let t = self.scope().tries.pop().expect("we pushed this try");
+        for exit in t.exits {
+            let skip = self.unless_exit_code(&how, exit.code());
+            if exit == Exit::Return {
+                self.load_symbol(&value);
+            }
+            self.exit(exit);
+            let next = self.scope().instructions.len();
+            self.change_operand(skip, next);
+        }
+
+        let skip = self.unless_exit_code(&how, RETHROWING);
+        self.load_symbol(&value);
+        self.emit(Opcode::Throw, &[]);
+        self.mark(Site::of(e));
+        let next = self.scope().instructions.len();
+        self.change_operand(skip, next);
+
+        self.load_symbol(&value);
+        Ok(())
+    }
+
+    fn current_try(&mut self) -> &mut Try {
+        self.scope()
+            .tries
+            .last_mut()
+            .expect("only called while compiling a try")
+    }
+
+    /// Compiles the body of a loop, returning the jumps out of it.
+    fn loop_body(&mut self, body: &Block) -> Result<Loop, String> {
+        self.loops.push(Loop {
+            temporaries: self.temporaries,
+            breaks: Vec::new(),
+            continues: Vec::new(),
+        });
+        let compiled = self.block(body);
+        let body = self.loops.pop().expect("we pushed this loop");
+        compiled.map(|_| body)
+    }
+
+    /// Points the jumps out of a loop that ends here, given the one taken
+    /// when its condition fails and where `continue` goes.
+    fn end_loop(&mut self, body: Loop, exit: usize, next: usize) {
+        let end = self.scope().instructions.len();
+        self.change_operand(exit, end);
+        for jump in body.breaks {
+            self.change_operand(jump, end);
+        }
+        for jump in body.continues {
+            self.change_operand(jump, next);
+        }
+    }
+
+    /// The module `i` imports, compiling its code the first time.
+    fn import(&mut self, i: &ImportInternal) -> Result<Rc<object::Module>, String> {
+        let module = match self.modules.find(self.file.as_deref(), i.path()) {
+            Some(m) => m.clone(),
+            None => return Err(format!("module {} isn't loaded", i.path())),
+        };
+        if let Some(compiled) = self.loaded.get(&module.id) {
+            return Ok(compiled.clone());
+        }
+
+        self.symbols.enter_module(&self.shared);
+        let importer = self.file.replace(module.path.clone());
+        let compiled = self.statements(&module.program.statements);
+        let exports = module
+            .exports
+            .iter()
+            .filter_map(|name| Some((name.clone(), self.symbols.global(name)?)))
+            .collect();
+        self.file = importer;
+        self.symbols.leave_module();
+        compiled?;
+
+        let compiled = Rc::new(object::Module {
+            name: module.name.clone(),
+            exports,
+        });
+        self.loaded.insert(module.id, compiled.clone());
+        Ok(compiled)
+    }
+
+    fn block(&mut self, block: &Block) -> Result<(), String> {
+        for stmt in &block.statements {
+            self.statement(stmt)?;
+        }
+        Ok(())
+    }
+
+    /// Compiles a block whose value is left on the stack, which is null
+    /// unless the block ends in an expression.
+    fn block_value(&mut self, block: &Block) -> Result<(), String> {
+        self.block(block)?;
+        if self.last_is(Opcode::Pop) {
+            self.remove_last_pop();
+        } else {
+            self.emit(Opcode::Null, &[]);
+        }
+        Ok(())
+    }
+
+    /// Compiles a block whose value the function returns, like
+    /// `block_value`, but with the expression it ends in in tail position.
+    fn tail_block_value(&mut self, block: &Block) -> Result<(), String> {
+        match block.statements.split_last() {
+            Some((Statement::Expression(i), rest)) if i.expression().is_some() => {
+                for stmt in rest {
+                    self.statement(stmt)?;
+                }
+                self.tail_expression(i.expression().expect("checked"))
+            }
+            _ => self.block_value(block),
+        }
+    }
+
+    /// Compiles `e`, whose value the function returns. A call is a tail
+    /// call, and so is one that's the value of a branch of an `if`.
+    fn tail_expression(&mut self, e: &Expression) -> Result<(), String> {
+        match e {
+            Expression::Call(i) => self.call(i, e, Opcode::TailCall),
+            Expression::If(i) => self.conditional(i, true),
+            _ => self.expression(e),
+        }
+    }
+
+    fn function(&mut self, f: &FunctionInternal, name: Option<&str>) -> Result<(), String> {
+        self.scopes.push(CompilationScope::default());
+        self.symbols.push();
+
+        if let Some(name) = name {
+            self.symbols.define_function_name(name);
+        }
+
+        for p in f.parameters() {
+            self.symbols.define(p.value());
+        }
+        for slot in self.symbols.define_cells(symbol_table::cells(f)) {
+            self.emit(Opcode::MakeCell, &[slot]);
+        }
+
+        match f.body().statements.split_last() {
+            Some((Statement::Expression(i), rest)) if i.expression().is_some() => {
+                for stmt in rest {
+                    self.statement(stmt)?;
+                }
+                self.tail_expression(i.expression().expect("checked"))?;
+                self.emit(Opcode::ReturnValue, &[]);
+            }
+            _ => {
+                self.block(f.body())?;
+                if self.last_is(Opcode::Pop) {
+                    self.replace_last_pop_with_return();
+                }
+                if !self.last_is(Opcode::ReturnValue) {
+                    self.emit(Opcode::Return, &[]);
+                }
+            }
+        }
+
+        let (free, num_locals) = self.symbols.pop();
+        let scope = self.scopes.pop().expect("we pushed this scope");
+
+        for s in &free {
+            self.load_slot(s);
+        }
+
+        let compiled = CompiledFunction {
+            instructions: scope.instructions,
+            num_locals,
+            num_parameters: f.parameters().len(),
+            name: name.map(String::from),
+            sites: scope.sites,
+        };
+        let idx = self.add_constant(Object::CompiledFunction(Rc::new(compiled)));
+        self.emit(Opcode::Closure, &[idx, free.len()]);
+
+        Ok(())
+    }
+
+    /// Applies `op`, from the expression `e`, to the two values on top of
+    /// the stack. `<` and `<=` are compiled by the caller, as there are only
+    /// greater than and greater or equal.
+    fn infix_operator(&mut self, op: &str, e: &Expression) -> Result<(), String> {
+        let opcode = match op {
+            "+" => Opcode::Add,
+            "-" => Opcode::Sub,
+            "*" => Opcode::Mul,
+            "/" => Opcode::Div,
+            "%" => Opcode::Mod,
+            "&" => Opcode::BitAnd,
+            "|" => Opcode::BitOr,
+            "^" => Opcode::BitXor,
+            "<<" => Opcode::ShiftLeft,
+            ">>" => Opcode::ShiftRight,
+            ">" => Opcode::GreaterThan,
+            ">=" => Opcode::GreaterEqual,
+            "==" => Opcode::Equal,
+            "!=" => Opcode::NotEqual,
+            op => return Err(format!("unknown operator {}", op)),
+        };
+        self.emit(opcode, &[]);
+        // anything can be compared for equality
+        if !matches!(opcode, Opcode::Equal | Opcode::NotEqual) {
+            self.mark(Site::of(e));
+        }
+        Ok(())
+    }
+
+    /// `&&` and `||`, which only run `right` when `left` doesn't decide the
+    /// result, and always give a boolean.
+    fn logical(&mut self, op: &str, left: &Expression, right: &Expression) -> Result<(), String> {
+        self.expression(left)?;
+        let jump_not_truthy = self.emit(Opcode::JumpNotTruthy, &[9999]);
+
+        if op == "||" {
+            self.emit(Opcode::True, &[]);
+        } else {
+            self.truthiness(right)?;
+        }
+        let jump = self.emit(Opcode::Jump, &[9999]);
+
+        let after_truthy = self.scope().instructions.len();
+        self.change_operand(jump_not_truthy, after_truthy);
+        if op == "||" {
+            self.truthiness(right)?;
+        } else {
+            self.emit(Opcode::False, &[]);
+        }
+
+        let after = self.scope().instructions.len();
+        self.change_operand(jump, after);
+        Ok(())
+    }
+
+    fn truthiness(&mut self, e: &Expression) -> Result<(), String> {
+        self.expression(e)?;
+        self.emit(Opcode::Bang, &[]);
+        self.emit(Opcode::Bang, &[]);
+        Ok(())
+    }
+
+    /// An `if`, whose branches are in tail position if `tail` is.
+    fn conditional(&mut self, i: &IfInternal, tail: bool) -> Result<(), String> {
+        self.expression(i.condition())?;
+
+        // jump targets get patched in once we know them
+        let jump_not_truthy = self.emit(Opcode::JumpNotTruthy, &[9999]);
+
+        if tail {
+            self.tail_block_value(i.consequence())?;
+        } else {
+            self.block_value(i.consequence())?;
+        }
+
+        let jump = self.emit(Opcode::Jump, &[9999]);
+
+        let after_consequence = self.scope().instructions.len();
+        self.change_operand(jump_not_truthy, after_consequence);
+
+        match i.alternative() {
+            Some(alt) if tail => self.tail_block_value(alt)?,
+            Some(alt) => self.block_value(alt)?,
+            None => {
+                self.emit(Opcode::Null, &[]);
+            }
+        }
+
+        let after_alternative = self.scope().instructions.len();
+        self.change_operand(jump, after_alternative);
+        Ok(())
+    }
+
+    /// The call `i`, which is `e`, made with `op`: `Call`, or `TailCall`
+    /// when it's the last thing the function does.
+    fn call(&mut self, i: &CallInternal, e: &Expression, op: Opcode) -> Result<(), String> {
+        self.expression(i.function())?;
+        self.temporaries += 1;
+        for arg in i.arguments() {
+            self.expression(arg)?;
+            self.temporaries += 1;
+        }
+        self.temporaries -= i.arguments().len() + 1;
+        self.emit(op, &[i.arguments().len()]);
+        self.mark(Site::of(e));
+        Ok(())
+    }
+
+    fn expression(&mut self, e: &Expression) -> Result<(), String> {
+        ast::deeper(|| {
+            match e {
+                Expression::Integer(i) => {
+                    let idx = self.add_constant(Object::Integer(i.value()));
+                    self.emit(Opcode::Constant, &[idx]);
+                }
+                Expression::Boolean(b) => {
+                    if b.value() {
+                        self.emit(Opcode::True, &[]);
+                    } else {
+                        self.emit(Opcode::False, &[]);
+                    }
+                }
+                Expression::Identifier(i) => match self.symbols.resolve(i.value()) {
+                    Some(s) => {
+                        self.load_symbol(&s);
+                        // only a global can be read before it's set
+                        if s.scope == SymbolScope::Global {
+                            self.mark(Site::of(e));
+                        }
+                    }
+                    None => return Err(format!("undefined variable {}", i.value())),
+                },
+                Expression::Prefix(i) => {
+                    self.expression(i.right())?;
+                    match i.operator() {
+                        "!" => self.emit(Opcode::Bang, &[]),
+                        "-" => self.emit(Opcode::Minus, &[]),
+                        "~" => self.emit(Opcode::BitNot, &[]),
+                        op => return Err(format!("unknown operator {}", op)),
+                    };
+                    if i.operator() != "!" {
+                        self.mark(Site::of(e));
+                    }
+                }
+                Expression::Infix(i) => {
+                    // there's no less than, so flip the operands of < and <=
+                    let flipped = match i.operator() {
+                        "<" => Some(Opcode::GreaterThan),
+                        "<=" => Some(Opcode::GreaterEqual),
+                        "&&" | "||" => return self.logical(i.operator(), i.left(), i.right()),
+                        _ => None,
+                    };
+                    if let Some(op) = flipped {
+                        self.expression(i.right())?;
+                        self.temporaries += 1;
+                        self.expression(i.left())?;
+                        self.temporaries -= 1;
+                        self.emit(op, &[]);
+                        self.mark(Site::of(e));
+                        return Ok(());
+                    }
+
+                    self.expression(i.left())?;
+                    self.temporaries += 1;
+                    self.expression(i.right())?;
+                    self.temporaries -= 1;
+                    self.infix_operator(i.operator(), e)?;
+                }
+                Expression::If(i) => self.conditional(i, false)?,
+                Expression::String(s) => {
+                    let idx = self.add_constant(Object::String(Rc::from(s.value())));
+                    self.emit(Opcode::Constant, &[idx]);
+                }
+                Expression::Try(i) => self.try_catch(i, e)?,
+                Expression::Function(f) => self.function(f, None)?,
+                Expression::Macro(_) => return Err(String::from(macros::STRAY_MACRO)),
+                Expression::Call(i) => self.call(i, e, Opcode::Call)?,
+                Expression::Assign(i) => {
+                    let symbol = self.symbols.resolve_assignment(i.name().value())?;
+                    match i.infix_operator() {
+                        Some(op) => {
+                            self.load_symbol(&symbol);
+                            if symbol.scope == SymbolScope::Global {
+                                self.mark(Site::of(e));
+                            }
+                            self.temporaries += 1;
+                            self.expression(i.value())?;
+                            self.temporaries -= 1;
+                            self.infix_operator(op, e)?;
+                        }
+                        None => self.expression(i.value())?,
+                    }
+
+                    // the assignment's value is what was assigned
+                    self.store_symbol(&symbol);
+                    self.load_symbol(&symbol);
+                }
+                Expression::Array(i) => {
+                    for element in i.elements() {
+                        self.expression(element)?;
+                        self.temporaries += 1;
+                    }
+                    self.temporaries -= i.elements().len();
+                    self.emit(Opcode::Array, &[i.elements().len()]);
+                }
+                Expression::Hash(i) => {
+                    for (key, value) in i.pairs() {
+                        self.expression(key)?;
+                        self.temporaries += 1;
+                        self.expression(value)?;
+                        self.temporaries += 1;
+                    }
+                    self.temporaries -= 2 * i.pairs().len();
+                    self.emit(Opcode::Hash, &[i.pairs().len()]);
+                    self.mark(Site::of(e));
+                }
+                Expression::Index(i) => {
+                    self.expression(i.left())?;
+                    self.temporaries += 1;
+                    self.expression(i.index())?;
+                    self.temporaries -= 1;
+                    self.emit(Opcode::Index, &[]);
+                    self.mark(Site::of(e));
+                }
+                Expression::IndexAssign(i) => {
+                    self.expression(i.target().left())?;
+                    self.temporaries += 1;
+                    self.expression(i.target().index())?;
+                    self.temporaries += 1;
+                    if let Some(op) = i.infix_operator() {
+                        self.emit(Opcode::DupPair, &[]);
+                        self.emit(Opcode::Index, &[]);
+                        self.mark(Site::of(e));
+                        self.temporaries += 1;
+                        self.expression(i.value())?;
+                        self.temporaries -= 1;
+                        self.infix_operator(op, e)?;
+                    } else {
+                        self.expression(i.value())?;
+                    }
+                    self.temporaries -= 2;
+                    self.emit(Opcode::SetIndex, &[]);
+                    self.mark(Site::of(e));
+                }
+                Expression::Member(i) => {
+                    self.expression(i.object())?;
+                    let name = i.name().value();
+                    let idx = match self.members.iter().position(|m| m == name) {
+                        Some(idx) => idx,
+                        None => {
+                            self.members.push(name.clone());
+                            self.members.len() - 1
+                        }
+                    };
+                    self.emit(Opcode::GetMember, &[idx]);
+                    self.mark(Site::of(e));
+                }
+            }
+
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::code::{disassemble, make};
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn compile(input: &str) -> Bytecode {
+        let l = Lexer::new(input, true, None);
+        let mut p = Parser::new(l);
+        let program = p.parse_program().expect("Program should be Some here");
+        assert_eq!(p.errors().len(), 0, "{:?}", p.errors());
+
+        let mut c = Compiler::new();
+        c.compile(&program).expect("program should compile");
+        c.bytecode()
+    }
+
+    fn function_instructions(obj: &Object) -> String {
+        match obj {
+            Object::CompiledFunction(f) => disassemble(&f.instructions),
+            _ => panic!("expected a compiled function, got {:?}", obj),
+        }
+    }
+
+    #[test]
+    fn test_arithmetic_and_conditionals() {
+        let bytecode = compile("1 < 2; if (true) { 10 }; 3333;");
+
+        let expected = [
+            make(Opcode::Constant, &[0]),
+            make(Opcode::Constant, &[1]),
+            make(Opcode::GreaterThan, &[]),
+            make(Opcode::Pop, &[]),
+            // 0008
+            make(Opcode::True, &[]),
+            make(Opcode::JumpNotTruthy, &[18]),
+            make(Opcode::Constant, &[2]),
+            make(Opcode::Jump, &[19]),
+            // 0018
+            make(Opcode::Null, &[]),
+            // 0019
+            make(Opcode::Pop, &[]),
+            make(Opcode::Constant, &[3]),
+            make(Opcode::Pop, &[]),
+        ]
+        .concat();
+
+        assert_eq!(disassemble(&bytecode.instructions), disassemble(&expected));
+        assert_eq!(
+            bytecode.constants,
+            [2, 1, 10, 3333].map(Object::Integer).to_vec()
+        );
+    }
+
+    #[test]
+    fn test_logical_operators() {
+        let bytecode = compile("1 <= 2 && false;");
+
+        let expected = [
+            make(Opcode::Constant, &[0]),
+            make(Opcode::Constant, &[1]),
+            make(Opcode::GreaterEqual, &[]),
+            make(Opcode::JumpNotTruthy, &[16]),
+            make(Opcode::False, &[]),
+            make(Opcode::Bang, &[]),
+            make(Opcode::Bang, &[]),
+            make(Opcode::Jump, &[17]),
+            // 0016
+            make(Opcode::False, &[]),
+            // 0017
+            make(Opcode::Pop, &[]),
+        ]
+        .concat();
+
+        assert_eq!(disassemble(&bytecode.instructions), disassemble(&expected));
+        assert_eq!(bytecode.constants, [2, 1].map(Object::Integer).to_vec());
+    }
+
+    #[test]
+    fn test_globals_and_locals() {
+        let bytecode = compile("let f = fn() { g() }; let g = fn() { let n = 55; n };");
+
+        let expected = [
+            make(Opcode::Closure, &[0, 0]),
+            make(Opcode::SetGlobal, &[0]),
+            make(Opcode::Closure, &[2, 0]),
+            make(Opcode::SetGlobal, &[1]),
+        ]
+        .concat();
+        assert_eq!(disassemble(&bytecode.instructions), disassemble(&expected));
+        assert_eq!(bytecode.globals, ["f", "g"]);
+
+        // `g` is declared later but still resolves to its global
+        let f = [
+            make(Opcode::GetGlobal, &[1]),
+            make(Opcode::TailCall, &[0]),
+            make(Opcode::ReturnValue, &[]),
+        ]
+        .concat();
+        assert_eq!(
+            function_instructions(&bytecode.constants[0]),
+            disassemble(&f)
+        );
+
+        let g = [
+            make(Opcode::Constant, &[1]),
+            make(Opcode::SetLocal, &[0]),
+            make(Opcode::GetLocal, &[0]),
+            make(Opcode::ReturnValue, &[]),
+        ]
+        .concat();
+        assert_eq!(
+            function_instructions(&bytecode.constants[2]),
+            disassemble(&g)
+        );
+    }
+
+    #[test]
+    fn test_closures_and_recursion() {
+        let bytecode =
+            compile("fn(a) { fn(b) { a + b } }; let countdown = fn(x) { countdown(x - 1); };");
+
+        let inner = [
+            make(Opcode::GetFree, &[0]),
+            make(Opcode::GetLocal, &[0]),
+            make(Opcode::Add, &[]),
+            make(Opcode::ReturnValue, &[]),
+        ]
+        .concat();
+        assert_eq!(
+            function_instructions(&bytecode.constants[0]),
+            disassemble(&inner)
+        );
+
+        let outer = [
+            make(Opcode::GetLocal, &[0]),
+            make(Opcode::Closure, &[0, 1]),
+            make(Opcode::ReturnValue, &[]),
+        ]
+        .concat();
+        assert_eq!(
+            function_instructions(&bytecode.constants[1]),
+            disassemble(&outer)
+        );
+
+        let countdown = [
+            make(Opcode::CurrentClosure, &[]),
+            make(Opcode::GetLocal, &[0]),
+            make(Opcode::Constant, &[2]),
+            make(Opcode::Sub, &[]),
+            make(Opcode::TailCall, &[1]),
+            make(Opcode::ReturnValue, &[]),
+        ]
+        .concat();
+        assert_eq!(
+            function_instructions(&bytecode.constants[3]),
+            disassemble(&countdown)
+        );
+    }
+
+    #[test]
+    fn test_loops() {
+        let bytecode = compile("while (true) { 1 + if (true) { break; } }");
+
+        let expected = [
+            make(Opcode::True, &[]),
+            make(Opcode::JumpNotTruthy, &[25]),
+            make(Opcode::Constant, &[0]),
+            make(Opcode::True, &[]),
+            make(Opcode::JumpNotTruthy, &[19]),
+            // the break pops the 1 waiting for the if's value
+            make(Opcode::Pop, &[]),
+            make(Opcode::Jump, &[25]),
+            make(Opcode::Null, &[]),
+            make(Opcode::Jump, &[20]),
+            // 0019
+            make(Opcode::Null, &[]),
+            // 0020
+            make(Opcode::Add, &[]),
+            make(Opcode::Pop, &[]),
+            make(Opcode::Jump, &[0]),
+        ]
+        .concat();
+        assert_eq!(disassemble(&bytecode.instructions), disassemble(&expected));
+    }
+
+    #[test]
+    fn test_undefined_variable() {
+        let l = Lexer::new("fn() { x }", true, None);
+        let mut p = Parser::new(l);
+        let program = p.parse_program().expect("Program should be Some here");
+
+        let mut c = Compiler::new();
+        assert_eq!(
+            c.compile(&program),
+            Err(String::from("undefined variable x"))
+        );
+    }
+
+    #[test]
+    fn test_assigning_a_captured_variable() {
+        let bytecode = compile("fn(a) { fn() { a += 1 } };");
+
+        let inner = [
+            make(Opcode::GetFree, &[0]),
+            make(Opcode::GetCell, &[]),
+            make(Opcode::Constant, &[0]),
+            make(Opcode::Add, &[]),
+            make(Opcode::GetFree, &[0]),
+            make(Opcode::SetCell, &[]),
+            make(Opcode::GetFree, &[0]),
+            make(Opcode::GetCell, &[]),
+            make(Opcode::ReturnValue, &[]),
+        ]
+        .concat();
+        assert_eq!(
+            function_instructions(&bytecode.constants[1]),
+            disassemble(&inner)
+        );
+
+        // the closure gets the cell, not the value in it
+        let outer = [
+            make(Opcode::MakeCell, &[0]),
+            make(Opcode::GetLocal, &[0]),
+            make(Opcode::Closure, &[1, 1]),
+            make(Opcode::ReturnValue, &[]),
+        ]
+        .concat();
+        assert_eq!(
+            function_instructions(&bytecode.constants[2]),
+            disassemble(&outer)
+        );
+    }
+}
@@ -0,0 +1,402 @@
+// Runs every program in `tests/programs` on each engine and checks that
+// they all print exactly what the reference evaluator prints. A program
+// that fails does so as the last line of its output, so the error has to
+// match too, and so does its stack trace. Each engine runs the program
+// both as written and optimized, the way `plmmky run` runs it, so a fold
+// that changes what a program does shows up as a disagreement.
+
+use crate::ast::Program;
+use crate::compiler::Compiler;
+use crate::emit_c;
+use crate::emit_wat;
+use crate::error::Error;
+use crate::evaluator::Evaluator;
+use crate::lexer::Lexer;
+use crate::macros::Macros;
+use crate::module::Modules;
+use crate::optimizer;
+use crate::parser::Parser;
+use crate::regcompiler::RegCompiler;
+use crate::regvm::RegVm;
+use crate::vm::Vm;
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::rc::Rc;
+
+/// Collects what a program prints, while the engine owns the writer.
+#[derive(Clone, Default)]
+struct Output(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Output {
+    /// What the program printed, and the stack trace if it failed.
+    fn finish(self, result: Result<(), Error>) -> (String, Vec<String>) {
+        let mut out = String::from_utf8(self.0.take()).expect("puts writes utf-8");
+        match result {
+            Ok(()) => (out, Vec::new()),
+            Err(e) => {
+                out.push_str(&format!("error: {}\n", e));
+                (out, e.trace_lines())
+            }
+        }
+    }
+}
+
+const ENGINES: [&str; 3] = ["eval", "vm", "regvm"];
+
+/// Parses `input` and expands its macros, then optimizes it if `optimize`
+/// says to, as `plmmky run` does. A program the parser rejects never
+/// reaches an engine, so it fails the same way on every one of them.
+fn parse<'a>(input: &str, path: &'a Path, optimize: bool) -> Result<Program<'a>, String> {
+    let l = Lexer::new(input, false, Some(path));
+    let mut p = Parser::new(l);
+    let mut program = p.parse_program().unwrap_or_default();
+    if !p.errors().is_empty() {
+        let errors: Vec<String> = p.errors().iter().map(|e| e.to_string()).collect();
+        return Err(errors.join("\n"));
+    }
+    Macros::new()
+        .expand(&mut program)
+        .unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+    if optimize {
+        optimizer::optimize(&mut program);
+    }
+    Ok(program)
+}
+
+fn run(engine: &str, input: &str, path: &Path, optimize: bool) -> (String, Vec<String>) {
+    let program = match parse(input, path, optimize) {
+        Ok(program) => program,
+        Err(e) => {
+            let name = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .unwrap_or_default();
+            assert!(name.starts_with("error_"), "{}: {}", path.display(), e);
+            return (format!("error: {}\n", e), Vec::new());
+        }
+    };
+
+    let out = Output::default();
+
+    let result = match engine {
+        "eval" => {
+            let mut modules = Modules::new();
+            modules
+                .load(&program, Some(path))
+                .expect("conformance programs load");
+
+            let mut e = Evaluator::new();
+            e.set_modules(&modules, Some(path));
+            e.set_output(Box::new(out.clone()));
+            e.eval(&program).map(|_| ())
+        }
+        "vm" => {
+            let mut c = Compiler::new();
+            c.set_file(Some(path));
+            c.compile(&program).expect("conformance programs compile");
+            let mut vm = Vm::new(c.bytecode());
+            vm.set_output(Box::new(out.clone()));
+            vm.run()
+        }
+        "regvm" => {
+            let mut c = RegCompiler::new();
+            c.set_file(Some(path));
+            c.compile(&program).expect("conformance programs compile");
+            let mut vm = RegVm::new(c.bytecode());
+            vm.set_output(Box::new(out.clone()));
+            vm.run()
+        }
+        _ => unreachable!("unknown engine {}", engine),
+    };
+
+    out.finish(result)
+}
+
+fn programs() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+    let mut programs: Vec<PathBuf> = std::fs::read_dir(&dir)
+        .expect("tests/programs should exist")
+        .map(|e| e.expect("should list tests/programs").path())
+        .filter(|p| p.extension().is_some_and(|e| e == "my"))
+        .collect();
+    programs.sort();
+    programs
+}
+
+/// Whether `input` sticks to what the C and Wasm backends can compile,
+/// which leaves out imports, strings and exceptions.
+fn portable(input: &str, path: &Path) -> bool {
+    let Ok(program) = parse(input, path, false) else {
+        return false;
+    };
+    match emit_c::emit(&program) {
+        Ok(_) => true,
+        Err(e) => !e.contains("aren't supported"),
+    }
+}
+
+#[test]
+fn test_engines_agree_with_the_evaluator() {
+    let programs = programs();
+    assert!(!programs.is_empty());
+
+    for path in programs {
+        let input = std::fs::read_to_string(&path).expect("should read program");
+        let expected = run(ENGINES[0], &input, &path, false);
+        assert!(!expected.0.is_empty(), "{} prints nothing", path.display());
+
+        for engine in ENGINES {
+            for optimize in [false, true] {
+                if engine == ENGINES[0] && !optimize {
+                    continue;
+                }
+                let got = run(engine, &input, &path, optimize);
+                let how = if optimize { "optimized" } else { "as written" };
+                assert_eq!(got, expected, "{} on {}, {}", path.display(), engine, how);
+            }
+        }
+    }
+}
+
+/// Builds `input` with `plmmky emit-c` and the system C compiler, and runs
+/// it. Runtime errors come out on stderr, so they're moved to the end of
+/// stdout the way `Output::finish` does it.
+fn run_c(input: &str, path: &Path, dir: &Path) -> String {
+    let program = parse(input, path, true).expect("portable programs parse");
+    let c = emit_c::emit(&program).expect("conformance programs compile");
+
+    let name = path.file_stem().expect("programs have names");
+    let source = dir.join(name).with_extension("c");
+    let binary = dir.join(name);
+    std::fs::write(&source, c).expect("should write the C file");
+
+    let status = Command::new("cc")
+        .arg("-std=c99")
+        .arg("-O1")
+        .arg("-o")
+        .arg(&binary)
+        .arg(&source)
+        .status()
+        .expect("cc should run");
+    assert!(status.success(), "{} doesn't build", source.display());
+
+    let output = Command::new(&binary).output().expect("should run");
+    let mut out = String::from_utf8(output.stdout).expect("puts writes utf-8");
+    let stderr = String::from_utf8(output.stderr).expect("errors are utf-8");
+    if let Some(e) = stderr.strip_prefix("runtime error: ") {
+        out.push_str(&format!("error: {}", e));
+    }
+    out
+}
+
+#[test]
+fn test_emitted_c_agrees_with_the_evaluator() {
+    // not every machine running the tests has a C compiler
+    if Command::new("cc").arg("--version").output().is_err() {
+        return;
+    }
+
+    let dir = std::env::temp_dir().join(format!("plmmky-emit-c-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("should create a build directory");
+
+    // the build directory goes whether or not the programs agree
+    let result = std::panic::catch_unwind(|| {
+        for path in programs() {
+            let input = std::fs::read_to_string(&path).expect("should read program");
+            if !portable(&input, &path) {
+                continue;
+            }
+            let (expected, _) = run(ENGINES[0], &input, &path, false);
+            let got = run_c(&input, &path, &dir);
+            assert_eq!(got, expected, "{} in C", path.display());
+        }
+    });
+    let _ = std::fs::remove_dir_all(&dir);
+    if let Err(panic) = result {
+        std::panic::resume_unwind(panic);
+    }
+}
+
+#[derive(Default)]
+struct WasmHost {
+    out: String,
+    error: Option<String>,
+}
+
+/// Reads the string the module passed to an import.
+fn wasm_string(caller: &wasmi::Caller<WasmHost>, ptr: i32, len: i32) -> String {
+    let memory = caller
+        .get_export("memory")
+        .and_then(wasmi::Extern::into_memory)
+        .expect("modules export their memory");
+    let bytes = &memory.data(caller)[ptr as usize..(ptr + len) as usize];
+    String::from_utf8(bytes.to_vec()).expect("modules write utf-8")
+}
+
+/// Runs `input` through `plmmky emit-wat` in an embedded interpreter.
+fn run_wasm(input: &str, path: &Path) -> String {
+    run_wasm_with_pages(input, path).0
+}
+
+/// Like `run_wasm`, also giving how many pages of memory the module ended
+/// up with.
+fn run_wasm_with_pages(input: &str, path: &Path) -> (String, u32) {
+    let program = parse(input, path, true).expect("portable programs parse");
+    let text = emit_wat::emit(&program).expect("conformance programs compile");
+    let wasm = wat::parse_str(&text).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+
+    // a Monkey call takes two Wasm frames
+    let mut config = wasmi::Config::default();
+    let limits = wasmi::StackLimits::new(1024, 1024 * 1024, 16 * 1024).expect("valid limits");
+    config.set_stack_limits(limits);
+    // calls in tail position are `return_call`s
+    config.wasm_tail_call(true);
+    let engine = wasmi::Engine::new(&config);
+    let module = wasmi::Module::new(&engine, &wasm[..]).expect("modules validate");
+
+    let mut store = wasmi::Store::new(&engine, WasmHost::default());
+    let mut linker = <wasmi::Linker<WasmHost>>::new(&engine);
+    linker
+        .func_wrap(
+            "env",
+            "print",
+            |mut caller: wasmi::Caller<WasmHost>, ptr: i32, len: i32| {
+                let s = wasm_string(&caller, ptr, len);
+                caller.data_mut().out.push_str(&s);
+            },
+        )
+        .expect("should define print");
+    linker
+        .func_wrap(
+            "env",
+            "error",
+            |mut caller: wasmi::Caller<WasmHost>, ptr: i32, len: i32| {
+                let s = wasm_string(&caller, ptr, len);
+                caller.data_mut().error = Some(s);
+            },
+        )
+        .expect("should define error");
+
+    let instance = linker
+        .instantiate(&mut store, &module)
+        .and_then(|i| i.start(&mut store))
+        .expect("should instantiate");
+    let main = instance
+        .get_typed_func::<(), ()>(&store, "main")
+        .expect("modules export main");
+    let result = main.call(&mut store, ());
+    let pages = instance
+        .get_memory(&store, "memory")
+        .expect("modules export their memory")
+        .current_pages(&store);
+
+    let host = store.into_data();
+    let mut out = host.out;
+    match (result, host.error) {
+        (Err(_), Some(e)) => out.push_str(&format!("error: {}\n", e)),
+        (Err(e), None) => panic!("{} trapped: {}", path.display(), e),
+        (Ok(()), _) => (),
+    }
+    (out, pages.into())
+}
+
+#[test]
+fn test_emitted_wasm_agrees_with_the_evaluator() {
+    for path in programs() {
+        let input = std::fs::read_to_string(&path).expect("should read program");
+        if !portable(&input, &path) {
+            continue;
+        }
+        let (expected, _) = run(ENGINES[0], &input, &path, false);
+        let got = run_wasm(&input, &path);
+        assert_eq!(got, expected, "{} in Wasm", path.display());
+    }
+}
+
+#[test]
+fn test_emitted_wasm_keeps_integers_out_of_memory() {
+    // boxing each integer this loop makes would take about 14 MB
+    let input = "let total = 0; for (i in 0..300000) { total += i & 7; } puts(total);";
+    let (out, pages) = run_wasm_with_pages(input, Path::new("loop.my"));
+    assert_eq!(out, "1050000\n");
+    assert_eq!(pages, 1);
+}
+
+/// The most deeply nested program `shape` makes that still parses.
+fn deepest(shape: impl Fn(usize) -> String) -> String {
+    let path = Path::new("deep.my");
+    let (mut lo, mut hi) = (1, crate::parser::MAX_NESTING);
+    while lo < hi {
+        let n = (lo + hi).div_ceil(2);
+        match parse(&shape(n), path, false) {
+            Ok(_) => lo = n,
+            Err(_) => hi = n - 1,
+        }
+    }
+    shape(lo)
+}
+
+#[test]
+fn test_deeply_nested_programs() {
+    let shapes: [fn(usize) -> String; 7] = [
+        |n| format!("puts({}1{});", "1 + (".repeat(n), ")".repeat(n)),
+        |n| format!("puts({});", vec!["1"; n].join(" + ")),
+        |n| format!("puts({}1{});", "-(".repeat(n), ")".repeat(n)),
+        |n| {
+            format!(
+                "let f = fn(x) {{ x }}; puts({}1{});",
+                "f(".repeat(n),
+                ")".repeat(n)
+            )
+        },
+        |n| format!("puts({}1{});", "fn() { ".repeat(n), " }()".repeat(n)),
+        |n| {
+            format!(
+                "puts(fn(x) {{ {}1{} }}(true));",
+                "if (x) { ".repeat(n),
+                " }".repeat(n)
+            )
+        },
+        |n| {
+            let loops = "while (i < 1) { ".repeat(n);
+            format!("let i = 0; {}i += 1;{} puts(i);", loops, " }".repeat(n))
+        },
+    ];
+
+    let path = Path::new("deep.my");
+    for shape in shapes {
+        let input = deepest(shape);
+        let expected = run(ENGINES[0], &input, path, false);
+        assert!(!expected.0.starts_with("error"), "{}", expected.0);
+        for engine in &ENGINES[1..] {
+            assert_eq!(run(engine, &input, path, false), expected, "{}", engine);
+        }
+
+        let program = parse(&input, path, true).expect("the deepest program parses");
+        crate::resolver::resolve(&program);
+        crate::lint::lint(&program, &[]);
+        emit_c::emit(&program).expect("deep programs compile to C");
+        emit_wat::emit(&program).expect("deep programs compile to Wasm");
+        let bytes = crate::astcache::encode(&program, 0);
+        let cached = crate::astcache::decode(&bytes, 0, path).expect("deep programs decode");
+        assert_eq!(cached.to_string(), program.to_string());
+        let config = crate::formatter::FormatConfig::default();
+        crate::formatter::format(&input, path, &config).expect("deep programs format");
+    }
+
+    let input = format!("puts({}1{});", "(".repeat(5000), ")".repeat(5000));
+    let e = parse(&input, path, false).expect_err("5000 parentheses are too deep");
+    assert_eq!(e, "1:1004: nested more than 1000 levels deep");
+}
@@ -0,0 +1,1391 @@
+use crate::ast::{self, Block, CallInternal, Expression, FunctionInternal, Program, Statement};
+use crate::macros;
+use crate::regcompiler::binds_locals;
+use crate::symbol_table::{self, Symbol, SymbolScope, SymbolTable};
+use crate::token::Token;
+use std::fmt::Write;
+
+/// Builtins the C runtime implements, by their index in its table.
+const C_BUILTINS: [&str; 1] = ["puts"];
+
+/// Imported files are run by the interpreters; a C program is one file.
+const NO_MODULES: &str = "imports aren't supported when compiling to C";
+
+/// The runtime has no strings, nor a way to unwind to a `catch`.
+const NO_STRINGS: &str = "strings aren't supported when compiling to C";
+const NO_EXCEPTIONS: &str = "exceptions aren't supported when compiling to C";
+const NO_COLLECTIONS: &str = "arrays and hashes aren't supported when compiling to C";
+
+/// A Monkey function being turned into a C one. Every value lives in a slot
+/// of `s`, which owns a reference to it; the slots are released when the
+/// function returns.
+#[derive(Default)]
+struct FunctionScope {
+    body: String,
+    indent: usize,
+    num_slots: usize,
+    /// Slot of each local, indexed like `SymbolScope::Local` symbols.
+    locals: Vec<usize>,
+}
+
+/// Lowers a program to a single C file. It runs with the same scoping rules
+/// and errors as `compiler::Compiler` and the vm.
+pub struct CEmitter {
+    symbols: SymbolTable,
+    scopes: Vec<FunctionScope>,
+    /// The finished C functions; `mk_fn_0` is the top level.
+    functions: Vec<String>,
+    /// Loops so far, which number their labels.
+    num_loops: usize,
+    /// The loops being emitted, innermost last, and whether they've had a
+    /// `continue`, which needs a label.
+    loops: Vec<(usize, bool)>,
+}
+
+impl Default for CEmitter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns `program` as C source for a program that runs it.
+pub fn emit(program: &Program) -> Result<String, String> {
+    let mut e = CEmitter::new();
+    e.program(program)?;
+    Ok(e.finish())
+}
+
+impl CEmitter {
+    pub fn new() -> CEmitter {
+        let mut symbols = SymbolTable::new();
+        for (idx, name) in C_BUILTINS.iter().enumerate() {
+            symbols.define_builtin(idx, name);
+        }
+
+        CEmitter {
+            symbols,
+            scopes: Vec::new(),
+            // the top level takes the first slot
+            functions: vec![String::new()],
+            num_loops: 0,
+            loops: Vec::new(),
+        }
+    }
+
+    pub fn program(&mut self, program: &Program) -> Result<(), String> {
+        for stmt in &program.statements {
+            if let Statement::Let(i) = stmt {
+                if let Some(name) = i.name() {
+                    self.symbols.define(name.value());
+                }
+            }
+        }
+
+        self.scopes.push(FunctionScope {
+            indent: 1,
+            ..Default::default()
+        });
+        for stmt in &program.statements {
+            self.statement(stmt)?;
+        }
+        self.line("goto out;");
+
+        let scope = self.scopes.pop().expect("we pushed this scope");
+        self.functions[0] = function_definition(0, scope);
+        Ok(())
+    }
+
+    pub fn finish(self) -> String {
+        let globals = self.symbols.global_names();
+
+        let mut out = String::new();
+        out.push_str("/* Generated by plmmky emit-c. */\n");
+        out.push_str(RUNTIME);
+
+        let _ = writeln!(out, "\n#define MK_NUM_GLOBALS {}", globals.len());
+        // C has no empty arrays, so there's always at least one global
+        let size = globals.len().max(1);
+        let _ = writeln!(out, "static mk_value mk_globals[{}];", size);
+        let _ = writeln!(out, "static int mk_globals_set[{}];", size);
+        let names: Vec<String> = globals.iter().map(|g| format!("\"{}\"", g)).collect();
+        let _ = writeln!(
+            out,
+            "static const char *mk_global_names[{}] = {{ {} }};",
+            size,
+            if names.is_empty() {
+                String::from("0")
+            } else {
+                names.join(", ")
+            }
+        );
+        out.push_str(GLOBALS);
+
+        out.push('\n');
+        for idx in 0..self.functions.len() {
+            let _ = writeln!(
+                out,
+                "static mk_value mk_fn_{}(mk_closure *self, mk_value *args);",
+                idx
+            );
+        }
+
+        for f in &self.functions {
+            out.push('\n');
+            out.push_str(f);
+        }
+
+        out.push_str(
+            "\nint main(void) {\n    mk_decref(mk_fn_0(NULL, NULL));\n    fflush(stdout);\n    return 0;\n}\n",
+        );
+        out
+    }
+
+    fn scope(&mut self) -> &mut FunctionScope {
+        self.scopes.last_mut().expect("inside a function")
+    }
+
+    fn line(&mut self, text: &str) {
+        let scope = self.scope();
+        for _ in 0..scope.indent {
+            scope.body.push_str("    ");
+        }
+        scope.body.push_str(text);
+        scope.body.push('\n');
+    }
+
+    fn new_slot(&mut self) -> usize {
+        let scope = self.scope();
+        scope.num_slots += 1;
+        scope.num_slots - 1
+    }
+
+    /// Stores the new reference `value` in slot `dst`.
+    fn set(&mut self, dst: usize, value: &str) {
+        self.line(&format!("mk_set(&s[{}], {});", dst, value));
+    }
+
+    fn bind_local(&mut self, symbol: &Symbol, slot: usize) {
+        if symbol.cell {
+            let cell = self.load_slot(symbol, None);
+            self.line(&format!(
+                "mk_cell_set(s[{}], mk_incref(s[{}]));",
+                cell, slot
+            ));
+            return;
+        }
+
+        let scope = self.scope();
+        if symbol.index == scope.locals.len() {
+            scope.locals.push(slot);
+        } else {
+            let dst = scope.locals[symbol.index];
+            self.set(dst, &format!("mk_incref(s[{}])", slot));
+        }
+    }
+
+    fn load_symbol(&mut self, s: &Symbol, dst: Option<usize>) -> usize {
+        if !s.cell {
+            return self.load_slot(s, dst);
+        }
+        let cell = self.load_slot(s, None);
+        let dst = dst.unwrap_or_else(|| self.new_slot());
+        self.set(dst, &format!("mk_cell_get(s[{}])", cell));
+        dst
+    }
+
+    /// Like `load_symbol`, but loads the cell of a cell.
+    fn load_slot(&mut self, s: &Symbol, dst: Option<usize>) -> usize {
+        if s.scope == SymbolScope::Local {
+            let local = self.scope().locals[s.index];
+            return match dst {
+                Some(dst) if dst != local => {
+                    self.set(dst, &format!("mk_incref(s[{}])", local));
+                    dst
+                }
+                _ => local,
+            };
+        }
+
+        let dst = dst.unwrap_or_else(|| self.new_slot());
+        let value = match s.scope {
+            SymbolScope::Global => format!("mk_get_global({})", s.index),
+            SymbolScope::Builtin => format!("mk_builtin_value({})", s.index),
+            SymbolScope::Free => format!("mk_incref(self->free[{}])", s.index),
+            SymbolScope::Function => String::from("mk_self(self)"),
+            SymbolScope::Local => unreachable!("handled above"),
+        };
+        self.set(dst, &value);
+        dst
+    }
+
+    fn statement(&mut self, stmt: &Statement) -> Result<(), String> {
+        ast::deeper(|| {
+            match stmt {
+                Statement::Expression(i) => {
+                    if let Some(e) = i.expression() {
+                        self.expression(e, None)?;
+                    }
+                }
+                Statement::Let(i) => {
+                    let name = match i.name() {
+                        Some(n) => n.value(),
+                        None => return Ok(()),
+                    };
+
+                    let slot = self.new_slot();
+                    match i.value() {
+                        Some(Expression::Function(f)) => self.function(f, Some(name), slot)?,
+                        Some(value) => {
+                            self.expression(value, Some(slot))?;
+                        }
+                        None => self.set(slot, "mk_null()"),
+                    }
+
+                    let symbol = self.symbols.define(name);
+                    if symbol.scope == SymbolScope::Global {
+                        self.line(&format!("mk_set_global({}, s[{}]);", symbol.index, slot));
+                    } else {
+                        self.bind_local(&symbol, slot);
+                    }
+                }
+                Statement::Return(i) => {
+                    match i.return_value() {
+                        // the top level is a function too, but not one that's
+                        // called
+                        Some(value) if self.scopes.len() > 1 => return self.tail_return(value),
+                        Some(value) => {
+                            let src = self.expression(value, None)?;
+                            self.line(&format!("ret = mk_incref(s[{}]);", src));
+                        }
+                        None => self.line("ret = mk_null();"),
+                    }
+                    self.line("goto out;");
+                }
+                Statement::Import(_) => return Err(String::from(NO_MODULES)),
+                Statement::Throw(_) => return Err(String::from(NO_EXCEPTIONS)),
+                Statement::While(i) => {
+                    let n = self.start_loop();
+                    self.line(&format!("loop_{}:;", n));
+                    let cond = self.expression(i.condition(), None)?;
+                    self.line(&format!("if (!mk_truthy(s[{}])) goto break_{};", cond, n));
+                    if self.loop_body(n, i.body())? {
+                        self.line(&format!("continue_{}:;", n));
+                    }
+                    self.line(&format!("goto loop_{};", n));
+                    self.line(&format!("break_{}:;", n));
+                }
+                Statement::For(i) => {
+                    let end_expr = i.end().ok_or(NO_COLLECTIONS)?;
+                    let n = self.start_loop();
+                    // copies, so that rebinding a local they came from doesn't
+                    // change how often the loop runs
+                    let counter = self.new_slot();
+                    self.expression(i.start(), Some(counter))?;
+                    let end = self.new_slot();
+                    self.expression(end_expr, Some(end))?;
+                    self.line(&format!("mk_check_range(s[{}], s[{}]);", counter, end));
+
+                    self.line(&format!("loop_{}:;", n));
+                    let cond = self.new_slot();
+                    self.set(
+                        cond,
+                        &format!("mk_greater_than(s[{}], s[{}])", end, counter),
+                    );
+                    self.line(&format!("if (!mk_truthy(s[{}])) goto break_{};", cond, n));
+
+                    let symbol = self.symbols.define(i.variable().value());
+                    if symbol.scope == SymbolScope::Global {
+                        self.line(&format!("mk_set_global({}, s[{}]);", symbol.index, counter));
+                    } else {
+                        let slot = self.new_slot();
+                        self.set(slot, &format!("mk_incref(s[{}])", counter));
+                        self.bind_local(&symbol, slot);
+                    }
+
+                    if self.loop_body(n, i.body())? {
+                        self.line(&format!("continue_{}:;", n));
+                    }
+                    self.set(
+                        counter,
+                        &format!(
+                            "mk_arithmetic('+', s[{}], mk_integer(mk_int_make(0, 1)), NULL)",
+                            counter
+                        ),
+                    );
+                    self.line(&format!("goto loop_{};", n));
+                    self.line(&format!("break_{}:;", n));
+                }
+                Statement::Break(_) | Statement::Continue(_) => {
+                    let (n, continued) = match self.loops.last_mut() {
+                        Some(l) => (l.0, &mut l.1),
+                        None => {
+                            return Err(format!(
+                                "{} outside of a loop",
+                                stmt.token().literal.to_lowercase()
+                            ))
+                        }
+                    };
+                    if let Statement::Break(_) = stmt {
+                        self.line(&format!("goto break_{};", n));
+                    } else {
+                        *continued = true;
+                        self.line(&format!("goto continue_{};", n));
+                    }
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Numbers a new loop for its labels.
+    fn start_loop(&mut self) -> usize {
+        self.num_loops += 1;
+        self.num_loops - 1
+    }
+
+    /// Emits the body of loop `n`, returning whether it has a `continue`.
+    fn loop_body(&mut self, n: usize, body: &Block) -> Result<bool, String> {
+        self.loops.push((n, false));
+        let emitted = body.statements.iter().try_for_each(|s| self.statement(s));
+        let (_, continued) = self.loops.pop().expect("we pushed this loop");
+        emitted.map(|_| continued)
+    }
+
+    fn block_value(&mut self, block: &Block, dst: usize) -> Result<(), String> {
+        let (last, rest) = match block.statements.split_last() {
+            Some(split) => split,
+            None => {
+                self.set(dst, "mk_null()");
+                return Ok(());
+            }
+        };
+
+        for stmt in rest {
+            self.statement(stmt)?;
+        }
+
+        match last {
+            Statement::Expression(i) if i.expression().is_some() => {
+                self.expression(i.expression().expect("checked"), Some(dst))?;
+            }
+            _ => {
+                self.statement(last)?;
+                self.set(dst, "mk_null()");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the value of `block`, with the expression it ends in in
+    /// tail position.
+    fn tail_block(&mut self, block: &Block) -> Result<(), String> {
+        match block.statements.split_last() {
+            Some((Statement::Expression(i), rest)) if i.expression().is_some() => {
+                for stmt in rest {
+                    self.statement(stmt)?;
+                }
+                self.tail_return(i.expression().expect("checked"))
+            }
+            _ => {
+                let src = self.new_slot();
+                self.block_value(block, src)?;
+                self.line(&format!("ret = mk_incref(s[{}]);", src));
+                self.line("goto out;");
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns the value of `e`. A call is left for `mk_call` to make once
+    /// this function has returned, and so is one that's the value of a
+    /// branch of an `if`.
+    fn tail_return(&mut self, e: &Expression) -> Result<(), String> {
+        match e {
+            Expression::Call(i) => {
+                let (func, args) = self.call_operands(i)?;
+                self.line(&format!(
+                    "ret = mk_tail_call(s[{}], {}, {});",
+                    func,
+                    i.arguments().len(),
+                    args
+                ));
+                self.line("goto out;");
+            }
+            Expression::If(i) => {
+                let cond = self.expression(i.condition(), None)?;
+                self.line(&format!("if (mk_truthy(s[{}])) {{", cond));
+                self.scope().indent += 1;
+                self.tail_block(i.consequence())?;
+                self.scope().indent -= 1;
+                self.line("}");
+                match i.alternative() {
+                    Some(alt) => self.tail_block(alt)?,
+                    None => {
+                        self.line("ret = mk_null();");
+                        self.line("goto out;");
+                    }
+                }
+            }
+            _ => {
+                let src = self.expression(e, None)?;
+                self.line(&format!("ret = mk_incref(s[{}]);", src));
+                self.line("goto out;");
+            }
+        }
+        Ok(())
+    }
+
+    /// The function slot of a call, and its arguments as a C array.
+    fn call_operands(&mut self, i: &CallInternal) -> Result<(usize, String), String> {
+        let arguments: Vec<&Expression> = i.arguments().iter().collect();
+        let func = self.operand(i.function(), &arguments)?;
+
+        let mut args = Vec::with_capacity(arguments.len());
+        for (idx, arg) in arguments.iter().enumerate() {
+            let slot = self.operand(arg, &arguments[idx + 1..])?;
+            args.push(format!("s[{}]", slot));
+        }
+
+        let args = if args.is_empty() {
+            String::from("NULL")
+        } else {
+            format!("(mk_value[]){{ {} }}", args.join(", "))
+        };
+        Ok((func, args))
+    }
+
+    fn function(
+        &mut self,
+        f: &FunctionInternal,
+        name: Option<&str>,
+        dst: usize,
+    ) -> Result<(), String> {
+        let idx = self.functions.len();
+        self.functions.push(String::new());
+
+        self.scopes.push(FunctionScope {
+            indent: 1,
+            ..Default::default()
+        });
+        self.symbols.push();
+
+        if let Some(name) = name {
+            self.symbols.define_function_name(name);
+        }
+
+        for (i, p) in f.parameters().iter().enumerate() {
+            let symbol = self.symbols.define(p.value());
+            let slot = self.new_slot();
+            self.set(slot, &format!("mk_incref(args[{}])", i));
+            self.bind_local(&symbol, slot);
+        }
+        for local in self.symbols.define_cells(symbol_table::cells(f)) {
+            if local == self.scope().locals.len() {
+                let slot = self.new_slot();
+                self.scope().locals.push(slot);
+            }
+            let slot = self.scope().locals[local];
+            self.set(slot, &format!("mk_cell_new(s[{}])", slot));
+        }
+
+        let statements = &f.body().statements;
+        match statements.split_last() {
+            Some((Statement::Expression(i), rest)) if i.expression().is_some() => {
+                for stmt in rest {
+                    self.statement(stmt)?;
+                }
+                self.tail_return(i.expression().expect("checked"))?;
+            }
+            _ => {
+                for stmt in statements {
+                    self.statement(stmt)?;
+                }
+                self.line("goto out;");
+            }
+        }
+
+        let (free, _) = self.symbols.pop();
+        let scope = self.scopes.pop().expect("we pushed this scope");
+        self.functions[idx] = function_definition(idx, scope);
+
+        let slots: Vec<String> = free
+            .iter()
+            .map(|s| format!("s[{}]", self.load_slot(s, None)))
+            .collect();
+        let values = if slots.is_empty() {
+            String::from("NULL")
+        } else {
+            format!("(mk_value[]){{ {} }}", slots.join(", "))
+        };
+
+        self.set(
+            dst,
+            &format!(
+                "mk_closure_new(mk_fn_{}, {}, {}, {})",
+                idx,
+                f.parameters().len(),
+                slots.len(),
+                values
+            ),
+        );
+        Ok(())
+    }
+
+    fn expression(&mut self, e: &Expression, dst: Option<usize>) -> Result<usize, String> {
+        ast::deeper(|| {
+            if let Expression::Identifier(i) = e {
+                return match self.symbols.resolve(i.value()) {
+                    Some(s) => Ok(self.load_symbol(&s, dst)),
+                    None => Err(format!("undefined variable {}", i.value())),
+                };
+            }
+
+            let dst = dst.unwrap_or_else(|| self.new_slot());
+
+            match e {
+                Expression::Integer(i) => {
+                    let v = i.value();
+                    let value = format!(
+                        "mk_integer(mk_int_make(UINT64_C({}), UINT64_C({})))",
+                        (v >> 64) as u64,
+                        v as u64
+                    );
+                    self.set(dst, &value);
+                }
+                Expression::Boolean(b) => {
+                    self.set(dst, &format!("mk_boolean({})", b.value() as u8));
+                }
+                Expression::Prefix(i) => {
+                    let src = self.expression(i.right(), None)?;
+                    let value = match i.operator() {
+                        "!" => format!("mk_bang(s[{}])", src),
+                        "-" => format!("mk_minus(s[{}], {})", src, c_location(i.token())),
+                        "~" => format!("mk_bit_not(s[{}])", src),
+                        op => return Err(format!("unknown operator {}", op)),
+                    };
+                    self.set(dst, &value);
+                }
+                Expression::Infix(i) if matches!(i.operator(), "&&" | "||") => {
+                    let cond = self.expression(i.left(), None)?;
+                    let (test, decided) = match i.operator() {
+                        "&&" => ("", "0"),
+                        _ => ("!", "1"),
+                    };
+                    self.line(&format!("if ({}mk_truthy(s[{}])) {{", test, cond));
+                    self.scope().indent += 1;
+                    let rhs = self.expression(i.right(), None)?;
+                    self.set(dst, &format!("mk_boolean(mk_truthy(s[{}]))", rhs));
+                    self.scope().indent -= 1;
+                    self.line("} else {");
+                    self.scope().indent += 1;
+                    self.set(dst, &format!("mk_boolean({})", decided));
+                    self.scope().indent -= 1;
+                    self.line("}");
+                }
+                Expression::Infix(i) => {
+                    let lhs = self.operand(i.left(), &[i.right()])?;
+                    let rhs = self.expression(i.right(), None)?;
+                    self.set(dst, &infix(i.operator(), lhs, rhs, i.token())?);
+                }
+                Expression::Assign(i) => {
+                    let symbol = self.symbols.resolve_assignment(i.name().value())?;
+                    match i.infix_operator() {
+                        Some(op) => {
+                            let lhs = if binds_locals(i.value()) {
+                                let copy = self.new_slot();
+                                self.load_symbol(&symbol, Some(copy))
+                            } else {
+                                self.load_symbol(&symbol, None)
+                            };
+                            let rhs = self.expression(i.value(), None)?;
+                            self.set(dst, &infix(op, lhs, rhs, i.token())?);
+                        }
+                        None => {
+                            self.expression(i.value(), Some(dst))?;
+                        }
+                    }
+
+                    if symbol.scope == SymbolScope::Global {
+                        self.line(&format!("mk_set_global({}, s[{}]);", symbol.index, dst));
+                    } else {
+                        self.bind_local(&symbol, dst);
+                    }
+                }
+                Expression::If(i) => {
+                    let cond = self.expression(i.condition(), None)?;
+                    self.line(&format!("if (mk_truthy(s[{}])) {{", cond));
+                    self.scope().indent += 1;
+                    self.block_value(i.consequence(), dst)?;
+                    self.scope().indent -= 1;
+                    self.line("} else {");
+                    self.scope().indent += 1;
+                    match i.alternative() {
+                        Some(alt) => self.block_value(alt, dst)?,
+                        None => self.set(dst, "mk_null()"),
+                    }
+                    self.scope().indent -= 1;
+                    self.line("}");
+                }
+                Expression::Function(f) => self.function(f, None, dst)?,
+                Expression::Call(i) => {
+                    let (func, args) = self.call_operands(i)?;
+                    self.set(
+                        dst,
+                        &format!("mk_call(s[{}], {}, {})", func, i.arguments().len(), args),
+                    );
+                }
+                Expression::Member(_) => return Err(String::from(NO_MODULES)),
+                Expression::String(_) => return Err(String::from(NO_STRINGS)),
+                Expression::Try(_) => return Err(String::from(NO_EXCEPTIONS)),
+                Expression::Array(_)
+                | Expression::Hash(_)
+                | Expression::Index(_)
+                | Expression::IndexAssign(_) => return Err(String::from(NO_COLLECTIONS)),
+                Expression::Macro(_) => return Err(String::from(macros::STRAY_MACRO)),
+                Expression::Identifier(_) => unreachable!("handled above"),
+            }
+
+            Ok(dst)
+        })
+    }
+
+    /// Like `regcompiler`, copies a local operand if a later one could
+    /// rebind it.
+    fn operand(&mut self, e: &Expression, later: &[&Expression]) -> Result<usize, String> {
+        if later.iter().any(|l| binds_locals(l)) {
+            let dst = self.new_slot();
+            self.expression(e, Some(dst))
+        } else {
+            self.expression(e, None)
+        }
+    }
+}
+
+/// The C expression applying the infix operator `op`, which is `token`, to
+/// two slots.
+fn infix(op: &str, lhs: usize, rhs: usize, token: &Token) -> Result<String, String> {
+    let at = c_location(token);
+    Ok(match op {
+        "+" | "-" | "*" | "/" | "%" => {
+            format!("mk_arithmetic('{}', s[{}], s[{}], {})", op, lhs, rhs, at)
+        }
+        "&" | "|" | "^" => format!("mk_bitwise('{}', s[{}], s[{}], NULL)", op, lhs, rhs),
+        "<<" | ">>" => format!("mk_bitwise('{}', s[{}], s[{}], {})", &op[..1], lhs, rhs, at),
+        ">" => format!("mk_greater_than(s[{}], s[{}])", lhs, rhs),
+        "<" => format!("mk_greater_than(s[{}], s[{}])", rhs, lhs),
+        ">=" => format!("mk_greater_equal(s[{}], s[{}])", lhs, rhs),
+        "<=" => format!("mk_greater_equal(s[{}], s[{}])", rhs, lhs),
+        "==" => format!("mk_boolean(mk_equal(s[{}], s[{}]))", lhs, rhs),
+        "!=" => format!("mk_boolean(!mk_equal(s[{}], s[{}]))", lhs, rhs),
+        op => return Err(format!("unknown operator {}", op)),
+    })
+}
+
+/// Where `token` is, as a C string literal for arithmetic errors, or `NULL`
+/// when the source has no locations.
+fn c_location(token: &Token) -> String {
+    let l = match token.local() {
+        Some(l) => l.to_string(),
+        None => return String::from("NULL"),
+    };
+
+    // `?` too, so that nothing in a path reads as a trigraph
+    let mut out = String::from("\"");
+    for b in l.bytes() {
+        match b {
+            b'"' | b'\\' | b'?' => {
+                out.push('\\');
+                out.push(b as char);
+            }
+            b' '..=b'~' => out.push(b as char),
+            _ => {
+                let _ = write!(out, "\\{:03o}", b);
+            }
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn function_definition(idx: usize, scope: FunctionScope) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "static mk_value mk_fn_{}(mk_closure *self, mk_value *args) {{",
+        idx
+    );
+    let _ = writeln!(
+        out,
+        "    mk_value s[{}] = {{ {{ 0 }} }};",
+        scope.num_slots.max(1)
+    );
+    out.push_str("    mk_value ret = { 0 };\n");
+    out.push_str("    (void)self;\n    (void)args;\n");
+    out.push_str(&scope.body);
+    out.push_str("out:\n");
+    let _ = writeln!(
+        out,
+        "    for (int i = 0; i < {}; i++) mk_decref(s[i]);",
+        scope.num_slots.max(1)
+    );
+    out.push_str("    return ret;\n}\n");
+    out
+}
+
+/// Tagged values, 128 bit integers, closures and the operators, in plain
+/// C99. Errors print like the vm's and exit, so nothing needs unwinding.
+const RUNTIME: &str = r#"
+#include <stdarg.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+#define MK_MAX_FRAMES 1024
+
+/* A program uses only some of the runtime, so keep compilers quiet about
+   the rest. */
+#if defined(__GNUC__)
+#define MK_FN static __attribute__((unused))
+#else
+#define MK_FN static
+#endif
+
+/* Monkey integers are 128 bit two's complement, which C doesn't have. */
+typedef struct {
+    uint64_t hi, lo;
+} mk_int;
+
+MK_FN mk_int mk_int_make(uint64_t hi, uint64_t lo) {
+    mk_int r;
+    r.hi = hi;
+    r.lo = lo;
+    return r;
+}
+
+MK_FN int mk_int_is_negative(mk_int a) { return (a.hi >> 63) != 0; }
+
+MK_FN mk_int mk_int_add(mk_int a, mk_int b) {
+    mk_int r;
+    r.lo = a.lo + b.lo;
+    r.hi = a.hi + b.hi + (r.lo < a.lo);
+    return r;
+}
+
+MK_FN mk_int mk_int_negate(mk_int a) {
+    mk_int r;
+    r.lo = ~a.lo + 1;
+    r.hi = ~a.hi + (r.lo == 0);
+    return r;
+}
+
+MK_FN mk_int mk_int_sub(mk_int a, mk_int b) { return mk_int_add(a, mk_int_negate(b)); }
+
+/* The full product of two 64 bit numbers. */
+MK_FN mk_int mk_mul64(uint64_t a, uint64_t b) {
+    uint64_t a0 = a & 0xffffffffu, a1 = a >> 32;
+    uint64_t b0 = b & 0xffffffffu, b1 = b >> 32;
+    uint64_t p00 = a0 * b0, p01 = a0 * b1, p10 = a1 * b0, p11 = a1 * b1;
+    uint64_t mid = (p00 >> 32) + (p01 & 0xffffffffu) + (p10 & 0xffffffffu);
+    return mk_int_make(p11 + (p01 >> 32) + (p10 >> 32) + (mid >> 32),
+                       (p00 & 0xffffffffu) | (mid << 32));
+}
+
+MK_FN int mk_int_is_min(mk_int a) { return a.hi == (uint64_t)1 << 63 && a.lo == 0; }
+
+/* The checked operations store the result in `r`, and give whether it fit. */
+MK_FN int mk_int_add_checked(mk_int a, mk_int b, mk_int *r) {
+    *r = mk_int_add(a, b);
+    /* it didn't if the operands have the same sign and the sum doesn't */
+    return (((r->hi ^ a.hi) & (r->hi ^ b.hi)) >> 63) == 0;
+}
+
+MK_FN int mk_int_sub_checked(mk_int a, mk_int b, mk_int *r) {
+    *r = mk_int_sub(a, b);
+    return (((a.hi ^ b.hi) & (a.hi ^ r->hi)) >> 63) == 0;
+}
+
+MK_FN int mk_int_mul_checked(mk_int a, mk_int b, mk_int *r) {
+    int negative = mk_int_is_negative(a) != mk_int_is_negative(b);
+    mk_int m, cross;
+    /* multiply the magnitudes, at most one of which has a high half */
+    a = mk_int_is_negative(a) ? mk_int_negate(a) : a;
+    b = mk_int_is_negative(b) ? mk_int_negate(b) : b;
+    if (a.hi != 0 && b.hi != 0) {
+        return 0;
+    }
+    if (a.hi == 0) {
+        m = a;
+        a = b;
+        b = m;
+    }
+    m = mk_mul64(a.lo, b.lo);
+    cross = mk_mul64(a.hi, b.lo);
+    if (cross.hi != 0 || m.hi + cross.lo < m.hi) {
+        return 0;
+    }
+    m.hi += cross.lo;
+    /* a magnitude of 2^127 or more only fits as the most negative number */
+    if (mk_int_is_negative(m) && !(negative && mk_int_is_min(m))) {
+        return 0;
+    }
+    *r = negative ? mk_int_negate(m) : m;
+    return 1;
+}
+
+MK_FN int mk_uint_less(mk_int a, mk_int b) {
+    return a.hi != b.hi ? a.hi < b.hi : a.lo < b.lo;
+}
+
+/* Unsigned long division, a bit at a time. */
+MK_FN mk_int mk_uint_divmod(mk_int n, mk_int d, mk_int *rem) {
+    mk_int q = mk_int_make(0, 0), r = mk_int_make(0, 0);
+    for (int i = 127; i >= 0; i--) {
+        uint64_t bit = i >= 64 ? (n.hi >> (i - 64)) & 1 : (n.lo >> i) & 1;
+        r.hi = (r.hi << 1) | (r.lo >> 63);
+        r.lo = (r.lo << 1) | bit;
+        if (!mk_uint_less(r, d)) {
+            r = mk_int_sub(r, d);
+            if (i >= 64) {
+                q.hi |= (uint64_t)1 << (i - 64);
+            } else {
+                q.lo |= (uint64_t)1 << i;
+            }
+        }
+    }
+    if (rem) {
+        *rem = r;
+    }
+    return q;
+}
+
+/* Division truncating towards zero, as in Rust. */
+MK_FN mk_int mk_int_div(mk_int a, mk_int b) {
+    int negative = mk_int_is_negative(a) != mk_int_is_negative(b);
+    mk_int q = mk_uint_divmod(mk_int_is_negative(a) ? mk_int_negate(a) : a,
+                              mk_int_is_negative(b) ? mk_int_negate(b) : b, NULL);
+    return negative ? mk_int_negate(q) : q;
+}
+
+/* The remainder of that division, which takes the sign of `a`. */
+MK_FN mk_int mk_int_rem(mk_int a, mk_int b) {
+    mk_int r;
+    mk_uint_divmod(mk_int_is_negative(a) ? mk_int_negate(a) : a,
+                   mk_int_is_negative(b) ? mk_int_negate(b) : b, &r);
+    return mk_int_is_negative(a) ? mk_int_negate(r) : r;
+}
+
+MK_FN int mk_int_greater(mk_int a, mk_int b) {
+    uint64_t sign = (uint64_t)1 << 63;
+    if (a.hi != b.hi) {
+        return (a.hi ^ sign) > (b.hi ^ sign);
+    }
+    return a.lo > b.lo;
+}
+
+/* Writes `a` in decimal to `out`, which has room for 41 characters. */
+MK_FN void mk_int_format(mk_int a, char *out) {
+    char digits[40];
+    int n = 0;
+    if (mk_int_is_negative(a)) {
+        *out++ = '-';
+        a = mk_int_negate(a);
+    }
+    do {
+        mk_int rem;
+        a = mk_uint_divmod(a, mk_int_make(0, 10), &rem);
+        digits[n++] = (char)('0' + rem.lo);
+    } while (a.hi != 0 || a.lo != 0);
+    while (n > 0) {
+        *out++ = digits[--n];
+    }
+    *out = '\0';
+}
+
+MK_FN void mk_int_print(mk_int a) {
+    char out[41];
+    mk_int_format(a, out);
+    fputs(out, stdout);
+}
+
+MK_FN mk_int mk_int_shift_left(mk_int a, unsigned n) {
+    if (n >= 64) {
+        return mk_int_make(a.lo << (n - 64), 0);
+    }
+    if (n == 0) {
+        return a;
+    }
+    return mk_int_make((a.hi << n) | (a.lo >> (64 - n)), a.lo << n);
+}
+
+/* Shifts in copies of the sign bit, as Rust does for signed integers. */
+MK_FN mk_int mk_int_shift_right(mk_int a, unsigned n) {
+    uint64_t sign = mk_int_is_negative(a) ? ~(uint64_t)0 : 0;
+    if (n >= 64) {
+        n -= 64;
+        return mk_int_make(sign, n == 0 ? a.hi : (a.hi >> n) | (sign << (64 - n)));
+    }
+    if (n == 0) {
+        return a;
+    }
+    return mk_int_make((a.hi >> n) | (sign << (64 - n)), (a.lo >> n) | (a.hi << (64 - n)));
+}
+
+typedef enum { MK_NULL, MK_INTEGER, MK_BOOLEAN, MK_FUNCTION, MK_BUILTIN, MK_CELL } mk_tag;
+
+typedef struct mk_closure mk_closure;
+typedef struct mk_cell mk_cell;
+
+typedef struct {
+    mk_tag tag;
+    union {
+        mk_int integer;
+        int boolean;
+        mk_closure *closure;
+        int builtin;
+        mk_cell *cell;
+    } as;
+} mk_value;
+
+typedef mk_value (*mk_code)(mk_closure *self, mk_value *args);
+
+/* A function with the values it closed over, reference counted. */
+struct mk_closure {
+    long refs;
+    mk_code code;
+    int num_parameters;
+    int num_free;
+    mk_value free[];
+};
+
+/* A local that a function shares with the closures made in it. Only ever
+   in a slot or a closure's free values, never a value of its own. */
+struct mk_cell {
+    long refs;
+    mk_value value;
+};
+
+MK_FN void mk_error(const char *fmt, ...) {
+    va_list ap;
+    fflush(stdout);
+    fputs("runtime error: ", stderr);
+    va_start(ap, fmt);
+    vfprintf(stderr, fmt, ap);
+    va_end(ap);
+    fputc('\n', stderr);
+    exit(1);
+}
+
+MK_FN mk_value mk_null(void) {
+    mk_value v = { MK_NULL };
+    return v;
+}
+
+MK_FN mk_value mk_integer(mk_int i) {
+    mk_value v;
+    v.tag = MK_INTEGER;
+    v.as.integer = i;
+    return v;
+}
+
+MK_FN mk_value mk_boolean(int b) {
+    mk_value v;
+    v.tag = MK_BOOLEAN;
+    v.as.boolean = b != 0;
+    return v;
+}
+
+MK_FN mk_value mk_builtin_value(int idx) {
+    mk_value v;
+    v.tag = MK_BUILTIN;
+    v.as.builtin = idx;
+    return v;
+}
+
+MK_FN mk_value mk_incref(mk_value v) {
+    if (v.tag == MK_FUNCTION) {
+        v.as.closure->refs++;
+    } else if (v.tag == MK_CELL) {
+        v.as.cell->refs++;
+    }
+    return v;
+}
+
+MK_FN void mk_decref(mk_value v) {
+    if (v.tag == MK_FUNCTION && --v.as.closure->refs == 0) {
+        for (int i = 0; i < v.as.closure->num_free; i++) {
+            mk_decref(v.as.closure->free[i]);
+        }
+        free(v.as.closure);
+    } else if (v.tag == MK_CELL && --v.as.cell->refs == 0) {
+        mk_decref(v.as.cell->value);
+        free(v.as.cell);
+    }
+}
+
+/* Replaces what's in `slot` with the new reference `v`. */
+MK_FN void mk_set(mk_value *slot, mk_value v) {
+    mk_value old = *slot;
+    *slot = v;
+    mk_decref(old);
+}
+
+MK_FN mk_value mk_closure_new(mk_code code, int num_parameters, int num_free, mk_value *free_values) {
+    mk_closure *c = malloc(sizeof(mk_closure) + sizeof(mk_value) * (size_t)num_free);
+    if (!c) {
+        mk_error("out of memory");
+    }
+    c->refs = 1;
+    c->code = code;
+    c->num_parameters = num_parameters;
+    c->num_free = num_free;
+    for (int i = 0; i < num_free; i++) {
+        c->free[i] = mk_incref(free_values[i]);
+    }
+
+    mk_value v;
+    v.tag = MK_FUNCTION;
+    v.as.closure = c;
+    return v;
+}
+
+/* A new cell holding a reference to `v`. */
+MK_FN mk_value mk_cell_new(mk_value v) {
+    mk_cell *c = malloc(sizeof(mk_cell));
+    if (!c) {
+        mk_error("out of memory");
+    }
+    c->refs = 1;
+    c->value = mk_incref(v);
+
+    mk_value cell;
+    cell.tag = MK_CELL;
+    cell.as.cell = c;
+    return cell;
+}
+
+MK_FN mk_value mk_cell_get(mk_value cell) {
+    return mk_incref(cell.as.cell->value);
+}
+
+/* Replaces what's in `cell` with the new reference `v`. */
+MK_FN void mk_cell_set(mk_value cell, mk_value v) {
+    mk_set(&cell.as.cell->value, v);
+}
+
+MK_FN mk_value mk_self(mk_closure *self) {
+    mk_value v;
+    v.tag = MK_FUNCTION;
+    v.as.closure = self;
+    return mk_incref(v);
+}
+
+MK_FN const char *mk_type_name(mk_value v) {
+    switch (v.tag) {
+    case MK_INTEGER:
+        return "INTEGER";
+    case MK_BOOLEAN:
+        return "BOOLEAN";
+    case MK_FUNCTION:
+        return "FUNCTION";
+    case MK_BUILTIN:
+        return "BUILTIN";
+    default:
+        return "NULL";
+    }
+}
+
+MK_FN int mk_truthy(mk_value v) {
+    return !(v.tag == MK_NULL || (v.tag == MK_BOOLEAN && !v.as.boolean));
+}
+
+MK_FN int mk_equal(mk_value l, mk_value r) {
+    if (l.tag != r.tag) {
+        return 0;
+    }
+    switch (l.tag) {
+    case MK_INTEGER:
+        return l.as.integer.hi == r.as.integer.hi && l.as.integer.lo == r.as.integer.lo;
+    case MK_BOOLEAN:
+        return l.as.boolean == r.as.boolean;
+    case MK_FUNCTION:
+        return l.as.closure == r.as.closure;
+    case MK_BUILTIN:
+        return l.as.builtin == r.as.builtin;
+    default:
+        return 1;
+    }
+}
+
+/* An error from the arithmetic of the operator at `at`, which is NULL when
+   the source had no locations. */
+MK_FN void mk_arithmetic_error(const char *message, const char *at) {
+    if (at) {
+        mk_error("%s at %s", message, at);
+    }
+    mk_error("%s", message);
+}
+
+/* Integer arithmetic is checked; there's no wrapping mode. */
+MK_FN mk_value mk_arithmetic(char op, mk_value l, mk_value r, const char *at) {
+    mk_int a, b, result = { 0, 0 };
+    int fits = 1;
+    if (l.tag != MK_INTEGER || r.tag != MK_INTEGER) {
+        mk_error("unsupported types for binary operation: %s %s", mk_type_name(l), mk_type_name(r));
+    }
+    a = l.as.integer;
+    b = r.as.integer;
+    switch (op) {
+    case '+':
+        fits = mk_int_add_checked(a, b, &result);
+        break;
+    case '-':
+        fits = mk_int_sub_checked(a, b, &result);
+        break;
+    case '*':
+        fits = mk_int_mul_checked(a, b, &result);
+        break;
+    default:
+        if (b.hi == 0 && b.lo == 0) {
+            mk_arithmetic_error("division by zero", at);
+        }
+        /* the one quotient that doesn't fit, which Rust's checked
+           remainder refuses too */
+        fits = !(mk_int_is_min(a) && b.hi == UINT64_MAX && b.lo == UINT64_MAX);
+        if (fits) {
+            result = op == '%' ? mk_int_rem(a, b) : mk_int_div(a, b);
+        }
+    }
+    if (!fits) {
+        mk_arithmetic_error("integer overflow", at);
+    }
+    return mk_integer(result);
+}
+
+/* `&`, `|` and `^`, and the shifts `<<` and `>>` as '<' and '>'. */
+MK_FN mk_value mk_bitwise(char op, mk_value l, mk_value r, const char *at) {
+    mk_int a, b;
+    if (l.tag != MK_INTEGER || r.tag != MK_INTEGER) {
+        mk_error("unsupported types for binary operation: %s %s", mk_type_name(l), mk_type_name(r));
+    }
+    a = l.as.integer;
+    b = r.as.integer;
+    switch (op) {
+    case '&':
+        return mk_integer(mk_int_make(a.hi & b.hi, a.lo & b.lo));
+    case '|':
+        return mk_integer(mk_int_make(a.hi | b.hi, a.lo | b.lo));
+    case '^':
+        return mk_integer(mk_int_make(a.hi ^ b.hi, a.lo ^ b.lo));
+    default:
+        if (b.hi != 0 || b.lo >= 128) {
+            char amount[41], message[80];
+            mk_int_format(b, amount);
+            sprintf(message, "shift amount out of range: %s", amount);
+            mk_arithmetic_error(message, at);
+        }
+        if (op == '<') {
+            mk_int shifted = mk_int_shift_left(a, (unsigned)b.lo);
+            mk_int back = mk_int_shift_right(shifted, (unsigned)b.lo);
+            if (back.hi != a.hi || back.lo != a.lo) {
+                mk_arithmetic_error("integer overflow", at);
+            }
+            return mk_integer(shifted);
+        }
+        return mk_integer(mk_int_shift_right(a, (unsigned)b.lo));
+    }
+}
+
+MK_FN mk_value mk_greater_than(mk_value l, mk_value r) {
+    if (l.tag != MK_INTEGER || r.tag != MK_INTEGER) {
+        mk_error("unknown operator: %s > %s", mk_type_name(l), mk_type_name(r));
+    }
+    return mk_boolean(mk_int_greater(l.as.integer, r.as.integer));
+}
+
+MK_FN mk_value mk_greater_equal(mk_value l, mk_value r) {
+    if (l.tag != MK_INTEGER || r.tag != MK_INTEGER) {
+        mk_error("unknown operator: %s >= %s", mk_type_name(l), mk_type_name(r));
+    }
+    return mk_boolean(!mk_int_greater(r.as.integer, l.as.integer));
+}
+
+MK_FN void mk_check_range(mk_value start, mk_value end) {
+    if (start.tag != MK_INTEGER || end.tag != MK_INTEGER) {
+        mk_error("unsupported types for range: %s %s", mk_type_name(start), mk_type_name(end));
+    }
+}
+
+MK_FN mk_value mk_minus(mk_value v, const char *at) {
+    if (v.tag != MK_INTEGER) {
+        mk_error("unsupported type for negation: %s", mk_type_name(v));
+    }
+    if (mk_int_is_min(v.as.integer)) {
+        mk_arithmetic_error("integer overflow", at);
+    }
+    return mk_integer(mk_int_negate(v.as.integer));
+}
+
+MK_FN mk_value mk_bit_not(mk_value v) {
+    if (v.tag != MK_INTEGER) {
+        mk_error("unsupported type for bitwise not: %s", mk_type_name(v));
+    }
+    return mk_integer(mk_int_make(~v.as.integer.hi, ~v.as.integer.lo));
+}
+
+MK_FN mk_value mk_bang(mk_value v) { return mk_boolean(!mk_truthy(v)); }
+
+static const char *mk_builtin_names[] = { "puts" };
+
+MK_FN void mk_print(mk_value v) {
+    switch (v.tag) {
+    case MK_INTEGER:
+        mk_int_print(v.as.integer);
+        break;
+    case MK_BOOLEAN:
+        fputs(v.as.boolean ? "true" : "false", stdout);
+        break;
+    case MK_FUNCTION:
+        fputs("<function>", stdout);
+        break;
+    case MK_BUILTIN:
+        printf("<builtin %s>", mk_builtin_names[v.as.builtin]);
+        break;
+    default:
+        fputs("null", stdout);
+    }
+}
+
+MK_FN mk_value mk_puts(int argc, mk_value *args) {
+    for (int i = 0; i < argc; i++) {
+        mk_print(args[i]);
+        putchar('\n');
+    }
+    return mk_null();
+}
+
+static mk_value (*mk_builtins[])(int, mk_value *) = { mk_puts };
+
+static int mk_depth;
+
+/* A call in tail position, made by `mk_call` once the function that made
+   it has returned. It holds references to the function and arguments. */
+static struct {
+    int pending;
+    mk_value f;
+    int argc;
+    mk_value *args;
+} mk_tail;
+
+MK_FN mk_value mk_tail_call(mk_value f, int argc, mk_value *args) {
+    mk_tail.args = malloc(sizeof(mk_value) * (size_t)(argc > 0 ? argc : 1));
+    if (!mk_tail.args) {
+        mk_error("out of memory");
+    }
+    for (int i = 0; i < argc; i++) {
+        mk_tail.args[i] = mk_incref(args[i]);
+    }
+    mk_tail.f = mk_incref(f);
+    mk_tail.argc = argc;
+    mk_tail.pending = 1;
+    return mk_null();
+}
+
+MK_FN mk_value mk_call(mk_value f, int argc, mk_value *args) {
+    mk_value result;
+    switch (f.tag) {
+    case MK_FUNCTION:
+        if (argc != f.as.closure->num_parameters) {
+            mk_error("wrong number of arguments: want=%d, got=%d", f.as.closure->num_parameters, argc);
+        }
+        /* the top level counts as a frame, as in the vm */
+        if (mk_depth + 1 >= MK_MAX_FRAMES) {
+            mk_error("stack overflow");
+        }
+        mk_depth++;
+        result = f.as.closure->code(f.as.closure, args);
+        /* tail calls run here, at the depth of the call that made the
+           first one */
+        while (mk_tail.pending) {
+            mk_value g = mk_tail.f;
+            int n = mk_tail.argc;
+            mk_value *a = mk_tail.args;
+            mk_tail.pending = 0;
+            if (g.tag == MK_FUNCTION && n == g.as.closure->num_parameters) {
+                result = g.as.closure->code(g.as.closure, a);
+            } else {
+                result = mk_call(g, n, a);
+            }
+            for (int i = 0; i < n; i++) {
+                mk_decref(a[i]);
+            }
+            free(a);
+            mk_decref(g);
+        }
+        mk_depth--;
+        return result;
+    case MK_BUILTIN:
+        return mk_builtins[f.as.builtin](argc, args);
+    default:
+        mk_error("calling non-function: %s", mk_type_name(f));
+        return mk_null();
+    }
+}
+"#;
+
+const GLOBALS: &str = r#"
+MK_FN mk_value mk_get_global(int idx) {
+    if (!mk_globals_set[idx]) {
+        mk_error("identifier not found: %s", mk_global_names[idx]);
+    }
+    return mk_incref(mk_globals[idx]);
+}
+
+MK_FN void mk_set_global(int idx, mk_value v) {
+    mk_set(&mk_globals[idx], mk_incref(v));
+    mk_globals_set[idx] = 1;
+}
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn emit_str(input: &str) -> Result<String, String> {
+        let l = Lexer::new(input, true, None);
+        let mut p = Parser::new(l);
+        let program = p.parse_program().expect("Program should be Some here");
+        assert_eq!(p.errors().len(), 0, "{:?}", p.errors());
+        emit(&program)
+    }
+
+    #[test]
+    fn test_emitted_functions() {
+        let c = emit_str("let add = fn(a, b) { a + b }; puts(add(1, 2));").expect("should emit");
+
+        let add = "static mk_value mk_fn_1(mk_closure *self, mk_value *args) {\n    \
+            mk_value s[3] = { { 0 } };\n    \
+            mk_value ret = { 0 };\n    \
+            (void)self;\n    \
+            (void)args;\n    \
+            mk_set(&s[0], mk_incref(args[0]));\n    \
+            mk_set(&s[1], mk_incref(args[1]));\n    \
+            mk_set(&s[2], mk_arithmetic('+', s[0], s[1], NULL));\n    \
+            ret = mk_incref(s[2]);\n    \
+            goto out;\n\
+            out:\n";
+        assert!(c.contains(add), "{}", c);
+
+        assert!(c.contains("    mk_set(&s[0], mk_closure_new(mk_fn_1, 2, 0, NULL));\n"));
+        assert!(c.contains("    mk_set_global(0, s[0]);\n"));
+        assert!(c.contains("mk_call(s[4], 2, (mk_value[]){ s[5], s[6] })"));
+
+        assert_eq!(
+            emit_str("fn() { x }"),
+            Err(String::from("undefined variable x"))
+        );
+    }
+}
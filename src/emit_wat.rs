@@ -0,0 +1,1514 @@
+// Lowers a program to a WebAssembly text module. Every value is an i32,
+// either an integer that fits in 31 bits, shifted left and with its low bit
+// set, or a pointer to an object in linear memory. Objects are 8-aligned, so
+// their pointers are even:
+//
+//   null      0    the object at address 0, so fresh locals are null
+//   true      8
+//   false     16
+//   puts      24   the one builtin
+//   integer        tag 1, then the low and high i64 halves at +8 and +16;
+//                  only for those that don't fit in 31 bits
+//   function       tag 3, table index +4, parameters +8, free count +12,
+//                  then the free values from +16
+//   cell           tag 5, then the value at +4; a local that a function
+//                  shares with its closures, which is never a value itself
+//
+// Literals live in the data segment and everything else comes from a bump
+// allocator that never frees. Since most integers aren't objects, it's
+// closures, cells and big integers that use it up. The module imports `env.print(ptr, len)` for
+// `puts` and `env.error(ptr, len)`, which is called with the error message
+// right before the program traps. It exports its `memory` and a `main`
+// function that runs the program. Calls in tail position use the tail call
+// proposal's `return_call`, so they don't grow the native stack.
+
+use crate::ast::{self, Block, Expression, FunctionInternal, IfInternal, Program, Statement};
+use crate::macros;
+use crate::symbol_table::{self, Symbol, SymbolScope, SymbolTable};
+use crate::token::Token;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write;
+
+/// Builtins the runtime implements, with the address of their object.
+const WAT_BUILTINS: [(&str, u32); 1] = [("puts", 24)];
+
+/// Imported files are run by the interpreters; a module is one file.
+const NO_MODULES: &str = "imports aren't supported when compiling to WebAssembly";
+
+/// The runtime has no strings, nor a way to unwind to a `catch`.
+const NO_STRINGS: &str = "strings aren't supported when compiling to WebAssembly";
+const NO_EXCEPTIONS: &str = "exceptions aren't supported when compiling to WebAssembly";
+const NO_COLLECTIONS: &str = "arrays and hashes aren't supported when compiling to WebAssembly";
+
+const NULL: u32 = 0;
+const TRUE: u32 = 8;
+const FALSE: u32 = 16;
+
+const PAGE_SIZE: usize = 65536;
+
+/// Room for runtime error messages and printed values, besides names.
+const SCRATCH_SIZE: usize = 64;
+
+/// The data segment, with the static objects at its start.
+struct Data {
+    bytes: Vec<u8>,
+    strings: HashMap<String, u32>,
+    integers: HashMap<i128, u32>,
+}
+
+impl Data {
+    fn new() -> Data {
+        let mut bytes = vec![0; 32];
+        // true, false and puts; null is all zeroes
+        bytes[8] = 2;
+        bytes[12] = 1;
+        bytes[16] = 2;
+        bytes[24] = 4;
+
+        Data {
+            bytes,
+            strings: HashMap::new(),
+            integers: HashMap::new(),
+        }
+    }
+
+    /// Returns the address and length of `s`.
+    fn string(&mut self, s: &str) -> (u32, usize) {
+        let addr = match self.strings.get(s) {
+            Some(addr) => *addr,
+            None => {
+                let addr = self.bytes.len() as u32;
+                self.bytes.extend_from_slice(s.as_bytes());
+                self.strings.insert(s.to_string(), addr);
+                addr
+            }
+        };
+        (addr, s.len())
+    }
+
+    /// Returns `value` as a value: the integer itself if it fits in 31
+    /// bits, or else the address of an integer object holding it.
+    fn integer(&mut self, value: i128) -> i32 {
+        if (-(1 << 30)..1 << 30).contains(&value) {
+            return ((value as i32) << 1) | 1;
+        }
+        if let Some(addr) = self.integers.get(&value) {
+            return *addr as i32;
+        }
+
+        self.align();
+        let addr = self.bytes.len() as u32;
+        self.bytes.extend_from_slice(&1u64.to_le_bytes());
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+        self.integers.insert(value, addr);
+        addr as i32
+    }
+
+    fn align(&mut self) {
+        while !self.bytes.len().is_multiple_of(8) {
+            self.bytes.push(0);
+        }
+    }
+
+    fn literal(&self) -> String {
+        let mut out = String::new();
+        for b in &self.bytes {
+            match b {
+                b' '..=b'~' if *b != b'"' && *b != b'\\' => out.push(*b as char),
+                _ => {
+                    let _ = write!(out, "\\{:02x}", b);
+                }
+            }
+        }
+        out
+    }
+}
+
+/// A Monkey function being turned into a Wasm one.
+#[derive(Default)]
+struct FunctionScope {
+    body: String,
+    indent: usize,
+}
+
+/// Lowers a program to a WebAssembly text module. It runs with the same
+/// scoping rules and errors as `compiler::Compiler` and the vm.
+pub struct WatEmitter {
+    symbols: SymbolTable,
+    scopes: Vec<FunctionScope>,
+    data: Data,
+    /// The finished functions, `$f0` onwards, by table index.
+    functions: Vec<String>,
+    main: String,
+    /// Parameter counts that need a function type.
+    arities: BTreeSet<usize>,
+    /// Argument counts that need a `$call_N` helper.
+    calls: BTreeSet<usize>,
+    /// Argument counts that need a `$tail_N` helper.
+    tail_calls: BTreeSet<usize>,
+    /// Loops so far, which number their labels.
+    num_loops: usize,
+    /// The loops being emitted, innermost last, and whether they're `for`
+    /// loops, which `continue` in a block of their own.
+    loops: Vec<(usize, bool)>,
+}
+
+impl Default for WatEmitter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns `program` as the text of a module that runs it.
+pub fn emit(program: &Program) -> Result<String, String> {
+    let mut e = WatEmitter::new();
+    e.program(program)?;
+    Ok(e.finish())
+}
+
+impl WatEmitter {
+    pub fn new() -> WatEmitter {
+        let mut symbols = SymbolTable::new();
+        for (idx, (name, _)) in WAT_BUILTINS.iter().enumerate() {
+            symbols.define_builtin(idx, name);
+        }
+
+        WatEmitter {
+            symbols,
+            scopes: Vec::new(),
+            data: Data::new(),
+            functions: Vec::new(),
+            main: String::new(),
+            arities: BTreeSet::new(),
+            calls: BTreeSet::new(),
+            tail_calls: BTreeSet::new(),
+            num_loops: 0,
+            loops: Vec::new(),
+        }
+    }
+
+    pub fn program(&mut self, program: &Program) -> Result<(), String> {
+        for stmt in &program.statements {
+            if let Statement::Let(i) = stmt {
+                if let Some(name) = i.name() {
+                    self.symbols.define(name.value());
+                }
+            }
+        }
+
+        self.scopes.push(FunctionScope {
+            body: String::new(),
+            indent: 2,
+        });
+        for stmt in &program.statements {
+            self.statement(stmt)?;
+        }
+
+        let scope = self.scopes.pop().expect("we pushed this scope");
+        self.main = format!(
+            "  (func $main (export \"main\")\n    (local $tmp i32)\n{}  )\n",
+            scope.body
+        );
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> String {
+        let globals = self.symbols.global_names();
+
+        // the runtime's strings go in the data segment, so it comes first
+        let mut runtime = self.expand(RUNTIME);
+        for n in self.calls.clone() {
+            runtime.push_str(&self.expand(&call_helper(n)));
+        }
+        for n in self.tail_calls.clone() {
+            runtime.push_str(&tail_call_helper(n));
+        }
+
+        let longest = globals.iter().map(|g| g.len()).max().unwrap_or(0);
+        self.data.align();
+        let scratch = self.data.bytes.len();
+        let heap = (scratch + SCRATCH_SIZE + longest + 7) & !7;
+
+        let mut out = String::new();
+        out.push_str(";; Generated by plmmky emit-wat.\n(module\n");
+        out.push_str("  (import \"env\" \"print\" (func $print (param i32 i32)))\n");
+        out.push_str("  (import \"env\" \"error\" (func $error (param i32 i32)))\n");
+        let _ = writeln!(
+            out,
+            "  (memory (export \"memory\") {})",
+            heap / PAGE_SIZE + 1
+        );
+
+        let mut arities = self.arities.clone();
+        arities.extend(self.calls.iter().copied());
+        for n in &arities {
+            let _ = writeln!(
+                out,
+                "  (type $fn{} (func (param{}) (result i32)))",
+                n,
+                " i32".repeat(n + 1)
+            );
+        }
+
+        let _ = writeln!(out, "  (table {} funcref)", self.functions.len());
+        if !self.functions.is_empty() {
+            let names: Vec<String> = (0..self.functions.len())
+                .map(|i| format!("$f{}", i))
+                .collect();
+            let _ = writeln!(out, "  (elem (i32.const 0) func {})", names.join(" "));
+        }
+
+        let _ = writeln!(out, "  (global $out_start i32 (i32.const {}))", scratch);
+        let _ = writeln!(out, "  (global $out (mut i32) (i32.const {}))", scratch);
+        let _ = writeln!(out, "  (global $heap (mut i32) (i32.const {}))", heap);
+        for idx in 0..globals.len() {
+            // -1 until the global is set
+            let _ = writeln!(out, "  (global $g{} (mut i32) (i32.const -1))", idx);
+        }
+
+        out.push_str(&runtime);
+
+        for f in &self.functions {
+            out.push('\n');
+            out.push_str(f);
+        }
+        out.push('\n');
+        out.push_str(&self.main);
+
+        let _ = writeln!(out, "  (data (i32.const 0) \"{}\")", self.data.literal());
+        out.push_str(")\n");
+        out
+    }
+
+    /// Replaces each `(str "...")` in `template` by the address and length
+    /// of the string.
+    fn expand(&mut self, template: &str) -> String {
+        let mut out = String::new();
+        let mut rest = template;
+        while let Some(start) = rest.find("(str \"") {
+            out.push_str(&rest[..start]);
+            rest = &rest[start + "(str \"".len()..];
+            let end = rest.find("\")").expect("strings are closed");
+            let (addr, len) = self.data.string(&rest[..end]);
+            let _ = write!(out, "(i32.const {}) (i32.const {})", addr, len);
+            rest = &rest[end + "\")".len()..];
+        }
+        out.push_str(rest);
+        out
+    }
+
+    fn scope(&mut self) -> &mut FunctionScope {
+        self.scopes.last_mut().expect("inside a function")
+    }
+
+    fn line(&mut self, text: &str) {
+        let scope = self.scope();
+        for _ in 0..scope.indent {
+            scope.body.push_str("  ");
+        }
+        scope.body.push_str(text);
+        scope.body.push('\n');
+    }
+
+    /// Tells the runtime where `op` is before its function is called, if
+    /// it's an operator whose arithmetic can fail and the source has
+    /// locations.
+    fn at(&mut self, op: &str, token: &Token) {
+        if !matches!(op, "+" | "-" | "*" | "/" | "%" | "<<" | ">>") {
+            return;
+        }
+        if let Some(l) = token.local() {
+            let (addr, len) = self.data.string(&l.to_string());
+            self.line(&format!("(global.set $at (i32.const {}))", addr));
+            self.line(&format!("(global.set $at_len (i32.const {}))", len));
+        }
+    }
+
+    fn load_symbol(&mut self, s: &Symbol) {
+        self.load_slot(s);
+        if s.cell {
+            self.line("i32.load offset=4");
+        }
+    }
+
+    /// Like `load_symbol`, but loads the cell of a cell.
+    fn load_slot(&mut self, s: &Symbol) {
+        match s.scope {
+            SymbolScope::Global => {
+                let (addr, len) = self.data.string(&s.name);
+                self.line(&format!("global.get $g{}", s.index));
+                self.line(&format!("i32.const {}", addr));
+                self.line(&format!("i32.const {}", len));
+                self.line("call $global");
+            }
+            SymbolScope::Local => self.line(&format!("local.get $l{}", s.index)),
+            SymbolScope::Builtin => {
+                self.line(&format!("i32.const {}", WAT_BUILTINS[s.index].1));
+            }
+            SymbolScope::Free => {
+                self.line("local.get $self");
+                self.line(&format!("i32.load offset={}", 16 + 4 * s.index));
+            }
+            SymbolScope::Function => self.line("local.get $self"),
+        }
+    }
+
+    fn statement(&mut self, stmt: &Statement) -> Result<(), String> {
+        ast::deeper(|| {
+            match stmt {
+                Statement::Expression(i) => {
+                    if let Some(e) = i.expression() {
+                        self.expression(e)?;
+                        self.line("drop");
+                    }
+                }
+                Statement::Let(i) => {
+                    let name = match i.name() {
+                        Some(n) => n.value(),
+                        None => return Ok(()),
+                    };
+
+                    match i.value() {
+                        Some(Expression::Function(f)) => self.function(f, Some(name))?,
+                        Some(value) => self.expression(value)?,
+                        None => self.line(&format!("i32.const {}", NULL)),
+                    }
+
+                    let symbol = self.symbols.define(name);
+                    self.store(&symbol);
+                }
+                Statement::Return(i) => {
+                    match i.return_value() {
+                        Some(value) if !self.symbols.is_global() => self.tail_value(value)?,
+                        Some(value) => self.expression(value)?,
+                        None => self.line(&format!("i32.const {}", NULL)),
+                    }
+                    // `$main` returns nothing
+                    if self.symbols.is_global() {
+                        self.line("drop");
+                    }
+                    self.line("return");
+                }
+                Statement::Import(_) => return Err(String::from(NO_MODULES)),
+                Statement::Throw(_) => return Err(String::from(NO_EXCEPTIONS)),
+                Statement::While(i) => {
+                    let n = self.start_loop();
+                    self.line(&format!("block $break_{}", n));
+                    self.scope().indent += 1;
+                    self.line(&format!("loop $loop_{}", n));
+                    self.scope().indent += 1;
+                    self.expression(i.condition())?;
+                    self.line("call $truthy");
+                    self.line("i32.eqz");
+                    self.line(&format!("br_if $break_{}", n));
+                    self.loop_body((n, false), i.body())?;
+                    self.line(&format!("br $loop_{}", n));
+                    self.end_loop();
+                }
+                Statement::For(i) => {
+                    let end_expr = i.end().ok_or(NO_COLLECTIONS)?;
+                    let n = self.start_loop();
+                    // the bounds go in variables of their own, so that
+                    // rebinding the loop variable doesn't change how often the
+                    // loop runs
+                    let depth = self.loops.len();
+                    let end = self.symbols.define(&format!("for.{}.end", depth));
+                    let counter = self.symbols.define(&format!("for.{}", depth));
+                    self.expression(i.start())?;
+                    self.store(&counter);
+                    self.expression(end_expr)?;
+                    self.store(&end);
+                    self.load_hidden(&counter);
+                    self.load_hidden(&end);
+                    self.line("call $check_range");
+
+                    self.line(&format!("block $break_{}", n));
+                    self.scope().indent += 1;
+                    self.line(&format!("loop $loop_{}", n));
+                    self.scope().indent += 1;
+                    self.load_hidden(&end);
+                    self.load_hidden(&counter);
+                    self.line("call $greater_than");
+                    self.line("call $truthy");
+                    self.line("i32.eqz");
+                    self.line(&format!("br_if $break_{}", n));
+
+                    self.load_hidden(&counter);
+                    let variable = self.symbols.define(i.variable().value());
+                    self.store(&variable);
+
+                    self.line(&format!("block $continue_{}", n));
+                    self.scope().indent += 1;
+                    self.loop_body((n, true), i.body())?;
+                    self.scope().indent -= 1;
+                    self.line("end");
+
+                    self.load_hidden(&counter);
+                    let one = self.data.integer(1);
+                    self.line(&format!("i32.const {}", one));
+                    self.line("call $add");
+                    self.store(&counter);
+                    self.line(&format!("br $loop_{}", n));
+                    self.end_loop();
+                }
+                Statement::Break(_) | Statement::Continue(_) => {
+                    let (n, is_for) = match self.loops.last() {
+                        Some(l) => *l,
+                        None => {
+                            return Err(format!(
+                                "{} outside of a loop",
+                                stmt.token().literal.to_lowercase()
+                            ))
+                        }
+                    };
+                    match stmt {
+                        Statement::Break(_) => self.line(&format!("br $break_{}", n)),
+                        _ if is_for => self.line(&format!("br $continue_{}", n)),
+                        _ => self.line(&format!("br $loop_{}", n)),
+                    }
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Sets a variable from the value on the stack.
+    fn store(&mut self, s: &Symbol) {
+        if s.scope == SymbolScope::Global {
+            self.line(&format!("global.set $g{}", s.index));
+        } else if s.cell {
+            self.load_slot(s);
+            self.line("call $set_cell");
+            self.line("drop");
+        } else {
+            self.line(&format!("local.set $l{}", s.index));
+        }
+    }
+
+    /// Loads one of a `for` loop's bounds, which are always set.
+    fn load_hidden(&mut self, s: &Symbol) {
+        if s.scope == SymbolScope::Global {
+            self.line(&format!("global.get $g{}", s.index));
+        } else {
+            self.line(&format!("local.get $l{}", s.index));
+        }
+    }
+
+    /// Numbers a new loop for its labels.
+    fn start_loop(&mut self) -> usize {
+        self.num_loops += 1;
+        self.num_loops - 1
+    }
+
+    fn loop_body(&mut self, l: (usize, bool), body: &Block) -> Result<(), String> {
+        self.loops.push(l);
+        let emitted = body.statements.iter().try_for_each(|s| self.statement(s));
+        self.loops.pop();
+        emitted
+    }
+
+    /// Closes the `loop` and `block` a loop is in.
+    fn end_loop(&mut self) {
+        for _ in 0..2 {
+            self.scope().indent -= 1;
+            self.line("end");
+        }
+    }
+
+    /// Leaves the value of `statements` on the stack.
+    fn block_value(&mut self, statements: &[Statement]) -> Result<(), String> {
+        match statements.split_last() {
+            Some((Statement::Expression(i), rest)) if i.expression().is_some() => {
+                for stmt in rest {
+                    self.statement(stmt)?;
+                }
+                self.expression(i.expression().expect("checked"))
+            }
+            _ => {
+                for stmt in statements {
+                    self.statement(stmt)?;
+                }
+                self.line(&format!("i32.const {}", NULL));
+                Ok(())
+            }
+        }
+    }
+
+    /// Leaves the value of `statements` on the stack, like `block_value`,
+    /// with the expression they end in in tail position.
+    fn tail_block_value(&mut self, statements: &[Statement]) -> Result<(), String> {
+        match statements.split_last() {
+            Some((Statement::Expression(i), rest)) if i.expression().is_some() => {
+                for stmt in rest {
+                    self.statement(stmt)?;
+                }
+                self.tail_value(i.expression().expect("checked"))
+            }
+            _ => self.block_value(statements),
+        }
+    }
+
+    /// Leaves the value of `e`, which the function returns, on the stack.
+    /// A call returns straight from the function, taking its place, and
+    /// so does one that's the value of a branch of an `if`.
+    fn tail_value(&mut self, e: &Expression) -> Result<(), String> {
+        match e {
+            Expression::Call(i) => {
+                self.expression(i.function())?;
+                for arg in i.arguments() {
+                    self.expression(arg)?;
+                }
+                // anything but a function is called as usual
+                self.calls.insert(i.arguments().len());
+                self.tail_calls.insert(i.arguments().len());
+                self.line(&format!("return_call $tail_{}", i.arguments().len()));
+                Ok(())
+            }
+            Expression::If(i) => self.conditional(i, true),
+            _ => self.expression(e),
+        }
+    }
+
+    fn conditional(&mut self, i: &IfInternal, tail: bool) -> Result<(), String> {
+        self.expression(i.condition())?;
+        self.line("call $truthy");
+        self.line("if (result i32)");
+        self.branch(Some(i.consequence()), tail)?;
+        self.line("else");
+        self.branch(i.alternative(), tail)?;
+        self.line("end");
+        Ok(())
+    }
+
+    fn branch(&mut self, block: Option<&Block>, tail: bool) -> Result<(), String> {
+        self.scope().indent += 1;
+        match block {
+            Some(block) if tail => self.tail_block_value(&block.statements)?,
+            Some(block) => self.block_value(&block.statements)?,
+            None => self.line(&format!("i32.const {}", NULL)),
+        }
+        self.scope().indent -= 1;
+        Ok(())
+    }
+
+    fn function(&mut self, f: &FunctionInternal, name: Option<&str>) -> Result<(), String> {
+        let idx = self.functions.len();
+        self.functions.push(String::new());
+        let num_parameters = f.parameters().len();
+        self.arities.insert(num_parameters);
+
+        self.scopes.push(FunctionScope {
+            body: String::new(),
+            indent: 2,
+        });
+        self.symbols.push();
+
+        if let Some(name) = name {
+            self.symbols.define_function_name(name);
+        }
+        for p in f.parameters() {
+            self.symbols.define(p.value());
+        }
+        for slot in self.symbols.define_cells(symbol_table::cells(f)) {
+            self.line(&format!("local.get $l{}", slot));
+            self.line("call $cell");
+            self.line(&format!("local.set $l{}", slot));
+        }
+
+        self.tail_block_value(&f.body().statements)?;
+
+        let (free, num_locals) = self.symbols.pop();
+        let scope = self.scopes.pop().expect("we pushed this scope");
+
+        let mut def = String::new();
+        let _ = write!(
+            def,
+            "  (func $f{} (type $fn{}) (param $self i32)",
+            idx, num_parameters
+        );
+        for i in 0..num_parameters {
+            let _ = write!(def, " (param $l{} i32)", i);
+        }
+        def.push_str(" (result i32)\n");
+        for i in num_parameters..num_locals {
+            let _ = writeln!(def, "    (local $l{} i32)", i);
+        }
+        def.push_str("    (local $tmp i32)\n");
+        def.push_str(&scope.body);
+        def.push_str("  )\n");
+        self.functions[idx] = def;
+
+        self.line(&format!("i32.const {}", idx));
+        self.line(&format!("i32.const {}", num_parameters));
+        self.line(&format!("i32.const {}", free.len()));
+        self.line("call $closure");
+        if !free.is_empty() {
+            self.line("local.set $tmp");
+            for (i, s) in free.iter().enumerate() {
+                self.line("local.get $tmp");
+                self.load_slot(s);
+                self.line(&format!("i32.store offset={}", 16 + 4 * i));
+            }
+            self.line("local.get $tmp");
+        }
+        Ok(())
+    }
+
+    /// Leaves the value of `e` on the stack.
+    fn expression(&mut self, e: &Expression) -> Result<(), String> {
+        ast::deeper(|| {
+            match e {
+                Expression::Identifier(i) => match self.symbols.resolve(i.value()) {
+                    Some(s) => self.load_symbol(&s),
+                    None => return Err(format!("undefined variable {}", i.value())),
+                },
+                Expression::Integer(i) => {
+                    let value = self.data.integer(i.value());
+                    self.line(&format!("i32.const {}", value));
+                }
+                Expression::Boolean(b) => {
+                    let addr = if b.value() { TRUE } else { FALSE };
+                    self.line(&format!("i32.const {}", addr));
+                }
+                Expression::Prefix(i) => {
+                    self.expression(i.right())?;
+                    self.at(i.operator(), i.token());
+                    match i.operator() {
+                        "!" => self.line("call $bang"),
+                        "-" => self.line("call $minus"),
+                        "~" => self.line("call $bit_not"),
+                        op => return Err(format!("unknown operator {}", op)),
+                    }
+                }
+                Expression::Infix(i) if matches!(i.operator(), "&&" | "||") => {
+                    self.expression(i.left())?;
+                    self.line("call $truthy");
+                    if i.operator() == "||" {
+                        self.line("i32.eqz");
+                    }
+                    self.line("if (result i32)");
+                    self.scope().indent += 1;
+                    self.expression(i.right())?;
+                    self.line("call $truthy");
+                    self.line("call $bool");
+                    self.scope().indent -= 1;
+                    self.line("else");
+                    self.scope().indent += 1;
+                    let decided = if i.operator() == "||" { TRUE } else { FALSE };
+                    self.line(&format!("i32.const {}", decided));
+                    self.scope().indent -= 1;
+                    self.line("end");
+                }
+                Expression::Infix(i) => {
+                    self.expression(i.left())?;
+                    self.expression(i.right())?;
+                    self.at(i.operator(), i.token());
+                    self.line(&format!("call {}", helper(i.operator())?));
+                }
+                Expression::Assign(i) => {
+                    let symbol = self.symbols.resolve_assignment(i.name().value())?;
+                    if let Some(op) = i.infix_operator() {
+                        self.load_symbol(&symbol);
+                        self.expression(i.value())?;
+                        self.at(op, i.token());
+                        self.line(&format!("call {}", helper(op)?));
+                    } else {
+                        self.expression(i.value())?;
+                    }
+
+                    // the assignment's value is what was assigned
+                    if symbol.scope == SymbolScope::Global {
+                        self.line(&format!("global.set $g{}", symbol.index));
+                        self.line(&format!("global.get $g{}", symbol.index));
+                    } else if symbol.cell {
+                        self.load_slot(&symbol);
+                        self.line("call $set_cell");
+                    } else {
+                        self.line(&format!("local.tee $l{}", symbol.index));
+                    }
+                }
+                Expression::If(i) => self.conditional(i, false)?,
+                Expression::Function(f) => self.function(f, None)?,
+                Expression::Call(i) => {
+                    self.expression(i.function())?;
+                    for arg in i.arguments() {
+                        self.expression(arg)?;
+                    }
+                    self.calls.insert(i.arguments().len());
+                    self.line(&format!("call $call_{}", i.arguments().len()));
+                }
+                Expression::Member(_) => return Err(String::from(NO_MODULES)),
+                Expression::String(_) => return Err(String::from(NO_STRINGS)),
+                Expression::Try(_) => return Err(String::from(NO_EXCEPTIONS)),
+                Expression::Array(_)
+                | Expression::Hash(_)
+                | Expression::Index(_)
+                | Expression::IndexAssign(_) => return Err(String::from(NO_COLLECTIONS)),
+                Expression::Macro(_) => return Err(String::from(macros::STRAY_MACRO)),
+            }
+
+            Ok(())
+        })
+    }
+}
+
+/// The runtime function for the infix operator `op`.
+fn helper(op: &str) -> Result<&'static str, String> {
+    Ok(match op {
+        "+" => "$add",
+        "-" => "$sub",
+        "*" => "$mul",
+        "/" => "$div",
+        "%" => "$mod",
+        "&" => "$bit_and",
+        "|" => "$bit_or",
+        "^" => "$bit_xor",
+        "<<" => "$shift_left",
+        ">>" => "$shift_right",
+        ">" => "$greater_than",
+        "<" => "$less_than",
+        ">=" => "$greater_equal",
+        "<=" => "$less_equal",
+        "==" => "$equal",
+        "!=" => "$not_equal",
+        op => return Err(format!("unknown operator {}", op)),
+    })
+}
+
+/// `$call_N` calls a function value with `n` arguments, checking its arity
+/// and the call depth like the vm does.
+fn call_helper(n: usize) -> String {
+    let mut params = String::new();
+    let mut args = String::new();
+    let mut puts = String::new();
+    for i in 0..n {
+        let _ = write!(params, " (param $a{} i32)", i);
+        let _ = write!(args, " (local.get $a{})", i);
+        let _ = write!(puts, "\n        (call $puts (local.get $a{}))", i);
+    }
+
+    format!(
+        r#"
+  (func $call_{n} (param $f i32){params} (result i32)
+    (local $result i32)
+    (if (i32.eq (call $tag (local.get $f)) (i32.const 3))
+      (then
+        (if (i32.ne (i32.load offset=8 (local.get $f)) (i32.const {n}))
+          (then (call $fail_arity (i32.load offset=8 (local.get $f)) (i32.const {n}))))
+        (call $enter)
+        (local.set $result
+          (call_indirect (type $fn{n}) (local.get $f){args} (i32.load offset=4 (local.get $f))))
+        (global.set $depth (i32.sub (global.get $depth) (i32.const 1)))
+        (return (local.get $result))))
+    (if (i32.eq (local.get $f) (i32.const 24))
+      (then{puts}
+        (return (i32.const 0))))
+    (call $fail_type (str "calling non-function: ") (local.get $f))
+    (unreachable)
+  )
+"#
+    )
+}
+
+/// `$tail_N` makes a call with `n` arguments in tail position. A function
+/// takes the place of the one calling it, at the same depth, and anything
+/// else goes to `$call_N`.
+fn tail_call_helper(n: usize) -> String {
+    let mut params = String::new();
+    let mut args = String::new();
+    for i in 0..n {
+        let _ = write!(params, " (param $a{} i32)", i);
+        let _ = write!(args, " (local.get $a{})", i);
+    }
+
+    format!(
+        r#"
+  (func $tail_{n} (param $f i32){params} (result i32)
+    (if (i32.eq (call $tag (local.get $f)) (i32.const 3))
+      (then
+        (if (i32.ne (i32.load offset=8 (local.get $f)) (i32.const {n}))
+          (then (call $fail_arity (i32.load offset=8 (local.get $f)) (i32.const {n}))))
+        (return_call_indirect (type $fn{n}) (local.get $f){args} (i32.load offset=4 (local.get $f)))))
+    (return_call $call_{n} (local.get $f){args})
+  )
+"#
+    )
+}
+
+/// Integers, objects, the operators and error reporting. `(str "...")` is
+/// replaced by a string's address and length in the data segment.
+const RUNTIME: &str = r#"
+  (global $depth (mut i32) (i32.const 0))
+  (global $q_lo (mut i64) (i64.const 0))
+  (global $q_hi (mut i64) (i64.const 0))
+  (global $r_lo (mut i64) (i64.const 0))
+  (global $r_hi (mut i64) (i64.const 0))
+  ;; Where the operator being applied is, for arithmetic errors; set by the
+  ;; program before it calls the operator's function.
+  (global $at (mut i32) (i32.const 0))
+  (global $at_len (mut i32) (i32.const 0))
+
+  (func $alloc (param $size i32) (result i32)
+    (local $p i32)
+    (local.set $p (global.get $heap))
+    (global.set $heap
+      (i32.and (i32.add (i32.add (local.get $p) (local.get $size)) (i32.const 7)) (i32.const -8)))
+    (if (i32.gt_u (global.get $heap) (i32.mul (memory.size) (i32.const 65536)))
+      (then
+        (if (i32.eq
+              (memory.grow
+                (i32.add
+                  (i32.div_u
+                    (i32.sub (global.get $heap) (i32.mul (memory.size) (i32.const 65536)))
+                    (i32.const 65536))
+                  (i32.const 1)))
+              (i32.const -1))
+          (then (call $fail (str "out of memory"))))))
+    (local.get $p)
+  )
+
+  ;; Error messages and printed values are built up at $out.
+  (func $write_byte (param $b i32)
+    (i32.store8 (global.get $out) (local.get $b))
+    (global.set $out (i32.add (global.get $out) (i32.const 1)))
+  )
+
+  (func $write_str (param $ptr i32) (param $len i32)
+    (local $i i32)
+    (block $done
+      (loop $next
+        (br_if $done (i32.ge_u (local.get $i) (local.get $len)))
+        (call $write_byte (i32.load8_u (i32.add (local.get $ptr) (local.get $i))))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $next)))
+  )
+
+  (func $write_int (param $lo i64) (param $hi i64)
+    (local $start i32) (local $end i32) (local $b i32)
+    (if (i64.lt_s (local.get $hi) (i64.const 0))
+      (then
+        (call $write_byte (i32.const 45))
+        (local.set $hi
+          (i64.sub (i64.sub (i64.const 0) (local.get $hi))
+                   (i64.extend_i32_u (i64.ne (local.get $lo) (i64.const 0)))))
+        (local.set $lo (i64.sub (i64.const 0) (local.get $lo)))))
+    ;; the digits come out backwards, so they're reversed afterwards
+    (local.set $start (global.get $out))
+    (loop $digit
+      (call $udivmod (local.get $lo) (local.get $hi) (i64.const 10) (i64.const 0))
+      (call $write_byte (i32.add (i32.const 48) (i32.wrap_i64 (global.get $r_lo))))
+      (local.set $lo (global.get $q_lo))
+      (local.set $hi (global.get $q_hi))
+      (br_if $digit (i64.ne (i64.or (local.get $lo) (local.get $hi)) (i64.const 0))))
+    (local.set $end (i32.sub (global.get $out) (i32.const 1)))
+    (block $done
+      (loop $swap
+        (br_if $done (i32.ge_u (local.get $start) (local.get $end)))
+        (local.set $b (i32.load8_u (local.get $start)))
+        (i32.store8 (local.get $start) (i32.load8_u (local.get $end)))
+        (i32.store8 (local.get $end) (local.get $b))
+        (local.set $start (i32.add (local.get $start) (i32.const 1)))
+        (local.set $end (i32.sub (local.get $end) (i32.const 1)))
+        (br $swap)))
+  )
+
+  (func $write_type (param $v i32)
+    (block $builtin
+      (block $function
+        (block $boolean
+          (block $integer
+            (block $null
+              (br_table $null $integer $boolean $function $builtin (call $tag (local.get $v))))
+            (call $write_str (str "NULL"))
+            (return))
+          (call $write_str (str "INTEGER"))
+          (return))
+        (call $write_str (str "BOOLEAN"))
+        (return))
+      (call $write_str (str "FUNCTION"))
+      (return))
+    (call $write_str (str "BUILTIN"))
+  )
+
+  (func $write_value (param $v i32)
+    (block $builtin
+      (block $function
+        (block $boolean
+          (block $integer
+            (block $null
+              (br_table $null $integer $boolean $function $builtin (call $tag (local.get $v))))
+            (call $write_str (str "null"))
+            (return))
+          (call $write_int (call $lo (local.get $v)) (call $hi (local.get $v)))
+          (return))
+        (if (i32.load offset=4 (local.get $v))
+          (then (call $write_str (str "true")))
+          (else (call $write_str (str "false"))))
+        (return))
+      (call $write_str (str "<function>"))
+      (return))
+    (call $write_str (str "<builtin puts>"))
+  )
+
+  (func $puts (param $v i32)
+    (call $write_value (local.get $v))
+    (call $write_byte (i32.const 10))
+    (call $print (global.get $out_start) (i32.sub (global.get $out) (global.get $out_start)))
+    (global.set $out (global.get $out_start))
+  )
+
+  (func $fail (param $ptr i32) (param $len i32)
+    (call $write_str (local.get $ptr) (local.get $len))
+    (call $error (global.get $out_start) (i32.sub (global.get $out) (global.get $out_start)))
+    (unreachable)
+  )
+
+  (func $fail_type (param $ptr i32) (param $len i32) (param $v i32)
+    (call $write_str (local.get $ptr) (local.get $len))
+    (call $write_type (local.get $v))
+    (call $fail (i32.const 0) (i32.const 0))
+  )
+
+  (func $fail_types (param $ptr i32) (param $len i32) (param $sep i32) (param $sep_len i32)
+                     (param $l i32) (param $r i32)
+    (call $write_str (local.get $ptr) (local.get $len))
+    (call $write_type (local.get $l))
+    (call $write_str (local.get $sep) (local.get $sep_len))
+    (call $write_type (local.get $r))
+    (call $fail (i32.const 0) (i32.const 0))
+  )
+
+  ;; Fails with an error from integer arithmetic, which says where the
+  ;; operator is when the program said.
+  (func $fail_arithmetic (param $ptr i32) (param $len i32)
+    (call $write_str (local.get $ptr) (local.get $len))
+    (if (global.get $at_len)
+      (then
+        (call $write_str (str " at "))
+        (call $write_str (global.get $at) (global.get $at_len))))
+    (call $fail (i32.const 0) (i32.const 0))
+  )
+
+  (func $overflow
+    (call $fail_arithmetic (str "integer overflow"))
+  )
+
+  (func $fail_arity (param $want i32) (param $got i32)
+    (call $write_str (str "wrong number of arguments: want="))
+    (call $write_int (i64.extend_i32_u (local.get $want)) (i64.const 0))
+    (call $write_str (str ", got="))
+    (call $write_int (i64.extend_i32_u (local.get $got)) (i64.const 0))
+    (call $fail (i32.const 0) (i32.const 0))
+  )
+
+  (func $global (param $v i32) (param $name i32) (param $len i32) (result i32)
+    (if (i32.eq (local.get $v) (i32.const -1))
+      (then
+        (call $write_str (str "identifier not found: "))
+        (call $fail (local.get $name) (local.get $len))))
+    (local.get $v)
+  )
+
+  ;; The top level counts as a frame, as in the vm.
+  (func $enter
+    (if (i32.ge_s (i32.add (global.get $depth) (i32.const 1)) (i32.const 1024))
+      (then (call $fail (str "stack overflow"))))
+    (global.set $depth (i32.add (global.get $depth) (i32.const 1)))
+  )
+
+  (func $closure (param $func i32) (param $params i32) (param $free i32) (result i32)
+    (local $p i32)
+    (local.set $p (call $alloc (i32.add (i32.const 16) (i32.shl (local.get $free) (i32.const 2)))))
+    (i32.store (local.get $p) (i32.const 3))
+    (i32.store offset=4 (local.get $p) (local.get $func))
+    (i32.store offset=8 (local.get $p) (local.get $params))
+    (i32.store offset=12 (local.get $p) (local.get $free))
+    (local.get $p)
+  )
+
+  (func $cell (param $v i32) (result i32)
+    (local $p i32)
+    (local.set $p (call $alloc (i32.const 8)))
+    (i32.store (local.get $p) (i32.const 5))
+    (i32.store offset=4 (local.get $p) (local.get $v))
+    (local.get $p)
+  )
+
+  ;; Stores `v` in `cell`, returning it as an assignment's value.
+  (func $set_cell (param $v i32) (param $cell i32) (result i32)
+    (i32.store offset=4 (local.get $cell) (local.get $v))
+    (local.get $v)
+  )
+
+  ;; Makes an integer, which is only an object if it doesn't fit in 31
+  ;; bits: if the high half and the top 33 bits of the low half aren't all
+  ;; copies of its sign.
+  (func $int (param $lo i64) (param $hi i64) (result i32)
+    (local $p i32)
+    (if (i32.and
+          (i64.eq (local.get $hi) (i64.shr_s (local.get $lo) (i64.const 63)))
+          (i64.eq (local.get $lo) (i64.shr_s (i64.shl (local.get $lo) (i64.const 33)) (i64.const 33))))
+      (then
+        (return (i32.or (i32.shl (i32.wrap_i64 (local.get $lo)) (i32.const 1)) (i32.const 1)))))
+    (local.set $p (call $alloc (i32.const 24)))
+    (i32.store (local.get $p) (i32.const 1))
+    (i64.store offset=8 (local.get $p) (local.get $lo))
+    (i64.store offset=16 (local.get $p) (local.get $hi))
+    (local.get $p)
+  )
+  ;; The type of a value: the tag of its object, or 1 for an integer that
+  ;; isn't one.
+  (func $tag (param $v i32) (result i32)
+    (if (result i32) (i32.and (local.get $v) (i32.const 1))
+      (then (i32.const 1))
+      (else (i32.load (local.get $v))))
+  )
+  ;; The low half of an integer.
+  (func $lo (param $v i32) (result i64)
+    (if (result i64) (i32.and (local.get $v) (i32.const 1))
+      (then (i64.extend_i32_s (i32.shr_s (local.get $v) (i32.const 1))))
+      (else (i64.load offset=8 (local.get $v))))
+  )
+  ;; The high half of an integer.
+  (func $hi (param $v i32) (result i64)
+    (if (result i64) (i32.and (local.get $v) (i32.const 1))
+      (then (i64.shr_s (i64.extend_i32_s (local.get $v)) (i64.const 63)))
+      (else (i64.load offset=16 (local.get $v))))
+  )
+
+  (func $bool (param $b i32) (result i32)
+    (select (i32.const 8) (i32.const 16) (local.get $b))
+  )
+
+  (func $truthy (param $v i32) (result i32)
+    (i32.and (i32.ne (local.get $v) (i32.const 0)) (i32.ne (local.get $v) (i32.const 16)))
+  )
+
+  (func $bang (param $v i32) (result i32)
+    (call $bool (i32.eqz (call $truthy (local.get $v))))
+  )
+
+  ;; The high half of the unsigned product of two i64s.
+  (func $mul_hi (param $a i64) (param $b i64) (result i64)
+    (local $a0 i64) (local $a1 i64) (local $b0 i64) (local $b1 i64)
+    (local $p01 i64) (local $p10 i64) (local $mid i64)
+    (local.set $a0 (i64.and (local.get $a) (i64.const 0xffffffff)))
+    (local.set $a1 (i64.shr_u (local.get $a) (i64.const 32)))
+    (local.set $b0 (i64.and (local.get $b) (i64.const 0xffffffff)))
+    (local.set $b1 (i64.shr_u (local.get $b) (i64.const 32)))
+    (local.set $p01 (i64.mul (local.get $a0) (local.get $b1)))
+    (local.set $p10 (i64.mul (local.get $a1) (local.get $b0)))
+    (local.set $mid
+      (i64.add
+        (i64.add
+          (i64.shr_u (i64.mul (local.get $a0) (local.get $b0)) (i64.const 32))
+          (i64.and (local.get $p01) (i64.const 0xffffffff)))
+        (i64.and (local.get $p10) (i64.const 0xffffffff))))
+    (i64.add
+      (i64.add
+        (i64.add (i64.mul (local.get $a1) (local.get $b1)) (i64.shr_u (local.get $p01) (i64.const 32)))
+        (i64.shr_u (local.get $p10) (i64.const 32)))
+      (i64.shr_u (local.get $mid) (i64.const 32)))
+  )
+
+  (func $ult (param $alo i64) (param $ahi i64) (param $blo i64) (param $bhi i64) (result i32)
+    (if (result i32) (i64.ne (local.get $ahi) (local.get $bhi))
+      (then (i64.lt_u (local.get $ahi) (local.get $bhi)))
+      (else (i64.lt_u (local.get $alo) (local.get $blo))))
+  )
+
+  ;; Unsigned long division, a bit at a time, into $q_* and $r_*.
+  (func $udivmod (param $nlo i64) (param $nhi i64) (param $dlo i64) (param $dhi i64)
+    (local $i i32) (local $bit i64)
+    (local $qlo i64) (local $qhi i64) (local $rlo i64) (local $rhi i64)
+    (local.set $i (i32.const 127))
+    (loop $next
+      (if (i32.ge_u (local.get $i) (i32.const 64))
+        (then
+          (local.set $bit
+            (i64.and
+              (i64.shr_u (local.get $nhi) (i64.extend_i32_u (i32.sub (local.get $i) (i32.const 64))))
+              (i64.const 1))))
+        (else
+          (local.set $bit
+            (i64.and (i64.shr_u (local.get $nlo) (i64.extend_i32_u (local.get $i))) (i64.const 1)))))
+      (local.set $rhi
+        (i64.or (i64.shl (local.get $rhi) (i64.const 1)) (i64.shr_u (local.get $rlo) (i64.const 63))))
+      (local.set $rlo (i64.or (i64.shl (local.get $rlo) (i64.const 1)) (local.get $bit)))
+      (if (i32.eqz (call $ult (local.get $rlo) (local.get $rhi) (local.get $dlo) (local.get $dhi)))
+        (then
+          (local.set $rhi
+            (i64.sub
+              (i64.sub (local.get $rhi) (local.get $dhi))
+              (i64.extend_i32_u (i64.lt_u (local.get $rlo) (local.get $dlo)))))
+          (local.set $rlo (i64.sub (local.get $rlo) (local.get $dlo)))
+          (if (i32.ge_u (local.get $i) (i32.const 64))
+            (then
+              (local.set $qhi
+                (i64.or (local.get $qhi)
+                  (i64.shl (i64.const 1) (i64.extend_i32_u (i32.sub (local.get $i) (i32.const 64)))))))
+            (else
+              (local.set $qlo
+                (i64.or (local.get $qlo) (i64.shl (i64.const 1) (i64.extend_i32_u (local.get $i)))))))))
+      (if (local.get $i)
+        (then
+          (local.set $i (i32.sub (local.get $i) (i32.const 1)))
+          (br $next))))
+    (global.set $q_lo (local.get $qlo))
+    (global.set $q_hi (local.get $qhi))
+    (global.set $r_lo (local.get $rlo))
+    (global.set $r_hi (local.get $rhi))
+  )
+
+  (func $check_integers (param $l i32) (param $r i32)
+    (if (i32.or (i32.ne (call $tag (local.get $l)) (i32.const 1))
+                (i32.ne (call $tag (local.get $r)) (i32.const 1)))
+      (then
+        (call $fail_types (str "unsupported types for binary operation: ")
+          (str " ") (local.get $l) (local.get $r))))
+  )
+
+  (func $add (param $l i32) (param $r i32) (result i32)
+    (local $lo i64) (local $hi i64)
+    (call $check_integers (local.get $l) (local.get $r))
+    (local.set $lo (i64.add (call $lo (local.get $l)) (call $lo (local.get $r))))
+    (local.set $hi
+      (i64.add
+        (i64.add (call $hi (local.get $l)) (call $hi (local.get $r)))
+        (i64.extend_i32_u (i64.lt_u (local.get $lo) (call $lo (local.get $l))))))
+    ;; it overflowed if the operands have the same sign and the sum doesn't
+    (if (i64.lt_s
+          (i64.and (i64.xor (local.get $hi) (call $hi (local.get $l)))
+                   (i64.xor (local.get $hi) (call $hi (local.get $r))))
+          (i64.const 0))
+      (then (call $overflow)))
+    (call $int (local.get $lo) (local.get $hi))
+  )
+
+  (func $sub (param $l i32) (param $r i32) (result i32)
+    (local $hi i64)
+    (call $check_integers (local.get $l) (local.get $r))
+    (local.set $hi
+      (i64.sub
+        (i64.sub (call $hi (local.get $l)) (call $hi (local.get $r)))
+        (i64.extend_i32_u
+          (i64.lt_u (call $lo (local.get $l)) (call $lo (local.get $r))))))
+    ;; it overflowed if the operands' signs differ and the result's isn't
+    ;; the left's
+    (if (i64.lt_s
+          (i64.and (i64.xor (call $hi (local.get $l)) (call $hi (local.get $r)))
+                   (i64.xor (call $hi (local.get $l)) (local.get $hi)))
+          (i64.const 0))
+      (then (call $overflow)))
+    (call $int
+      (i64.sub (call $lo (local.get $l)) (call $lo (local.get $r)))
+      (local.get $hi))
+  )
+
+  ;; Multiplies the magnitudes, only one of which can have a high half if
+  ;; the product is to fit.
+  (func $mul (param $l i32) (param $r i32) (result i32)
+    (local $alo i64) (local $ahi i64) (local $blo i64) (local $bhi i64)
+    (local $lo i64) (local $hi i64) (local $t i64) (local $negative i32)
+    (call $check_integers (local.get $l) (local.get $r))
+    (local.set $alo (call $lo (local.get $l)))
+    (local.set $ahi (call $hi (local.get $l)))
+    (local.set $blo (call $lo (local.get $r)))
+    (local.set $bhi (call $hi (local.get $r)))
+    (if (i64.lt_s (local.get $ahi) (i64.const 0))
+      (then
+        (local.set $negative (i32.const 1))
+        (local.set $ahi
+          (i64.sub (i64.sub (i64.const 0) (local.get $ahi))
+                   (i64.extend_i32_u (i64.ne (local.get $alo) (i64.const 0)))))
+        (local.set $alo (i64.sub (i64.const 0) (local.get $alo)))))
+    (if (i64.lt_s (local.get $bhi) (i64.const 0))
+      (then
+        (local.set $negative (i32.xor (local.get $negative) (i32.const 1)))
+        (local.set $bhi
+          (i64.sub (i64.sub (i64.const 0) (local.get $bhi))
+                   (i64.extend_i32_u (i64.ne (local.get $blo) (i64.const 0)))))
+        (local.set $blo (i64.sub (i64.const 0) (local.get $blo)))))
+    (if (i32.and (i64.ne (local.get $ahi) (i64.const 0)) (i64.ne (local.get $bhi) (i64.const 0)))
+      (then (call $overflow)))
+    (if (i64.eqz (local.get $ahi))
+      (then
+        (local.set $t (local.get $alo))
+        (local.set $alo (local.get $blo))
+        (local.set $ahi (local.get $bhi))
+        (local.set $blo (local.get $t))))
+    (local.set $lo (i64.mul (local.get $alo) (local.get $blo)))
+    (local.set $hi (call $mul_hi (local.get $alo) (local.get $blo)))
+    ;; a's high half times b lands in the high half of the product
+    (if (i64.ne (call $mul_hi (local.get $ahi) (local.get $blo)) (i64.const 0))
+      (then (call $overflow)))
+    (local.set $t (i64.add (local.get $hi) (i64.mul (local.get $ahi) (local.get $blo))))
+    (if (i64.lt_u (local.get $t) (local.get $hi))
+      (then (call $overflow)))
+    (local.set $hi (local.get $t))
+    ;; a magnitude of 2^127 or more only fits as the most negative number
+    (if (i64.lt_s (local.get $hi) (i64.const 0))
+      (then
+        (if (i32.eqz
+              (i32.and (local.get $negative)
+                (i32.and (i64.eq (local.get $hi) (i64.const 0x8000000000000000))
+                         (i64.eqz (local.get $lo)))))
+          (then (call $overflow)))))
+    (if (result i32) (local.get $negative)
+      (then
+        (call $int
+          (i64.sub (i64.const 0) (local.get $lo))
+          (i64.sub (i64.sub (i64.const 0) (local.get $hi))
+                   (i64.extend_i32_u (i64.ne (local.get $lo) (i64.const 0))))))
+      (else (call $int (local.get $lo) (local.get $hi))))
+  )
+
+  ;; Divides the magnitudes into $q_* and $r_*, and gives whether the
+  ;; signs differ.
+  (func $divide (param $l i32) (param $r i32) (result i32)
+    (local $alo i64) (local $ahi i64) (local $blo i64) (local $bhi i64) (local $negative i32)
+    (call $check_integers (local.get $l) (local.get $r))
+    (local.set $alo (call $lo (local.get $l)))
+    (local.set $ahi (call $hi (local.get $l)))
+    (local.set $blo (call $lo (local.get $r)))
+    (local.set $bhi (call $hi (local.get $r)))
+    (if (i64.eqz (i64.or (local.get $blo) (local.get $bhi)))
+      (then (call $fail_arithmetic (str "division by zero"))))
+    (if (i64.lt_s (local.get $ahi) (i64.const 0))
+      (then
+        (local.set $negative (i32.const 1))
+        (local.set $ahi
+          (i64.sub (i64.sub (i64.const 0) (local.get $ahi))
+                   (i64.extend_i32_u (i64.ne (local.get $alo) (i64.const 0)))))
+        (local.set $alo (i64.sub (i64.const 0) (local.get $alo)))))
+    (if (i64.lt_s (local.get $bhi) (i64.const 0))
+      (then
+        (local.set $negative (i32.xor (local.get $negative) (i32.const 1)))
+        (local.set $bhi
+          (i64.sub (i64.sub (i64.const 0) (local.get $bhi))
+                   (i64.extend_i32_u (i64.ne (local.get $blo) (i64.const 0)))))
+        (local.set $blo (i64.sub (i64.const 0) (local.get $blo)))))
+    (call $udivmod (local.get $alo) (local.get $ahi) (local.get $blo) (local.get $bhi))
+    ;; only the most negative number over -1 gives a quotient of 2^127,
+    ;; which Rust's checked remainder refuses too
+    (if (i32.and (i32.eqz (local.get $negative)) (i64.lt_s (global.get $q_hi) (i64.const 0)))
+      (then (call $overflow)))
+    (local.get $negative)
+  )
+
+  ;; Truncates towards zero, as Rust does.
+  (func $div (param $l i32) (param $r i32) (result i32)
+    (if (result i32) (call $divide (local.get $l) (local.get $r))
+      (then
+        (call $int
+          (i64.sub (i64.const 0) (global.get $q_lo))
+          (i64.sub (i64.sub (i64.const 0) (global.get $q_hi))
+                   (i64.extend_i32_u (i64.ne (global.get $q_lo) (i64.const 0))))))
+      (else (call $int (global.get $q_lo) (global.get $q_hi))))
+  )
+
+  ;; The remainder takes the sign of the left side.
+  (func $mod (param $l i32) (param $r i32) (result i32)
+    (drop (call $divide (local.get $l) (local.get $r)))
+    (if (result i32) (i64.lt_s (call $hi (local.get $l)) (i64.const 0))
+      (then
+        (call $int
+          (i64.sub (i64.const 0) (global.get $r_lo))
+          (i64.sub (i64.sub (i64.const 0) (global.get $r_hi))
+                   (i64.extend_i32_u (i64.ne (global.get $r_lo) (i64.const 0))))))
+      (else (call $int (global.get $r_lo) (global.get $r_hi))))
+  )
+
+  (func $bit_and (param $l i32) (param $r i32) (result i32)
+    (call $check_integers (local.get $l) (local.get $r))
+    (call $int
+      (i64.and (call $lo (local.get $l)) (call $lo (local.get $r)))
+      (i64.and (call $hi (local.get $l)) (call $hi (local.get $r))))
+  )
+
+  (func $bit_or (param $l i32) (param $r i32) (result i32)
+    (call $check_integers (local.get $l) (local.get $r))
+    (call $int
+      (i64.or (call $lo (local.get $l)) (call $lo (local.get $r)))
+      (i64.or (call $hi (local.get $l)) (call $hi (local.get $r))))
+  )
+
+  (func $bit_xor (param $l i32) (param $r i32) (result i32)
+    (call $check_integers (local.get $l) (local.get $r))
+    (call $int
+      (i64.xor (call $lo (local.get $l)) (call $lo (local.get $r)))
+      (i64.xor (call $hi (local.get $l)) (call $hi (local.get $r))))
+  )
+
+  ;; The amount to shift `$l` by, which has to be less than its 128 bits.
+  (func $shift_amount (param $l i32) (param $r i32) (result i64)
+    (call $check_integers (local.get $l) (local.get $r))
+    (if (i32.or (i64.ne (call $hi (local.get $r)) (i64.const 0))
+                (i64.ge_u (call $lo (local.get $r)) (i64.const 128)))
+      (then
+        (call $write_str (str "shift amount out of range: "))
+        (call $write_int (call $lo (local.get $r)) (call $hi (local.get $r)))
+        (call $fail_arithmetic (i32.const 0) (i32.const 0))))
+    (call $lo (local.get $r))
+  )
+
+  (func $shift_left (param $l i32) (param $r i32) (result i32)
+    (local $n i64) (local $lo i64) (local $hi i64) (local $shifted i32) (local $back i32)
+    (local.set $n (call $shift_amount (local.get $l) (local.get $r)))
+    (local.set $lo (call $lo (local.get $l)))
+    (local.set $hi (call $hi (local.get $l)))
+    (if (i64.ge_u (local.get $n) (i64.const 64))
+      (then
+        (local.set $hi (i64.shl (local.get $lo) (i64.sub (local.get $n) (i64.const 64))))
+        (local.set $lo (i64.const 0)))
+      (else
+        (if (i64.ne (local.get $n) (i64.const 0))
+          (then
+            (local.set $hi
+              (i64.or (i64.shl (local.get $hi) (local.get $n))
+                      (i64.shr_u (local.get $lo) (i64.sub (i64.const 64) (local.get $n)))))
+            (local.set $lo (i64.shl (local.get $lo) (local.get $n)))))))
+    ;; it overflowed if shifting back doesn't give the operand
+    (local.set $shifted (call $int (local.get $lo) (local.get $hi)))
+    (local.set $back (call $shift_right (local.get $shifted) (local.get $r)))
+    (if (i32.or (i64.ne (call $lo (local.get $back)) (call $lo (local.get $l)))
+                (i64.ne (call $hi (local.get $back)) (call $hi (local.get $l))))
+      (then (call $overflow)))
+    (local.get $shifted)
+  )
+
+  ;; Shifts in copies of the sign bit, as Rust does for signed integers.
+  (func $shift_right (param $l i32) (param $r i32) (result i32)
+    (local $n i64) (local $lo i64) (local $hi i64)
+    (local.set $n (call $shift_amount (local.get $l) (local.get $r)))
+    (local.set $lo (call $lo (local.get $l)))
+    (local.set $hi (call $hi (local.get $l)))
+    (if (i64.ge_u (local.get $n) (i64.const 64))
+      (then
+        (local.set $lo (i64.shr_s (local.get $hi) (i64.sub (local.get $n) (i64.const 64))))
+        (local.set $hi (i64.shr_s (local.get $hi) (i64.const 63))))
+      (else
+        (if (i64.ne (local.get $n) (i64.const 0))
+          (then
+            (local.set $lo
+              (i64.or (i64.shr_u (local.get $lo) (local.get $n))
+                      (i64.shl (local.get $hi) (i64.sub (i64.const 64) (local.get $n)))))
+            (local.set $hi (i64.shr_s (local.get $hi) (local.get $n)))))))
+    (call $int (local.get $lo) (local.get $hi))
+  )
+
+  (func $bit_not (param $v i32) (result i32)
+    (if (i32.ne (call $tag (local.get $v)) (i32.const 1))
+      (then (call $fail_type (str "unsupported type for bitwise not: ") (local.get $v))))
+    (call $int
+      (i64.xor (call $lo (local.get $v)) (i64.const -1))
+      (i64.xor (call $hi (local.get $v)) (i64.const -1)))
+  )
+
+  (func $minus (param $v i32) (result i32)
+    (if (i32.ne (call $tag (local.get $v)) (i32.const 1))
+      (then (call $fail_type (str "unsupported type for negation: ") (local.get $v))))
+    (if (i32.and (i64.eqz (call $lo (local.get $v)))
+                 (i64.eq (call $hi (local.get $v)) (i64.const 0x8000000000000000)))
+      (then (call $overflow)))
+    (call $int
+      (i64.sub (i64.const 0) (call $lo (local.get $v)))
+      (i64.sub (i64.sub (i64.const 0) (call $hi (local.get $v)))
+               (i64.extend_i32_u (i64.ne (call $lo (local.get $v)) (i64.const 0)))))
+  )
+
+  (func $check_range (param $start i32) (param $end i32)
+    (if (i32.or (i32.ne (call $tag (local.get $start)) (i32.const 1))
+                (i32.ne (call $tag (local.get $end)) (i32.const 1)))
+      (then
+        (call $fail_types (str "unsupported types for range: ")
+          (str " ") (local.get $start) (local.get $end))))
+  )
+
+  (func $greater_than (param $l i32) (param $r i32) (result i32)
+    (if (i32.or (i32.ne (call $tag (local.get $l)) (i32.const 1))
+                (i32.ne (call $tag (local.get $r)) (i32.const 1)))
+      (then
+        (call $fail_types (str "unknown operator: ")
+          (str " > ") (local.get $l) (local.get $r))))
+    (call $bool
+      (if (result i32)
+          (i64.ne (call $hi (local.get $l)) (call $hi (local.get $r)))
+        (then (i64.gt_s (call $hi (local.get $l)) (call $hi (local.get $r))))
+        (else (i64.gt_u (call $lo (local.get $l)) (call $lo (local.get $r))))))
+  )
+
+  (func $less_than (param $l i32) (param $r i32) (result i32)
+    (call $greater_than (local.get $r) (local.get $l))
+  )
+
+  (func $greater_equal (param $l i32) (param $r i32) (result i32)
+    (if (i32.or (i32.ne (call $tag (local.get $l)) (i32.const 1))
+                (i32.ne (call $tag (local.get $r)) (i32.const 1)))
+      (then
+        (call $fail_types (str "unknown operator: ")
+          (str " >= ") (local.get $l) (local.get $r))))
+    (call $bang (call $greater_than (local.get $r) (local.get $l)))
+  )
+
+  (func $less_equal (param $l i32) (param $r i32) (result i32)
+    (call $greater_equal (local.get $r) (local.get $l))
+  )
+
+  ;; Integers compare by value; everything else is a unique object.
+  (func $same (param $l i32) (param $r i32) (result i32)
+    (if (i32.eq (local.get $l) (local.get $r))
+      (then (return (i32.const 1))))
+    (if (i32.or (i32.ne (call $tag (local.get $l)) (i32.const 1))
+                (i32.ne (call $tag (local.get $r)) (i32.const 1)))
+      (then (return (i32.const 0))))
+    (i32.and
+      (i64.eq (call $lo (local.get $l)) (call $lo (local.get $r)))
+      (i64.eq (call $hi (local.get $l)) (call $hi (local.get $r))))
+  )
+
+  (func $equal (param $l i32) (param $r i32) (result i32)
+    (call $bool (call $same (local.get $l) (local.get $r)))
+  )
+
+  (func $not_equal (param $l i32) (param $r i32) (result i32)
+    (call $bool (i32.eqz (call $same (local.get $l) (local.get $r))))
+  )
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn emit_str(input: &str) -> Result<String, String> {
+        let l = Lexer::new(input, true, None);
+        let mut p = Parser::new(l);
+        let program = p.parse_program().expect("Program should be Some here");
+        assert_eq!(p.errors().len(), 0, "{:?}", p.errors());
+        emit(&program)
+    }
+
+    #[test]
+    fn test_emitted_functions() {
+        let wat = emit_str("let add = fn(a, b) { a + b }; puts(add(1, 2));").expect("should emit");
+
+        let add = "  (func $f0 (type $fn2) (param $self i32) (param $l0 i32) (param $l1 i32) (result i32)\n    \
+            (local $tmp i32)\n    \
+            local.get $l0\n    \
+            local.get $l1\n    \
+            call $add\n  \
+            )\n";
+        assert!(wat.contains(add), "{}", wat);
+        assert!(wat.contains("  (elem (i32.const 0) func $f0)\n"));
+        assert!(wat.contains("    call $closure\n    global.set $g0\n"));
+        assert!(wat.contains("    call $call_2\n    call $call_1\n    drop\n"));
+
+        assert_eq!(
+            emit_str("fn() { x }"),
+            Err(String::from("undefined variable x"))
+        );
+    }
+}
@@ -0,0 +1,268 @@
+use crate::ast::Expression;
+use crate::formatter;
+use crate::token::{Span, Token};
+use crate::vm::LimitExceeded;
+use std::fmt;
+
+/// The name traces give the code outside any function.
+pub const MAIN: &str = "<main>";
+/// The name traces give a function that no `let` named.
+pub const ANONYMOUS: &str = "<anonymous>";
+
+/// An expression that can fail at runtime, kept with the code compiled from
+/// it so that errors can say which one failed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Site {
+    /// The expression as the formatter prints it, up to the end of its
+    /// first line.
+    pub expression: String,
+    /// Where its token is, when the source had locations.
+    pub span: Option<Span>,
+}
+
+impl Site {
+    pub fn new(expression: String, token: &Token) -> Site {
+        Site {
+            expression,
+            span: token.local().map(|l| l.span()),
+        }
+    }
+
+    pub fn of(e: &Expression) -> Site {
+        Site::new(formatter::first_line(e), e.token())
+    }
+
+    /// The bounds of the `for` loop `token` starts, which have to be
+    /// integers.
+    pub fn range(token: &Token, start: &Expression, end: &Expression) -> Site {
+        Site::new(
+            format!(
+                "{}..{}",
+                formatter::first_line(start),
+                formatter::first_line(end)
+            ),
+            token,
+        )
+    }
+
+    /// The `throw` statement `token` starts, which fails on purpose.
+    pub fn throw(token: &Token, value: &Expression) -> Site {
+        Site::new(format!("throw {}", formatter::first_line(value)), token)
+    }
+}
+
+impl fmt::Display for Site {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.span {
+            Some(span) => write!(f, "{}: {}", span, self.expression),
+            None => write!(f, "{}", self.expression),
+        }
+    }
+}
+
+/// What sort of error something is, which `catch` hands scripts as the
+/// error's `kind` so they needn't match on messages. Whatever raises an
+/// error says which kind it is.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Kind {
+    /// Dividing, or taking a remainder, by zero.
+    Division,
+    /// Integer arithmetic whose result doesn't fit.
+    Overflow,
+    /// An operand outside the values an operator takes, like a shift
+    /// amount.
+    Range,
+    /// A value of the wrong type for what was done with it.
+    Type,
+    /// A name that isn't bound, or a member that doesn't exist.
+    Name,
+    /// A call with the wrong number of arguments.
+    Arity,
+    /// Calls, or the values they leave, going too deep.
+    Stack,
+    /// A value thrown with `throw`, rather than a runtime error.
+    Thrown,
+    /// Anything else, like an error from a host function that didn't say.
+    #[default]
+    Other,
+}
+
+impl Kind {
+    /// The name scripts see.
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::Division => "division",
+            Kind::Overflow => "overflow",
+            Kind::Range => "range",
+            Kind::Type => "type",
+            Kind::Name => "name",
+            Kind::Arity => "arity",
+            Kind::Stack => "stack",
+            Kind::Thrown => "throw",
+            Kind::Other => "error",
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// A runtime error on its way up to a `catch`, or out of the run. Engines
+/// and the functions they call fail with one; a plain `String` converts to
+/// one of kind `Kind::Other`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Failure {
+    pub kind: Kind,
+    pub message: String,
+}
+
+impl Failure {
+    pub fn new(kind: Kind, message: impl Into<String>) -> Failure {
+        Failure {
+            kind,
+            message: message.into(),
+        }
+    }
+
+    /// Calls, or the values they leave, outgrowing the stack.
+    pub fn stack_overflow() -> Failure {
+        Failure::new(Kind::Stack, "stack overflow")
+    }
+
+    /// Reading `name` when nothing is bound to it.
+    pub fn not_found(name: &str) -> Failure {
+        Failure::new(Kind::Name, format!("identifier not found: {}", name))
+    }
+
+    /// Calling a value of type `type_name`, which isn't a function.
+    pub fn not_callable(type_name: &str) -> Failure {
+        Failure::new(Kind::Type, format!("calling non-function: {}", type_name))
+    }
+
+    /// Calling something that takes `want` arguments with `got`.
+    pub fn wrong_arguments(want: usize, got: usize) -> Failure {
+        Failure::new(
+            Kind::Arity,
+            format!("wrong number of arguments: want={}, got={}", want, got),
+        )
+    }
+
+    pub fn division_by_zero() -> Failure {
+        Failure::new(Kind::Division, "division by zero")
+    }
+
+    pub fn shift_out_of_range(amount: i128) -> Failure {
+        Failure::new(
+            Kind::Range,
+            format!("shift amount out of range: {}", amount),
+        )
+    }
+}
+
+impl From<String> for Failure {
+    fn from(message: String) -> Failure {
+        Failure::new(Kind::Other, message)
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// A call that was in progress when an error happened.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFrame {
+    /// The name the function was bound to by `let`, or `ANONYMOUS`, or
+    /// `MAIN`.
+    pub function: String,
+    /// What the function was running: the call into the frame inside it,
+    /// or for the innermost frame, the expression that failed. Errors that
+    /// don't come from an expression, like running out of fuel, leave the
+    /// last one that ran, if it could have failed.
+    pub site: Option<Site>,
+}
+
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "in {}", self.function)?;
+        if let Some(site) = &self.site {
+            write!(f, " at {}", site)?;
+        }
+        Ok(())
+    }
+}
+
+/// An error from running Monkey code. The message is the same on every
+/// engine; the trace lists the calls in progress, innermost first, and is
+/// empty for errors from before the code ran, like parse errors.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    pub message: String,
+    /// What sort of error it was. Errors from before the code ran are
+    /// `Kind::Other`.
+    pub kind: Kind,
+    pub trace: Vec<TraceFrame>,
+    /// The limit the run went over, when that's what stopped it rather
+    /// than the code.
+    pub limit: Option<LimitExceeded>,
+}
+
+impl Error {
+    /// The trace, a frame a line, with runs of the same frame, as deep
+    /// recursion leaves, cut down to one.
+    pub fn trace_lines(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut frames = self.trace.iter().peekable();
+        while let Some(frame) = frames.next() {
+            lines.push(frame.to_string());
+
+            let mut repeats = 0;
+            while frames.next_if_eq(&frame).is_some() {
+                repeats += 1;
+            }
+            if repeats > 0 {
+                lines.push(format!("... the same {} more times", repeats));
+            }
+        }
+        lines
+    }
+}
+
+impl From<String> for Error {
+    fn from(message: String) -> Error {
+        Error {
+            message,
+            kind: Kind::Other,
+            trace: Vec::new(),
+            limit: None,
+        }
+    }
+}
+
+impl From<Failure> for Error {
+    fn from(failure: Failure) -> Error {
+        Error {
+            kind: failure.kind,
+            ..Error::from(failure.message)
+        }
+    }
+}
+
+impl From<Error> for String {
+    fn from(e: Error) -> String {
+        e.message
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for Error {}
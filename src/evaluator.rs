@@ -0,0 +1,1596 @@
+use crate::ast::{
+    self, Block, BooleanInternal, Expression, ForInternal, FunctionInternal, Identifier,
+    ImportInternal, IntegerInternal, Program, Statement, StringInternal, TryInternal,
+};
+use crate::error::{self, Error, Failure, Kind, Site, TraceFrame};
+use crate::gc::{Heap, Trace, Traced};
+use crate::macros;
+use crate::module::Modules;
+use crate::object::{
+    self, Builtin, Element, Exception, GcConfig, GcStats, HashKey, Host, Object, Overflow, BUILTINS,
+};
+use crate::token::{Location, Token, TokenKind};
+use crate::vm::{InterruptHandle, Limits, Meter, MAX_FRAMES};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
+use std::path::Path;
+use std::rc::Rc;
+
+/// A value of the tree walking evaluator. Functions point back into the
+/// program, which is why this isn't `object::Object`.
+#[derive(Clone)]
+pub enum Value<'p, 'a> {
+    Integer(i128),
+    Boolean(bool),
+    Null,
+    String(Rc<str>),
+    Error(Rc<Exception>),
+    GcStats(GcStats),
+    Function(Rc<Function<'p, 'a>>),
+    Builtin(&'static Builtin),
+    Module(Rc<Module<'p, 'a>>),
+    Array(object::Array<Value<'p, 'a>>),
+    Hash(object::Hash<Value<'p, 'a>>),
+    /// A piece of the program, which only macros see: it's what they're
+    /// passed and what `quote` gives them.
+    Quote(Rc<Expression<'a>>),
+}
+
+pub struct Function<'p, 'a> {
+    /// The name `let` bound it to, for stack traces.
+    name: Option<&'p str>,
+    parameters: &'p [Identifier<'a>],
+    body: &'p Block<'a>,
+    env: Env<'p, 'a>,
+}
+
+/// An imported file. Its bindings are read from its environment when
+/// they're used, like the compiled engines read them from globals.
+pub struct Module<'p, 'a> {
+    name: String,
+    exports: &'p [String],
+    env: Env<'p, 'a>,
+}
+
+impl<'p, 'a> Module<'p, 'a> {
+    fn get(&self, name: &str) -> Result<Value<'p, 'a>, Failure> {
+        if !self.exports.iter().any(|e| e == name) {
+            return Err(Failure::new(
+                Kind::Name,
+                format!("module {} has no member {}", self.name, name),
+            ));
+        }
+        match self.env.borrow().store.get(name) {
+            Some(v) => Ok(v.clone()),
+            None => Err(Failure::not_found(name)),
+        }
+    }
+}
+
+type Env<'p, 'a> = Rc<RefCell<Environment<'p, 'a>>>;
+
+#[derive(Default)]
+struct Environment<'p, 'a> {
+    store: HashMap<String, Value<'p, 'a>>,
+    outer: Option<Env<'p, 'a>>,
+}
+
+impl<'p, 'a> Environment<'p, 'a> {
+    fn get(&self, name: &str) -> Option<Value<'p, 'a>> {
+        match self.store.get(name) {
+            Some(v) => Some(v.clone()),
+            None => self.outer.as_ref()?.borrow().get(name),
+        }
+    }
+
+    /// Rebinds `name` in the nearest environment that has it, which a
+    /// closure shares with the function that made it.
+    fn assign(&mut self, name: &str, value: Value<'p, 'a>) -> Result<(), Failure> {
+        match (self.store.get_mut(name), &self.outer) {
+            (Some(Value::Builtin(b)), None) if b.name == name => Err(Failure::new(
+                Kind::Name,
+                format!("can't assign to builtin {}", name),
+            )),
+            (Some(v), _) => {
+                *v = value;
+                Ok(())
+            }
+            (None, Some(outer)) => outer.borrow_mut().assign(name, value),
+            (None, None) => Err(Failure::not_found(name)),
+        }
+    }
+}
+
+/// An environment in the middle of being changed can't be read, which
+/// leaves the collection for next time.
+impl<'p, 'a> Trace<'p> for RefCell<Environment<'p, 'a>> {
+    fn trace(&self, f: &mut dyn FnMut(Rc<dyn Trace<'p> + 'p>)) -> bool {
+        let Ok(env) = self.try_borrow() else {
+            return false;
+        };
+        if let Some(outer) = &env.outer {
+            f(outer.clone());
+        }
+        env.store.values().filter_map(Traced::node).for_each(f);
+        true
+    }
+
+    fn clear(&self) -> bool {
+        let env = std::mem::take(&mut *self.borrow_mut());
+        drop(env);
+        true
+    }
+}
+
+impl<'p, 'a> Trace<'p> for Function<'p, 'a> {
+    fn trace(&self, f: &mut dyn FnMut(Rc<dyn Trace<'p> + 'p>)) -> bool {
+        f(self.env.clone());
+        true
+    }
+
+    fn clear(&self) -> bool {
+        false
+    }
+}
+
+impl<'p, 'a> Trace<'p> for Module<'p, 'a> {
+    fn trace(&self, f: &mut dyn FnMut(Rc<dyn Trace<'p> + 'p>)) -> bool {
+        f(self.env.clone());
+        true
+    }
+
+    fn clear(&self) -> bool {
+        false
+    }
+}
+
+impl<'p, 'a> Traced<'p> for Value<'p, 'a> {
+    fn node(&self) -> Option<Rc<dyn Trace<'p> + 'p>> {
+        match self {
+            Value::Function(f) => Some(f.clone()),
+            Value::Module(m) => Some(m.clone()),
+            Value::Array(a) => Some(a.clone()),
+            Value::Hash(h) => Some(h.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl Value<'_, '_> {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Integer(_) => "INTEGER",
+            Value::Boolean(_) => "BOOLEAN",
+            Value::Null => "NULL",
+            Value::String(_) => "STRING",
+            Value::Error(_) => "ERROR",
+            Value::GcStats(_) => "GC_STATS",
+            Value::Function(_) => "FUNCTION",
+            Value::Builtin(_) => "BUILTIN",
+            Value::Module(_) => "MODULE",
+            Value::Array(_) => "ARRAY",
+            Value::Hash(_) => "HASH",
+            Value::Quote(_) => "QUOTE",
+        }
+    }
+
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Boolean(false) | Value::Null)
+    }
+
+    fn from_object(obj: Object) -> Self {
+        match obj {
+            Object::Integer(i) => Value::Integer(i),
+            Object::Boolean(b) => Value::Boolean(b),
+            Object::String(s) => Value::String(s),
+            Object::Error(e) => Value::Error(e),
+            Object::GcStats(s) => Value::GcStats(s),
+            Object::Builtin(b) => Value::Builtin(b),
+            _ => Value::Null,
+        }
+    }
+}
+
+impl Element for Value<'_, '_> {
+    fn integer(i: i128) -> Self {
+        Value::Integer(i)
+    }
+
+    fn null() -> Self {
+        Value::Null
+    }
+
+    fn gc_stats(stats: GcStats) -> Self {
+        Value::GcStats(stats)
+    }
+
+    fn type_name(&self) -> &'static str {
+        Value::type_name(self)
+    }
+
+    fn array(&self) -> Option<&object::Array<Self>> {
+        match self {
+            Value::Array(a) => Some(a),
+            _ => None,
+        }
+    }
+
+    fn hash(&self) -> Option<&object::Hash<Self>> {
+        match self {
+            Value::Hash(h) => Some(h),
+            _ => None,
+        }
+    }
+
+    fn key(&self) -> Option<HashKey> {
+        match self {
+            Value::Integer(i) => Some(HashKey::Integer(*i)),
+            Value::Boolean(b) => Some(HashKey::Boolean(*b)),
+            Value::String(s) => Some(HashKey::String(s.clone())),
+            _ => None,
+        }
+    }
+
+    fn from_key(key: &HashKey) -> Self {
+        match key {
+            HashKey::Integer(i) => Value::Integer(*i),
+            HashKey::Boolean(b) => Value::Boolean(*b),
+            HashKey::String(s) => Value::String(s.clone()),
+        }
+    }
+}
+
+impl PartialEq for Value<'_, '_> {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Integer(l), Value::Integer(r)) => l == r,
+            (Value::Boolean(l), Value::Boolean(r)) => l == r,
+            (Value::Null, Value::Null) => true,
+            (Value::String(l), Value::String(r)) => l == r,
+            (Value::Error(l), Value::Error(r)) => Rc::ptr_eq(l, r),
+            (Value::GcStats(l), Value::GcStats(r)) => l == r,
+            (Value::Function(l), Value::Function(r)) => Rc::ptr_eq(l, r),
+            (Value::Builtin(l), Value::Builtin(r)) => std::ptr::eq(*l, *r),
+            (Value::Module(l), Value::Module(r)) => Rc::ptr_eq(l, r),
+            (Value::Array(l), Value::Array(r)) => Rc::ptr_eq(l, r),
+            (Value::Hash(l), Value::Hash(r)) => Rc::ptr_eq(l, r),
+            (Value::Quote(l), Value::Quote(r)) => Rc::ptr_eq(l, r),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Debug for Value<'_, '_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl fmt::Display for Value<'_, '_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Integer(i) => write!(f, "{}", i),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Null => write!(f, "null"),
+            Value::String(s) => write!(f, "{}", s),
+            Value::Error(e) => write!(f, "<error: {}>", e.message),
+            Value::GcStats(s) => write!(f, "{}", s),
+            Value::Function(_) => write!(f, "<function>"),
+            Value::Builtin(b) => write!(f, "<builtin {}>", b.name),
+            Value::Module(m) => write!(f, "<module {}>", m.name),
+            Value::Array(a) => object::fmt_array(f, a),
+            Value::Hash(h) => object::fmt_hash(f, h),
+            Value::Quote(e) => write!(f, "QUOTE({})", e),
+        }
+    }
+}
+
+/// How evaluating a statement ended.
+enum Flow<'p, 'a> {
+    Next(Value<'p, 'a>),
+    Return(Value<'p, 'a>),
+    /// Leaving the innermost loop, which the parser makes sure there is.
+    Break,
+    Continue,
+    /// The call `Expression` is the last thing the running function does,
+    /// so it's left to `Evaluator::call` to make in the function's place.
+    Tail(&'p Expression<'a>, Value<'p, 'a>, Vec<Value<'p, 'a>>),
+}
+
+/// The reference semantics of Monkey: a direct walk over the AST that the
+/// compiled engines are checked against. It's slow, but simple enough to
+/// trust.
+pub struct Evaluator<'p, 'a> {
+    depth: usize,
+    /// Whether a `return` can make a tail call: it's in a function, and not
+    /// in a `try`, which has to see what the call does.
+    tail_calls: bool,
+    /// Whether a macro is being expanded, which is when `quote` means
+    /// something.
+    quoting: bool,
+    out: Box<dyn Write>,
+    modules: Option<&'p Modules>,
+    /// The file being run, which imports are relative to.
+    file: Option<&'p Path>,
+    /// Modules that have run, by `module::Module::id`.
+    loaded: HashMap<usize, Value<'p, 'a>>,
+    overflow: Overflow,
+    /// The expression that failed, until the call it failed in is added to
+    /// `trace`. The compiled engines know the same ones as `error::Site`s.
+    site: Option<Site>,
+    /// The calls an error has come out of so far, innermost first.
+    trace: Vec<TraceFrame>,
+    /// What was thrown, when the error in flight came from `throw`.
+    thrown: Option<Rc<Exception>>,
+    heap: Heap<'p>,
+    meter: Meter,
+}
+
+impl Default for Evaluator<'_, '_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'p, 'a> Evaluator<'p, 'a> {
+    pub fn new() -> Evaluator<'p, 'a> {
+        Evaluator {
+            depth: 0,
+            tail_calls: false,
+            quoting: false,
+            out: Box::new(std::io::stdout()),
+            modules: None,
+            file: None,
+            loaded: HashMap::new(),
+            overflow: Overflow::default(),
+            site: None,
+            trace: Vec::new(),
+            thrown: None,
+            heap: Heap::default(),
+            meter: Meter::default(),
+        }
+    }
+
+    /// Changes when the collector runs.
+    pub fn set_gc(&mut self, config: GcConfig) {
+        self.heap.set_config(config);
+    }
+
+    /// How the collector has done so far, as `gc_stats()` sees it.
+    pub fn gc_stats(&self) -> GcStats {
+        self.heap.stats()
+    }
+
+    fn global_env(&mut self) -> Env<'p, 'a> {
+        let mut env = Environment::default();
+        for builtin in BUILTINS.iter() {
+            env.store
+                .insert(builtin.name.to_string(), Value::Builtin(builtin));
+        }
+        self.alloc(env)
+    }
+
+    /// Makes `env` an environment the collector knows about. A function
+    /// holds the environment it was made in and is usually stored there
+    /// too, so reference counting on its own would leak every one that
+    /// a function is defined in.
+    fn alloc(&mut self, env: Environment<'p, 'a>) -> Env<'p, 'a> {
+        let env = Rc::new(RefCell::new(env));
+        self.heap.track(&env);
+        env
+    }
+
+    /// Sends what `puts` prints to `out` rather than stdout.
+    pub fn set_output(&mut self, out: Box<dyn Write>) {
+        self.out = out;
+    }
+
+    /// Makes integer arithmetic wrap around instead of failing when it
+    /// overflows.
+    pub fn set_overflow(&mut self, overflow: Overflow) {
+        self.overflow = overflow;
+    }
+
+    /// Limits what each `eval` and `expand_macro` may use, like
+    /// `Vm::set_limits`.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.meter.set_limits(limits);
+    }
+
+    /// A handle that interrupts this evaluator.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.meter.interrupt_handle()
+    }
+
+    /// Makes `handle` interrupt this evaluator, instead of its own.
+    pub fn set_interrupt_handle(&mut self, handle: InterruptHandle) {
+        self.meter.set_interrupt_handle(handle);
+    }
+
+    /// Lets `import` run what `Modules::load` loaded for the program in
+    /// `file`. Without it, imports fail.
+    pub fn set_modules(&mut self, modules: &'p Modules, file: Option<&'p Path>) {
+        self.modules = Some(modules);
+        self.file = file;
+    }
+
+    /// Runs `program`, returning the value of its last statement.
+    pub fn eval(&mut self, program: &'p Program<'a>) -> Result<Value<'p, 'a>, Error> {
+        self.site = None;
+        self.trace.clear();
+        self.thrown = None;
+        self.meter.start();
+
+        let env = self.global_env();
+        self.tail_calls = false;
+        let result = self.statements(&program.statements, &env);
+        // nothing can refer to the program's environment once it's done,
+        // except what it returns
+        drop(env);
+        self.heap.collect();
+
+        match result {
+            Ok(Flow::Next(v) | Flow::Return(v)) => Ok(v),
+            Ok(Flow::Break | Flow::Continue) => unreachable!("the parser keeps these in loops"),
+            Ok(Flow::Tail(..)) => unreachable!("only functions make tail calls"),
+            Err(failure) => {
+                self.trace.push(TraceFrame {
+                    function: String::from(error::MAIN),
+                    site: self.site.take(),
+                });
+                Err(Error {
+                    message: failure.message,
+                    kind: failure.kind,
+                    trace: std::mem::take(&mut self.trace),
+                    limit: self.meter.exceeded(),
+                })
+            }
+        }
+    }
+
+    /// Runs the macro `m`, which `let` bound to `name`, on `args`, giving
+    /// the code that replaces the call. `quote` only works in here.
+    pub fn expand_macro(
+        &mut self,
+        name: &str,
+        m: &'p FunctionInternal<'a>,
+        args: Vec<Expression<'a>>,
+    ) -> Result<Expression<'a>, Error> {
+        self.site = None;
+        self.trace.clear();
+        self.thrown = None;
+        self.meter.start();
+
+        let env = self.global_env();
+        for (p, arg) in m.parameters().iter().zip(args) {
+            env.borrow_mut()
+                .store
+                .insert(p.value().clone(), Value::Quote(Rc::new(arg)));
+        }
+        self.tail_calls = false;
+        self.quoting = true;
+        let result = self.block(m.body(), &env);
+        self.quoting = false;
+        drop(env);
+        self.heap.collect();
+
+        let failure = match result {
+            Ok(Flow::Next(Value::Quote(e)) | Flow::Return(Value::Quote(e))) => {
+                return Ok(Rc::try_unwrap(e).unwrap_or_else(|e| (*e).clone()))
+            }
+            Ok(Flow::Next(v) | Flow::Return(v)) => Failure::from(format!(
+                "macro {} returned {}, not a quote",
+                name,
+                v.type_name()
+            )),
+            Ok(Flow::Break | Flow::Continue) => unreachable!("the parser keeps these in loops"),
+            Ok(Flow::Tail(..)) => unreachable!("only functions make tail calls"),
+            Err(failure) => failure,
+        };
+        self.trace.push(TraceFrame {
+            function: name.to_string(),
+            site: self.site.take(),
+        });
+        Err(Error {
+            message: failure.message,
+            kind: failure.kind,
+            trace: std::mem::take(&mut self.trace),
+            limit: self.meter.exceeded(),
+        })
+    }
+
+    /// The value of `quote(e)`: `e` itself, with each `unquote(x)` in it
+    /// replaced by the value of `x`.
+    fn quote(&mut self, e: &'p Expression<'a>, env: &Env<'p, 'a>) -> Result<Flow<'p, 'a>, Failure> {
+        let mut values = Vec::new();
+        if let Some(flow) = self.unquoted(e, env, &mut values)? {
+            return Ok(flow);
+        }
+
+        let mut quoted = e.clone();
+        let mut values = values.into_iter();
+        splice(&mut quoted, &mut values)?;
+        Ok(Flow::Next(Value::Quote(Rc::new(quoted))))
+    }
+
+    /// Evaluates the `unquote`s in `e` in the order they're written, which
+    /// is the order `splice` puts them back in.
+    fn unquoted(
+        &mut self,
+        e: &'p Expression<'a>,
+        env: &Env<'p, 'a>,
+        values: &mut Vec<Value<'p, 'a>>,
+    ) -> Result<Option<Flow<'p, 'a>>, Failure> {
+        if let Some(arg) = unquote_argument(e)? {
+            match self.expression(arg, env)? {
+                Flow::Next(v) => values.push(v),
+                ret => return Ok(Some(ret)),
+            }
+            return Ok(None);
+        }
+        for child in e.children() {
+            if let Some(ret) = self.unquoted(child, env, values)? {
+                return Ok(Some(ret));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The module `i` imports, running it the first time.
+    fn import(&mut self, i: &ImportInternal) -> Result<Value<'p, 'a>, Failure> {
+        let module = match self.modules.and_then(|m| m.find(self.file, i.path())) {
+            Some(m) => m,
+            None => {
+                return Err(Failure::new(
+                    Kind::Name,
+                    format!("module {} isn't loaded", i.path()),
+                ))
+            }
+        };
+        if let Some(value) = self.loaded.get(&module.id) {
+            return Ok(value.clone());
+        }
+
+        let env = self.global_env();
+        let importer = self.file.replace(&module.path);
+        let tail_calls = std::mem::replace(&mut self.tail_calls, false);
+        let result = self.statements(&module.program.statements, &env);
+        self.tail_calls = tail_calls;
+        self.file = importer;
+        result?;
+
+        let value = Value::Module(Rc::new(Module {
+            name: module.name.clone(),
+            exports: &module.exports,
+            env,
+        }));
+        self.loaded.insert(module.id, value.clone());
+        Ok(value)
+    }
+
+    fn statements(
+        &mut self,
+        statements: &'p [Statement<'a>],
+        env: &Env<'p, 'a>,
+    ) -> Result<Flow<'p, 'a>, Failure> {
+        let mut result = Value::Null;
+
+        for stmt in statements {
+            // loops nest in each other without any expressions in between
+            match ast::deeper(|| self.statement(stmt, env))? {
+                Flow::Next(v) => result = v,
+                ret => return Ok(ret),
+            }
+        }
+
+        Ok(Flow::Next(result))
+    }
+
+    fn statement(
+        &mut self,
+        stmt: &'p Statement<'a>,
+        env: &Env<'p, 'a>,
+    ) -> Result<Flow<'p, 'a>, Failure> {
+        match stmt {
+            Statement::Expression(i) => match i.expression() {
+                Some(e) => self.expression(e, env),
+                None => Ok(Flow::Next(Value::Null)),
+            },
+            Statement::Let(i) => {
+                let name = match i.name() {
+                    Some(n) => n.value(),
+                    None => return Ok(Flow::Next(Value::Null)),
+                };
+                let value = match i.value() {
+                    Some(Expression::Function(f)) => {
+                        self.meter.allocate(std::mem::size_of::<Function>())?;
+                        Value::Function(Rc::new(Function {
+                            name: Some(name),
+                            parameters: f.parameters(),
+                            body: f.body(),
+                            env: env.clone(),
+                        }))
+                    }
+                    Some(e) => match self.expression(e, env)? {
+                        Flow::Next(v) => v,
+                        ret => return Ok(ret),
+                    },
+                    None => Value::Null,
+                };
+                env.borrow_mut().store.insert(name.to_string(), value);
+                Ok(Flow::Next(Value::Null))
+            }
+            Statement::Return(i) => {
+                let value = match i.return_value() {
+                    Some(e) if self.tail_calls => match self.tail(e, env)? {
+                        Flow::Next(v) => v,
+                        ret => return Ok(ret),
+                    },
+                    Some(e) => match self.expression(e, env)? {
+                        Flow::Next(v) => v,
+                        ret => return Ok(ret),
+                    },
+                    None => Value::Null,
+                };
+                Ok(Flow::Return(value))
+            }
+            Statement::Import(i) => {
+                let module = self.import(i)?;
+                env.borrow_mut()
+                    .store
+                    .insert(i.name().value().clone(), module);
+                Ok(Flow::Next(Value::Null))
+            }
+            Statement::While(i) => {
+                loop {
+                    let condition = match self.expression(i.condition(), env)? {
+                        Flow::Next(v) => v,
+                        ret => return Ok(ret),
+                    };
+                    if !condition.is_truthy() {
+                        break;
+                    }
+                    match self.statements(&i.body().statements, env)? {
+                        Flow::Next(_) | Flow::Continue => {}
+                        Flow::Break => break,
+                        ret => return Ok(ret),
+                    }
+                }
+                Ok(Flow::Next(Value::Null))
+            }
+            Statement::For(i) => {
+                let start = match self.expression(i.start(), env)? {
+                    Flow::Next(v) => v,
+                    ret => return Ok(ret),
+                };
+                let Some(end_expr) = i.end() else {
+                    return self.for_each(i, start, env);
+                };
+                let end = match self.expression(end_expr, env)? {
+                    Flow::Next(v) => v,
+                    ret => return Ok(ret),
+                };
+                let (start, end) = match (start, end) {
+                    (Value::Integer(start), Value::Integer(end)) => (start, end),
+                    (start, end) => {
+                        self.site = Some(Site::range(i.token(), i.start(), end_expr));
+                        return Err(Failure::new(
+                            Kind::Type,
+                            format!(
+                                "unsupported types for range: {} {}",
+                                start.type_name(),
+                                end.type_name()
+                            ),
+                        ));
+                    }
+                };
+
+                for n in start..end {
+                    self.meter.step()?;
+                    env.borrow_mut()
+                        .store
+                        .insert(i.variable().value().clone(), Value::Integer(n));
+                    match self.statements(&i.body().statements, env)? {
+                        Flow::Next(_) | Flow::Continue => {}
+                        Flow::Break => break,
+                        ret => return Ok(ret),
+                    }
+                }
+                Ok(Flow::Next(Value::Null))
+            }
+            Statement::Break(_) => Ok(Flow::Break),
+            Statement::Continue(_) => Ok(Flow::Continue),
+            Statement::Throw(i) => {
+                let exception = match self.expression(i.value(), env)? {
+                    Flow::Next(Value::Error(e)) => e,
+                    Flow::Next(v) => Rc::new(Exception {
+                        message: v.to_string(),
+                        kind: Kind::Thrown,
+                        span: i.token().local().map(|l| l.span()),
+                    }),
+                    ret => return Ok(ret),
+                };
+                self.site = Some(Site::throw(i.token(), i.value()));
+                let failure = Failure::new(exception.kind, exception.message.clone());
+                self.thrown = Some(exception);
+                Err(failure)
+            }
+        }
+    }
+
+    /// Runs `for (x in items)` over an array's elements or a hash's keys.
+    /// It goes by position like the compiled engines do, so it sees what
+    /// the body adds.
+    fn for_each(
+        &mut self,
+        i: &'p ForInternal<'a>,
+        items: Value<'p, 'a>,
+        env: &Env<'p, 'a>,
+    ) -> Result<Flow<'p, 'a>, Failure> {
+        for position in 0.. {
+            let item = match object::nth(&items, position) {
+                Ok(Some(item)) => item,
+                Ok(None) => break,
+                Err(failure) => {
+                    self.site = Some(Site::of(i.start()));
+                    return Err(failure);
+                }
+            };
+            self.meter.step()?;
+            env.borrow_mut()
+                .store
+                .insert(i.variable().value().clone(), item);
+            match self.statements(&i.body().statements, env)? {
+                Flow::Next(_) | Flow::Continue => {}
+                Flow::Break => break,
+                ret => return Ok(ret),
+            }
+        }
+        Ok(Flow::Next(Value::Null))
+    }
+
+    /// Runs a `try`. The `finally` block runs however the rest was left,
+    /// and leaving it with `return`, `break` or `continue`, or an error,
+    /// overrides that.
+    fn try_catch(
+        &mut self,
+        i: &'p TryInternal<'a>,
+        env: &Env<'p, 'a>,
+    ) -> Result<Flow<'p, 'a>, Failure> {
+        let depth = self.trace.len();
+        let tail_calls = std::mem::replace(&mut self.tail_calls, false);
+        let result = self.try_catch_finally(i, env, depth);
+        self.tail_calls = tail_calls;
+        result
+    }
+
+    fn try_catch_finally(
+        &mut self,
+        i: &'p TryInternal<'a>,
+        env: &Env<'p, 'a>,
+        depth: usize,
+    ) -> Result<Flow<'p, 'a>, Failure> {
+        let mut result = self.block(i.body(), env);
+        if let Err(failure) = result {
+            if !self.catchable() {
+                return Err(failure);
+            }
+            let exception = self.catch(failure, depth);
+            env.borrow_mut()
+                .store
+                .insert(i.name().value().clone(), Value::Error(exception));
+            result = self.block(i.handler(), env);
+        }
+
+        let finally = match i.finally() {
+            Some(finally) => finally,
+            None => return result,
+        };
+        let pending = match result {
+            Ok(flow) => Ok(flow),
+            Err(failure) if self.catchable() => Err(self.catch(failure, depth)),
+            Err(failure) => return Err(failure),
+        };
+
+        match self.block(finally, env)? {
+            Flow::Next(_) => {}
+            exit => return Ok(exit),
+        }
+
+        // an error the handler let out carries on from the `try`, as if
+        // it had failed there
+        match pending {
+            Ok(flow) => Ok(flow),
+            Err(exception) => {
+                let failure = Failure::new(exception.kind, exception.message.clone());
+                self.thrown = Some(exception);
+                Err(failure)
+            }
+        }
+    }
+
+    /// Whether a `try` can stop the error in flight. A thrown value always
+    /// can, whatever it says.
+    fn catchable(&self) -> bool {
+        self.thrown.is_some() || self.meter.catchable()
+    }
+
+    /// Stops the error in flight, turning it into what `catch` binds. The
+    /// trace it's gathered since the `try`, which had `depth` frames, is
+    /// dropped.
+    fn catch(&mut self, failure: Failure, depth: usize) -> Rc<Exception> {
+        let site = match self.trace.get_mut(depth) {
+            Some(frame) => frame.site.take(),
+            None => self.site.take(),
+        };
+        self.trace.truncate(depth);
+        self.site = None;
+
+        match self.thrown.take() {
+            Some(exception) => exception,
+            None => Rc::new(Exception {
+                kind: failure.kind,
+                message: failure.message,
+                span: site.and_then(|s| s.span),
+            }),
+        }
+    }
+
+    /// The value of a block, which is null unless it ends in an expression.
+    fn block(&mut self, block: &'p Block<'a>, env: &Env<'p, 'a>) -> Result<Flow<'p, 'a>, Failure> {
+        let flow = self.statements(&block.statements, env)?;
+        match (flow, block.statements.last()) {
+            (Flow::Next(v), Some(Statement::Expression(_))) => Ok(Flow::Next(v)),
+            (Flow::Next(_), _) => Ok(Flow::Next(Value::Null)),
+            (ret, _) => Ok(ret),
+        }
+    }
+
+    /// The value of a function's body, or a block that is in tail position
+    /// in one, like `block`.
+    fn tail_block(
+        &mut self,
+        block: &'p Block<'a>,
+        env: &Env<'p, 'a>,
+    ) -> Result<Flow<'p, 'a>, Failure> {
+        match block.statements.split_last() {
+            Some((Statement::Expression(i), rest)) if i.expression().is_some() => {
+                match self.statements(rest, env)? {
+                    Flow::Next(_) => self.tail(i.expression().expect("checked"), env),
+                    ret => Ok(ret),
+                }
+            }
+            _ => self.block(block, env),
+        }
+    }
+
+    /// The value of `e`, which the running function returns. A call is
+    /// left as a `Flow::Tail`, and so is one that's the value of a branch
+    /// of an `if`.
+    fn tail(&mut self, e: &'p Expression<'a>, env: &Env<'p, 'a>) -> Result<Flow<'p, 'a>, Failure> {
+        macro_rules! eval {
+            ($e:expr) => {
+                match self.expression($e, env)? {
+                    Flow::Next(v) => v,
+                    ret => return Ok(ret),
+                }
+            };
+        }
+
+        match e {
+            Expression::Call(i) => {
+                let function = eval!(i.function());
+                let mut args = Vec::with_capacity(i.arguments().len());
+                for arg in i.arguments() {
+                    args.push(eval!(arg));
+                }
+                Ok(Flow::Tail(e, function, args))
+            }
+            Expression::If(i) => {
+                let condition = eval!(i.condition());
+                if condition.is_truthy() {
+                    return self.tail_block(i.consequence(), env);
+                }
+                match i.alternative() {
+                    Some(alt) => self.tail_block(alt, env),
+                    None => Ok(Flow::Next(Value::Null)),
+                }
+            }
+            _ => self.expression(e, env),
+        }
+    }
+
+    fn expression(
+        &mut self,
+        e: &'p Expression<'a>,
+        env: &Env<'p, 'a>,
+    ) -> Result<Flow<'p, 'a>, Failure> {
+        // every call and nested expression comes through here, so this is
+        // where the native stack is grown before it can run out
+        let result = self
+            .meter
+            .step()
+            .and_then(|()| ast::deeper(|| self.evaluate(e, env)));
+        // the first expression an error comes out of is the one that
+        // failed, unless it came out of a call, which has taken the site
+        if result.is_err() && self.site.is_none() {
+            self.site = Some(Site::of(e));
+        }
+        result
+    }
+
+    fn evaluate(
+        &mut self,
+        e: &'p Expression<'a>,
+        env: &Env<'p, 'a>,
+    ) -> Result<Flow<'p, 'a>, Failure> {
+        // evaluates a subexpression, passing a `return` inside it straight up
+        macro_rules! eval {
+            ($e:expr) => {
+                match self.expression($e, env)? {
+                    Flow::Next(v) => v,
+                    ret => return Ok(ret),
+                }
+            };
+        }
+
+        let value = match e {
+            Expression::Integer(i) => Value::Integer(i.value()),
+            Expression::String(s) => Value::String(Rc::from(s.value())),
+            Expression::Boolean(b) => Value::Boolean(b.value()),
+            Expression::Identifier(i) => match env.borrow().get(i.value()) {
+                Some(v) => v,
+                None => return Err(Failure::not_found(i.value())),
+            },
+            Expression::Prefix(i) => {
+                let right = eval!(i.right());
+                match (i.operator(), right) {
+                    ("!", v) => Value::Boolean(!v.is_truthy()),
+                    ("-", Value::Integer(r)) => {
+                        let negated =
+                            self.overflow
+                                .apply(0, r, i128::checked_sub, i128::wrapping_sub);
+                        let at = i.token().local();
+                        Value::Integer(negated.map_err(|e| object::arithmetic_error(e, at))?)
+                    }
+                    ("-", v) => {
+                        return Err(Failure::new(
+                            Kind::Type,
+                            format!("unsupported type for negation: {}", v.type_name()),
+                        ))
+                    }
+                    ("~", Value::Integer(i)) => Value::Integer(!i),
+                    ("~", v) => {
+                        return Err(Failure::new(
+                            Kind::Type,
+                            format!("unsupported type for bitwise not: {}", v.type_name()),
+                        ))
+                    }
+                    (op, _) => {
+                        return Err(Failure::new(Kind::Type, format!("unknown operator {}", op)))
+                    }
+                }
+            }
+            Expression::Infix(i) => {
+                let left = eval!(i.left());
+                // `&&` and `||` only look at the right when the left doesn't
+                // decide the result
+                match i.operator() {
+                    "&&" if !left.is_truthy() => Value::Boolean(false),
+                    "||" if left.is_truthy() => Value::Boolean(true),
+                    "&&" | "||" => Value::Boolean(eval!(i.right()).is_truthy()),
+                    op => {
+                        let right = eval!(i.right());
+                        infix(op, left, right, self.overflow, i.token().local())?
+                    }
+                }
+            }
+            Expression::If(i) => {
+                let condition = eval!(i.condition());
+                if condition.is_truthy() {
+                    return self.block(i.consequence(), env);
+                }
+                match i.alternative() {
+                    Some(alt) => return self.block(alt, env),
+                    None => Value::Null,
+                }
+            }
+            Expression::Try(i) => return self.try_catch(i, env),
+            Expression::Function(f) => {
+                self.meter.allocate(std::mem::size_of::<Function>())?;
+                Value::Function(Rc::new(Function {
+                    name: None,
+                    parameters: f.parameters(),
+                    body: f.body(),
+                    env: env.clone(),
+                }))
+            }
+            Expression::Call(i) if self.quoting && is_named(i.function(), "quote") => {
+                return match i.arguments() {
+                    [arg] => self.quote(arg, env),
+                    args => Err(Failure::wrong_arguments(1, args.len())),
+                };
+            }
+            Expression::Call(i) => {
+                let function = eval!(i.function());
+                let mut args = Vec::with_capacity(i.arguments().len());
+                for arg in i.arguments() {
+                    args.push(eval!(arg));
+                }
+                self.call(function, args)?
+            }
+            Expression::Macro(_) => return Err(Failure::from(String::from(macros::STRAY_MACRO))),
+            Expression::Assign(i) => {
+                let name = i.name().value();
+                // a compound assignment reads the name before the value
+                let current = match i.infix_operator() {
+                    Some(op) => match env.borrow().get(name) {
+                        Some(v) => Some((op, v)),
+                        None => return Err(Failure::not_found(name)),
+                    },
+                    None => None,
+                };
+                let mut value = eval!(i.value());
+                if let Some((op, current)) = current {
+                    value = infix(op, current, value, self.overflow, i.token().local())?;
+                }
+                env.borrow_mut().assign(name, value.clone())?;
+                value
+            }
+            Expression::Array(i) => {
+                self.meter
+                    .allocate(i.elements().len() * std::mem::size_of::<Value>())?;
+                let mut elements = Vec::with_capacity(i.elements().len());
+                for e in i.elements() {
+                    elements.push(eval!(e));
+                }
+                let array = Rc::new(RefCell::new(elements));
+                self.heap.track(&array);
+                Value::Array(array)
+            }
+            Expression::Hash(i) => {
+                self.meter
+                    .allocate(i.pairs().len() * 2 * std::mem::size_of::<Value>())?;
+                let mut pairs = Vec::with_capacity(i.pairs().len());
+                for (key, value) in i.pairs() {
+                    let key = eval!(key);
+                    pairs.push((key, eval!(value)));
+                }
+                let hash = object::make_hash(pairs)?;
+                self.heap.track(&hash);
+                Value::Hash(hash)
+            }
+            Expression::Index(i) => {
+                let left = eval!(i.left());
+                let index = eval!(i.index());
+                object::index(&left, &index)?
+            }
+            Expression::IndexAssign(i) => {
+                let left = eval!(i.target().left());
+                let index = eval!(i.target().index());
+                // a compound assignment reads the element before the value
+                let current = match i.infix_operator() {
+                    Some(op) => Some((op, object::index(&left, &index)?)),
+                    None => None,
+                };
+                let mut value = eval!(i.value());
+                if let Some((op, current)) = current {
+                    value = infix(op, current, value, self.overflow, i.token().local())?;
+                }
+                object::set_index(&mut self.meter, &left, &index, value.clone())?;
+                value
+            }
+            Expression::Member(i) => match eval!(i.object()) {
+                Value::Module(m) => m.get(i.name().value())?,
+                Value::Error(e) => Value::from_object(e.member(i.name().value())?),
+                Value::GcStats(s) => Value::from_object(s.member(i.name().value())?),
+                v => {
+                    return Err(Failure::new(
+                        Kind::Type,
+                        format!("unsupported type for member access: {}", v.type_name()),
+                    ))
+                }
+            },
+        };
+
+        Ok(Flow::Next(value))
+    }
+
+    fn call(
+        &mut self,
+        function: Value<'p, 'a>,
+        mut args: Vec<Value<'p, 'a>>,
+    ) -> Result<Value<'p, 'a>, Failure> {
+        match function {
+            Value::Function(mut f) => {
+                if args.len() != f.parameters.len() {
+                    return Err(Failure::wrong_arguments(f.parameters.len(), args.len()));
+                }
+
+                // the vms count their main frame too
+                if self.depth + 1 >= MAX_FRAMES {
+                    return Err(Failure::stack_overflow());
+                }
+                self.meter.call(self.depth)?;
+
+                self.depth += 1;
+                let tail_calls = std::mem::replace(&mut self.tail_calls, true);
+                // a tail call runs here, in the place of the function that
+                // made it, so a chain of them doesn't get any deeper
+                let flow = loop {
+                    let mut env = Environment {
+                        store: HashMap::new(),
+                        outer: Some(f.env.clone()),
+                    };
+                    for (p, arg) in f.parameters.iter().zip(args) {
+                        env.store.insert(p.value().clone(), arg);
+                    }
+
+                    let env = self.alloc(env);
+                    match self.tail_block(f.body, &env) {
+                        Ok(Flow::Tail(_, Value::Function(g), tail_args))
+                            if tail_args.len() == g.parameters.len() =>
+                        {
+                            f = g;
+                            args = tail_args;
+                        }
+                        // anything else is called as usual, failing in
+                        // the function that called it
+                        Ok(Flow::Tail(call, function, args)) => {
+                            let result = self.call(function, args);
+                            if result.is_err() && self.site.is_none() {
+                                self.site = Some(Site::of(call));
+                            }
+                            break result.map(Flow::Next);
+                        }
+                        flow => break flow,
+                    }
+                };
+                self.tail_calls = tail_calls;
+                self.depth -= 1;
+
+                if flow.is_err() {
+                    self.trace.push(TraceFrame {
+                        function: f.name.unwrap_or(error::ANONYMOUS).to_string(),
+                        site: self.site.take(),
+                    });
+                }
+
+                match flow? {
+                    Flow::Next(v) | Flow::Return(v) => Ok(v),
+                    Flow::Break | Flow::Continue => unreachable!("the parser keeps these in loops"),
+                    Flow::Tail(..) => unreachable!("tail calls are made above"),
+                }
+            }
+            Value::Builtin(b) => {
+                let idx = BUILTINS.iter().position(|x| std::ptr::eq(x, b));
+                let func = object::builtin(idx.expect("builtins are all in BUILTINS"));
+                let mut host = Host {
+                    out: self.out.as_mut(),
+                    gc: self.heap.stats(),
+                    meter: &mut self.meter,
+                };
+                func(&mut host, &args)
+            }
+            v => Err(Failure::not_callable(v.type_name())),
+        }
+    }
+}
+
+/// Applies an infix operator. Errors from the integer arithmetic itself
+/// say where the operator is, when `at` knows.
+fn infix<'p, 'a>(
+    operator: &str,
+    left: Value<'p, 'a>,
+    right: Value<'p, 'a>,
+    overflow: Overflow,
+    at: Option<&Location>,
+) -> Result<Value<'p, 'a>, Failure> {
+    match operator {
+        "==" => return Ok(Value::Boolean(left == right)),
+        "!=" => return Ok(Value::Boolean(left != right)),
+        _ => (),
+    }
+
+    let (l, r) = match (&left, &right) {
+        (Value::Integer(l), Value::Integer(r)) => (*l, *r),
+        // the compiled engines only have greater than and greater or equal,
+        // so `a < b` fails as `b > a` would
+        _ if operator == "<" || operator == "<=" => {
+            return Err(Failure::new(
+                Kind::Type,
+                format!(
+                    "unknown operator: {} {} {}",
+                    right.type_name(),
+                    operator.replace('<', ">"),
+                    left.type_name()
+                ),
+            ))
+        }
+        _ if operator == ">" || operator == ">=" => {
+            return Err(Failure::new(
+                Kind::Type,
+                format!(
+                    "unknown operator: {} {} {}",
+                    left.type_name(),
+                    operator,
+                    right.type_name()
+                ),
+            ))
+        }
+        _ => {
+            return Err(Failure::new(
+                Kind::Type,
+                format!(
+                    "unsupported types for binary operation: {} {}",
+                    left.type_name(),
+                    right.type_name()
+                ),
+            ))
+        }
+    };
+
+    let value = match operator {
+        "+" | "-" | "*" | "/" | "%" | "<<" | ">>" => {
+            let result = arithmetic(operator, l, r, overflow);
+            Value::Integer(result.map_err(|e| object::arithmetic_error(e, at))?)
+        }
+        "&" => Value::Integer(l & r),
+        "|" => Value::Integer(l | r),
+        "^" => Value::Integer(l ^ r),
+        "<" => Value::Boolean(l < r),
+        ">" => Value::Boolean(l > r),
+        "<=" => Value::Boolean(l <= r),
+        ">=" => Value::Boolean(l >= r),
+        op => return Err(Failure::new(Kind::Type, format!("unknown operator {}", op))),
+    };
+
+    Ok(value)
+}
+
+/// The integer operators that can fail.
+fn arithmetic(operator: &str, l: i128, r: i128, overflow: Overflow) -> Result<i128, Failure> {
+    match operator {
+        "+" => overflow.apply(l, r, i128::checked_add, i128::wrapping_add),
+        "-" => overflow.apply(l, r, i128::checked_sub, i128::wrapping_sub),
+        "*" => overflow.apply(l, r, i128::checked_mul, i128::wrapping_mul),
+        "/" | "%" if r == 0 => Err(Failure::division_by_zero()),
+        "/" => overflow.apply(l, r, i128::checked_div, i128::wrapping_div),
+        "%" => overflow.apply(l, r, i128::checked_rem, i128::wrapping_rem),
+        _ if !(0..128).contains(&r) => Err(Failure::shift_out_of_range(r)),
+        "<<" => overflow.apply(l, r, object::checked_shl, |l, r| l << r),
+        _ => Ok(l >> r),
+    }
+}
+
+fn is_named(e: &Expression, name: &str) -> bool {
+    matches!(e, Expression::Identifier(i) if i.value() == name)
+}
+
+/// What's unquoted, if `e` is a call to `unquote`.
+fn unquote_argument<'e, 'a>(e: &'e Expression<'a>) -> Result<Option<&'e Expression<'a>>, Failure> {
+    match e {
+        Expression::Call(i) if is_named(i.function(), "unquote") => match i.arguments() {
+            [arg] => Ok(Some(arg)),
+            args => Err(Failure::wrong_arguments(1, args.len())),
+        },
+        _ => Ok(None),
+    }
+}
+
+/// Replaces the `unquote`s in `e` with `values`, in the order `unquoted`
+/// found them.
+fn splice<'p, 'a: 'p>(
+    e: &mut Expression<'a>,
+    values: &mut impl Iterator<Item = Value<'p, 'a>>,
+) -> Result<(), Failure> {
+    if unquote_argument(e)?.is_some() {
+        let value = values.next().expect("unquoted finds each unquote");
+        *e = to_expression(value, e.token().local().cloned())?;
+        return Ok(());
+    }
+    for child in e.children_mut() {
+        splice(child, values)?;
+    }
+    Ok(())
+}
+
+/// The code that gives `value`, at `local`. Only values with literals can
+/// be put back into the program.
+fn to_expression<'a>(
+    value: Value<'_, 'a>,
+    local: Option<Location<'a>>,
+) -> Result<Expression<'a>, Failure> {
+    Ok(match value {
+        Value::Integer(v) => Expression::Integer(IntegerInternal::new(
+            Token::new(TokenKind::INT(v), local),
+            v,
+        )),
+        Value::Boolean(b) => {
+            let kind = if b { TokenKind::TRUE } else { TokenKind::FALSE };
+            Expression::Boolean(BooleanInternal::new(Token::new(kind, local), b))
+        }
+        Value::String(s) => Expression::String(StringInternal::new(Token::new(
+            TokenKind::STRING(s.to_string()),
+            local,
+        ))),
+        Value::Quote(e) => Rc::try_unwrap(e).unwrap_or_else(|e| (*e).clone()),
+        v => {
+            return Err(Failure::new(
+                Kind::Type,
+                format!("unsupported type for unquote: {}", v.type_name()),
+            ))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use crate::vm::LimitExceeded;
+    use std::time::Duration;
+
+    fn eval(input: &str) -> Result<String, String> {
+        eval_with(input, Overflow::Error, None)
+    }
+
+    fn eval_with(input: &str, overflow: Overflow, file: Option<&Path>) -> Result<String, String> {
+        let l = Lexer::new(input, file.is_none(), file);
+        let mut p = Parser::new(l);
+        let program = p.parse_program().expect("Program should be Some here");
+        assert_eq!(p.errors().len(), 0, "{:?}", p.errors());
+
+        let mut e = Evaluator::new();
+        e.set_output(Box::new(std::io::sink()));
+        e.set_overflow(overflow);
+        e.eval(&program)
+            .map(|v| v.to_string())
+            .map_err(String::from)
+    }
+
+    #[test]
+    fn test_eval() {
+        let tests = [
+            ("1 + 2 * 3 - 4 / 2", "5"),
+            ("if (1 < 2) { 10 } else { 20 }", "10"),
+            ("if (false) { 10 }", "null"),
+            ("if (true) { let x = 1; }", "null"),
+            ("let a = 5; let b = a * 2; a + b", "15"),
+            (
+                "let f = fn(x) { if (x > 1) { return x; } 0 }; f(3) + f(1)",
+                "3",
+            ),
+            ("let adder = fn(a) { fn(b) { a + b } }; adder(1)(2)", "3"),
+            ("return 3; 4", "3"),
+            ("puts", "<builtin puts>"),
+            ("let x = 1; x += 2; x * (x = 10)", "30"),
+            ("let n = 0; let f = fn() { n = n + 1; }; f(); f(); n", "2"),
+        ];
+
+        for (input, expected) in tests {
+            assert_eq!(eval(input), Ok(String::from(expected)), "{}", input);
+        }
+
+        assert_eq!(eval("x"), Err(String::from("identifier not found: x")));
+        assert_eq!(
+            eval("let n = 1; n.x"),
+            Err(String::from("unsupported type for member access: INTEGER"))
+        );
+        assert_eq!(
+            eval("let f = fn() { let a = 1; let g = fn() { a = 2 }; g(); a }; f()"),
+            Ok(String::from("2"))
+        );
+        assert_eq!(
+            eval("puts = 1"),
+            Err(String::from("can't assign to builtin puts"))
+        );
+        assert_eq!(eval("y = 1"), Err(String::from("identifier not found: y")));
+        assert_eq!(
+            eval("1 + true"),
+            Err(String::from(
+                "unsupported types for binary operation: INTEGER BOOLEAN"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_limits() {
+        let spin = "let spin = fn(n) { if (n == 0) { 0 } else { 1 + spin(n - 1) } };";
+        let tests = [
+            (
+                "try { spin(100) } catch (e) { 0 }",
+                Limits {
+                    fuel: Some(100),
+                    ..Limits::default()
+                },
+                Some(LimitExceeded::Fuel),
+            ),
+            // a loop takes fuel even when its body is empty
+            (
+                "for (i in 0..1000) { }",
+                Limits {
+                    fuel: Some(100),
+                    ..Limits::default()
+                },
+                Some(LimitExceeded::Fuel),
+            ),
+            (
+                "spin(100)",
+                Limits {
+                    max_depth: Some(100),
+                    ..Limits::default()
+                },
+                Some(LimitExceeded::Depth),
+            ),
+            (
+                "spin(99)",
+                Limits {
+                    max_depth: Some(100),
+                    ..Limits::default()
+                },
+                None,
+            ),
+            (
+                "let f = fn(x) { fn() { x } }; f(1); f(2); f(3)",
+                Limits {
+                    max_heap: Some(100),
+                    ..Limits::default()
+                },
+                Some(LimitExceeded::Heap),
+            ),
+            (
+                "spin(500); spin(500); spin(500); spin(500)",
+                Limits {
+                    timeout: Some(Duration::ZERO),
+                    ..Limits::default()
+                },
+                Some(LimitExceeded::Timeout),
+            ),
+            (
+                "try { throw \"out of fuel\"; } catch (e) { 0 }",
+                Limits {
+                    fuel: Some(100),
+                    ..Limits::default()
+                },
+                None,
+            ),
+        ];
+
+        for (input, limits, expected) in tests {
+            let input = format!("{}{}", spin, input);
+            let l = Lexer::new(&input, true, None);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().expect("Program should be Some here");
+
+            let mut e = Evaluator::new();
+            e.set_limits(limits);
+            let got = e.eval(&program).err().map(|e| e.limit);
+            assert_eq!(got, expected.map(Some), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_garbage_collection() {
+        // every call to leak makes an environment that f, stored in it,
+        // refers back to
+        let input = "let leak = fn(n) { let f = fn() { n }; f() };\
+                     let keep = fn(n) { fn() { n } };\
+                     let kept = keep(7);\
+                     let i = 0;\
+                     while (i < 1000) { leak(i); i += 1; }\
+                     let s = gc_stats();\
+                     if (s.collections > 0 && s.live < 100) { kept() } else { s }";
+        let l = Lexer::new(input, true, None);
+        let mut p = Parser::new(l);
+        let program = p.parse_program().expect("Program should be Some here");
+
+        let mut e = Evaluator::new();
+        e.set_gc(GcConfig {
+            threshold: 10,
+            growth: 2,
+        });
+        let value = e.eval(&program).map(|v| v.to_string());
+        assert_eq!(value, Ok(String::from("7")));
+
+        // once the program's done, so is everything it made. The calls to
+        // f made environments that nothing refers back to, which reference
+        // counting freed
+        let stats = e.gc_stats();
+        assert_eq!(stats.allocated, 2003);
+        assert_eq!(stats.freed, 1002);
+        assert_eq!(stats.live, 0);
+    }
+
+    #[test]
+    fn test_garbage_collection_through_collections() {
+        // an array that holds itself, and a hash holding a function that
+        // refers back to it through the environment it was made in
+        let input = "let leak = fn() {\
+                         let xs = [0];\
+                         xs[0] = xs;\
+                         let h = {};\
+                         h[0] = fn() { h };\
+                     };\
+                     for (i in 0..1000) { leak(); }";
+        let l = Lexer::new(input, true, None);
+        let mut p = Parser::new(l);
+        let program = p.parse_program().expect("Program should be Some here");
+
+        let mut e = Evaluator::new();
+        e.set_gc(GcConfig {
+            threshold: 10,
+            growth: 2,
+        });
+        e.eval(&program).unwrap();
+
+        let stats = e.gc_stats();
+        assert_eq!(stats.live, 0);
+        assert!(stats.collections > 1, "{}", stats);
+        assert!(stats.freed >= 2000, "{}", stats);
+    }
+
+    #[test]
+    fn test_integer_overflow() {
+        let tests = [
+            (
+                "let max = ~(-1 << 127); max + 1",
+                "-170141183460469231731687303715884105728",
+            ),
+            (
+                "let min = -1 << 127; min - 1",
+                "170141183460469231731687303715884105727",
+            ),
+            ("(1 << 126) * 4", "0"),
+            (
+                "let min = -1 << 127; -min",
+                "-170141183460469231731687303715884105728",
+            ),
+            (
+                "let min = -1 << 127; min / -1",
+                "-170141183460469231731687303715884105728",
+            ),
+            ("let min = -1 << 127; min % -1", "0"),
+            ("3 << 127", "-170141183460469231731687303715884105728"),
+            ("5 << 126", "85070591730234615865843651857942052864"),
+            ("-3 << 126", "85070591730234615865843651857942052864"),
+        ];
+
+        for (input, wrapped) in tests {
+            assert_eq!(
+                eval(input),
+                Err(String::from("integer overflow")),
+                "{}",
+                input
+            );
+            assert_eq!(
+                eval_with(input, Overflow::Wrap, None),
+                Ok(String::from(wrapped)),
+                "{}",
+                input
+            );
+        }
+
+        // wrapping doesn't make dividing by zero any less of an error, and
+        // the error says which operator it was
+        let file = Some(Path::new("test.my"));
+        assert_eq!(
+            eval_with("let x = 1;\nx += 1 / (x - 1)", Overflow::Wrap, file),
+            Err(String::from("division by zero at test.my:2:8"))
+        );
+        assert_eq!(
+            eval_with("let x = ~(-1 << 127);\nx += 1", Overflow::Error, file),
+            Err(String::from("integer overflow at test.my:2:3"))
+        );
+        // only arithmetic errors do
+        assert_eq!(
+            eval_with("1 + true", Overflow::Error, file),
+            Err(String::from(
+                "unsupported types for binary operation: INTEGER BOOLEAN"
+            ))
+        );
+    }
+}
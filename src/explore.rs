@@ -0,0 +1,615 @@
+use crate::ast::{Block, Expression, Program, Statement};
+use crate::lexer::Lexer;
+use crate::parser::Parser;
+use crate::token::{Token, TokenKind};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::path::Path;
+
+/// A node of the tree as the explorer shows it. `lo` and `hi` are the
+/// indexes of the first and last token the node covers.
+#[derive(Debug)]
+struct TreeNode {
+    label: String,
+    lo: usize,
+    hi: usize,
+    children: Vec<TreeNode>,
+}
+
+/// Lexes and parses `input`, returning a self-contained HTML page with the
+/// source next to the AST, along with the parse errors. These are also
+/// listed above the tree, which shows whatever the parser did produce.
+pub fn explore(input: &str, path: &Path) -> (String, Vec<String>) {
+    let tokens = lex(input, path);
+
+    let l = Lexer::new(input, false, Some(path));
+    let mut p = Parser::new(l);
+    let program = p.parse_program().unwrap_or_default();
+    let errors: Vec<String> = p.errors().iter().map(|e| e.to_string()).collect();
+
+    let tree = Builder::new(&tokens).program(&program);
+
+    let mut html = String::new();
+    html.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    let _ = writeln!(
+        html,
+        "<title>{}</title>",
+        escape(&path.display().to_string())
+    );
+    let _ = writeln!(html, "<style>{}</style>", STYLE);
+    html.push_str("</head>\n<body>\n");
+
+    let _ = writeln!(html, "<h1>{}</h1>", escape(&path.display().to_string()));
+    if !errors.is_empty() {
+        html.push_str("<ul class=\"errors\">\n");
+        for e in &errors {
+            let _ = writeln!(html, "<li>{}</li>", escape(e));
+        }
+        html.push_str("</ul>\n");
+    }
+
+    html.push_str("<div class=\"panes\">\n<pre id=\"source\">");
+    source(&mut html, input, &tokens);
+    html.push_str("</pre>\n<div id=\"tree\">\n");
+    if let Some(tree) = &tree {
+        node(&mut html, tree);
+    }
+    html.push_str("</div>\n</div>\n<div id=\"status\"></div>\n");
+
+    let _ = writeln!(html, "<script>{}</script>", SCRIPT);
+    html.push_str("</body>\n</html>\n");
+
+    (html, errors)
+}
+
+fn lex<'a>(input: &str, path: &'a Path) -> Vec<Token<'a>> {
+    let mut l = Lexer::new(input, false, Some(path));
+    let mut tokens = Vec::new();
+
+    loop {
+        let tok = l.next_token();
+        if tok.ttype == TokenKind::EOF {
+            break;
+        }
+        tokens.push(tok);
+    }
+
+    tokens
+}
+
+/// Maps where each token starts to its index.
+fn token_starts(tokens: &[Token]) -> HashMap<(usize, usize), usize> {
+    let mut starts = HashMap::new();
+    for (idx, tok) in tokens.iter().enumerate() {
+        if let Some(l) = tok.local() {
+            let (row, col, _) = l.get();
+            starts.insert((*row, *col), idx);
+        }
+    }
+    starts
+}
+
+/// Writes the source with each token in a span numbered by its index, and
+/// the text after token `i` in a gap span numbered `i`.
+fn source(html: &mut String, input: &str, tokens: &[Token]) {
+    let starts = token_starts(tokens);
+
+    let mut chars = input.trim_end().chars().peekable();
+    let (mut row, mut col) = (1, 1);
+    let mut gap: Option<usize> = None;
+    let mut text = String::new();
+
+    let flush = |html: &mut String, gap: Option<usize>, text: &mut String| {
+        if text.is_empty() {
+            return;
+        }
+        match gap {
+            Some(i) => {
+                let _ = write!(
+                    html,
+                    "<span class=\"gap\" data-gap=\"{}\">{}</span>",
+                    i,
+                    escape(text)
+                );
+            }
+            None => html.push_str(&escape(text)),
+        }
+        text.clear();
+    };
+
+    while chars.peek().is_some() {
+        if let Some(&idx) = starts.get(&(row, col)) {
+            flush(html, gap, &mut text);
+
+            let tok = &tokens[idx];
+            let mut lexeme = String::new();
+            for _ in 0..tok.source_len() {
+                match chars.next() {
+                    Some(c) => lexeme.push(c),
+                    None => break,
+                }
+                col += 1;
+            }
+
+            let _ = write!(
+                html,
+                "<span class=\"tok\" data-tok=\"{}\" title=\"{}\">{}</span>",
+                idx,
+                escape(&describe(tok)),
+                escape(&lexeme)
+            );
+            gap = Some(idx);
+            continue;
+        }
+
+        let c = chars.next().expect("peeked");
+        text.push(c);
+        if c == '\n' {
+            row += 1;
+            col = 1;
+        } else {
+            col += 1;
+        }
+    }
+
+    flush(html, gap, &mut text);
+}
+
+/// The token's kind and where it starts, e.g. `IDENT("x") at 1:5`.
+fn describe(tok: &Token) -> String {
+    match tok.local() {
+        Some(l) => {
+            let (row, col, _) = l.get();
+            format!("{:?} at {}:{}", tok.ttype, row, col)
+        }
+        None => format!("{:?}", tok.ttype),
+    }
+}
+
+fn node(html: &mut String, n: &TreeNode) {
+    let _ = write!(
+        html,
+        "<details open data-lo=\"{}\" data-hi=\"{}\">",
+        n.lo, n.hi
+    );
+    let _ = write!(html, "<summary>{}</summary>", escape(&n.label));
+    for child in &n.children {
+        node(html, child);
+    }
+    html.push_str("</details>\n");
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Builds the `TreeNode`s, working out each node's token range from the
+/// tokens its AST node kept.
+struct Builder<'t, 'a> {
+    tokens: &'t [Token<'a>],
+    index: HashMap<(usize, usize), usize>,
+}
+
+impl<'t, 'a> Builder<'t, 'a> {
+    fn new(tokens: &'t [Token<'a>]) -> Self {
+        Builder {
+            tokens,
+            index: token_starts(tokens),
+        }
+    }
+
+    fn token_index(&self, tok: &Token) -> Option<usize> {
+        let (row, col, _) = tok.local()?.get();
+        self.index.get(&(*row, *col)).copied()
+    }
+
+    /// A node spanning its own token and its children, widened so that
+    /// the brackets in it are balanced.
+    fn make(&self, label: String, own: &[&Token], children: Vec<TreeNode>) -> Option<TreeNode> {
+        let mut lo = usize::MAX;
+        let mut hi = 0;
+
+        for idx in own.iter().filter_map(|t| self.token_index(t)) {
+            lo = lo.min(idx);
+            hi = hi.max(idx);
+        }
+        for child in &children {
+            lo = lo.min(child.lo);
+            hi = hi.max(child.hi);
+        }
+
+        if lo == usize::MAX {
+            return None;
+        }
+
+        let (lo, hi) = self.balance(lo, hi);
+        Some(TreeNode {
+            label,
+            lo,
+            hi,
+            children,
+        })
+    }
+
+    fn balance(&self, mut lo: usize, mut hi: usize) -> (usize, usize) {
+        let (mut depth, mut min_depth) = (0i64, 0i64);
+        for tok in &self.tokens[lo..=hi] {
+            match tok.ttype {
+                TokenKind::LPAREN | TokenKind::LBRACE | TokenKind::LBRACKET => depth += 1,
+                TokenKind::RPAREN | TokenKind::RBRACE | TokenKind::RBRACKET => {
+                    depth -= 1;
+                    min_depth = min_depth.min(depth);
+                }
+                _ => (),
+            }
+        }
+
+        let mut closers = -min_depth;
+        let mut openers = depth - min_depth;
+
+        while closers > 0 && lo > 0 && is_opener(&self.tokens[lo - 1]) {
+            lo -= 1;
+            closers -= 1;
+        }
+        while openers > 0 && hi + 1 < self.tokens.len() && is_closer(&self.tokens[hi + 1]) {
+            hi += 1;
+            openers -= 1;
+        }
+
+        (lo, hi)
+    }
+
+    fn program(&self, program: &Program) -> Option<TreeNode> {
+        let children = self.statements(&program.statements);
+        self.make(String::from("Program"), &[], children)
+    }
+
+    fn statements(&self, statements: &[Statement]) -> Vec<TreeNode> {
+        statements
+            .iter()
+            .filter_map(|s| self.statement(s))
+            .collect()
+    }
+
+    fn statement(&self, stmt: &Statement) -> Option<TreeNode> {
+        let mut n = match stmt {
+            Statement::Let(i) => {
+                let mut children = Vec::new();
+                let label = match i.name() {
+                    Some(name) => {
+                        let ident = format!("Identifier {}", name.value());
+                        children.extend(self.make(ident, &[name.token()], Vec::new()));
+                        format!("Let {}", name.value())
+                    }
+                    None => String::from("Let"),
+                };
+                children.extend(i.value().and_then(|v| self.expression(v)));
+                self.make(label, &[stmt.token()], children)?
+            }
+            Statement::Return(i) => {
+                let children = i.return_value().and_then(|v| self.expression(v));
+                self.make(
+                    String::from("Return"),
+                    &[stmt.token()],
+                    children.into_iter().collect(),
+                )?
+            }
+            Statement::Expression(i) => {
+                let children = i.expression().and_then(|e| self.expression(e));
+                self.make(
+                    String::from("ExpressionStatement"),
+                    &[],
+                    children.into_iter().collect(),
+                )?
+            }
+            Statement::Import(i) => self.make(
+                format!("Import {}", i.name().value()),
+                &[stmt.token(), i.name().token()],
+                Vec::new(),
+            )?,
+            Statement::While(i) => {
+                let mut children: Vec<TreeNode> =
+                    self.expression(i.condition()).into_iter().collect();
+                children.extend(self.block("body", i.body()));
+                self.make(String::from("While"), &[stmt.token()], children)?
+            }
+            Statement::For(i) => {
+                let name = i.variable().value();
+                let mut children: Vec<TreeNode> = self
+                    .make(
+                        format!("Identifier {}", name),
+                        &[i.variable().token()],
+                        Vec::new(),
+                    )
+                    .into_iter()
+                    .collect();
+                children.extend(self.expression(i.start()));
+                children.extend(i.end().and_then(|e| self.expression(e)));
+                children.extend(self.block("body", i.body()));
+                self.make(format!("For {}", name), &[stmt.token()], children)?
+            }
+            Statement::Break(_) => self.make(String::from("Break"), &[stmt.token()], Vec::new())?,
+            Statement::Continue(_) => {
+                self.make(String::from("Continue"), &[stmt.token()], Vec::new())?
+            }
+            Statement::Throw(i) => {
+                let children = self.expression(i.value()).into_iter().collect();
+                self.make(String::from("Throw"), &[stmt.token()], children)?
+            }
+        };
+
+        if n.hi + 1 < self.tokens.len() && self.tokens[n.hi + 1].ttype == TokenKind::SEMICOLON {
+            n.hi += 1;
+        }
+
+        Some(n)
+    }
+
+    fn block(&self, role: &str, block: &Block) -> Option<TreeNode> {
+        let children = self.statements(&block.statements);
+        self.make(format!("{}: Block", role), &[block.token()], children)
+    }
+
+    fn expression(&self, e: &Expression) -> Option<TreeNode> {
+        match e {
+            Expression::Identifier(i) => self.make(
+                format!("Identifier {}", i.value()),
+                &[e.token()],
+                Vec::new(),
+            ),
+            Expression::Integer(i) => {
+                self.make(format!("Integer {}", i.value()), &[e.token()], Vec::new())
+            }
+            Expression::Boolean(i) => {
+                self.make(format!("Boolean {}", i.value()), &[e.token()], Vec::new())
+            }
+            Expression::String(i) => {
+                self.make(format!("String {:?}", i.value()), &[e.token()], Vec::new())
+            }
+            Expression::Prefix(i) => {
+                let children = self.expression(i.right()).into_iter().collect();
+                self.make(format!("Prefix {}", i.operator()), &[e.token()], children)
+            }
+            Expression::Infix(i) => {
+                let children = [self.expression(i.left()), self.expression(i.right())]
+                    .into_iter()
+                    .flatten()
+                    .collect();
+                self.make(format!("Infix {}", i.operator()), &[e.token()], children)
+            }
+            Expression::If(i) => {
+                let mut children: Vec<TreeNode> =
+                    self.expression(i.condition()).into_iter().collect();
+                children.extend(self.block("consequence", i.consequence()));
+                children.extend(i.alternative().and_then(|b| self.block("alternative", b)));
+                self.make(String::from("If"), &[e.token()], children)
+            }
+            Expression::Try(i) => {
+                let mut children: Vec<TreeNode> =
+                    self.block("body", i.body()).into_iter().collect();
+                let name = i.name();
+                children.extend(self.make(
+                    format!("Identifier {}", name.value()),
+                    &[name.token()],
+                    Vec::new(),
+                ));
+                children.extend(self.block("handler", i.handler()));
+                children.extend(i.finally().and_then(|b| self.block("finally", b)));
+                self.make(String::from("Try"), &[e.token()], children)
+            }
+            Expression::Function(i) | Expression::Macro(i) => {
+                let names: Vec<&str> = i.parameters().iter().map(|p| p.value().as_str()).collect();
+                let mut children: Vec<TreeNode> = i
+                    .parameters()
+                    .iter()
+                    .filter_map(|p| {
+                        self.make(format!("Parameter {}", p.value()), &[p.token()], Vec::new())
+                    })
+                    .collect();
+                children.extend(self.block("body", i.body()));
+                let kind = match e {
+                    Expression::Macro(_) => "Macro",
+                    _ => "Function",
+                };
+                self.make(
+                    format!("{} ({})", kind, names.join(", ")),
+                    &[e.token()],
+                    children,
+                )
+            }
+            Expression::Call(i) => {
+                let mut children: Vec<TreeNode> =
+                    self.expression(i.function()).into_iter().collect();
+                children.extend(i.arguments().iter().filter_map(|a| self.expression(a)));
+                self.make(String::from("Call"), &[e.token()], children)
+            }
+            Expression::Member(i) => {
+                let children = self.expression(i.object()).into_iter().collect();
+                self.make(
+                    format!("Member {}", i.name().value()),
+                    &[e.token(), i.name().token()],
+                    children,
+                )
+            }
+            Expression::Array(i) => {
+                let children = i
+                    .elements()
+                    .iter()
+                    .filter_map(|e| self.expression(e))
+                    .collect();
+                self.make(String::from("Array"), &[e.token()], children)
+            }
+            Expression::Hash(i) => {
+                let children = i
+                    .pairs()
+                    .iter()
+                    .flat_map(|(k, v)| [self.expression(k), self.expression(v)])
+                    .flatten()
+                    .collect();
+                self.make(String::from("Hash"), &[e.token()], children)
+            }
+            Expression::Index(i) => {
+                let children = [self.expression(i.left()), self.expression(i.index())]
+                    .into_iter()
+                    .flatten()
+                    .collect();
+                self.make(String::from("Index"), &[e.token()], children)
+            }
+            Expression::IndexAssign(i) => {
+                let target = i.target();
+                let children = [
+                    self.expression(target.left()),
+                    self.expression(target.index()),
+                    self.expression(i.value()),
+                ]
+                .into_iter()
+                .flatten()
+                .collect();
+                self.make(
+                    format!("IndexAssign {}", i.operator()),
+                    &[target.token(), e.token()],
+                    children,
+                )
+            }
+            Expression::Assign(i) => {
+                let children = self.expression(i.value()).into_iter().collect();
+                self.make(
+                    format!("Assign {} {}", i.name().value(), i.operator()),
+                    &[i.name().token(), e.token()],
+                    children,
+                )
+            }
+        }
+    }
+}
+
+fn is_opener(tok: &Token) -> bool {
+    matches!(
+        tok.ttype,
+        TokenKind::LPAREN | TokenKind::LBRACE | TokenKind::LBRACKET
+    )
+}
+
+fn is_closer(tok: &Token) -> bool {
+    matches!(
+        tok.ttype,
+        TokenKind::RPAREN | TokenKind::RBRACE | TokenKind::RBRACKET
+    )
+}
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 1em; }
+.errors { color: #b00020; font-family: monospace; }
+.panes { display: flex; gap: 2em; align-items: flex-start; }
+#source { flex: 1; padding: 1em; background: #f6f6f6; font-size: 14px; }
+#tree { flex: 1; font-family: monospace; font-size: 14px; }
+#tree details { margin-left: 1.2em; }
+#tree summary { cursor: pointer; }
+#tree summary:hover { background: #e0ecff; }
+.tok:hover { outline: 1px solid #888; }
+.hl { background: #ffe58a; }
+#status { position: fixed; bottom: 0; left: 0; right: 0; padding: 0.3em 1em;
+          background: #333; color: #eee; font-family: monospace; min-height: 1.2em; }
+";
+
+const SCRIPT: &str = "
+const status = document.getElementById('status');
+function clear() {
+  document.querySelectorAll('.hl').forEach(e => e.classList.remove('hl'));
+}
+document.querySelectorAll('#tree summary').forEach(s => {
+  const node = s.parentElement;
+  const lo = Number(node.dataset.lo), hi = Number(node.dataset.hi);
+  s.addEventListener('mouseenter', () => {
+    clear();
+    document.querySelectorAll('#source [data-tok]').forEach(t => {
+      const i = Number(t.dataset.tok);
+      if (i >= lo && i <= hi) t.classList.add('hl');
+    });
+    document.querySelectorAll('#source [data-gap]').forEach(g => {
+      const i = Number(g.dataset.gap);
+      if (i >= lo && i < hi) g.classList.add('hl');
+    });
+    status.textContent = s.textContent + ' (tokens ' + lo + '..' + hi + ')';
+  });
+  s.addEventListener('mouseleave', clear);
+});
+document.querySelectorAll('#source .tok').forEach(t => {
+  t.addEventListener('mouseenter', () => { status.textContent = t.title; });
+});
+";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree(input: &str) -> TreeNode {
+        let path = Path::new("explore.my");
+        let tokens = lex(input, path);
+        let l = Lexer::new(input, false, Some(path));
+        let mut p = Parser::new(l);
+        let program = p.parse_program().expect("Program should be Some here");
+        assert_eq!(p.errors().len(), 0);
+
+        Builder::new(&tokens)
+            .program(&program)
+            .expect("tree should not be empty")
+    }
+
+    fn ranges(n: &TreeNode, out: &mut Vec<(String, usize, usize)>) {
+        out.push((n.label.clone(), n.lo, n.hi));
+        for child in &n.children {
+            ranges(child, out);
+        }
+    }
+
+    #[test]
+    fn test_node_ranges() {
+        // tokens: let x = ( 1 + 2 ) * f ( y  )  ;
+        //         0   1 2 3 4 5 6 7 8 9 10 11 12 13
+        let mut out = Vec::new();
+        ranges(&tree("let x = (1 + 2) * f(y);"), &mut out);
+
+        let expected = [
+            ("Program", 0, 13),
+            ("Let x", 0, 13),
+            ("Identifier x", 1, 1),
+            ("Infix *", 3, 12),
+            ("Infix +", 4, 6),
+            ("Integer 1", 4, 4),
+            ("Integer 2", 6, 6),
+            ("Call", 9, 12),
+            ("Identifier f", 9, 9),
+            ("Identifier y", 11, 11),
+        ];
+
+        assert_eq!(out.len(), expected.len());
+        for ((label, lo, hi), (e_label, e_lo, e_hi)) in out.iter().zip(expected) {
+            assert_eq!((label.as_str(), *lo, *hi), (e_label, e_lo, e_hi));
+        }
+    }
+
+    #[test]
+    fn test_page_has_tokens_and_tree() {
+        let (html, errors) = explore("let f = fn(a) {\n    a < 1 // small\n};", Path::new("f.my"));
+
+        assert!(html.contains("<span class=\"tok\" data-tok=\"0\" title=\"LET at 1:1\">let</span>"));
+        assert!(html.contains("title=\"LT at 2:7\">&lt;</span>"));
+        assert!(html.contains("<span class=\"gap\" data-gap=\"10\"> // small\n</span>"));
+        assert!(html.contains("<summary>Function (a)</summary>"));
+        assert!(html.contains("<summary>body: Block</summary>"));
+        assert!(errors.is_empty());
+        assert!(!html.contains("class=\"errors\""));
+    }
+}
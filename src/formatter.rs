@@ -0,0 +1,1008 @@
+use crate::ast::{self, Block, Expression, IndexInternal, Program, Statement};
+use crate::lexer::Lexer;
+use crate::parser::{Parser, Precedence};
+use crate::token::{Comment, Token};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+const INDENT: &str = "    ";
+
+pub struct FormatConfig {
+    /// Lines longer than this get their call arguments and function
+    /// parameters broken onto separate lines.
+    pub width: usize,
+}
+
+impl Default for FormatConfig {
+    fn default() -> Self {
+        FormatConfig { width: 100 }
+    }
+}
+
+/// Parses `input` and prints it back out in canonical form. Returns the
+/// parser errors if the input doesn't parse, since we can't format what we
+/// don't understand.
+pub fn format(input: &str, path: &Path, config: &FormatConfig) -> Result<String, Vec<String>> {
+    let l = Lexer::new(input, false, Some(path));
+    let mut p = Parser::new(l);
+    let program = p.parse_program();
+
+    if !p.errors().is_empty() {
+        return Err(p.errors().iter().map(|e| e.to_string()).collect());
+    }
+
+    let program = match program {
+        Some(program) => program,
+        None => return Err(vec![String::from("could not parse program")]),
+    };
+
+    let lines: Vec<&str> = input.lines().collect();
+
+    let mut f = Formatter {
+        config,
+        brackets: brackets(&lines),
+        lines,
+        comments: p.comments().iter().collect(),
+        next_comment: 0,
+        flat: false,
+        first_line: false,
+    };
+
+    Ok(f.program(&program))
+}
+
+/// Formats a single expression, as it would appear at the start of a line.
+pub fn format_expression(e: &Expression, config: &FormatConfig) -> String {
+    let mut f = Formatter {
+        config,
+        lines: Vec::new(),
+        brackets: BTreeMap::new(),
+        comments: Vec::new(),
+        next_comment: 0,
+        flat: false,
+        first_line: false,
+    };
+
+    f.expression(e, 0, 0)
+}
+
+/// The first line of `e`, formatted without breaking any lists, followed
+/// by ` ...` if there's more.
+pub fn first_line(e: &Expression) -> String {
+    let config = FormatConfig::default();
+    let mut f = Formatter {
+        config: &config,
+        lines: Vec::new(),
+        brackets: BTreeMap::new(),
+        comments: Vec::new(),
+        next_comment: 0,
+        flat: true,
+        first_line: true,
+    };
+
+    let text = f.expression(e, 0, 0);
+    match text.split_once('\n') {
+        Some((first, _)) => format!("{} ...", first),
+        None => text,
+    }
+}
+
+struct Formatter<'c, 'a> {
+    config: &'c FormatConfig,
+    lines: Vec<&'c str>,
+    /// Where each bracket in the source closes, by where it opens, and
+    /// whether the last code before the close is a `;`.
+    brackets: BTreeMap<Position, (Position, bool)>,
+    comments: Vec<&'c Comment<'a>>,
+    next_comment: usize,
+    /// Set while measuring a layout, so that nothing inside it breaks
+    /// either. Trying both layouts at every level would take exponential
+    /// time on nested calls.
+    flat: bool,
+    /// Set when only the first line is wanted, so that blocks are left out
+    /// rather than formatted and thrown away.
+    first_line: bool,
+}
+
+/// A row and column in the source, both counted from 1.
+type Position = (usize, usize);
+
+fn row_of(s: &Statement) -> Option<usize> {
+    s.token().local().map(|l| *l.get().0)
+}
+
+fn comment_row(c: &Comment) -> usize {
+    c.local().map(|l| *l.get().0).unwrap_or(0)
+}
+
+fn position(t: &Token) -> Position {
+    t.local()
+        .map(|l| (*l.get().0, *l.get().1))
+        .unwrap_or((0, 0))
+}
+
+fn comment_position(c: &Comment) -> Position {
+    c.local()
+        .map(|l| (*l.get().0, *l.get().1))
+        .unwrap_or((0, 0))
+}
+
+/// Where `e` starts, which isn't where its token is for an operator or a
+/// call.
+fn start_of(e: &Expression) -> Position {
+    match e {
+        Expression::Infix(i) => start_of(i.left()),
+        Expression::Call(i) => start_of(i.function()),
+        Expression::Member(i) => start_of(i.object()),
+        Expression::Index(i) => start_of(i.left()),
+        Expression::IndexAssign(i) => start_of(i.target().left()),
+        Expression::Assign(i) => position(i.name().token()),
+        _ => position(e.token()),
+    }
+}
+
+/// Column reached after writing `s` starting at column `col`.
+fn advance(col: usize, s: &str) -> usize {
+    match s.rfind('\n') {
+        Some(i) => s.len() - i - 1,
+        None => col + s.len(),
+    }
+}
+
+fn first_line_len(s: &str) -> usize {
+    s.find('\n').unwrap_or(s.len())
+}
+
+/// `text`, the formatted `stmt`, without its `;` if it's an expression a
+/// block ends in that was written without one.
+fn tail_of<'t>(text: &'t str, stmt: &Statement, tail: bool) -> &'t str {
+    match stmt {
+        Statement::Expression(_) if tail => text.strip_suffix(';').unwrap_or(text),
+        _ => text,
+    }
+}
+
+/// Where each bracket in `lines` closes, by where it opens, and whether the
+/// last code before the close is a `;`. Strings and comments are left out.
+fn brackets(lines: &[&str]) -> BTreeMap<Position, (Position, bool)> {
+    let mut brackets = BTreeMap::new();
+    let mut open = Vec::new();
+    let (mut last, mut before) = (' ', ' ');
+    for (row, line) in lines.iter().enumerate() {
+        let mut in_string = false;
+        let mut prev = ' ';
+        for (idx, ch) in line.chars().enumerate() {
+            let at = (row + 1, idx + 1);
+            match ch {
+                '"' => in_string = !in_string,
+                '/' if prev == '/' && !in_string => {
+                    // the first `/` wasn't code after all
+                    last = before;
+                    break;
+                }
+                '(' | '{' | '[' if !in_string => open.push(at),
+                ')' | '}' | ']' if !in_string => {
+                    if let Some(start) = open.pop() {
+                        brackets.insert(start, (at, last == ';'));
+                    }
+                }
+                _ => {}
+            }
+            if !ch.is_whitespace() {
+                (last, before) = (ch, last);
+            }
+            prev = ch;
+        }
+    }
+    brackets
+}
+
+const PARENS: [char; 2] = ['(', ')'];
+const BRACKETS: [char; 2] = ['[', ']'];
+const BRACES: [char; 2] = ['{', '}'];
+
+fn infix_precedence(e: &Expression) -> Option<Precedence> {
+    match e.unfolded() {
+        Expression::Infix(i) => Some(Precedence::of(&i.token().ttype)),
+        Expression::Assign(_) | Expression::IndexAssign(_) => Some(Precedence::Assign),
+        _ => None,
+    }
+}
+
+impl<'c, 'a> Formatter<'c, 'a> {
+    fn program(&mut self, program: &Program) -> String {
+        let mut out = self.statements(&program.statements, 0, false);
+
+        // anything left over trails the last statement
+        let rest = self.take_comments_before(usize::MAX, 0);
+        if !rest.is_empty() {
+            if !out.is_empty() {
+                out.push('\n');
+            }
+            out.push_str(&rest);
+        }
+
+        if !out.is_empty() && !out.ends_with('\n') {
+            out.push('\n');
+        }
+        out
+    }
+
+    fn is_blank_line(&self, row: usize) -> bool {
+        row >= 1 && row <= self.lines.len() && self.lines[row - 1].trim().is_empty()
+    }
+
+    /// Own-line comments that appear before `row`, each on its own line.
+    fn take_comments_before(&mut self, row: usize, indent: usize) -> String {
+        let mut out = String::new();
+        while self.next_comment < self.comments.len()
+            && comment_row(self.comments[self.next_comment]) < row
+        {
+            let c = self.comments[self.next_comment];
+            out.push_str(&INDENT.repeat(indent));
+            out.push_str(&format!("// {}\n", c.text));
+            self.next_comment += 1;
+        }
+        out
+    }
+
+    /// The comments before `to`, where something that goes on a line of
+    /// its own starts. One after code stays at the end of the line, and the
+    /// rest go on lines of their own.
+    fn comments_before(&mut self, to: Position, indent: usize) -> String {
+        let mut out = String::new();
+        while let Some(c) = self.comments.get(self.next_comment) {
+            let at = comment_position(c);
+            if at >= to {
+                break;
+            }
+            if self.has_code_before(at) && out.is_empty() {
+                out.push_str(&format!(" // {}", c.text));
+            } else {
+                out.push_str(&format!("\n{}// {}", INDENT.repeat(indent), c.text));
+            }
+            self.next_comment += 1;
+        }
+        out
+    }
+
+    /// A comment after the code of what starts at `from`, on one of its
+    /// lines, and before `next_row`, where whatever follows it starts. One
+    /// after the `}` or `)` around it belongs to what that closes.
+    fn take_trailing_comment(&mut self, from: Position, next_row: Option<usize>) -> Option<String> {
+        let c = *self.comments.get(self.next_comment)?;
+        let at = comment_position(c);
+        if at < from
+            || next_row.is_some_and(|row| at.0 >= row)
+            || !self.has_code_before(at)
+            || self.closes_before(from, at)
+        {
+            return None;
+        }
+        self.next_comment += 1;
+        Some(format!(" // {}", c.text))
+    }
+
+    fn has_code_before(&self, (row, col): Position) -> bool {
+        row >= 1
+            && row <= self.lines.len()
+            && self.lines[row - 1]
+                .chars()
+                .take(col.saturating_sub(1))
+                .any(|c| !c.is_whitespace())
+    }
+
+    /// Calls `f` with each bracket in the source from `from` on, leaving out
+    /// strings and comments, until it returns true.
+    fn scan(&self, from: Position, mut f: impl FnMut(Position, char) -> bool) {
+        for row in from.0.max(1)..=self.lines.len() {
+            let first = if row == from.0 { from.1 } else { 1 };
+            let mut in_string = false;
+            let mut prev = ' ';
+            for (idx, ch) in self.lines[row - 1].chars().enumerate() {
+                let col = idx + 1;
+                if col < first {
+                    continue;
+                }
+                match ch {
+                    '"' => in_string = !in_string,
+                    '/' if prev == '/' && !in_string => break,
+                    '(' | ')' | '{' | '}' | '[' | ']' if !in_string && f((row, col), ch) => return,
+                    _ => {}
+                }
+                prev = ch;
+            }
+        }
+    }
+
+    /// Whether the source from `from` up to `to` closes a bracket it didn't
+    /// open, which ends the block or list that `from` is in.
+    fn closes_before(&self, from: Position, to: Position) -> bool {
+        let mut depth = 0;
+        let mut closed = false;
+        self.scan(from, |at, ch| {
+            if at >= to {
+                return true;
+            }
+            depth += if matches!(ch, '(' | '{' | '[') { 1 } else { -1 };
+            closed = depth < 0;
+            closed
+        });
+        closed
+    }
+
+    /// Where the first bracket from `from` on is closed, as the `)` of a
+    /// call or a function's parameters.
+    fn closing_bracket(&self, from: Position) -> Option<Position> {
+        self.brackets
+            .range(from..)
+            .next()
+            .map(|(_, (close, _))| *close)
+    }
+
+    /// Where the list that `open` starts closes, if a comment that hasn't
+    /// been written yet is in it.
+    fn commented_list_close(&self, open: Position) -> Option<Position> {
+        if self.next_comment >= self.comments.len() {
+            return None;
+        }
+        let close = self.closing_bracket(open)?;
+        self.comments[self.next_comment..]
+            .iter()
+            .map(|c| comment_position(c))
+            .take_while(|at| *at < close)
+            .any(|at| at > open)
+            .then_some(close)
+    }
+
+    /// Whether the source from `open` to `close`, on one line, could fit
+    /// on a line from `col`. Formatting only changes the spacing, give or
+    /// take some parentheses, so that's only if its code would, which saves
+    /// laying out a long line of nested blocks only to break it.
+    fn could_fit(&self, open: Position, close: Option<Position>, col: usize) -> bool {
+        let Some(close) = close else {
+            return false;
+        };
+        let code = self.lines[open.0 - 1]
+            .chars()
+            .skip(open.1 - 1)
+            .take(close.1 + 1 - open.1)
+            .filter(|c| !c.is_whitespace())
+            .count();
+        col + code <= self.config.width
+    }
+
+    /// Formats `statements` one per line. If `tail` is set, an expression
+    /// they end in is left without a `;`, as it was written.
+    fn statements(&mut self, statements: &[Statement], indent: usize, tail: bool) -> String {
+        let mut out = String::new();
+
+        for (idx, stmt) in statements.iter().enumerate() {
+            let row = row_of(stmt).unwrap_or(0);
+            let next_row = statements.get(idx + 1).and_then(row_of);
+            let first_row = if self.next_comment < self.comments.len() {
+                comment_row(self.comments[self.next_comment]).min(row)
+            } else {
+                row
+            };
+
+            if idx > 0 && self.is_blank_line(first_row.saturating_sub(1)) {
+                out.push('\n');
+            }
+
+            out.push_str(&self.take_comments_before(row, indent));
+            out.push_str(&INDENT.repeat(indent));
+            let text = self.statement(stmt, indent);
+            out.push_str(tail_of(&text, stmt, tail && idx + 1 == statements.len()));
+            if let Some(c) = self.take_trailing_comment(position(stmt.token()), next_row) {
+                out.push_str(&c);
+            }
+            out.push('\n');
+        }
+
+        if out.ends_with('\n') {
+            out.pop();
+        }
+        out
+    }
+
+    fn statement(&mut self, stmt: &Statement, indent: usize) -> String {
+        ast::deeper(|| {
+            let col = indent * INDENT.len();
+            match stmt {
+                Statement::Let(i) => {
+                    let mut s = String::from("let ");
+                    if let Some(name) = i.name() {
+                        s.push_str(name.value());
+                    }
+                    s.push_str(" = ");
+                    if let Some(value) = i.value() {
+                        let v = self.expression(value, indent, col + s.len());
+                        s.push_str(&v);
+                    }
+                    s.push(';');
+                    s
+                }
+                Statement::Return(i) => match i.return_value() {
+                    Some(value) => {
+                        let v = self.expression(value, indent, col + "return ".len());
+                        format!("return {};", v)
+                    }
+                    None => String::from("return;"),
+                },
+                Statement::Expression(i) => match i.expression() {
+                    Some(e @ (Expression::If(_) | Expression::Try(_))) => {
+                        self.expression(e, indent, col)
+                    }
+                    Some(e) => format!("{};", self.expression(e, indent, col)),
+                    None => String::new(),
+                },
+                Statement::Import(i) => format!("import \"{}\";", i.path()),
+                Statement::While(i) => {
+                    let cond = self.expression(i.condition(), indent, col + "while (".len());
+                    let head = format!("while ({}) ", cond);
+                    let body = self.block(i.body(), indent, advance(col, &head));
+                    format!("{}{}", head, body)
+                }
+                Statement::For(i) => {
+                    let head = format!("for ({} in ", i.variable().value());
+                    let start = self.expression(i.start(), indent, col + head.len());
+                    let end = match i.end() {
+                        Some(end) => {
+                            let head = format!("{}{}..", head, start);
+                            let end = self.expression(end, indent, advance(col, &head));
+                            format!("{}{}", head, end)
+                        }
+                        None => format!("{}{}", head, start),
+                    };
+                    let head = format!("{}) ", end);
+                    let body = self.block(i.body(), indent, advance(col, &head));
+                    format!("{}{}", head, body)
+                }
+                Statement::Break(_) => String::from("break;"),
+                Statement::Continue(_) => String::from("continue;"),
+                Statement::Throw(i) => {
+                    let v = self.expression(i.value(), indent, col + "throw ".len());
+                    format!("throw {};", v)
+                }
+            }
+        })
+    }
+
+    /// Formats `block`, which starts at column `col`. One written on a
+    /// single line stays on one if it still fits.
+    fn block(&mut self, block: &Block, indent: usize, col: usize) -> String {
+        if self.first_line && !block.statements.is_empty() {
+            return String::from("{\n}");
+        }
+        let open = position(block.token());
+        let (close, semicolon) = self.brackets.get(&open).copied().unzip();
+        let tail = semicolon == Some(false);
+
+        // a comment runs to the end of its line, so there can't be one in
+        // a block that closes on the line it opens on
+        if !block.statements.is_empty()
+            && close.is_some_and(|close| close.0 == open.0)
+            && (self.flat || self.could_fit(open, close, col))
+        {
+            let saved = (self.next_comment, self.flat);
+            self.flat = true;
+            let mut statements = Vec::new();
+            for (idx, stmt) in block.statements.iter().enumerate() {
+                let text = self.statement(stmt, indent);
+                let last = tail && idx + 1 == block.statements.len();
+                statements.push(tail_of(&text, stmt, last).to_string());
+            }
+            (self.next_comment, self.flat) = saved;
+            let line = format!("{{ {} }}", statements.join(" "));
+            if self.flat || (!line.contains('\n') && col + line.len() <= self.config.width) {
+                return line;
+            }
+        }
+
+        let body = self.statements(&block.statements, indent + 1, tail);
+        if body.is_empty() {
+            return String::from("{}");
+        }
+        format!("{{\n{}\n{}}}", body, INDENT.repeat(indent))
+    }
+
+    /// Joins `items` as a parenthesised list, breaking one item per line
+    /// when it would overflow the configured width.
+    fn list(&mut self, items: Vec<String>, indent: usize, col: usize) -> String {
+        let flat = format!("({})", items.join(", "));
+        if self.flat || col + first_line_len(&flat) <= self.config.width || items.is_empty() {
+            return flat;
+        }
+
+        let inner = INDENT.repeat(indent + 1);
+        let mut s = String::from("(\n");
+        for (idx, item) in items.iter().enumerate() {
+            s.push_str(&inner);
+            s.push_str(item);
+            if idx + 1 < items.len() {
+                s.push(',');
+            }
+            s.push('\n');
+        }
+        s.push_str(&INDENT.repeat(indent));
+        s.push(')');
+        s
+    }
+
+    /// Lays out `items`, a list with comments in it that closes at `close`,
+    /// one item per line. Each comment stays next to the item it follows,
+    /// after its comma, or on its own line where it was on one.
+    fn commented_list<T>(
+        &mut self,
+        items: &[T],
+        start: impl Fn(&T) -> Position,
+        mut item: impl FnMut(&mut Self, &T) -> String,
+        brackets: [char; 2],
+        close: Position,
+        indent: usize,
+    ) -> String {
+        let inner = INDENT.repeat(indent + 1);
+        let mut s = format!("{}\n", brackets[0]);
+        for (idx, it) in items.iter().enumerate() {
+            let from = start(it);
+            s.push_str(&self.take_comments_before(from.0, indent + 1));
+            s.push_str(&inner);
+            s.push_str(&item(self, it));
+            if idx + 1 < items.len() {
+                s.push(',');
+            }
+            let next_row = items.get(idx + 1).map(|next| start(next).0);
+            if let Some(c) = self.take_trailing_comment(from, next_row) {
+                s.push_str(&c);
+            }
+            s.push('\n');
+        }
+        s.push_str(&self.take_comments_before(close.0, indent + 1));
+        s.push_str(&INDENT.repeat(indent));
+        s.push(brackets[1]);
+        s
+    }
+
+    fn wrapped(&mut self, e: &Expression, indent: usize, col: usize, parens: bool) -> String {
+        if parens {
+            format!("({})", self.expression(e, indent, col + 1))
+        } else {
+            self.expression(e, indent, col)
+        }
+    }
+
+    fn expression(&mut self, e: &Expression, indent: usize, col: usize) -> String {
+        ast::deeper(|| match e.unfolded() {
+            Expression::Identifier(i) => i.value().clone(),
+            Expression::Integer(i) => i.value().to_string(),
+            Expression::Boolean(i) => i.value().to_string(),
+            Expression::String(i) => format!("\"{}\"", i.value()),
+            Expression::Prefix(i) => {
+                let needs_parens = matches!(
+                    i.right().unfolded(),
+                    Expression::Infix(_) | Expression::Assign(_) | Expression::IndexAssign(_)
+                );
+                let right = self.wrapped(i.right(), indent, col + i.operator().len(), needs_parens);
+                format!("{}{}", i.operator(), right)
+            }
+            Expression::Infix(i) => {
+                let prec = Precedence::of(&i.token().ttype);
+                let left_parens = infix_precedence(i.left()).is_some_and(|p| p < prec);
+                let right_parens = infix_precedence(i.right()).is_some_and(|p| p <= prec);
+
+                let left = self.wrapped(i.left(), indent, col, left_parens);
+
+                // comments between the operands stay after the operator,
+                // with the right one on the next line
+                let comments = self.comments_before(start_of(i.right()), indent + 1);
+                if !comments.is_empty() {
+                    let inner = INDENT.repeat(indent + 1);
+                    let right = self.wrapped(i.right(), indent + 1, inner.len(), right_parens);
+                    return format!("{} {}{}\n{}{}", left, i.operator(), comments, inner, right);
+                }
+
+                let op = format!(" {} ", i.operator());
+                let right_col = advance(col, &left) + op.len();
+                let right = self.wrapped(i.right(), indent, right_col, right_parens);
+                format!("{}{}{}", left, op, right)
+            }
+            Expression::If(i) => {
+                let cond = self.expression(i.condition(), indent, col + "if (".len());
+                let mut s = format!("if ({}) ", cond);
+                let consequence = self.block(i.consequence(), indent, advance(col, &s));
+                s.push_str(&consequence);
+                if let Some(alt) = i.alternative() {
+                    s.push_str(" else ");
+                    let alt = self.block(alt, indent, advance(col, &s));
+                    s.push_str(&alt);
+                }
+                s
+            }
+            Expression::Try(i) => {
+                let mut s = String::from("try ");
+                let body = self.block(i.body(), indent, advance(col, &s));
+                s.push_str(&body);
+                s.push_str(&format!(" catch ({}) ", i.name()));
+                let handler = self.block(i.handler(), indent, advance(col, &s));
+                s.push_str(&handler);
+                if let Some(finally) = i.finally() {
+                    s.push_str(" finally ");
+                    let finally = self.block(finally, indent, advance(col, &s));
+                    s.push_str(&finally);
+                }
+                s
+            }
+            Expression::Function(i) | Expression::Macro(i) => {
+                let keyword = match e {
+                    Expression::Macro(_) => "macro",
+                    _ => "fn",
+                };
+                let params = match self.commented_list_close(position(i.token())) {
+                    Some(close) => self.commented_list(
+                        i.parameters(),
+                        |p| position(p.token()),
+                        |_, p| p.to_string(),
+                        PARENS,
+                        close,
+                        indent,
+                    ),
+                    None => {
+                        let params = i.parameters().iter().map(|p| p.to_string()).collect();
+                        self.list(params, indent, col + keyword.len())
+                    }
+                };
+                let head = format!("{}{} ", keyword, params);
+                let body = self.block(i.body(), indent, advance(col, &head));
+                format!("{}{}", head, body)
+            }
+            Expression::Call(i) => {
+                let needs_parens = matches!(
+                    i.function().unfolded(),
+                    Expression::Infix(_)
+                        | Expression::Prefix(_)
+                        | Expression::Assign(_)
+                        | Expression::IndexAssign(_)
+                );
+                let function = self.wrapped(i.function(), indent, col, needs_parens);
+                let args_col = advance(col, &function);
+
+                // comments between the arguments keep them on their own lines
+                if let Some(close) = self.commented_list_close(position(i.token())) {
+                    let arg_col = (indent + 1) * INDENT.len();
+                    let args = self.commented_list(
+                        i.arguments(),
+                        start_of,
+                        |f, a| f.expression(a, indent + 1, arg_col),
+                        PARENS,
+                        close,
+                        indent,
+                    );
+                    return format!("{}{}", function, args);
+                }
+
+                // lay the arguments out flat first, and only break them if
+                // that doesn't fit
+                let flat_args: Vec<String> = i
+                    .arguments()
+                    .iter()
+                    .map(|a| self.expression_flat(a, indent))
+                    .collect();
+                let flat = format!("({})", flat_args.join(", "));
+                if self.flat || args_col + first_line_len(&flat) <= self.config.width {
+                    return format!("{}{}", function, flat);
+                }
+
+                let arg_col = (indent + 1) * INDENT.len();
+                let args: Vec<String> = i
+                    .arguments()
+                    .iter()
+                    .map(|a| self.expression(a, indent + 1, arg_col))
+                    .collect();
+                format!("{}{}", function, self.list(args, indent, args_col))
+            }
+            Expression::Member(i) => {
+                let needs_parens = matches!(
+                    i.object().unfolded(),
+                    Expression::Infix(_)
+                        | Expression::Prefix(_)
+                        | Expression::Assign(_)
+                        | Expression::IndexAssign(_)
+                );
+                let object = self.wrapped(i.object(), indent, col, needs_parens);
+                format!("{}.{}", object, i.name())
+            }
+            Expression::Array(i) => {
+                if let Some(close) = self.commented_list_close(position(e.token())) {
+                    let item_col = (indent + 1) * INDENT.len();
+                    return self.commented_list(
+                        i.elements(),
+                        start_of,
+                        |f, x| f.expression(x, indent + 1, item_col),
+                        BRACKETS,
+                        close,
+                        indent,
+                    );
+                }
+                self.items(i.elements(), BRACKETS, indent, col, |f, x, indent, col| {
+                    f.expression(x, indent, col)
+                })
+            }
+            Expression::Hash(i) => {
+                let pair = |f: &mut Self, (k, v): &(Expression, Expression), indent, col| {
+                    let key = f.expression(k, indent, col);
+                    let value = f.expression(v, indent, advance(col, &key) + ": ".len());
+                    format!("{}: {}", key, value)
+                };
+                if let Some(close) = self.commented_list_close(position(e.token())) {
+                    let item_col = (indent + 1) * INDENT.len();
+                    return self.commented_list(
+                        i.pairs(),
+                        |(k, _)| start_of(k),
+                        |f, p| pair(f, p, indent + 1, item_col),
+                        BRACES,
+                        close,
+                        indent,
+                    );
+                }
+                self.items(i.pairs(), BRACES, indent, col, pair)
+            }
+            Expression::Index(i) => self.index(i, indent, col),
+            Expression::IndexAssign(i) => {
+                let target = self.index(i.target(), indent, col);
+                let op = format!(" {} ", i.operator());
+                let value_col = advance(col, &target) + op.len();
+                let value = self.expression(i.value(), indent, value_col);
+                format!("{}{}{}", target, op, value)
+            }
+            Expression::Assign(i) => {
+                let op = format!(" {} ", i.operator());
+                let value_col = col + i.name().value().len() + op.len();
+                let value = self.expression(i.value(), indent, value_col);
+                format!("{}{}{}", i.name(), op, value)
+            }
+        })
+    }
+
+    fn index(&mut self, i: &IndexInternal, indent: usize, col: usize) -> String {
+        let needs_parens = matches!(
+            i.left().unfolded(),
+            Expression::Infix(_)
+                | Expression::Prefix(_)
+                | Expression::Assign(_)
+                | Expression::IndexAssign(_)
+        );
+        let left = self.wrapped(i.left(), indent, col, needs_parens);
+        let index = self.expression(i.index(), indent, advance(col, &left) + 1);
+        format!("{}[{}]", left, index)
+    }
+
+    /// Lays out `xs` between `brackets`, flat if the whole list fits on
+    /// the line from `col` and one item per line if not.
+    fn items<T>(
+        &mut self,
+        xs: &[T],
+        brackets: [char; 2],
+        indent: usize,
+        col: usize,
+        mut item: impl FnMut(&mut Self, &T, usize, usize) -> String,
+    ) -> String {
+        let saved = (self.next_comment, self.flat);
+        self.flat = true;
+        let flat: Vec<String> = xs.iter().map(|x| item(self, x, indent, 0)).collect();
+        (self.next_comment, self.flat) = saved;
+        let flat = format!("{}{}{}", brackets[0], flat.join(", "), brackets[1]);
+        if self.flat || xs.is_empty() || col + first_line_len(&flat) <= self.config.width {
+            return flat;
+        }
+
+        let inner = INDENT.repeat(indent + 1);
+        let mut s = format!("{}\n", brackets[0]);
+        for (idx, x) in xs.iter().enumerate() {
+            s.push_str(&inner);
+            s.push_str(&item(self, x, indent + 1, inner.len()));
+            if idx + 1 < xs.len() {
+                s.push(',');
+            }
+            s.push('\n');
+        }
+        s.push_str(&INDENT.repeat(indent));
+        s.push(brackets[1]);
+        s
+    }
+
+    /// Formats `e` without consuming any comments, used to measure whether
+    /// something fits before committing to a layout.
+    fn expression_flat(&mut self, e: &Expression, indent: usize) -> String {
+        let saved = (self.next_comment, self.flat);
+        self.flat = true;
+        let s = self.expression(e, indent, 0);
+        (self.next_comment, self.flat) = saved;
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fmt(input: &str, width: usize) -> String {
+        let path = Path::new("test.my");
+        format(input, path, &FormatConfig { width }).expect("input should parse")
+    }
+
+    #[test]
+    fn test_format_spacing_and_braces() {
+        let input = "let add=fn(x,y){x+y;};\n\
+            let result = add( 5,10 )\n\
+            if(result>10){return true}else{return false;}";
+
+        let expected = "let add = fn(x, y) { x + y; };\n\
+            let result = add(5, 10);\n\
+            if (result > 10) { return true; } else { return false; }\n";
+
+        assert_eq!(fmt(input, 100), expected);
+    }
+
+    #[test]
+    fn test_format_keeps_needed_parens_only() {
+        let input = "((1 + 2)) * 3 - (4 - 5) + (a * b);\n-(a + b);\n(-a) * b;";
+        let expected = "(1 + 2) * 3 - (4 - 5) + a * b;\n-(a + b);\n-a * b;\n";
+
+        assert_eq!(fmt(input, 100), expected);
+    }
+
+    #[test]
+    fn test_format_imports_and_members() {
+        let input = "import   \"lib/math.my\"\nmath . square((-a).b)";
+        let expected = "import \"lib/math.my\";\nmath.square((-a).b);\n";
+
+        assert_eq!(fmt(input, 100), expected);
+    }
+
+    #[test]
+    fn test_format_loops() {
+        let input = "while(x>0){\n if (x > 5) { break } x }\nfor (i in 0..n+1) { continue; }";
+        let expected = "while (x > 0) {\n    if (x > 5) { break; }\n    x\n}\n\
+            for (i in 0..n + 1) { continue; }\n";
+
+        assert_eq!(fmt(input, 100), expected);
+    }
+
+    #[test]
+    fn test_format_collections() {
+        let input = "let h={\"a\":[1,2],b:(x+1)[0]};for(k in h){puts(k)}\n\
+            let long = [aaaaaaaaaa, {\"cccccccccc\": dddddddddd, \"eeeeeeeeee\": ffff}];\n\
+            let c = [1, // one\n2];\nh [\"k\"]+=xs[ 0 ]=1;";
+        let expected = "let h = {\"a\": [1, 2], b: (x + 1)[0]};\n\
+            for (k in h) { puts(k) }\n\
+            let long = [\n    aaaaaaaaaa,\n    {\n        \"cccccccccc\": dddddddddd,\n        \
+            \"eeeeeeeeee\": ffff\n    }\n];\n\
+            let c = [\n    1, // one\n    2\n];\n\
+            h[\"k\"] += xs[0] = 1;\n";
+
+        assert_eq!(fmt(input, 40), expected);
+    }
+
+    #[test]
+    fn test_format_exceptions() {
+        let input =
+            "let n=try{parse(x)}catch(e){\n if (e.kind==\"type\") { 0 } else { throw e } };\n\
+            try { f() } catch (e) { } finally { done(); }";
+        let expected = "let n = try { parse(x) } catch (e) {\n    \
+            if (e.kind == \"type\") { 0 } else { throw e; }\n};\n\
+            try { f() } catch (e) {} finally { done(); }\n";
+
+        assert_eq!(fmt(input, 100), expected);
+    }
+
+    #[test]
+    fn test_format_logical_and_bitwise_operators() {
+        let input = "(a||b)&&c%2>=1;a||(b&&c);!(a&&b);(a|b)&~c<<2;a|(b^c)";
+        let expected = "(a || b) && c % 2 >= 1;\na || b && c;\n!(a && b);\n\
+            (a | b) & ~c << 2;\na | b ^ c;\n";
+
+        assert_eq!(fmt(input, 100), expected);
+    }
+
+    #[test]
+    fn test_format_assignments() {
+        let input = "x+=1;a=b=(c-=2)*3;f(y=1);(n=2).m";
+        let expected = "x += 1;\na = b = (c -= 2) * 3;\nf(y = 1);\n(n = 2).m;\n";
+
+        assert_eq!(fmt(input, 100), expected);
+    }
+
+    #[test]
+    fn test_format_preserves_comments_and_blank_lines() {
+        let input = "// helpers\n\
+            let five = 5; // five\n\
+            \n\
+            let f = fn() {\n\
+            // inside\n\
+            five\n\
+            };\n\
+            // the end";
+
+        let expected = "// helpers\n\
+            let five = 5; // five\n\
+            \n\
+            let f = fn() {\n    // inside\n    five\n};\n\
+            // the end\n";
+
+        assert_eq!(fmt(input, 100), expected);
+    }
+
+    #[test]
+    fn test_format_keeps_comments_on_their_arguments() {
+        let input = "add(\n1, // first\n// then\n2 // second\n); // sum\nlet x = 1;";
+        let expected =
+            "add(\n    1, // first\n    // then\n    2 // second\n); // sum\nlet x = 1;\n";
+
+        assert_eq!(fmt(input, 100), expected);
+        assert_eq!(fmt(expected, 100), expected);
+    }
+
+    #[test]
+    fn test_format_keeps_comments_on_their_parameters() {
+        let input = "let f = fn(a, // first\nb) { a };";
+        let expected = "let f = fn(\n    a, // first\n    b\n) { a };\n";
+
+        assert_eq!(fmt(input, 100), expected);
+        assert_eq!(fmt(expected, 100), expected);
+    }
+
+    #[test]
+    fn test_format_keeps_comments_after_blocks() {
+        let input = "if (x) {\na;\n} // done\nb;";
+        let expected = "if (x) {\n    a;\n} // done\nb;\n";
+        assert_eq!(fmt(input, 100), expected);
+
+        // on one line, the comment is still after the block, not in it
+        let input = "if (x) { a; } // done\nwhile (y) { if (z) { b; } // inner\n}";
+        let expected = "if (x) { a; } // done\nwhile (y) {\n    if (z) { b; } // inner\n}\n";
+        assert_eq!(fmt(input, 100), expected);
+    }
+
+    #[test]
+    fn test_format_keeps_short_blocks_on_one_line() {
+        // only while they fit, and a block written over several lines stays
+        // that way
+        let input = "let f = fn(x) { x * 2 };\n\
+            let g = fn(x) { let y = x + 1; y * y };\n\
+            let h = fn(x) {\nx }\nlet k = fn(x) {\nx // last\n};";
+        let expected = "let f = fn(x) { x * 2 };\n\
+            let g = fn(x) {\n    let y = x + 1;\n    y * y\n};\n\
+            let h = fn(x) {\n    x\n};\n\
+            let k = fn(x) {\n    x // last\n};\n";
+
+        assert_eq!(fmt(input, 30), expected);
+        assert_eq!(fmt(expected, 30), expected);
+    }
+
+    #[test]
+    fn test_format_keeps_comments_between_operands() {
+        let input = "let total = base + // the rest\nextra * 2;\nlet x = a\n// why\n- b;";
+        let expected = "let total = base + // the rest\n    extra * 2;\n\
+            let x = a -\n    // why\n    b;\n";
+
+        assert_eq!(fmt(input, 100), expected);
+        assert_eq!(fmt(expected, 100), expected);
+    }
+
+    #[test]
+    fn test_format_wraps_long_calls() {
+        let input = "let total = add(first_argument, second_argument, third_argument);";
+        let expected =
+            "let total = add(\n    first_argument,\n    second_argument,\n    third_argument\n);\n";
+
+        assert_eq!(fmt(input, 40), expected);
+        assert_eq!(fmt(&fmt(input, 40), 40), expected);
+    }
+
+    #[test]
+    fn test_format_rejects_invalid_input() {
+        let path = Path::new("test.my");
+        assert!(format("let = 5;", path, &FormatConfig::default()).is_err());
+    }
+}
@@ -0,0 +1,173 @@
+//! Frees what reference counting can't: values that only refer to each
+//! other. A function usually lives in the environment or cell it closed
+//! over, and an array can hold itself, so counting alone would leak both.
+//! Every engine tracks the values that can be changed to refer back to
+//! themselves, and collects them the same way.
+
+use crate::object::{GcConfig, GcStats, Table};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+
+/// Something the collector can see into: a value holding references to
+/// others.
+pub trait Trace<'h> {
+    /// Calls `f` with each reference this holds to something traced, once
+    /// per reference. Returns false if it's being changed and can't be
+    /// read, which leaves the collection for next time.
+    fn trace(&self, f: &mut dyn FnMut(Rc<dyn Trace<'h> + 'h>)) -> bool;
+
+    /// Drops everything this holds, which breaks the cycles it's in.
+    /// Returns false if it can't be emptied, like a function, whose
+    /// cycles go through something that can.
+    fn clear(&self) -> bool;
+}
+
+/// A value that may hold references the collector traces.
+pub trait Traced<'h> {
+    fn node(&self) -> Option<Rc<dyn Trace<'h> + 'h>>;
+}
+
+impl<'h, V: Traced<'h>> Trace<'h> for RefCell<Vec<V>> {
+    fn trace(&self, f: &mut dyn FnMut(Rc<dyn Trace<'h> + 'h>)) -> bool {
+        match self.try_borrow() {
+            Ok(items) => {
+                items.iter().filter_map(Traced::node).for_each(f);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn clear(&self) -> bool {
+        let items = std::mem::take(&mut *self.borrow_mut());
+        drop(items);
+        true
+    }
+}
+
+impl<'h, V: Traced<'h>> Trace<'h> for RefCell<Table<V>> {
+    fn trace(&self, f: &mut dyn FnMut(Rc<dyn Trace<'h> + 'h>)) -> bool {
+        match self.try_borrow() {
+            Ok(table) => {
+                table
+                    .entries()
+                    .iter()
+                    .filter_map(|(_, v)| v.node())
+                    .for_each(f);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn clear(&self) -> bool {
+        let table = std::mem::take(&mut *self.borrow_mut());
+        drop(table);
+        true
+    }
+}
+
+/// Everything an engine has made that could end up in a cycle, so the
+/// ones that only cycles keep alive can be found and freed.
+#[derive(Default)]
+pub struct Heap<'h> {
+    tracked: Vec<Weak<dyn Trace<'h> + 'h>>,
+    config: GcConfig,
+    /// Values tracked since the last collection.
+    since: usize,
+    stats: GcStats,
+}
+
+impl<'h> Heap<'h> {
+    pub fn set_config(&mut self, config: GcConfig) {
+        self.config = config;
+    }
+
+    pub fn stats(&self) -> GcStats {
+        self.stats
+    }
+
+    /// Tracks `value`, which was just made, and collects if enough has
+    /// been made since the last collection.
+    pub fn track<T: Trace<'h> + 'h>(&mut self, value: &Rc<T>) {
+        let weak: Weak<T> = Rc::downgrade(value);
+        self.tracked.push(weak);
+        self.stats.allocated += 1;
+        self.since += 1;
+        if self.since >= self.config.next(self.stats.live) {
+            self.collect();
+        }
+    }
+
+    /// Marks and sweeps what's tracked. The roots are whatever's held from
+    /// outside the heap: the stack, globals and frames of the engine, and
+    /// whatever the host holds on to. Rather than find those, this counts
+    /// the references from inside the heap, and anything with more
+    /// references than that is a root. What isn't reachable from one is
+    /// emptied, which breaks its cycles and lets reference counting free
+    /// it.
+    pub fn collect(&mut self) {
+        self.since = 0;
+        // holding these adds one to each count, and so does holding what
+        // they refer to as it's found
+        let mut nodes: Vec<Rc<dyn Trace<'h> + 'h>> =
+            self.tracked.iter().filter_map(Weak::upgrade).collect();
+        let mut index: HashMap<*const (), usize> = nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| (address(node), i))
+            .collect();
+        let mut inner = vec![0; nodes.len()];
+        let mut edges = Vec::new();
+
+        let mut i = 0;
+        while i < nodes.len() {
+            let node = nodes[i].clone();
+            let mut out = Vec::new();
+            let readable = node.trace(&mut |child| {
+                let t = *index.entry(address(&child)).or_insert_with(|| {
+                    nodes.push(child);
+                    inner.push(0);
+                    nodes.len() - 1
+                });
+                inner[t] += 1;
+                out.push(t);
+            });
+            if !readable {
+                return;
+            }
+            edges.push(out);
+            i += 1;
+        }
+
+        let mut marked = vec![false; nodes.len()];
+        let mut pending: Vec<usize> = (0..nodes.len())
+            .filter(|&i| Rc::strong_count(&nodes[i]) - 1 > inner[i])
+            .collect();
+        while let Some(i) = pending.pop() {
+            if !marked[i] {
+                marked[i] = true;
+                pending.extend(&edges[i]);
+            }
+        }
+
+        // the nodes are all held until the end, so emptying one never
+        // frees another while it's being emptied
+        let freed = nodes
+            .iter()
+            .zip(&marked)
+            .filter(|(node, marked)| !**marked && node.clear())
+            .count();
+        drop(nodes);
+
+        self.tracked.retain(|value| value.strong_count() > 0);
+        self.stats.collections += 1;
+        self.stats.freed += freed;
+        self.stats.live = self.tracked.len();
+    }
+}
+
+fn address<T: ?Sized>(node: &Rc<T>) -> *const () {
+    Rc::as_ptr(node) as *const ()
+}
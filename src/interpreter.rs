@@ -0,0 +1,701 @@
+use crate::ast::{Program, Statement};
+use crate::code::{self, Opcode};
+use crate::compiler::{Bytecode, Compiler};
+use crate::error::{Error, Failure, Kind};
+use crate::gc::Heap;
+use crate::lexer::Lexer;
+use crate::macros::Macros;
+use crate::object::{GcConfig, Native, Object, Overflow};
+use crate::parser::Parser;
+use crate::vm::{InterruptHandle, Limits, Vm};
+use std::io::Write;
+use std::path::Path;
+use std::rc::Rc;
+
+/// Runs Monkey code for a host application. Every `eval_*` call compiles
+/// onto the same globals, so a `let` in one is visible to the next, and the
+/// host can read and write globals and call Monkey functions in between.
+/// Code runs on the stack vm; errors have the same messages it reports, and
+/// a stack trace when they happened while running.
+pub struct Interpreter {
+    compiler: Compiler,
+    globals: Vec<Option<Object>>,
+    /// What the globals refer to that could end up in a cycle, kept from
+    /// one run to the next so those cycles are still collected.
+    heap: Heap<'static>,
+    out: Option<Box<dyn Write>>,
+    limits: Limits,
+    interrupt: InterruptHandle,
+    overflow: Overflow,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Interpreter {
+    pub fn new() -> Interpreter {
+        Interpreter {
+            compiler: Compiler::new(),
+            globals: Vec::new(),
+            heap: Heap::default(),
+            out: None,
+            limits: Limits::default(),
+            interrupt: InterruptHandle::new(),
+            overflow: Overflow::default(),
+        }
+    }
+
+    /// Sends what `puts` prints to `out` rather than stdout.
+    pub fn set_output(&mut self, out: Box<dyn Write>) {
+        self.out = Some(out);
+    }
+
+    /// Limits what each `eval_*` and `call_function` may use, macros
+    /// included. Going over fails it with an error whose `limit` says
+    /// which; the globals it set before then stay set.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Changes when garbage is collected.
+    pub fn set_gc(&mut self, config: GcConfig) {
+        self.heap.set_config(config);
+    }
+
+    /// Makes integer arithmetic wrap around instead of failing when it
+    /// overflows.
+    pub fn set_overflow(&mut self, overflow: Overflow) {
+        self.overflow = overflow;
+    }
+
+    /// A handle that stops the code this interpreter is running, from
+    /// another thread. The interrupted call fails with `vm::INTERRUPTED`.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
+    }
+
+    /// Runs `input`, returning the value of its last statement when that's
+    /// an expression, and null otherwise.
+    pub fn eval_str<T: FromValue>(&mut self, input: &str) -> Result<T, Error> {
+        let l = Lexer::new(input, true, None);
+        let mut p = Parser::new(l);
+        let mut program = p.parse_program().unwrap_or_default();
+        if !p.errors().is_empty() {
+            let errors: Vec<String> = p.errors().iter().map(|e| e.to_string()).collect();
+            return Err(Error::from(errors.join("\n")));
+        }
+        self.macros().expand(&mut program)?;
+
+        let value = self.eval_program(&program)?;
+        Ok(T::from_value(value)?)
+    }
+
+    /// Like `eval_str`, with the file's contents. Parse errors start with
+    /// the path, and imports are relative to it.
+    pub fn eval_file<T: FromValue>(&mut self, path: &Path) -> Result<T, Error> {
+        let input = std::fs::read_to_string(path)
+            .map_err(|e| format!("error reading {}: {}", path.display(), e))?;
+
+        let l = Lexer::new(&input, false, Some(path));
+        let mut p = Parser::new(l);
+        let mut program = p.parse_program().unwrap_or_default();
+        if !p.errors().is_empty() {
+            let errors: Vec<String> = p
+                .errors()
+                .iter()
+                .map(|e| format!("{}:{}", path.display(), e))
+                .collect();
+            return Err(Error::from(errors.join("\n")));
+        }
+        self.macros().expand(&mut program)?;
+
+        // imports are relative to the file, but only while it's running
+        self.compiler.set_file(Some(path));
+        let value = self.eval_program(&program);
+        self.compiler.set_file(None);
+        Ok(T::from_value(value?)?)
+    }
+
+    pub fn eval_program(&mut self, program: &Program) -> Result<Object, Error> {
+        let compiled = self.compiler.compile(program);
+        // taken even on errors, so they don't leave half a program behind
+        let bytecode = self.compiler.take_bytecode();
+        compiled?;
+
+        let value = self.run(bytecode)?;
+        match program.statements.last() {
+            Some(Statement::Expression(_)) => Ok(value),
+            _ => Ok(Object::Null),
+        }
+    }
+
+    /// Binds the global `name` to `value`, defining it if need be.
+    pub fn set_global(&mut self, name: &str, value: impl IntoValue) {
+        let idx = self.compiler.define_global(name);
+        if idx >= self.globals.len() {
+            self.globals.resize(idx + 1, None);
+        }
+        self.globals[idx] = Some(value.into_value());
+    }
+
+    /// Binds the global `name` to a Rust function, which Monkey code calls
+    /// like any other. Arguments are converted with `FromValue` and the
+    /// result with `IntoValue`; a function returning `Err` fails the call
+    /// with its message, as a runtime error.
+    ///
+    /// ```
+    /// let mut interp = plmmky::Interpreter::new();
+    /// interp.register_fn("clamp", |x: i64, max: i64| x.min(max));
+    /// assert_eq!(interp.eval_str("clamp(15, 10)"), Ok(10));
+    /// ```
+    pub fn register_fn<Args>(&mut self, name: &str, f: impl IntoNative<Args>) {
+        let native = f.into_native(name);
+        self.set_global(name, Object::Native(Rc::new(native)));
+    }
+
+    pub fn get_global<T: FromValue>(&self, name: &str) -> Result<T, Error> {
+        let value = self
+            .compiler
+            .global(name)
+            .and_then(|idx| self.globals.get(idx).cloned().flatten());
+
+        match value {
+            Some(value) => Ok(T::from_value(value)?),
+            None => Err(Error::from(Failure::not_found(name))),
+        }
+    }
+
+    /// Calls the function bound to the global `name` with `args`, which
+    /// `IntoValue::into_value` makes from Rust values.
+    pub fn call_function<T: FromValue>(
+        &mut self,
+        name: &str,
+        args: Vec<Object>,
+    ) -> Result<T, Error> {
+        let idx = match self.compiler.global(name) {
+            Some(idx) => idx,
+            None => return Err(Error::from(Failure::not_found(name))),
+        };
+
+        // a main function of its own that calls the global with the
+        // arguments as constants
+        let mut bytecode = self.compiler.bytecode();
+        bytecode.instructions = code::make(Opcode::GetGlobal, &[idx]);
+        let num_args = args.len();
+        for arg in args {
+            bytecode.constants.push(arg);
+            let constant = code::make(Opcode::Constant, &[bytecode.constants.len() - 1]);
+            bytecode.instructions.extend(constant);
+        }
+        bytecode
+            .instructions
+            .extend(code::make(Opcode::Call, &[num_args]));
+        bytecode.instructions.extend(code::make(Opcode::Pop, &[]));
+
+        let value = self.run(bytecode)?;
+        Ok(T::from_value(value)?)
+    }
+
+    /// Expands macros with the same limits as the code they expand into.
+    fn macros<'a>(&self) -> Macros<'a> {
+        let mut macros = Macros::new();
+        macros.set_limits(self.limits);
+        macros.set_interrupt_handle(self.interrupt.clone());
+        macros
+    }
+
+    fn run(&mut self, bytecode: Bytecode) -> Result<Object, Error> {
+        let mut vm = Vm::with_globals(bytecode, std::mem::take(&mut self.globals));
+        vm.set_heap(std::mem::take(&mut self.heap));
+        vm.set_limits(self.limits);
+        vm.set_interrupt_handle(self.interrupt.clone());
+        vm.set_overflow(self.overflow);
+        if let Some(out) = self.out.take() {
+            vm.set_output(out);
+        }
+
+        let result = vm.run();
+        let value = vm.last_popped().clone();
+
+        self.out = Some(vm.take_output());
+        self.heap = vm.take_heap();
+        self.globals = vm.into_globals();
+
+        result.map(|_| value)
+    }
+}
+
+/// Rust values the interpreter can hand to Monkey code.
+pub trait IntoValue {
+    fn into_value(self) -> Object;
+}
+
+/// Rust values the interpreter can get back from Monkey code. Values of
+/// the wrong type fail with `Kind::Type`.
+pub trait FromValue: Sized {
+    fn from_value(value: Object) -> Result<Self, Failure>;
+}
+
+/// The error for getting `obj` back when a `want` was expected.
+fn expected(want: &str, obj: &Object) -> Failure {
+    Failure::new(
+        Kind::Type,
+        format!("expected {}, got {}", want, obj.type_name()),
+    )
+}
+
+impl IntoValue for Object {
+    fn into_value(self) -> Object {
+        self
+    }
+}
+
+impl FromValue for Object {
+    fn from_value(value: Object) -> Result<Self, Failure> {
+        Ok(value)
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self) -> Object {
+        Object::Boolean(self)
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(value: Object) -> Result<Self, Failure> {
+        match value {
+            Object::Boolean(b) => Ok(b),
+            obj => Err(expected("BOOLEAN", &obj)),
+        }
+    }
+}
+
+impl IntoValue for () {
+    fn into_value(self) -> Object {
+        Object::Null
+    }
+}
+
+impl FromValue for () {
+    fn from_value(value: Object) -> Result<Self, Failure> {
+        match value {
+            Object::Null => Ok(()),
+            obj => Err(expected("NULL", &obj)),
+        }
+    }
+}
+
+impl IntoValue for String {
+    fn into_value(self) -> Object {
+        Object::String(Rc::from(self))
+    }
+}
+
+impl IntoValue for &str {
+    fn into_value(self) -> Object {
+        Object::String(Rc::from(self))
+    }
+}
+
+impl FromValue for String {
+    fn from_value(value: Object) -> Result<Self, Failure> {
+        match value {
+            Object::String(s) => Ok(s.to_string()),
+            obj => Err(expected("STRING", &obj)),
+        }
+    }
+}
+
+/// `None` is null.
+impl<T: IntoValue> IntoValue for Option<T> {
+    fn into_value(self) -> Object {
+        match self {
+            Some(v) => v.into_value(),
+            None => Object::Null,
+        }
+    }
+}
+
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(value: Object) -> Result<Self, Failure> {
+        match value {
+            Object::Null => Ok(None),
+            value => T::from_value(value).map(Some),
+        }
+    }
+}
+
+macro_rules! integer_conversions {
+    ($($t:ty),*) => {
+        $(
+            impl IntoValue for $t {
+                fn into_value(self) -> Object {
+                    Object::Integer(self as i128)
+                }
+            }
+
+            impl FromValue for $t {
+                fn from_value(value: Object) -> Result<Self, Failure> {
+                    match value {
+                        Object::Integer(i) => <$t>::try_from(i).map_err(|_| {
+                            Failure::new(
+                                Kind::Range,
+                                format!("{} doesn't fit in {}", i, stringify!($t)),
+                            )
+                        }),
+                        obj => Err(expected("INTEGER", &obj)),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+integer_conversions!(i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, usize);
+
+/// What a registered function can return: any value, or a `Result` with
+/// a message as the error, or a `Failure` to say what kind of error it is.
+/// A plain message is `Kind::Other`.
+pub trait IntoNativeResult {
+    fn into_native_result(self) -> Result<Object, Failure>;
+}
+
+impl<T: IntoValue> IntoNativeResult for T {
+    fn into_native_result(self) -> Result<Object, Failure> {
+        Ok(self.into_value())
+    }
+}
+
+impl<T: IntoValue> IntoNativeResult for Result<T, String> {
+    fn into_native_result(self) -> Result<Object, Failure> {
+        self.map(IntoValue::into_value).map_err(Failure::from)
+    }
+}
+
+impl<T: IntoValue> IntoNativeResult for Result<T, Failure> {
+    fn into_native_result(self) -> Result<Object, Failure> {
+        self.map(IntoValue::into_value)
+    }
+}
+
+/// Rust closures that can be registered as Monkey functions. `Args` is the
+/// tuple of their parameter types.
+pub trait IntoNative<Args> {
+    fn into_native(self, name: &str) -> Native;
+}
+
+macro_rules! native_functions {
+    ($(($($arg:ident $idx:tt),*)),*) => {
+        $(
+            impl<F, R, $($arg),*> IntoNative<($($arg,)*)> for F
+            where
+                F: Fn($($arg),*) -> R + 'static,
+                R: IntoNativeResult,
+                $($arg: FromValue,)*
+            {
+                #[allow(unused_variables)]
+                fn into_native(self, name: &str) -> Native {
+                    let fn_name = name.to_string();
+                    let func = move |args: &[Object]| {
+                        self($(
+                            $arg::from_value(args[$idx].clone()).map_err(|e| {
+                                let message =
+                                    format!("{}: argument {}: {}", fn_name, $idx + 1, e);
+                                Failure::new(e.kind, message)
+                            })?
+                        ),*)
+                        .into_native_result()
+                    };
+
+                    Native {
+                        name: name.to_string(),
+                        num_parameters: <[usize]>::len(&[$($idx),*]),
+                        func: Box::new(func),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+native_functions!(
+    (),
+    (A 0),
+    (A 0, B 1),
+    (A 0, B 1, C 2),
+    (A 0, B 1, C 2, D 3),
+    (A 0, B 1, C 2, D 3, E 4),
+    (A 0, B 1, C 2, D 3, E 4, G 5)
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::LimitExceeded;
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_globals_and_calls() {
+        let mut interp = Interpreter::new();
+        let out = Output::default();
+        interp.set_output(Box::new(out.clone()));
+
+        interp.set_global("limit", 10);
+        interp
+            .eval_str::<()>("let double = fn(x) { x * 2 }; let over = fn(x) { x > limit };")
+            .expect("should define functions");
+
+        assert_eq!(
+            interp.call_function::<i64>("double", vec![21.into_value()]),
+            Ok(42)
+        );
+        assert_eq!(
+            interp.call_function("over", vec![11.into_value()]),
+            Ok(true)
+        );
+
+        interp.set_global("limit", 20);
+        assert_eq!(interp.eval_str("over(11)"), Ok(false));
+        assert_eq!(interp.eval_str("puts(double(limit))"), Ok(()));
+        assert_eq!(interp.get_global::<u8>("limit"), Ok(20));
+        assert_eq!(interp.eval_str::<Option<i32>>("if (false) { 1 }"), Ok(None));
+
+        assert_eq!(String::from_utf8(out.0.take()), Ok(String::from("40\n")));
+    }
+
+    #[test]
+    fn test_native_functions() {
+        let mut interp = Interpreter::new();
+        interp.register_fn("answer", || 42);
+        interp.register_fn("add", |a: i64, b: i64| a + b);
+        interp.register_fn("checked_div", |a: i64, b: i64| match a.checked_div(b) {
+            Some(q) => Ok(q),
+            None => Err(format!("can't divide {} by {}", a, b)),
+        });
+        interp.register_fn("either", |a: bool, b: Option<bool>| a || b == Some(true));
+
+        let tests = [
+            ("answer()", Ok(Object::Integer(42))),
+            ("add(answer(), -2)", Ok(Object::Integer(40))),
+            (
+                "let twice = fn(f) { fn(x) { f(x, x) } }; twice(add)(4)",
+                Ok(Object::Integer(8)),
+            ),
+            (
+                "either(false, if (false) { true })",
+                Ok(Object::Boolean(false)),
+            ),
+            ("checked_div(7, 2)", Ok(Object::Integer(3))),
+            ("checked_div(7, 0)", Err("can't divide 7 by 0")),
+            ("add(1)", Err("wrong number of arguments: want=2, got=1")),
+            (
+                "add(1, true)",
+                Err("add: argument 2: expected INTEGER, got BOOLEAN"),
+            ),
+            (
+                "add(170141183460469231731687303715884105727, 1)",
+                Err("add: argument 1: 170141183460469231731687303715884105727 doesn't fit in i64"),
+            ),
+        ];
+
+        for (input, expected) in tests {
+            let expected = expected.map_err(String::from);
+            let got = interp.eval_str(input).map_err(|e| e.message);
+            assert_eq!(got, expected, "{}", input);
+        }
+
+        // a host function can say what kind of error it raised
+        interp.register_fn("nth", |i: i64| match i {
+            0..=2 => Ok(i),
+            _ => Err(Failure::new(Kind::Range, format!("no element {}", i))),
+        });
+        let kind_of = |input| format!("try {{ {} }} catch (e) {{ e.kind }}", input);
+        assert_eq!(
+            interp.eval_str(&kind_of("nth(5)")),
+            Ok(String::from("range"))
+        );
+        assert_eq!(
+            interp.eval_str(&kind_of("nth(true)")),
+            Ok(String::from("type"))
+        );
+        assert_eq!(
+            interp.eval_str(&kind_of("checked_div(7, 0)")),
+            Ok(String::from("error"))
+        );
+        assert_eq!(
+            interp.eval_str::<Object>("nth(5)").map_err(|e| e.kind),
+            Err(Kind::Range)
+        );
+
+        assert_eq!(
+            interp.eval_str::<Object>("add").map(|f| f.to_string()),
+            Ok(String::from("<builtin add>"))
+        );
+    }
+
+    #[test]
+    fn test_strings() {
+        let mut interp = Interpreter::new();
+        interp.register_fn("hash", |s: String| {
+            s.bytes()
+                .fold(0u64, |h, b| h.wrapping_mul(31).wrapping_add(b as u64))
+                % 1000
+        });
+        interp.register_fn("greet", |name: String| format!("hello {}", name));
+        interp.set_global("name", "monkey");
+
+        assert_eq!(interp.eval_str("hash(\"ab\")"), Ok(105));
+        assert_eq!(
+            interp.eval_str("greet(name)"),
+            Ok(String::from("hello monkey"))
+        );
+        assert_eq!(
+            interp.get_global::<String>("name"),
+            Ok(String::from("monkey"))
+        );
+        assert_eq!(
+            interp.eval_str::<String>("1").map_err(|e| e.message),
+            Err(String::from("expected STRING, got INTEGER"))
+        );
+        assert_eq!(
+            interp.eval_str::<Object>("hash(1)").map_err(|e| e.message),
+            Err(String::from(
+                "hash: argument 1: expected STRING, got INTEGER"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut interp = Interpreter::new();
+        interp.set_limits(Limits {
+            fuel: Some(1000),
+            ..Limits::default()
+        });
+
+        interp
+            .eval_str::<()>("let loop = fn(n) { loop(n + 1) };")
+            .expect("should define loop");
+        let err = interp
+            .call_function::<Object>("loop", vec![0.into_value()])
+            .expect_err("should run out of fuel");
+        assert_eq!(err.limit, Some(LimitExceeded::Fuel));
+
+        // the fuel is per call
+        assert_eq!(interp.eval_str("1 + 1"), Ok(2));
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut interp = Interpreter::new();
+
+        assert_eq!(
+            interp.eval_str::<Object>("let x = ;"),
+            Err(Error::from(String::from(
+                "no prefix parse function for SEMICOLON found"
+            )))
+        );
+        assert_eq!(
+            interp.eval_str::<Object>("puts(99999999999999999999999999999999999999999999);"),
+            Err(Error::from(String::from(
+                "integer literal out of range: 99999999999999999999999999999999999999999999"
+            )))
+        );
+        let nested = format!("{}1{}", "(".repeat(5000), ")".repeat(5000));
+        assert_eq!(
+            interp.eval_str::<Object>(&nested),
+            Err(Error::from(String::from(
+                "nested more than 1000 levels deep"
+            )))
+        );
+        assert_eq!(
+            interp.eval_str::<Object>("fn() { y }"),
+            Err(Error::from(String::from("undefined variable y")))
+        );
+        assert_eq!(
+            interp.call_function::<Object>("f", vec![]),
+            Err(Error::from(Failure::not_found("f")))
+        );
+        assert_eq!(
+            interp.get_global::<i32>("f"),
+            Err(Error::from(Failure::not_found("f")))
+        );
+
+        // macros run on the evaluator, which stops at the frame limit
+        // rather than running out of native stack
+        let err = interp
+            .eval_str::<Object>("let m = macro() { let f = fn() { 1 + f() }; f() }; m()")
+            .unwrap_err();
+        assert_eq!(err.message, "stack overflow");
+
+        // a failed compile doesn't leave the interpreter inside a function
+        assert_eq!(interp.eval_str("let f = fn(a) { a }; f(true)"), Ok(true));
+        assert_eq!(
+            interp.call_function::<bool>("f", vec![1.into_value()]),
+            Err(Error::from(Failure::new(
+                Kind::Type,
+                "expected BOOLEAN, got INTEGER"
+            )))
+        );
+        assert_eq!(
+            interp.call_function::<u8>("f", vec![(-1).into_value()]),
+            Err(Error::from(Failure::new(
+                Kind::Range,
+                "-1 doesn't fit in u8"
+            )))
+        );
+        assert_eq!(
+            interp
+                .call_function::<Object>("f", vec![])
+                .map_err(|e| e.message),
+            Err(String::from("wrong number of arguments: want=1, got=0"))
+        );
+    }
+
+    #[test]
+    fn test_stack_traces() {
+        let mut interp = Interpreter::new();
+        interp
+            .eval_str::<()>("let inner = fn(x) { x / 0 }; let outer = fn(f) { 1 + f(1) };")
+            .expect("should define functions");
+
+        let err = interp
+            .eval_str::<Object>("outer(inner)")
+            .expect_err("should divide by zero");
+        assert_eq!(err.message, "division by zero");
+        assert_eq!(
+            err.trace_lines(),
+            [
+                "in inner at x / 0",
+                "in outer at f(1)",
+                "in <main> at outer(inner)"
+            ]
+        );
+
+        // calls from the host have no call site of their own
+        let err = interp
+            .call_function::<Object>("outer", vec![Object::Null])
+            .expect_err("should fail to call null");
+        assert_eq!(err.message, "calling non-function: NULL");
+        assert_eq!(err.trace_lines(), ["in outer at f(1)", "in <main>"]);
+    }
+}
@@ -1,5 +1,5 @@
 use crate::token;
-use crate::token::{Token, TokenKind};
+use crate::token::{Comment, Token, TokenKind};
 use std::path::Path;
 
 pub struct Lexer<'a> {
@@ -11,19 +11,21 @@ pub struct Lexer<'a> {
     curr_col: usize,
     repl: bool,
     path: Option<&'a Path>,
+    comments: Vec<Comment<'a>>,
 }
 
 impl<'a> Lexer<'a> {
     pub fn new(s: &str, repl: bool, path: Option<&'a Path>) -> Lexer<'a> {
         let mut l = Lexer {
-            input: s.trim().to_string().chars().collect(),
+            input: s.trim_end().chars().collect(),
             position: 0,
             read_position: 0,
             ch: ' ',
             curr_line: 1,
-            curr_col: 1,
+            curr_col: 0,
             repl,
             path,
+            comments: Vec::new(),
         };
         l.read_char();
         l
@@ -44,12 +46,7 @@ impl<'a> Lexer<'a> {
     }
 
     fn read_char(&mut self) {
-        if self.read_position >= self.input.len() {
-            self.ch = '\0';
-        } else {
-            self.ch = self.input[self.read_position];
-        }
-
+        // the row and column follow the character we are moving onto
         if self.ch == '\n' {
             self.curr_line += 1;
             self.curr_col = 1;
@@ -57,18 +54,19 @@ impl<'a> Lexer<'a> {
             self.curr_col += 1;
         }
 
+        if self.read_position >= self.input.len() {
+            self.ch = '\0';
+        } else {
+            self.ch = self.input[self.read_position];
+        }
+
         self.position = self.read_position;
         self.read_position += 1;
     }
 
-    fn read_number(&mut self) -> Token<'a> {
-        let position = self.position;
-
-        while self.ch.is_ascii_digit() {
-            self.read_char();
-        }
-
-        let loc = if self.repl {
+    /// Where `self.ch` sits in the source, or nothing in the repl.
+    fn location(&self) -> Option<token::Location<'a>> {
+        if self.repl {
             None
         } else {
             Some(token::Location::new(
@@ -76,36 +74,58 @@ impl<'a> Lexer<'a> {
                 self.curr_col,
                 self.path.expect("There is a problem with the file"),
             ))
-        };
+        }
+    }
 
-        Token::new(
-            TokenKind::INT(
-                self.input[position..self.position]
-                    .iter()
-                    .collect::<String>()
-                    .parse::<i128>()
-                    .unwrap(),
-            ),
-            loc,
-        )
+    fn read_comment(&mut self, loc: Option<token::Location<'a>>) {
+        // skip the two slashes
+        self.read_char();
+        self.read_char();
+        let position = self.position;
+
+        while self.ch != '\n' && self.ch != '\0' {
+            self.read_char();
+        }
+
+        let text = self.input[position..self.position]
+            .iter()
+            .collect::<String>()
+            .trim()
+            .to_string();
+
+        self.comments.push(Comment::new(text, loc));
     }
 
-    fn read_identifier(&mut self) -> Token<'a> {
+    pub fn comments(&self) -> &[Comment<'a>] {
+        &self.comments
+    }
+
+    /// Reads an integer literal. One too big for an `i128` is `ILLEGAL`,
+    /// with its digits as the literal so the parser can say what's wrong.
+    fn read_number(&mut self, loc: Option<token::Location<'a>>) -> Token<'a> {
         let position = self.position;
 
-        while self.ch.is_alphabetic() || self.ch == '_' {
+        while self.ch.is_ascii_digit() {
             self.read_char();
         }
 
-        let loc = if self.repl {
-            None
-        } else {
-            Some(token::Location::new(
-                self.curr_line,
-                self.curr_col,
-                self.path.expect("There is a problem with the file"),
-            ))
-        };
+        let digits: String = self.input[position..self.position].iter().collect();
+        match digits.parse::<i128>() {
+            Ok(value) => Token::new(TokenKind::INT(value), loc),
+            Err(_) => {
+                let mut token = Token::new(TokenKind::ILLEGAL, loc);
+                token.literal = digits;
+                token
+            }
+        }
+    }
+
+    fn read_identifier(&mut self, loc: Option<token::Location<'a>>) -> Token<'a> {
+        let position = self.position;
+
+        while self.ch.is_alphabetic() || self.ch == '_' {
+            self.read_char();
+        }
 
         Token::new(
             token::lookup_ident(
@@ -118,17 +138,45 @@ impl<'a> Lexer<'a> {
         )
     }
 
+    /// Reads a string up to its closing quote, which is left as the current
+    /// character. Strings can't span lines or escape anything.
+    fn read_string(&mut self, loc: Option<token::Location<'a>>) -> Token<'a> {
+        let position = self.position + 1;
+
+        loop {
+            self.read_char();
+            match self.ch {
+                '"' => break,
+                '\n' | '\0' => return Token::new(TokenKind::ILLEGAL, loc),
+                _ => (),
+            }
+        }
+
+        Token::new(
+            TokenKind::STRING(self.input[position..self.position].iter().collect()),
+            loc,
+        )
+    }
+
+    /// `op`, or `with_next` if `next` follows, as in `+=` or `&&`.
+    fn followed_by(
+        &mut self,
+        next: char,
+        op: TokenKind,
+        with_next: TokenKind,
+        loc: Option<token::Location<'a>>,
+    ) -> Token<'a> {
+        if self.peek_char() == next {
+            self.read_char();
+            Token::new(with_next, loc)
+        } else {
+            Token::new(op, loc)
+        }
+    }
+
     pub fn next_token(&mut self) -> Token<'a> {
         self.skip_whitespace();
-        let loc = if self.repl {
-            None
-        } else {
-            Some(token::Location::new(
-                self.curr_line,
-                self.curr_col,
-                self.path.expect("There is a problem with the file"),
-            ))
-        };
+        let loc = self.location();
 
         let tok = match self.ch {
             '=' => {
@@ -143,9 +191,21 @@ impl<'a> Lexer<'a> {
             '(' => Token::new(TokenKind::LPAREN, loc),
             ')' => Token::new(TokenKind::RPAREN, loc),
             ',' => Token::new(TokenKind::COMMA, loc),
-            '+' => Token::new(TokenKind::PLUS, loc),
+            '.' => {
+                if self.peek_char() == '.' {
+                    self.read_char();
+                    Token::new(TokenKind::DOTDOT, loc)
+                } else {
+                    Token::new(TokenKind::DOT, loc)
+                }
+            }
+            '"' => self.read_string(loc),
+            '+' => self.followed_by('=', TokenKind::PLUS, TokenKind::PLUSEQ, loc),
             '{' => Token::new(TokenKind::LBRACE, loc),
             '}' => Token::new(TokenKind::RBRACE, loc),
+            '[' => Token::new(TokenKind::LBRACKET, loc),
+            ']' => Token::new(TokenKind::RBRACKET, loc),
+            ':' => Token::new(TokenKind::COLON, loc),
             '!' => {
                 if self.peek_char() == '=' {
                     self.read_char();
@@ -154,17 +214,36 @@ impl<'a> Lexer<'a> {
                     Token::new(TokenKind::BANG, loc)
                 }
             }
-            '-' => Token::new(TokenKind::MINUS, loc),
-            '*' => Token::new(TokenKind::ASTERISK, loc),
-            '/' => Token::new(TokenKind::SLASH, loc),
-            '<' => Token::new(TokenKind::LT, loc),
-            '>' => Token::new(TokenKind::GT, loc),
+            '-' => self.followed_by('=', TokenKind::MINUS, TokenKind::MINUSEQ, loc),
+            '*' => self.followed_by('=', TokenKind::ASTERISK, TokenKind::ASTERISKEQ, loc),
+            '/' => {
+                if self.peek_char() == '/' {
+                    self.read_comment(loc);
+                    return self.next_token();
+                }
+                self.followed_by('=', TokenKind::SLASH, TokenKind::SLASHEQ, loc)
+            }
+            '%' => Token::new(TokenKind::PERCENT, loc),
+            '<' if self.peek_char() == '<' => {
+                self.read_char();
+                Token::new(TokenKind::SHL, loc)
+            }
+            '>' if self.peek_char() == '>' => {
+                self.read_char();
+                Token::new(TokenKind::SHR, loc)
+            }
+            '<' => self.followed_by('=', TokenKind::LT, TokenKind::LTE, loc),
+            '>' => self.followed_by('=', TokenKind::GT, TokenKind::GTE, loc),
+            '&' => self.followed_by('&', TokenKind::AMPERSAND, TokenKind::AND, loc),
+            '|' => self.followed_by('|', TokenKind::PIPE, TokenKind::OR, loc),
+            '^' => Token::new(TokenKind::CARET, loc),
+            '~' => Token::new(TokenKind::TILDE, loc),
             '\0' => Token::new(TokenKind::EOF, loc),
             _ => {
                 if self.ch.is_alphabetic() || self.ch == '_' {
-                    return self.read_identifier();
+                    return self.read_identifier(loc);
                 } else if self.ch.is_ascii_digit() {
-                    return self.read_number();
+                    return self.read_number(loc);
                 } else {
                     Token::new(TokenKind::ILLEGAL, loc)
                 }
@@ -271,14 +350,13 @@ pub mod tests {
 
                 let l = Lexer::new(input, false, Some(path));
 
-                for (tt, tok) in test_arr.iter().zip(l.into_iter()) {
+                for (tt, tok) in test_arr.iter().zip(l) {
                     assert_eq!(tok.ttype, tt.ttype);
                     assert_eq!(tok.literal, tt.literal)
                 }
             }
             Err(e) => {
-                println!("Error reading file: {}", e);
-                assert!(false);
+                panic!("Error reading file: {}", e);
             }
         }
     }
@@ -382,9 +460,288 @@ pub mod tests {
 
         let l = Lexer::new(input, true, None);
 
-        for (tt, tok) in test_arr.iter().zip(l.into_iter()) {
+        for (tt, tok) in test_arr.iter().zip(l) {
             assert_eq!(tok.ttype, tt.ttype);
             assert_eq!(tok.literal, tt.literal)
         }
     }
+
+    #[test]
+    fn next_token_skips_comments() {
+        let input = "// leading\n\
+            let x = 10 / 2; // trailing\n\
+            //\n\
+            x";
+
+        let test_arr = [
+            Token::new(TokenKind::LET, None),
+            Token::new(TokenKind::IDENT(String::from("x")), None),
+            Token::new(TokenKind::ASSIGN, None),
+            Token::new(TokenKind::INT(10), None),
+            Token::new(TokenKind::SLASH, None),
+            Token::new(TokenKind::INT(2), None),
+            Token::new(TokenKind::SEMICOLON, None),
+            Token::new(TokenKind::IDENT(String::from("x")), None),
+            Token::new(TokenKind::EOF, None),
+        ];
+
+        let mut l = Lexer::new(input, true, None);
+
+        for tt in test_arr.iter() {
+            let tok = l.next_token();
+            assert_eq!(tok.ttype, tt.ttype);
+        }
+
+        let comments: Vec<&str> = l.comments().iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(comments, ["leading", "trailing", ""]);
+    }
+
+    #[test]
+    fn next_token_imports() {
+        let input = "import \"lib/util.my\"; util.max\n\"open";
+
+        let test_arr = [
+            Token::new(TokenKind::IMPORT, None),
+            Token::new(TokenKind::STRING(String::from("lib/util.my")), None),
+            Token::new(TokenKind::SEMICOLON, None),
+            Token::new(TokenKind::IDENT(String::from("util")), None),
+            Token::new(TokenKind::DOT, None),
+            Token::new(TokenKind::IDENT(String::from("max")), None),
+            Token::new(TokenKind::ILLEGAL, None),
+            Token::new(TokenKind::EOF, None),
+        ];
+
+        let mut l = Lexer::new(input, true, None);
+
+        for tt in test_arr.iter() {
+            let tok = l.next_token();
+            assert_eq!(tok.ttype, tt.ttype);
+        }
+    }
+
+    #[test]
+    fn next_token_out_of_range_integers() {
+        let input =
+            "170141183460469231731687303715884105727 170141183460469231731687303715884105728;";
+
+        let mut l = Lexer::new(input, true, None);
+
+        assert_eq!(l.next_token().ttype, TokenKind::INT(i128::MAX));
+        let tok = l.next_token();
+        assert_eq!(tok.ttype, TokenKind::ILLEGAL);
+        assert_eq!(tok.literal, "170141183460469231731687303715884105728");
+        assert_eq!(l.next_token().ttype, TokenKind::SEMICOLON);
+    }
+
+    #[test]
+    fn next_token_loops() {
+        let input = "while (go) { break; } for (i in 0..n) { continue; } lib.x";
+
+        let test_arr = [
+            Token::new(TokenKind::WHILE, None),
+            Token::new(TokenKind::LPAREN, None),
+            Token::new(TokenKind::IDENT(String::from("go")), None),
+            Token::new(TokenKind::RPAREN, None),
+            Token::new(TokenKind::LBRACE, None),
+            Token::new(TokenKind::BREAK, None),
+            Token::new(TokenKind::SEMICOLON, None),
+            Token::new(TokenKind::RBRACE, None),
+            Token::new(TokenKind::FOR, None),
+            Token::new(TokenKind::LPAREN, None),
+            Token::new(TokenKind::IDENT(String::from("i")), None),
+            Token::new(TokenKind::IN, None),
+            Token::new(TokenKind::INT(0), None),
+            Token::new(TokenKind::DOTDOT, None),
+            Token::new(TokenKind::IDENT(String::from("n")), None),
+            Token::new(TokenKind::RPAREN, None),
+            Token::new(TokenKind::LBRACE, None),
+            Token::new(TokenKind::CONTINUE, None),
+            Token::new(TokenKind::SEMICOLON, None),
+            Token::new(TokenKind::RBRACE, None),
+            Token::new(TokenKind::IDENT(String::from("lib")), None),
+            Token::new(TokenKind::DOT, None),
+            Token::new(TokenKind::IDENT(String::from("x")), None),
+            Token::new(TokenKind::EOF, None),
+        ];
+
+        let mut l = Lexer::new(input, true, None);
+
+        for tt in test_arr.iter() {
+            let tok = l.next_token();
+            assert_eq!(tok.ttype, tt.ttype);
+        }
+    }
+
+    #[test]
+    fn next_token_exceptions() {
+        let input = "try { throw \"bad\"; } catch (e) { e.kind } finally { }";
+
+        let test_arr = [
+            Token::new(TokenKind::TRY, None),
+            Token::new(TokenKind::LBRACE, None),
+            Token::new(TokenKind::THROW, None),
+            Token::new(TokenKind::STRING(String::from("bad")), None),
+            Token::new(TokenKind::SEMICOLON, None),
+            Token::new(TokenKind::RBRACE, None),
+            Token::new(TokenKind::CATCH, None),
+            Token::new(TokenKind::LPAREN, None),
+            Token::new(TokenKind::IDENT(String::from("e")), None),
+            Token::new(TokenKind::RPAREN, None),
+            Token::new(TokenKind::LBRACE, None),
+            Token::new(TokenKind::IDENT(String::from("e")), None),
+            Token::new(TokenKind::DOT, None),
+            Token::new(TokenKind::IDENT(String::from("kind")), None),
+            Token::new(TokenKind::RBRACE, None),
+            Token::new(TokenKind::FINALLY, None),
+            Token::new(TokenKind::LBRACE, None),
+            Token::new(TokenKind::RBRACE, None),
+            Token::new(TokenKind::EOF, None),
+        ];
+
+        let mut l = Lexer::new(input, true, None);
+
+        for tt in test_arr.iter() {
+            let tok = l.next_token();
+            assert_eq!(tok.ttype, tt.ttype);
+        }
+    }
+
+    #[test]
+    fn next_token_assignments() {
+        let input = "x = 1; x += 2; x -= y-=1; x *= 3; x /= 4 / 2;";
+
+        let test_arr = [
+            TokenKind::IDENT(String::from("x")),
+            TokenKind::ASSIGN,
+            TokenKind::INT(1),
+            TokenKind::SEMICOLON,
+            TokenKind::IDENT(String::from("x")),
+            TokenKind::PLUSEQ,
+            TokenKind::INT(2),
+            TokenKind::SEMICOLON,
+            TokenKind::IDENT(String::from("x")),
+            TokenKind::MINUSEQ,
+            TokenKind::IDENT(String::from("y")),
+            TokenKind::MINUSEQ,
+            TokenKind::INT(1),
+            TokenKind::SEMICOLON,
+            TokenKind::IDENT(String::from("x")),
+            TokenKind::ASTERISKEQ,
+            TokenKind::INT(3),
+            TokenKind::SEMICOLON,
+            TokenKind::IDENT(String::from("x")),
+            TokenKind::SLASHEQ,
+            TokenKind::INT(4),
+            TokenKind::SLASH,
+            TokenKind::INT(2),
+            TokenKind::SEMICOLON,
+            TokenKind::EOF,
+        ];
+
+        let mut l = Lexer::new(input, true, None);
+
+        for tt in test_arr.iter() {
+            assert_eq!(l.next_token().ttype, *tt);
+        }
+    }
+
+    #[test]
+    fn next_token_collections() {
+        let input = "[1, x][0]; {\"a\": 1}";
+
+        let test_arr = [
+            TokenKind::LBRACKET,
+            TokenKind::INT(1),
+            TokenKind::COMMA,
+            TokenKind::IDENT(String::from("x")),
+            TokenKind::RBRACKET,
+            TokenKind::LBRACKET,
+            TokenKind::INT(0),
+            TokenKind::RBRACKET,
+            TokenKind::SEMICOLON,
+            TokenKind::LBRACE,
+            TokenKind::STRING(String::from("a")),
+            TokenKind::COLON,
+            TokenKind::INT(1),
+            TokenKind::RBRACE,
+            TokenKind::EOF,
+        ];
+
+        let mut l = Lexer::new(input, true, None);
+
+        for tt in test_arr.iter() {
+            assert_eq!(l.next_token().ttype, *tt);
+        }
+    }
+
+    #[test]
+    fn next_token_logical_and_bitwise_operators() {
+        let input = "a && b || c <= 10 >= d % 2 & | <\n\
+            ~a ^ b << 2 >> 1 &&& |||";
+
+        let test_arr = [
+            TokenKind::IDENT(String::from("a")),
+            TokenKind::AND,
+            TokenKind::IDENT(String::from("b")),
+            TokenKind::OR,
+            TokenKind::IDENT(String::from("c")),
+            TokenKind::LTE,
+            TokenKind::INT(10),
+            TokenKind::GTE,
+            TokenKind::IDENT(String::from("d")),
+            TokenKind::PERCENT,
+            TokenKind::INT(2),
+            TokenKind::AMPERSAND,
+            TokenKind::PIPE,
+            TokenKind::LT,
+            TokenKind::TILDE,
+            TokenKind::IDENT(String::from("a")),
+            TokenKind::CARET,
+            TokenKind::IDENT(String::from("b")),
+            TokenKind::SHL,
+            TokenKind::INT(2),
+            TokenKind::SHR,
+            TokenKind::INT(1),
+            TokenKind::AND,
+            TokenKind::AMPERSAND,
+            TokenKind::OR,
+            TokenKind::PIPE,
+            TokenKind::EOF,
+        ];
+
+        let mut l = Lexer::new(input, true, None);
+
+        for tt in test_arr.iter() {
+            assert_eq!(l.next_token().ttype, *tt);
+        }
+    }
+
+    #[test]
+    fn next_token_locations() {
+        let path = std::path::Path::new("locations.my");
+        let input = "\nlet five = 5;\n  five > 10";
+
+        let expected = [
+            (2, 1),
+            (2, 5),
+            (2, 10),
+            (2, 12),
+            (2, 13),
+            (3, 3),
+            (3, 8),
+            (3, 10),
+        ];
+
+        let mut l = Lexer::new(input, false, Some(path));
+
+        for (row, col) in expected {
+            let tok = l.next_token();
+            assert_eq!(
+                tok.local(),
+                Some(&Location::new(row, col, path)),
+                "{:?}",
+                tok
+            );
+        }
+    }
 }
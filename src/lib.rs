@@ -0,0 +1,38 @@
+//! The Monkey programming language: a lexer and parser, a tree-walking
+//! evaluator, two virtual machines and a few backends. Hosts embedding the
+//! language only need `Interpreter`; the `plmmky` binary is built on the
+//! modules below.
+
+pub mod ast;
+pub mod astcache;
+pub mod code;
+pub mod compiler;
+#[cfg(test)]
+mod conformance;
+pub mod emit_c;
+pub mod emit_wat;
+pub mod error;
+pub mod evaluator;
+pub mod explore;
+pub mod formatter;
+pub mod gc;
+pub mod interpreter;
+pub mod lexer;
+pub mod lint;
+pub mod lsp;
+pub mod macros;
+pub mod module;
+pub mod object;
+pub mod optimizer;
+pub mod parser;
+pub mod regcode;
+pub mod regcompiler;
+pub mod regvm;
+pub mod repl;
+pub mod resolver;
+pub mod symbol_table;
+pub mod token;
+pub mod vm;
+
+pub use error::{Error, Failure, Kind};
+pub use interpreter::{FromValue, Interpreter, IntoValue};
@@ -0,0 +1,432 @@
+use crate::ast::{self, Block, Expression, Program, Statement};
+use crate::resolver::{self, Access, BindingKind};
+use crate::token::{Location, Token};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LintCode {
+    UnusedLet,
+    UnusedParameter,
+    Shadowing,
+    Undefined,
+    Unreachable,
+    UnusedImport,
+}
+
+impl LintCode {
+    pub const ALL: [LintCode; 6] = [
+        LintCode::UnusedLet,
+        LintCode::UnusedParameter,
+        LintCode::Shadowing,
+        LintCode::Undefined,
+        LintCode::Unreachable,
+        LintCode::UnusedImport,
+    ];
+
+    /// The stable code, which never changes once released.
+    pub fn code(&self) -> &'static str {
+        match self {
+            LintCode::UnusedLet => "L001",
+            LintCode::UnusedParameter => "L002",
+            LintCode::Shadowing => "L003",
+            LintCode::Undefined => "L004",
+            LintCode::Unreachable => "L005",
+            LintCode::UnusedImport => "L006",
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LintCode::UnusedLet => "unused-let",
+            LintCode::UnusedParameter => "unused-parameter",
+            LintCode::Shadowing => "shadowing",
+            LintCode::Undefined => "undefined",
+            LintCode::Unreachable => "unreachable",
+            LintCode::UnusedImport => "unused-import",
+        }
+    }
+
+    /// Looks a lint up by its code or its name.
+    pub fn lookup(s: &str) -> Option<LintCode> {
+        LintCode::ALL
+            .into_iter()
+            .find(|c| c.code().eq_ignore_ascii_case(s) || c.name() == s)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic<'a> {
+    pub code: LintCode,
+    pub message: String,
+    local: Option<Location<'a>>,
+    len: usize,
+}
+
+impl<'a> Diagnostic<'a> {
+    /// A diagnostic about `token`.
+    fn new(code: LintCode, message: String, token: &Token<'a>) -> Self {
+        Diagnostic {
+            code,
+            message,
+            local: token.local().cloned(),
+            len: token.source_len(),
+        }
+    }
+
+    pub fn local(&self) -> Option<&Location<'a>> {
+        self.local.as_ref()
+    }
+
+    /// How long the token it's about is in the source.
+    pub fn source_len(&self) -> usize {
+        self.len
+    }
+}
+
+impl fmt::Display for Diagnostic<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(l) = &self.local {
+            let (row, col, _) = l.get();
+            write!(f, "{}:{}: ", row, col)?;
+        }
+        write!(
+            f,
+            "{} {}: {}",
+            self.code.code(),
+            self.code.name(),
+            self.message
+        )
+    }
+}
+
+/// Runs every lint over `program`, skipping those in `allow`. Names
+/// starting with `_` are never reported as unused.
+pub fn lint<'a>(program: &Program<'a>, allow: &[LintCode]) -> Vec<Diagnostic<'a>> {
+    let mut out = Vec::new();
+    let r = resolver::resolve(program);
+
+    for (idx, b) in r.bindings.iter().enumerate() {
+        let name = b.name.value();
+        let token = b.name.token();
+
+        if b.kind == BindingKind::Builtin {
+            continue;
+        }
+
+        if r.uses(idx) == 0 && !name.starts_with('_') {
+            let (code, what) = match b.kind {
+                BindingKind::Let => (LintCode::UnusedLet, "binding"),
+                BindingKind::Parameter => (LintCode::UnusedParameter, "parameter"),
+                BindingKind::Import => (LintCode::UnusedImport, "import"),
+                BindingKind::Loop => (LintCode::UnusedLet, "loop variable"),
+                BindingKind::Catch => (LintCode::UnusedLet, "catch variable"),
+                BindingKind::Builtin => unreachable!("skipped above"),
+            };
+            out.push(Diagnostic::new(
+                code,
+                format!("unused {} `{}`", what, name),
+                token,
+            ));
+        }
+
+        if let Some(shadowed) = b.shadows {
+            let shadowed = &r.bindings[shadowed];
+            let message = match shadowed.name.token().local() {
+                _ if shadowed.kind == BindingKind::Builtin => {
+                    format!("`{}` shadows the builtin", name)
+                }
+                Some(l) => {
+                    let (row, col, _) = l.get();
+                    format!("`{}` shadows the binding declared at {}:{}", name, row, col)
+                }
+                None => format!("`{}` shadows the binding", name),
+            };
+            out.push(Diagnostic::new(LintCode::Shadowing, message, token));
+        }
+    }
+
+    for reference in &r.references {
+        if reference.target.is_none() {
+            let what = match reference.access {
+                Access::Read => "undefined identifier",
+                Access::Write | Access::ReadWrite => "assignment to undeclared",
+            };
+            out.push(Diagnostic::new(
+                LintCode::Undefined,
+                format!("{} `{}`", what, reference.name.value()),
+                reference.name.token(),
+            ));
+        }
+    }
+
+    unreachable_statements(&program.statements, &mut out);
+
+    out.retain(|d| !allow.contains(&d.code));
+    out.sort_by_key(|d| d.local.as_ref().map(|l| (*l.get().0, *l.get().1)));
+    out
+}
+
+/// Whether running `stmt` always ends in a `return`.
+fn always_returns(stmt: &Statement) -> bool {
+    ast::deeper(|| match stmt {
+        Statement::Return(_) => true,
+        Statement::Expression(i) => match i.expression() {
+            Some(Expression::If(e)) => {
+                block_returns(e.consequence()) && e.alternative().is_some_and(block_returns)
+            }
+            Some(Expression::Try(e)) => {
+                (block_returns(e.body()) && block_returns(e.handler()))
+                    || e.finally().is_some_and(block_returns)
+            }
+            _ => false,
+        },
+        // a loop's body might not run at all
+        Statement::Let(_)
+        | Statement::Import(_)
+        | Statement::While(_)
+        | Statement::For(_)
+        | Statement::Break(_)
+        | Statement::Continue(_)
+        | Statement::Throw(_) => false,
+    })
+}
+
+fn block_returns(block: &Block) -> bool {
+    block.statements.iter().any(always_returns)
+}
+
+fn unreachable_statements<'a>(statements: &[Statement<'a>], out: &mut Vec<Diagnostic<'a>>) {
+    ast::deeper(|| {
+        // what the statements so far always end in
+        let mut jumped: Option<&str> = None;
+
+        for stmt in statements {
+            if let Some(jump) = jumped {
+                out.push(Diagnostic::new(
+                    LintCode::Unreachable,
+                    format!("unreachable statement after {}", jump),
+                    stmt.token(),
+                ));
+                // one report per block is enough
+                break;
+            }
+
+            let expr = match stmt {
+                Statement::Let(i) => i.value(),
+                Statement::Return(i) => i.return_value(),
+                Statement::Expression(i) => i.expression(),
+                Statement::While(i) => {
+                    unreachable_statements(&i.body().statements, out);
+                    Some(i.condition())
+                }
+                Statement::For(i) => {
+                    unreachable_in_expression(i.start(), out);
+                    unreachable_statements(&i.body().statements, out);
+                    i.end()
+                }
+                Statement::Throw(i) => Some(i.value()),
+                Statement::Import(_) | Statement::Break(_) | Statement::Continue(_) => None,
+            };
+            if let Some(e) = expr {
+                unreachable_in_expression(e, out);
+            }
+
+            jumped = match stmt {
+                Statement::Break(_) => Some("break"),
+                Statement::Continue(_) => Some("continue"),
+                Statement::Throw(_) => Some("throw"),
+                _ if always_returns(stmt) => Some("return"),
+                _ => None,
+            };
+        }
+    })
+}
+
+fn unreachable_in_expression<'a>(e: &Expression<'a>, out: &mut Vec<Diagnostic<'a>>) {
+    ast::deeper(|| match e {
+        Expression::Identifier(_)
+        | Expression::Integer(_)
+        | Expression::Boolean(_)
+        | Expression::String(_) => {}
+        Expression::Prefix(i) => unreachable_in_expression(i.right(), out),
+        Expression::Infix(i) => {
+            unreachable_in_expression(i.left(), out);
+            unreachable_in_expression(i.right(), out);
+        }
+        Expression::If(i) => {
+            unreachable_in_expression(i.condition(), out);
+            unreachable_statements(&i.consequence().statements, out);
+            if let Some(alt) = i.alternative() {
+                unreachable_statements(&alt.statements, out);
+            }
+        }
+        Expression::Try(i) => {
+            for block in i.blocks() {
+                unreachable_statements(&block.statements, out);
+            }
+        }
+        Expression::Function(i) | Expression::Macro(i) => {
+            unreachable_statements(&i.body().statements, out)
+        }
+        Expression::Call(i) => {
+            unreachable_in_expression(i.function(), out);
+            for arg in i.arguments() {
+                unreachable_in_expression(arg, out);
+            }
+        }
+        Expression::Array(i) => {
+            for element in i.elements() {
+                unreachable_in_expression(element, out);
+            }
+        }
+        Expression::Hash(i) => {
+            for (key, value) in i.pairs() {
+                unreachable_in_expression(key, out);
+                unreachable_in_expression(value, out);
+            }
+        }
+        Expression::Index(i) => {
+            unreachable_in_expression(i.left(), out);
+            unreachable_in_expression(i.index(), out);
+        }
+        Expression::Member(i) => unreachable_in_expression(i.object(), out),
+        Expression::Assign(i) => unreachable_in_expression(i.value(), out),
+        Expression::IndexAssign(i) => {
+            unreachable_in_expression(i.target().left(), out);
+            unreachable_in_expression(i.target().index(), out);
+            unreachable_in_expression(i.value(), out);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn run(input: &str, allow: &[LintCode]) -> Vec<String> {
+        let path = std::path::Path::new("lint.my");
+        let l = Lexer::new(input, false, Some(path));
+        let mut p = Parser::new(l);
+        let program = p.parse_program().expect("Program should be Some here");
+        assert_eq!(p.errors().len(), 0);
+
+        lint(&program, allow)
+            .iter()
+            .map(|d| d.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_lint_reports_each_kind() {
+        let input = "let x = 1;\n\
+            let f = fn(x, y) {\n\
+            return x;\n\
+            z;\n\
+            };\n\
+            f(1, 2);";
+
+        assert_eq!(
+            run(input, &[]),
+            [
+                "1:5: L001 unused-let: unused binding `x`",
+                "2:12: L003 shadowing: `x` shadows the binding declared at 1:5",
+                "2:15: L002 unused-parameter: unused parameter `y`",
+                "4:1: L004 undefined: undefined identifier `z`",
+                "4:1: L005 unreachable: unreachable statement after return",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lint_knows_builtins() {
+        let input = "puts(1);\n\
+            let stats = gc_stats();\n\
+            let f = fn(puts) { puts };\n\
+            f(stats.collections);";
+
+        assert_eq!(
+            run(input, &[]),
+            ["3:12: L003 shadowing: `puts` shadows the builtin"]
+        );
+    }
+
+    #[test]
+    fn test_lint_unused_imports() {
+        let input = "import \"lib/math.my\";\n\
+            import \"util.my\";\n\
+            math.max(1, 2);";
+
+        assert_eq!(
+            run(input, &[]),
+            ["2:8: L006 unused-import: unused import `util`"]
+        );
+    }
+
+    #[test]
+    fn test_lint_loops() {
+        let input = "for (i in 0..3) {\n\
+            if (true) { break; }\n\
+            continue;\n\
+            1;\n\
+            }";
+
+        assert_eq!(
+            run(input, &[]),
+            [
+                "1:6: L001 unused-let: unused loop variable `i`",
+                "4:1: L005 unreachable: unreachable statement after continue",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lint_exceptions() {
+        let input = "let f = fn(x) {\n\
+            try { return x / 0; } catch (e) { throw e; 1; } finally { x; }\n\
+            };\n\
+            try { f(1) } catch (unused) { 0 }";
+
+        assert_eq!(
+            run(input, &[]),
+            [
+                "2:44: L005 unreachable: unreachable statement after throw",
+                "4:21: L001 unused-let: unused catch variable `unused`",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lint_assignments() {
+        let input = "let total = 0;\n\
+            let count = 0;\n\
+            count = 1;\n\
+            total += 2;\n\
+            missing = 3;";
+
+        assert_eq!(
+            run(input, &[]),
+            [
+                "2:5: L001 unused-let: unused binding `count`",
+                "5:1: L004 undefined: assignment to undeclared `missing`",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lint_allow_list_and_clean_programs() {
+        let input = "let _unused = 1;\n\
+            let fact = fn(n) { if (n < 2) { return 1; } else { return n * fact(n - 1); } };\n\
+            let n = fact(5);\n\
+            n;";
+
+        assert!(run(input, &[]).is_empty());
+        assert!(run("let a = b;", &[LintCode::UnusedLet, LintCode::Undefined]).is_empty());
+        assert_eq!(LintCode::lookup("l004"), Some(LintCode::Undefined));
+        assert_eq!(
+            LintCode::lookup("unused-parameter"),
+            Some(LintCode::UnusedParameter)
+        );
+    }
+}
@@ -0,0 +1,770 @@
+use crate::ast::{Block, Expression, Program, Statement};
+use crate::formatter::{self, FormatConfig};
+use crate::lexer::Lexer;
+use crate::lint;
+use crate::parser::{ParseError, Parser};
+use crate::resolver::{self, BindingKind, Resolution};
+use crate::token::{Location, Token, TokenKind};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+const SEMANTIC_TOKEN_TYPES: [&str; 6] = [
+    "keyword", "variable", "number", "operator", "comment", "string",
+];
+
+// LSP `SymbolKind`s
+const SYMBOL_FUNCTION: u32 = 12;
+const SYMBOL_VARIABLE: u32 = 13;
+
+/// Serves the language server protocol over `reader` and `writer` until the
+/// client sends `exit`. Returns the exit code the process should use: 0 if
+/// the client asked us to shut down first, 1 otherwise.
+pub fn run<R: BufRead, W: Write>(mut reader: R, mut writer: W) -> io::Result<i32> {
+    let mut server = Server::default();
+
+    while let Some(body) = read_message(&mut reader)? {
+        let responses = match serde_json::from_slice::<Value>(&body) {
+            Ok(msg) => server.handle(&msg),
+            Err(e) => vec![error_response(Value::Null, -32700, &e.to_string())],
+        };
+
+        for r in responses {
+            write_message(&mut writer, &r)?;
+        }
+
+        if server.exited {
+            break;
+        }
+    }
+
+    Ok(if server.shutdown { 0 } else { 1 })
+}
+
+/// Reads one `Content-Length` framed message, or `None` at end of input.
+fn read_message<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut length = None;
+
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+
+    let length = match length {
+        Some(l) => l,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "message without a Content-Length header",
+            ))
+        }
+    };
+
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    Ok(Some(body))
+}
+
+fn write_message<W: Write>(writer: &mut W, msg: &Value) -> io::Result<()> {
+    let body = msg.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    writer.flush()
+}
+
+fn response(id: Value, result: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "result": result })
+}
+
+fn error_response(id: Value, code: i64, message: &str) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+fn notification(method: &str, params: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "method": method, "params": params })
+}
+
+#[derive(Default)]
+struct Server {
+    documents: HashMap<String, String>,
+    shutdown: bool,
+    exited: bool,
+}
+
+impl Server {
+    fn handle(&mut self, msg: &Value) -> Vec<Value> {
+        let method = match msg.get("method").and_then(Value::as_str) {
+            Some(m) => m,
+            // a response to something we never send
+            None => return vec![],
+        };
+        let params = msg.get("params").cloned().unwrap_or(Value::Null);
+
+        match msg.get("id") {
+            Some(id) => vec![self.request(id.clone(), method, &params)],
+            None => self.notification(method, &params),
+        }
+    }
+
+    fn request(&mut self, id: Value, method: &str, params: &Value) -> Value {
+        let result = match method {
+            "initialize" => json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "definitionProvider": true,
+                    "hoverProvider": true,
+                    "documentSymbolProvider": true,
+                    "semanticTokensProvider": {
+                        "legend": { "tokenTypes": SEMANTIC_TOKEN_TYPES, "tokenModifiers": [] },
+                        "full": true,
+                    },
+                },
+                "serverInfo": { "name": "plmmky" },
+            }),
+            "shutdown" => {
+                self.shutdown = true;
+                Value::Null
+            }
+            "textDocument/definition" | "textDocument/hover" => {
+                let (uri, text) = match self.document(params) {
+                    Some(d) => d,
+                    None => return response(id, Value::Null),
+                };
+                let line = params["position"]["line"].as_u64().unwrap_or(0) as usize;
+                let character = params["position"]["character"].as_u64().unwrap_or(0) as usize;
+
+                if method == "textDocument/definition" {
+                    definition(uri, text, line, character)
+                } else {
+                    hover(uri, text, line, character)
+                }
+            }
+            "textDocument/documentSymbol" => match self.document(params) {
+                Some((uri, text)) => document_symbols(uri, text),
+                None => Value::Null,
+            },
+            "textDocument/semanticTokens/full" => match self.document(params) {
+                Some((uri, text)) => semantic_tokens(uri, text),
+                None => Value::Null,
+            },
+            _ => return error_response(id, -32601, &format!("unknown method {}", method)),
+        };
+
+        response(id, result)
+    }
+
+    fn notification(&mut self, method: &str, params: &Value) -> Vec<Value> {
+        let uri = params["textDocument"]["uri"]
+            .as_str()
+            .unwrap_or("")
+            .to_string();
+
+        match method {
+            "exit" => {
+                self.exited = true;
+                vec![]
+            }
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or("");
+                self.documents.insert(uri.clone(), text.to_string());
+                vec![diagnostics(&uri, text)]
+            }
+            "textDocument/didChange" => {
+                // we only ask for full syncs, so the last change is the document
+                let changes = params["contentChanges"].as_array();
+                let text = changes
+                    .and_then(|c| c.last())
+                    .and_then(|c| c["text"].as_str())
+                    .unwrap_or("");
+                self.documents.insert(uri.clone(), text.to_string());
+                vec![diagnostics(&uri, text)]
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                vec![notification(
+                    "textDocument/publishDiagnostics",
+                    json!({ "uri": uri, "diagnostics": [] }),
+                )]
+            }
+            _ => vec![],
+        }
+    }
+
+    fn document<'s>(&'s self, params: &'s Value) -> Option<(&'s str, &'s str)> {
+        let uri = params["textDocument"]["uri"].as_str()?;
+        let text = self.documents.get(uri)?;
+        Some((uri, text))
+    }
+}
+
+fn position(l: &Location) -> Value {
+    let (row, col, _) = l.get();
+    json!({ "line": row - 1, "character": col - 1 })
+}
+
+fn range(l: &Location, len: usize) -> Value {
+    let (row, col, _) = l.get();
+    json!({
+        "start": { "line": row - 1, "character": col - 1 },
+        "end": { "line": row - 1, "character": col - 1 + len },
+    })
+}
+
+fn token_range(t: &Token) -> Value {
+    match t.local() {
+        Some(l) => range(l, t.source_len()),
+        None => {
+            json!({ "start": { "line": 0, "character": 0 }, "end": { "line": 0, "character": 0 } })
+        }
+    }
+}
+
+fn token_contains(t: &Token, line: usize, character: usize) -> bool {
+    match t.local() {
+        Some(l) => {
+            let (row, col, _) = l.get();
+            *row == line + 1 && *col <= character + 1 && character + 1 < col + t.source_len()
+        }
+        None => false,
+    }
+}
+
+fn parse<'a>(text: &str, path: &'a Path) -> (Program<'a>, Vec<ParseError<'a>>) {
+    let l = Lexer::new(text, false, Some(path));
+    let mut p = Parser::new(l);
+    let program = p.parse_program().unwrap_or_default();
+    let errors = p.errors().to_vec();
+    (program, errors)
+}
+
+fn diagnostics(uri: &str, text: &str) -> Value {
+    let path = Path::new(uri);
+    let (program, errors) = parse(text, path);
+
+    let mut diagnostics: Vec<Value> = errors
+        .iter()
+        .map(|e| {
+            let range = match e.local() {
+                Some(l) => range(l, e.source_len()),
+                None => range(&Location::new(1, 1, path), 0),
+            };
+            json!({ "range": range, "severity": 1, "source": "plmmky", "message": e.message })
+        })
+        .collect();
+
+    // lints on a half parsed program would mostly be noise
+    if errors.is_empty() {
+        for d in lint::lint(&program, &[]) {
+            let range = match d.local() {
+                Some(l) => range(l, d.source_len()),
+                None => range(&Location::new(1, 1, path), 0),
+            };
+            diagnostics.push(json!({
+                "range": range,
+                "severity": 2,
+                "code": d.code.code(),
+                "source": "plmmky",
+                "message": d.message,
+            }));
+        }
+    }
+
+    notification(
+        "textDocument/publishDiagnostics",
+        json!({ "uri": uri, "diagnostics": diagnostics }),
+    )
+}
+
+/// The binding whose name, or one of whose uses, is at `line`/`character`.
+fn binding_at(r: &Resolution, line: usize, character: usize) -> Option<usize> {
+    for reference in &r.references {
+        if token_contains(reference.name.token(), line, character) {
+            return reference.target;
+        }
+    }
+
+    r.bindings
+        .iter()
+        .position(|b| token_contains(b.name.token(), line, character))
+}
+
+fn definition(uri: &str, text: &str, line: usize, character: usize) -> Value {
+    let path = Path::new(uri);
+    let (program, _) = parse(text, path);
+    let r = resolver::resolve(&program);
+
+    match binding_at(&r, line, character) {
+        Some(idx) if r.bindings[idx].kind != BindingKind::Builtin => {
+            json!({ "uri": uri, "range": token_range(r.bindings[idx].name.token()) })
+        }
+        _ => Value::Null,
+    }
+}
+
+fn hover(uri: &str, text: &str, line: usize, character: usize) -> Value {
+    let path = Path::new(uri);
+    let (program, _) = parse(text, path);
+    let r = resolver::resolve(&program);
+
+    let binding = match binding_at(&r, line, character) {
+        Some(idx) => r.bindings[idx],
+        None => return Value::Null,
+    };
+
+    let code = match (binding.kind, binding.value) {
+        (BindingKind::Let, Some(value)) => format!(
+            "let {} = {};",
+            binding.name.value(),
+            formatter::format_expression(value, &FormatConfig::default())
+        ),
+        (BindingKind::Let, None) => format!("let {}", binding.name.value()),
+        (BindingKind::Parameter, _) => format!("(parameter) {}", binding.name.value()),
+        // the name of an import is its path token
+        (BindingKind::Import, _) => format!("import \"{}\";", binding.name.token().literal),
+        (BindingKind::Loop, _) => format!("(loop variable) {}", binding.name.value()),
+        (BindingKind::Catch, _) => format!("(catch variable) {}", binding.name.value()),
+        (BindingKind::Builtin, _) => format!("(builtin) {}", binding.name.value()),
+    };
+
+    json!({
+        "contents": { "kind": "markdown", "value": format!("```monkey\n{}\n```", code) },
+        "range": token_range(binding.name.token()),
+    })
+}
+
+fn block_last_token<'p, 'a>(block: &'p Block<'a>) -> &'p Token<'a> {
+    match block.statements.last() {
+        Some(s) => statement_last_token(s),
+        None => block.token(),
+    }
+}
+
+fn statement_last_token<'p, 'a>(stmt: &'p Statement<'a>) -> &'p Token<'a> {
+    let expr = match stmt {
+        Statement::Let(i) => i.value(),
+        Statement::Return(i) => i.return_value(),
+        Statement::Expression(i) => i.expression(),
+        Statement::Import(i) => return i.name().token(),
+        Statement::While(i) => return block_last_token(i.body()),
+        Statement::For(i) => return block_last_token(i.body()),
+        Statement::Throw(i) => Some(i.value()),
+        Statement::Break(_) | Statement::Continue(_) => None,
+    };
+
+    match expr {
+        Some(e) => expression_last_token(e),
+        None => stmt.token(),
+    }
+}
+
+/// The last token we still have for `e`; closing brackets aren't kept.
+fn expression_last_token<'p, 'a>(e: &'p Expression<'a>) -> &'p Token<'a> {
+    match e {
+        Expression::Identifier(_)
+        | Expression::Integer(_)
+        | Expression::Boolean(_)
+        | Expression::String(_) => e.token(),
+        Expression::Prefix(i) => expression_last_token(i.right()),
+        Expression::Infix(i) => expression_last_token(i.right()),
+        Expression::If(i) => match i.alternative() {
+            Some(alt) => block_last_token(alt),
+            None => block_last_token(i.consequence()),
+        },
+        Expression::Try(i) => match i.finally() {
+            Some(finally) => block_last_token(finally),
+            None => block_last_token(i.handler()),
+        },
+        Expression::Function(i) | Expression::Macro(i) => block_last_token(i.body()),
+        Expression::Call(i) => match i.arguments().last() {
+            Some(arg) => expression_last_token(arg),
+            None => expression_last_token(i.function()),
+        },
+        Expression::Array(i) => match i.elements().last() {
+            Some(element) => expression_last_token(element),
+            None => e.token(),
+        },
+        Expression::Hash(i) => match i.pairs().last() {
+            Some((_, value)) => expression_last_token(value),
+            None => e.token(),
+        },
+        Expression::Index(i) => expression_last_token(i.index()),
+        Expression::Member(i) => i.name().token(),
+        Expression::Assign(i) => expression_last_token(i.value()),
+        Expression::IndexAssign(i) => expression_last_token(i.value()),
+    }
+}
+
+fn symbols(statements: &[Statement]) -> Vec<Value> {
+    let mut out = Vec::new();
+
+    for stmt in statements {
+        let let_stmt = match stmt {
+            Statement::Let(i) => i,
+            _ => continue,
+        };
+        let name = match let_stmt.name() {
+            Some(n) => n,
+            None => continue,
+        };
+
+        let (kind, children) = match let_stmt.value() {
+            Some(Expression::Function(f)) => (SYMBOL_FUNCTION, symbols(&f.body().statements)),
+            _ => (SYMBOL_VARIABLE, vec![]),
+        };
+
+        let start = let_stmt.token().local().map(position);
+        let end = statement_last_token(stmt);
+        let end = end.local().map(|l| {
+            let (row, col, _) = l.get();
+            json!({ "line": row - 1, "character": col - 1 + end.source_len() })
+        });
+
+        out.push(json!({
+            "name": name.value(),
+            "kind": kind,
+            "range": { "start": start, "end": end },
+            "selectionRange": token_range(name.token()),
+            "children": children,
+        }));
+    }
+
+    out
+}
+
+fn document_symbols(uri: &str, text: &str) -> Value {
+    let path = Path::new(uri);
+    let (program, _) = parse(text, path);
+    Value::Array(symbols(&program.statements))
+}
+
+/// The index in `SEMANTIC_TOKEN_TYPES` of how to highlight `t`. Every kind
+/// is listed, so a new one doesn't build until it's been given a type.
+fn semantic_token_type(t: &TokenKind) -> Option<u32> {
+    match t {
+        TokenKind::FUNCTION
+        | TokenKind::LET
+        | TokenKind::TRUE
+        | TokenKind::FALSE
+        | TokenKind::IF
+        | TokenKind::ELSE
+        | TokenKind::RETURN
+        | TokenKind::IMPORT
+        | TokenKind::WHILE
+        | TokenKind::FOR
+        | TokenKind::IN
+        | TokenKind::BREAK
+        | TokenKind::CONTINUE
+        | TokenKind::TRY
+        | TokenKind::CATCH
+        | TokenKind::FINALLY
+        | TokenKind::THROW
+        | TokenKind::MACRO => Some(0),
+        TokenKind::IDENT(_) => Some(1),
+        TokenKind::INT(_) => Some(2),
+        TokenKind::ASSIGN
+        | TokenKind::PLUS
+        | TokenKind::MINUS
+        | TokenKind::BANG
+        | TokenKind::ASTERISK
+        | TokenKind::SLASH
+        | TokenKind::PERCENT
+        | TokenKind::PLUSEQ
+        | TokenKind::MINUSEQ
+        | TokenKind::ASTERISKEQ
+        | TokenKind::SLASHEQ
+        | TokenKind::LT
+        | TokenKind::GT
+        | TokenKind::LTE
+        | TokenKind::GTE
+        | TokenKind::EQ
+        | TokenKind::NEQ
+        | TokenKind::AND
+        | TokenKind::OR
+        | TokenKind::AMPERSAND
+        | TokenKind::PIPE
+        | TokenKind::CARET
+        | TokenKind::TILDE
+        | TokenKind::SHL
+        | TokenKind::SHR
+        | TokenKind::DOTDOT => Some(3),
+        TokenKind::STRING(_) => Some(5),
+        TokenKind::COMMA
+        | TokenKind::COLON
+        | TokenKind::SEMICOLON
+        | TokenKind::DOT
+        | TokenKind::LPAREN
+        | TokenKind::RPAREN
+        | TokenKind::LBRACE
+        | TokenKind::RBRACE
+        | TokenKind::LBRACKET
+        | TokenKind::RBRACKET
+        | TokenKind::ILLEGAL
+        | TokenKind::EOF => None,
+    }
+}
+
+fn semantic_tokens(uri: &str, text: &str) -> Value {
+    let path = Path::new(uri);
+    let mut l = Lexer::new(text, false, Some(path));
+
+    // (row, col, length, type), all 1-based like `Location`
+    let mut found: Vec<(usize, usize, usize, u32)> = Vec::new();
+
+    loop {
+        let tok = l.next_token();
+        if tok.ttype == TokenKind::EOF {
+            break;
+        }
+
+        if let (Some(kind), Some(loc)) = (semantic_token_type(&tok.ttype), tok.local()) {
+            let (row, col, _) = loc.get();
+            found.push((*row, *col, tok.source_len(), kind));
+        }
+    }
+
+    let lines: Vec<&str> = text.lines().collect();
+    for c in l.comments() {
+        if let Some(loc) = c.local() {
+            let (row, col, _) = loc.get();
+            let line_len = lines.get(row - 1).map(|s| s.chars().count()).unwrap_or(0);
+            found.push((*row, *col, line_len + 1 - col, 4));
+        }
+    }
+
+    found.sort();
+
+    let mut data = Vec::with_capacity(found.len() * 5);
+    let (mut prev_row, mut prev_col) = (1, 1);
+    for (row, col, len, kind) in found {
+        let delta_start = if row == prev_row {
+            col - prev_col
+        } else {
+            col - 1
+        };
+        data.extend([row - prev_row, delta_start, len, kind as usize, 0]);
+        prev_row = row;
+        prev_col = col;
+    }
+
+    json!({ "data": data })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URI: &str = "file:///test.my";
+
+    fn frame(msg: Value) -> String {
+        let body = msg.to_string();
+        format!("Content-Length: {}\r\n\r\n{}", body.len(), body)
+    }
+
+    /// Runs the server over a scripted session, returning its replies.
+    fn session(messages: Vec<Value>) -> (i32, Vec<Value>) {
+        let input: String = messages.into_iter().map(frame).collect();
+        let mut output = Vec::new();
+
+        let code = run(input.as_bytes(), &mut output).expect("session should run");
+
+        let mut reader = output.as_slice();
+        let mut replies = Vec::new();
+        while let Some(body) = read_message(&mut reader).unwrap() {
+            replies.push(serde_json::from_slice(&body).unwrap());
+        }
+        (code, replies)
+    }
+
+    fn open(text: &str) -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": { "textDocument": { "uri": URI, "languageId": "monkey", "version": 1, "text": text } },
+        })
+    }
+
+    fn request(id: u64, method: &str, params: Value) -> Value {
+        json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params })
+    }
+
+    fn at(line: u64, character: u64) -> Value {
+        json!({ "textDocument": { "uri": URI }, "position": { "line": line, "character": character } })
+    }
+
+    #[test]
+    fn test_lifecycle_and_diagnostics() {
+        let (code, replies) = session(vec![
+            request(1, "initialize", json!({})),
+            open("let x = 5;\nlet = 10;"),
+            request(2, "shutdown", Value::Null),
+            json!({ "jsonrpc": "2.0", "method": "exit" }),
+        ]);
+
+        assert_eq!(code, 0);
+        assert_eq!(replies[0]["result"]["capabilities"]["hoverProvider"], true);
+
+        let diagnostics = &replies[1]["params"]["diagnostics"];
+        assert_eq!(
+            diagnostics[0]["message"],
+            "expected next token to be IDENT = /*something*/, got ASSIGN instead"
+        );
+        assert_eq!(
+            diagnostics[0]["range"]["start"],
+            json!({ "line": 1, "character": 4 })
+        );
+
+        assert_eq!(replies[2]["id"], 2);
+    }
+
+    #[test]
+    fn test_diagnostics_cover_the_token() {
+        let change = json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didChange",
+            "params": {
+                "textDocument": { "uri": URI, "version": 2 },
+                "contentChanges": [{ "text": "let z = 99999999999999999999999999999999999999999999;" }],
+            },
+        });
+        let (code, replies) = session(vec![
+            open("let unused = 1;"),
+            change,
+            request(1, "shutdown", Value::Null),
+            json!({ "jsonrpc": "2.0", "method": "exit" }),
+        ]);
+
+        assert_eq!(code, 0);
+        let lint = &replies[0]["params"]["diagnostics"][0];
+        assert_eq!(lint["message"], "unused binding `unused`");
+        assert_eq!(
+            lint["range"],
+            json!({ "start": { "line": 0, "character": 4 }, "end": { "line": 0, "character": 10 } })
+        );
+
+        let diagnostics = replies[1]["params"]["diagnostics"]
+            .as_array()
+            .expect("diagnostics should be a list");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(
+            diagnostics[0]["message"],
+            "integer literal out of range: 99999999999999999999999999999999999999999999"
+        );
+        assert_eq!(
+            diagnostics[0]["range"],
+            json!({ "start": { "line": 0, "character": 8 }, "end": { "line": 0, "character": 52 } })
+        );
+        assert_eq!(replies[2]["id"], 1);
+    }
+
+    #[test]
+    fn test_definition_and_hover() {
+        let text = "let add = fn(x, y) { x + y };\nlet result = add(1, 2);";
+        let (_, replies) = session(vec![
+            open(text),
+            request(1, "textDocument/definition", at(1, 14)),
+            request(2, "textDocument/hover", at(1, 14)),
+            request(3, "textDocument/definition", at(0, 21)),
+            request(4, "textDocument/definition", at(1, 20)),
+        ]);
+
+        assert_eq!(
+            replies[1]["result"]["range"]["start"],
+            json!({ "line": 0, "character": 4 })
+        );
+        assert_eq!(
+            replies[2]["result"]["contents"]["value"],
+            "```monkey\nlet add = fn(x, y) {\n    x + y;\n};\n```"
+        );
+        assert_eq!(
+            replies[3]["result"]["range"]["start"],
+            json!({ "line": 0, "character": 13 })
+        );
+        assert_eq!(replies[4]["result"], Value::Null);
+    }
+
+    #[test]
+    fn test_document_symbols_and_semantic_tokens() {
+        let text = "let f = fn(a) { let b = a; b };\n// done";
+        let (_, replies) = session(vec![
+            open(text),
+            request(
+                1,
+                "textDocument/documentSymbol",
+                json!({ "textDocument": { "uri": URI } }),
+            ),
+            request(
+                2,
+                "textDocument/semanticTokens/full",
+                json!({ "textDocument": { "uri": URI } }),
+            ),
+            request(3, "textDocument/unknown", Value::Null),
+        ]);
+
+        let symbols = &replies[1]["result"];
+        assert_eq!(symbols[0]["name"], "f");
+        assert_eq!(symbols[0]["kind"], SYMBOL_FUNCTION);
+        assert_eq!(symbols[0]["children"][0]["name"], "b");
+
+        let data: Vec<u64> = replies[2]["result"]["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| v.as_u64().unwrap())
+            .collect();
+        // `let` keyword, then `f` four characters later
+        assert_eq!(&data[..10], &[0, 0, 3, 0, 0, 0, 4, 1, 1, 0]);
+        // the comment on the next line
+        assert_eq!(&data[data.len() - 5..], &[1, 0, 7, 4, 0]);
+
+        assert_eq!(replies[3]["error"]["code"], -32601);
+    }
+
+    #[test]
+    fn test_semantic_tokens_for_every_keyword_and_operator() {
+        let text = "while (true) { break; } let s = \"x\" && 1 <= 2;";
+        let (_, replies) = session(vec![
+            open(text),
+            request(
+                1,
+                "textDocument/semanticTokens/full",
+                json!({ "textDocument": { "uri": URI } }),
+            ),
+        ]);
+
+        // (length, type) of each token, in order
+        let data: Vec<u64> = replies[1]["result"]["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| v.as_u64().unwrap())
+            .collect();
+        let tokens: Vec<(u64, u64)> = data.chunks(5).map(|t| (t[2], t[3])).collect();
+        assert_eq!(
+            tokens,
+            [
+                (5, 0),
+                (4, 0),
+                (5, 0),
+                (3, 0),
+                (1, 1),
+                (1, 3),
+                (3, 5),
+                (2, 3),
+                (1, 2),
+                (2, 3),
+                (1, 2),
+            ]
+        );
+    }
+}
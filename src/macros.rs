@@ -0,0 +1,288 @@
+use crate::ast::{self, Expression, FunctionInternal, Program, Statement};
+use crate::error::{self, Error, Kind, Site, TraceFrame};
+use crate::evaluator::Evaluator;
+use crate::resolver;
+use crate::vm::{InterruptHandle, Limits};
+use std::collections::HashMap;
+
+/// What the engines say about a macro literal that's still there when the
+/// program runs, and what expansion says about one that isn't defined
+/// where it can be.
+pub const STRAY_MACRO: &str = "macros can only be defined by a top-level let";
+
+/// How many times a macro call can expand into another one before we give
+/// up on it ever stopping.
+const MAX_DEPTH: usize = 100;
+
+/// The macros a program has defined, which `expand` runs on the code they
+/// are called with before the program is compiled or evaluated.
+///
+/// A macro is defined by a top-level `let name = macro(params) { body };`,
+/// and a call to `name` anywhere in the program, before or after it,
+/// passes the arguments' code to the body unevaluated, as quotes. What
+/// the body returns, which has to be a quote too, replaces the call. A
+/// call is left alone where a `let`, parameter, or loop or `catch`
+/// variable of the same name hides the macro.
+#[derive(Default)]
+pub struct Macros<'a> {
+    definitions: HashMap<String, FunctionInternal<'a>>,
+    limits: Limits,
+    interrupt: InterruptHandle,
+}
+
+impl<'a> Macros<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limits what each macro call may use while it's expanded, as
+    /// `Evaluator::set_limits` does.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Makes `handle` interrupt expansion.
+    pub fn set_interrupt_handle(&mut self, handle: InterruptHandle) {
+        self.interrupt = handle;
+    }
+
+    /// Takes the macro definitions out of `program`, then expands the calls
+    /// to them and to those of programs expanded before, like earlier
+    /// lines in the REPL. Arguments are expanded before the call they're
+    /// in, and the code a call expands to is expanded again.
+    pub fn expand(&mut self, program: &mut Program<'a>) -> Result<(), Error> {
+        let definitions = &mut self.definitions;
+        program.statements.retain(|stmt| match stmt {
+            Statement::Let(i) => match (i.name(), i.value()) {
+                (Some(name), Some(Expression::Macro(m))) => {
+                    definitions.insert(name.value().clone(), m.clone());
+                    false
+                }
+                _ => true,
+            },
+            _ => true,
+        });
+
+        let hidden = resolver::bound_names(&[], &program.statements);
+        for e in program
+            .statements
+            .iter_mut()
+            .flat_map(Statement::expressions_mut)
+        {
+            self.expression(e, 0, &hidden)?;
+        }
+        Ok(())
+    }
+
+    /// Expands the calls in `e`, where the names in `hidden` aren't macros.
+    fn expression(
+        &self,
+        e: &mut Expression<'a>,
+        depth: usize,
+        hidden: &[String],
+    ) -> Result<(), Error> {
+        let mut inner;
+        let hidden = match &*e {
+            Expression::Macro(_) => return Err(failed(STRAY_MACRO.to_string(), e)),
+            Expression::Function(f) => {
+                inner = hidden.to_vec();
+                inner.extend(resolver::bound_names(f.parameters(), &f.body().statements));
+                &inner
+            }
+            _ => hidden,
+        };
+        for child in e.children_mut() {
+            ast::deeper(|| self.expression(child, depth, hidden))?;
+        }
+
+        let (name, m, args) = match &*e {
+            Expression::Call(i) => match i.function() {
+                Expression::Identifier(name) if hidden.contains(name.value()) => return Ok(()),
+                Expression::Identifier(name) => match self.definitions.get(name.value()) {
+                    Some(m) => (name.value(), m, i.arguments()),
+                    None => return Ok(()),
+                },
+                _ => return Ok(()),
+            },
+            _ => return Ok(()),
+        };
+
+        if args.len() != m.parameters().len() {
+            let message = format!(
+                "wrong number of arguments: want={}, got={}",
+                m.parameters().len(),
+                args.len()
+            );
+            return Err(failed(message, e));
+        }
+        if depth >= MAX_DEPTH {
+            return Err(failed(format!("macro {} expands too deeply", name), e));
+        }
+
+        let mut evaluator = Evaluator::new();
+        evaluator.set_limits(self.limits);
+        evaluator.set_interrupt_handle(self.interrupt.clone());
+        let expanded = match evaluator.expand_macro(name, m, args.to_vec()) {
+            Ok(expanded) => expanded,
+            Err(mut err) => {
+                err.trace.push(TraceFrame {
+                    function: String::from(error::MAIN),
+                    site: Some(Site::of(e)),
+                });
+                return Err(err);
+            }
+        };
+        *e = expanded;
+        self.expression(e, depth + 1, hidden)
+    }
+}
+
+/// An error expanding the macro call `e`, or from the macro literal `e`.
+fn failed(message: String, e: &Expression) -> Error {
+    Error {
+        message,
+        kind: Kind::Other,
+        trace: vec![TraceFrame {
+            function: String::from(error::MAIN),
+            site: Some(Site::of(e)),
+        }],
+        limit: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn expanded(input: &str) -> Result<String, String> {
+        let l = Lexer::new(input, true, None);
+        let mut p = Parser::new(l);
+        let mut program = p.parse_program().expect("Program should be Some here");
+        assert_eq!(p.errors().len(), 0, "{:?}", p.errors());
+
+        Macros::new().expand(&mut program).map_err(String::from)?;
+        Ok(program.to_string())
+    }
+
+    #[test]
+    fn test_expand_macros() {
+        let tests = [
+            ("let m = macro() { quote(1 + 2) }; m()", "(1 + 2)"),
+            (
+                "let unless = macro(c, a, b) { quote(if (!(unquote(c))) { unquote(a) } else { unquote(b) }) };
+                unless(x > 1, puts(\"no\"), puts(\"yes\"))",
+                "if (!(x > 1)) { puts(\"no\") } else { puts(\"yes\") }",
+            ),
+            (
+                "let m = macro(a) { let b = quote(y); quote(unquote(a) * unquote(b)) }; m(x + 1)",
+                "((x + 1) * y)",
+            ),
+            (
+                "let m = macro() { quote(unquote(2 * 3) + unquote(1 < 2) + unquote(\"s\")) }; m()",
+                "((6 + true) + \"s\")",
+            ),
+            (
+                "let m = macro(a) { if (true) { return quote(unquote(a)); } }; fn() { m(f(1)) }",
+                "fn() { f(1) }",
+            ),
+            // arguments are expanded first, and so is what a call expands to
+            (
+                "let one = macro() { quote(1) }; let inc = macro(a) { quote(unquote(a) + one()) };
+                inc(inc(one()))",
+                "((1 + 1) + 1)",
+            ),
+            // outside a macro, quote is an ordinary name
+            ("quote(1)", "quote(1)"),
+            // a binding of the same name hides the macro
+            (
+                "let unless = macro(c, a, b) { quote(unquote(b)) };
+                let r = fn() { let unless = fn(a, b, c) { 42 }; unless(true, 1, 2) };
+                unless(true, 1, 2)",
+                "let r = fn() { let unless = fn(a, b, c) { 42 };unless(true, 1, 2) };2",
+            ),
+            (
+                "let m = macro() { quote(1) }; fn(m) { m() }; m()",
+                "fn(m) { m() }1",
+            ),
+            (
+                "let m = macro() { quote(1) }; fn() { for (m in 0..3) { m() } }; fn() { try { 0 } catch (m) { m() } }",
+                "fn() { for m in 0..3 { m() } }fn() { try { 0 } catch (m) { m() } }",
+            ),
+            (
+                "let m = macro() { quote(1) }; let m = 2; m()",
+                "let m = 2;m()",
+            ),
+            // a macro body can recurse as deep as any program, on a test
+            // thread's stack
+            (
+                "let m = macro() { let f = fn(n) { if (n == 0) { 0 } else { 1 + f(n - 1) } };
+                quote(unquote(f(1000))) }; m()",
+                "1000",
+            ),
+        ];
+
+        for (input, expected) in tests {
+            assert_eq!(expanded(input), Ok(expected.to_string()), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_macro_errors() {
+        let tests = [
+            (
+                "let f = fn() { let m = macro() { quote(1) }; };",
+                STRAY_MACRO,
+            ),
+            ("puts(macro() { quote(1) })", STRAY_MACRO),
+            (
+                "let m = macro(a) { quote(a) }; m()",
+                "wrong number of arguments: want=1, got=0",
+            ),
+            (
+                "let m = macro() { quote(1, 2) }; m()",
+                "wrong number of arguments: want=1, got=2",
+            ),
+            (
+                "let m = macro() { quote(unquote(fn() { 1 })) }; m()",
+                "unsupported type for unquote: FUNCTION",
+            ),
+            (
+                "let m = macro() { 1 }; m()",
+                "macro m returned INTEGER, not a quote",
+            ),
+            (
+                "let m = macro() { quote(m()) }; m()",
+                "macro m expands too deeply",
+            ),
+            ("let m = macro() { 1 / 0 }; m()", "division by zero"),
+            (
+                "let m = macro() { let f = fn() { 1 + f() }; f() }; m()",
+                "stack overflow",
+            ),
+        ];
+
+        for (input, expected) in tests {
+            assert_eq!(expanded(input), Err(expected.to_string()), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_errors_point_at_the_call() {
+        let path = std::path::Path::new("macros.my");
+        let input = "let m = macro(a) {\n  let f = fn() { 1 / 0 };\n  f()\n};\nlet x = m(2);";
+        let mut p = Parser::new(Lexer::new(input, false, Some(path)));
+        let mut program = p.parse_program().expect("Program should be Some here");
+
+        let err = Macros::new().expand(&mut program).unwrap_err();
+        assert_eq!(
+            err.trace_lines(),
+            [
+                "in f at macros.my:2:20: 1 / 0",
+                "in m at macros.my:3:4: f()",
+                "in <main> at macros.my:5:10: m(2)",
+            ]
+        );
+    }
+}
@@ -1,10 +1,26 @@
-pub mod ast;
-pub mod lexer;
-pub mod parser;
-pub mod repl;
-pub mod token;
+use plmmky::{
+    ast, astcache, compiler, emit_c, emit_wat, evaluator, explore, formatter, lexer, lint, lsp,
+    macros, module, object, optimizer, parser, regcompiler, regvm, repl, vm, Error,
+};
+use std::path::Path;
 
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+
+    match args.get(1).map(|a| a.as_str()) {
+        Some("fmt") => std::process::exit(fmt_command(&args[2..])),
+        Some("lint") => std::process::exit(lint_command(&args[2..])),
+        Some("explore") => std::process::exit(explore_command(&args[2..])),
+        Some("emit-c") => std::process::exit(emit_command(&args[2..], EMIT_C_USAGE, emit_c::emit)),
+        Some("emit-wat") => {
+            std::process::exit(emit_command(&args[2..], EMIT_WAT_USAGE, emit_wat::emit))
+        }
+        Some("lsp") => std::process::exit(lsp_command()),
+        Some("run") => std::process::exit(run_command(&args[2..])),
+        Some(_) => std::process::exit(run_command(&args[1..])),
+        None => (),
+    }
+
     let user = match std::env::var("USER") {
         Ok(u) => u,
         Err(e) => {
@@ -13,15 +29,490 @@ fn main() {
         }
     };
 
-    if !given_file_arg() {
-        println!("Hello {}! This is the Monkey programming language!", user);
-        repl::start();
+    println!("Hello {}! This is the Monkey programming language!", user);
+    repl::start();
+}
+
+const RUN_USAGE: &str = "usage: plmmky run [--engine=vm|regvm|eval] [--no-cache] [--wrapping] \
+                         [--gc-threshold=<n>] <file.my>";
+
+/// `plmmky run`: compiles a file and runs it. Plain `plmmky <file.my>` does
+/// the same. Parsed files are cached by content hash in
+/// `astcache::Cache::default_dir`, unless `--no-cache` is given. Integer
+/// overflow is an error unless `--wrapping` is given. `--gc-threshold` is
+/// how many environments, cells, arrays and hashes the engine makes before
+/// it first collects garbage. Runtime errors are followed by the calls that were in progress.
+fn run_command(args: &[String]) -> i32 {
+    let mut file = None;
+    let mut use_cache = true;
+    let mut engine = "vm";
+    let mut overflow = object::Overflow::Error;
+    let mut gc = object::GcConfig::default();
+
+    for arg in args {
+        match arg.as_str() {
+            "--engine=vm" | "--engine=regvm" | "--engine=eval" => {
+                engine = &arg["--engine=".len()..];
+            }
+            "--no-cache" => use_cache = false,
+            "--wrapping" => overflow = object::Overflow::Wrap,
+            _ if arg.starts_with("--gc-threshold=") => {
+                match arg["--gc-threshold=".len()..].parse() {
+                    Ok(n) => gc.threshold = n,
+                    Err(_) => {
+                        eprintln!("--gc-threshold expects a number\n{}", RUN_USAGE);
+                        return 2;
+                    }
+                }
+            }
+            _ if arg.starts_with("--engine=") => {
+                eprintln!(
+                    "unknown engine {}\n{}",
+                    &arg["--engine=".len()..],
+                    RUN_USAGE
+                );
+                return 2;
+            }
+            _ if arg.starts_with('-') => {
+                eprintln!("unknown option {}\n{}", arg, RUN_USAGE);
+                return 2;
+            }
+            _ if file.is_none() => file = Some(arg),
+            _ => {
+                eprintln!("{}", RUN_USAGE);
+                return 2;
+            }
+        }
+    }
+
+    let file = match file {
+        Some(f) => f,
+        None => {
+            eprintln!("{}", RUN_USAGE);
+            return 2;
+        }
+    };
+
+    let path = Path::new(file);
+    let input = match std::fs::read_to_string(path) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Error reading {}: {}", file, e);
+            return 2;
+        }
+    };
+
+    let cache = astcache::Cache::default_dir()
+        .filter(|_| use_cache)
+        .map(astcache::Cache::new);
+    let cached = cache.as_ref().and_then(|c| c.load(&input, path));
+
+    let mut program = match cached {
+        Some(program) => program,
+        None => {
+            let l = lexer::Lexer::new(&input, false, Some(path));
+            let mut p = parser::Parser::new(l);
+            let program = p.parse_program().unwrap_or_default();
+
+            if !p.errors().is_empty() {
+                for e in p.errors() {
+                    eprintln!("{}:{}", file, e);
+                }
+                return 2;
+            }
+
+            // the cache only saves time, so failing to write it isn't an error
+            if let Some(cache) = &cache {
+                let _ = cache.store(&input, &program);
+            }
+            program
+        }
+    };
+
+    let result = match macros::Macros::new().expand(&mut program) {
+        Ok(()) => {
+            optimizer::optimize(&mut program);
+            execute(engine, &program, path, overflow, gc)
+        }
+        Err(e) => Err(("macro", e)),
+    };
+
+    match result {
+        Ok(()) => 0,
+        Err((stage, e)) => {
+            eprintln!("{}: {} error: {}", file, stage, e);
+            for line in e.trace_lines() {
+                eprintln!("  {}", line);
+            }
+            1
+        }
+    }
+}
+
+/// Runs `program`, which came from `path`, on one of the engines. Errors say
+/// whether they happened while compiling or running.
+fn execute(
+    engine: &str,
+    program: &ast::Program,
+    path: &Path,
+    overflow: object::Overflow,
+    gc: object::GcConfig,
+) -> Result<(), (&'static str, Error)> {
+    let compile_error = |e| ("compile", Error::from(e));
+    match engine {
+        "eval" => {
+            let mut modules = module::Modules::new();
+            modules.load(program, Some(path)).map_err(compile_error)?;
+
+            let mut e = evaluator::Evaluator::new();
+            e.set_modules(&modules, Some(path));
+            e.set_overflow(overflow);
+            e.set_gc(gc);
+            e.eval(program).map_err(|e| ("runtime", e))?;
+        }
+        "regvm" => {
+            let mut c = regcompiler::RegCompiler::new();
+            c.set_file(Some(path));
+            c.compile(program).map_err(compile_error)?;
+            let mut machine = regvm::RegVm::new(c.bytecode());
+            machine.set_overflow(overflow);
+            machine.set_gc(gc);
+            machine.run().map_err(|e| ("runtime", e))?;
+        }
+        _ => {
+            let mut c = compiler::Compiler::new();
+            c.set_file(Some(path));
+            c.compile(program).map_err(compile_error)?;
+            let mut machine = vm::Vm::new(c.bytecode());
+            machine.set_overflow(overflow);
+            machine.set_gc(gc);
+            machine.run().map_err(|e| ("runtime", e))?;
+        }
+    }
+
+    Ok(())
+}
+
+const LINT_USAGE: &str = "usage: plmmky lint [--allow <code>]... <file.my>...";
+
+/// `plmmky lint`: reports lint diagnostics for each file, and exits with 1
+/// if there were any. `--allow` takes a code (`L001`) or a name
+/// (`unused-let`) and can be repeated.
+fn lint_command(args: &[String]) -> i32 {
+    let mut allow = Vec::new();
+    let mut files = Vec::new();
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--allow" => match iter.next().and_then(|c| lint::LintCode::lookup(c)) {
+                Some(code) => allow.push(code),
+                None => {
+                    eprintln!("--allow expects a lint code or name\n{}", LINT_USAGE);
+                    return 2;
+                }
+            },
+            _ if arg.starts_with('-') => {
+                eprintln!("unknown option {}\n{}", arg, LINT_USAGE);
+                return 2;
+            }
+            _ => files.push(arg),
+        }
+    }
+
+    if files.is_empty() {
+        eprintln!("{}", LINT_USAGE);
+        return 2;
+    }
+
+    let mut status = 0;
+
+    for file in files {
+        let path = Path::new(file);
+        let input = match std::fs::read_to_string(path) {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("Error reading {}: {}", file, e);
+                status = 2;
+                continue;
+            }
+        };
+
+        let l = lexer::Lexer::new(&input, false, Some(path));
+        let mut p = parser::Parser::new(l);
+        let program = p.parse_program().unwrap_or_default();
+
+        if !p.errors().is_empty() {
+            for e in p.errors() {
+                eprintln!("{}:{}", file, e);
+            }
+            status = 2;
+            continue;
+        }
+
+        for d in lint::lint(&program, &allow) {
+            println!("{}:{}", file, d);
+            status = status.max(1);
+        }
+    }
+
+    status
+}
+
+const EXPLORE_USAGE: &str = "usage: plmmky explore [-o <out.html>] <file.my>";
+
+/// `plmmky explore`: writes an HTML page showing the file's tokens next to
+/// its AST, to stdout or the file given with `-o`. The page is written even
+/// when there are parse errors, but then the exit code is 1.
+fn explore_command(args: &[String]) -> i32 {
+    let mut out = None;
+    let mut file = None;
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-o" | "--output" => match iter.next() {
+                Some(o) => out = Some(o),
+                None => {
+                    eprintln!("{} expects a file\n{}", arg, EXPLORE_USAGE);
+                    return 2;
+                }
+            },
+            _ if arg.starts_with('-') => {
+                eprintln!("unknown option {}\n{}", arg, EXPLORE_USAGE);
+                return 2;
+            }
+            _ if file.is_none() => file = Some(arg),
+            _ => {
+                eprintln!("{}", EXPLORE_USAGE);
+                return 2;
+            }
+        }
+    }
+
+    let file = match file {
+        Some(f) => f,
+        None => {
+            eprintln!("{}", EXPLORE_USAGE);
+            return 2;
+        }
+    };
+
+    let path = Path::new(file);
+    let input = match std::fs::read_to_string(path) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Error reading {}: {}", file, e);
+            return 2;
+        }
+    };
+
+    let (html, errors) = explore::explore(&input, path);
+
+    match out {
+        Some(o) => {
+            if let Err(e) = std::fs::write(o, html) {
+                eprintln!("Error writing {}: {}", o, e);
+                return 2;
+            }
+        }
+        None => print!("{}", html),
+    }
+
+    for e in &errors {
+        eprintln!("{}:{}", file, e);
+    }
+
+    if errors.is_empty() {
+        0
     } else {
-        eprintln!("File argument not yet supported, functionality coming soon!");
+        1
     }
 }
 
-fn given_file_arg() -> bool {
-    let args: Vec<String> = std::env::args().collect();
-    args.len() > 1
+const EMIT_C_USAGE: &str = "usage: plmmky emit-c [-o <out.c>] <file.my>";
+
+const EMIT_WAT_USAGE: &str = "usage: plmmky emit-wat [-o <out.wat>] <file.my>";
+
+/// `plmmky emit-c` and `plmmky emit-wat`: translate a file to a standalone
+/// C program or WebAssembly text module, written to stdout or the file given
+/// with `-o`. The C builds with any C99 compiler; see `emit_wat` for what
+/// the module imports and exports.
+fn emit_command(
+    args: &[String],
+    usage: &str,
+    emit: fn(&ast::Program) -> Result<String, String>,
+) -> i32 {
+    let mut out = None;
+    let mut file = None;
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-o" | "--output" => match iter.next() {
+                Some(o) => out = Some(o),
+                None => {
+                    eprintln!("{} expects a file\n{}", arg, usage);
+                    return 2;
+                }
+            },
+            _ if arg.starts_with('-') => {
+                eprintln!("unknown option {}\n{}", arg, usage);
+                return 2;
+            }
+            _ if file.is_none() => file = Some(arg),
+            _ => {
+                eprintln!("{}", usage);
+                return 2;
+            }
+        }
+    }
+
+    let file = match file {
+        Some(f) => f,
+        None => {
+            eprintln!("{}", usage);
+            return 2;
+        }
+    };
+
+    let path = Path::new(file);
+    let input = match std::fs::read_to_string(path) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Error reading {}: {}", file, e);
+            return 2;
+        }
+    };
+
+    let l = lexer::Lexer::new(&input, false, Some(path));
+    let mut p = parser::Parser::new(l);
+    let mut program = p.parse_program().unwrap_or_default();
+    if !p.errors().is_empty() {
+        for e in p.errors() {
+            eprintln!("{}:{}", file, e);
+        }
+        return 2;
+    }
+
+    if let Err(e) = macros::Macros::new().expand(&mut program) {
+        eprintln!("{}: macro error: {}", file, e);
+        for line in e.trace_lines() {
+            eprintln!("  {}", line);
+        }
+        return 1;
+    }
+    optimizer::optimize(&mut program);
+
+    let text = match emit(&program) {
+        Ok(text) => text,
+        Err(e) => {
+            eprintln!("{}: compile error: {}", file, e);
+            return 1;
+        }
+    };
+
+    match out {
+        Some(o) => {
+            if let Err(e) = std::fs::write(o, text) {
+                eprintln!("Error writing {}: {}", o, e);
+                return 2;
+            }
+        }
+        None => print!("{}", text),
+    }
+
+    0
+}
+
+/// `plmmky lsp`: a language server speaking over stdin and stdout.
+fn lsp_command() -> i32 {
+    let stdin = std::io::stdin();
+    match lsp::run(stdin.lock(), std::io::stdout()) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Error in language server: {}", e);
+            1
+        }
+    }
+}
+
+const FMT_USAGE: &str = "usage: plmmky fmt [--check] [--write] [--width <n>] <file.my>...";
+
+/// `plmmky fmt`: prints the formatted files to stdout, or with `--write`
+/// rewrites them in place. `--check` only reports files that aren't
+/// formatted and exits with 1 if there are any.
+fn fmt_command(args: &[String]) -> i32 {
+    let mut check = false;
+    let mut write = false;
+    let mut config = formatter::FormatConfig::default();
+    let mut files = Vec::new();
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--check" => check = true,
+            "--write" | "-w" => write = true,
+            "--width" => match iter.next().map(|w| w.parse::<usize>()) {
+                Some(Ok(w)) => config.width = w,
+                _ => {
+                    eprintln!("--width expects a number\n{}", FMT_USAGE);
+                    return 2;
+                }
+            },
+            _ if arg.starts_with('-') => {
+                eprintln!("unknown option {}\n{}", arg, FMT_USAGE);
+                return 2;
+            }
+            _ => files.push(arg),
+        }
+    }
+
+    if files.is_empty() {
+        eprintln!("{}", FMT_USAGE);
+        return 2;
+    }
+
+    let mut status = 0;
+
+    for file in files {
+        let path = Path::new(file);
+        let input = match std::fs::read_to_string(path) {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("Error reading {}: {}", file, e);
+                status = 2;
+                continue;
+            }
+        };
+
+        let output = match formatter::format(&input, path, &config) {
+            Ok(s) => s,
+            Err(errors) => {
+                for e in errors {
+                    eprintln!("{}: {}", file, e);
+                }
+                status = 2;
+                continue;
+            }
+        };
+
+        if check {
+            if output != input {
+                println!("{} is not formatted", file);
+                status = status.max(1);
+            }
+        } else if write {
+            if output != input {
+                if let Err(e) = std::fs::write(path, output) {
+                    eprintln!("Error writing {}: {}", file, e);
+                    status = 2;
+                }
+            }
+        } else {
+            print!("{}", output);
+        }
+    }
+
+    status
 }
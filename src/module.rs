@@ -0,0 +1,279 @@
+use crate::ast::{Expression, Program, Statement};
+use crate::lexer::Lexer;
+use crate::macros::Macros;
+use crate::parser::Parser;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// A file some program imports.
+pub struct Module {
+    /// Its position in `Modules`, which engines key what they've run by.
+    pub id: usize,
+    /// What importing it binds: the file name without the extension.
+    pub name: String,
+    /// The path it was first imported by. Its own imports are relative to
+    /// this.
+    pub path: PathBuf,
+    /// Parsed with locations, which borrow a copy of `path` that lives as
+    /// long as the process.
+    pub program: Program<'static>,
+    /// Its top level `let` bindings, which are what importers can read.
+    pub exports: Vec<String>,
+}
+
+/// Every module a program imports, directly or not, parsed ahead of running
+/// anything. A file is loaded once however many files import it, since
+/// they're told apart by canonical path.
+#[derive(Default)]
+pub struct Modules {
+    modules: Vec<Rc<Module>>,
+    by_path: HashMap<PathBuf, usize>,
+    /// Each import seen so far, by the path it names relative to the current
+    /// directory.
+    imports: HashMap<PathBuf, usize>,
+}
+
+impl Modules {
+    pub fn new() -> Modules {
+        Modules::default()
+    }
+
+    /// Loads everything `program` imports. `file` is where `program` came
+    /// from; imports are relative to its directory, or to the current one
+    /// for code that isn't in a file.
+    pub fn load(&mut self, program: &Program, file: Option<&Path>) -> Result<(), String> {
+        let mut chain = Vec::new();
+        if let Some(file) = file {
+            if let Ok(canonical) = file.canonicalize() {
+                chain.push((canonical, file.to_path_buf()));
+            }
+        }
+        self.load_imports(program, file, &mut chain)
+    }
+
+    /// The module `import "path"` refers to in `file`, once it's loaded.
+    pub fn find(&self, file: Option<&Path>, path: &str) -> Option<&Rc<Module>> {
+        let idx = self.imports.get(&relative_to(file, path))?;
+        Some(&self.modules[*idx])
+    }
+
+    /// `chain` holds the files being loaded, outermost first, as canonical
+    /// paths and the way they were imported.
+    fn load_imports(
+        &mut self,
+        program: &Program,
+        file: Option<&Path>,
+        chain: &mut Vec<(PathBuf, PathBuf)>,
+    ) -> Result<(), String> {
+        // two files with one name would bind it twice
+        let mut names: HashMap<&str, &str> = HashMap::new();
+        for stmt in &program.statements {
+            if let Statement::Import(i) = stmt {
+                match names.insert(i.name().value(), i.path()) {
+                    Some(first) if first != i.path() => {
+                        return Err(format!(
+                            "can't import {}: {} is already imported from {}",
+                            i.path(),
+                            i.name().value(),
+                            first
+                        ));
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        for stmt in &program.statements {
+            let i = match stmt {
+                Statement::Import(i) => i,
+                _ => continue,
+            };
+
+            let path = relative_to(file, i.path());
+            if self.imports.contains_key(&path) {
+                continue;
+            }
+
+            let canonical = path
+                .canonicalize()
+                .map_err(|e| format!("can't import {}: {}", path.display(), e))?;
+
+            if let Some(start) = chain.iter().position(|(c, _)| *c == canonical) {
+                let mut files: Vec<String> = chain[start..]
+                    .iter()
+                    .map(|(_, p)| p.display().to_string())
+                    .collect();
+                files.push(path.display().to_string());
+                return Err(format!("import cycle: {}", files.join(" -> ")));
+            }
+
+            let idx = match self.by_path.get(&canonical) {
+                Some(idx) => *idx,
+                None => {
+                    chain.push((canonical.clone(), path.clone()));
+                    let loaded = parse_module(&path, i.name().value()).and_then(|module| {
+                        self.load_imports(&module.program, Some(&path), chain)?;
+                        Ok(module)
+                    });
+                    chain.pop();
+
+                    let idx = self.modules.len();
+                    self.modules.push(Rc::new(Module { id: idx, ..loaded? }));
+                    self.by_path.insert(canonical, idx);
+                    idx
+                }
+            };
+            self.imports.insert(path, idx);
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_module(path: &Path, name: &str) -> Result<Module, String> {
+    let input = std::fs::read_to_string(path)
+        .map_err(|e| format!("can't import {}: {}", path.display(), e))?;
+
+    // the program's locations borrow the path for as long as it's loaded,
+    // which is one small allocation a module, for the rest of the run
+    let file: &'static Path = Box::leak(path.to_path_buf().into_boxed_path());
+    let mut p = Parser::new(Lexer::new(&input, false, Some(file)));
+    let mut program = p.parse_program().unwrap_or_default();
+    if let Some(e) = p.errors().first() {
+        return Err(format!("{}:{}", path.display(), e));
+    }
+    Macros::new()
+        .expand(&mut program)
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    if returns_from_top_level(&program.statements) {
+        return Err(format!(
+            "{}: modules can't return from their top level",
+            path.display()
+        ));
+    }
+
+    let mut exports: Vec<String> = Vec::new();
+    for stmt in &program.statements {
+        if let Statement::Let(i) = stmt {
+            if let Some(name) = i.name() {
+                if !exports.contains(name.value()) {
+                    exports.push(name.value().clone());
+                }
+            }
+        }
+    }
+
+    Ok(Module {
+        id: 0,
+        name: name.to_string(),
+        path: path.to_path_buf(),
+        program,
+        exports,
+    })
+}
+
+fn relative_to(file: Option<&Path>, path: &str) -> PathBuf {
+    match file.and_then(|f| f.parent()) {
+        Some(dir) => dir.join(path),
+        None => PathBuf::from(path),
+    }
+}
+
+/// Whether a `return` outside of any function would end the module early,
+/// which the engines that run modules inline can't do.
+fn returns_from_top_level(statements: &[Statement]) -> bool {
+    statements.iter().any(|stmt| {
+        let expr = match stmt {
+            Statement::Return(_) => return true,
+            Statement::Let(i) => i.value(),
+            Statement::Expression(i) => i.expression(),
+            Statement::While(i) => return returns_from_top_level(&i.body().statements),
+            Statement::For(i) => return returns_from_top_level(&i.body().statements),
+            Statement::Throw(i) => Some(i.value()),
+            Statement::Import(_) | Statement::Break(_) | Statement::Continue(_) => None,
+        };
+
+        match expr {
+            Some(Expression::If(i)) => {
+                returns_from_top_level(&i.consequence().statements)
+                    || i.alternative()
+                        .is_some_and(|alt| returns_from_top_level(&alt.statements))
+            }
+            Some(Expression::Try(i)) => i
+                .blocks()
+                .any(|block| returns_from_top_level(&block.statements)),
+            _ => false,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(input: &str) -> Result<Modules, String> {
+        let l = Lexer::new(input, true, None);
+        let mut p = Parser::new(l);
+        let program = p.parse_program().expect("Program should be Some here");
+        assert_eq!(p.errors().len(), 0, "{:?}", p.errors());
+
+        let mut modules = Modules::new();
+        modules.load(&program, Some(Path::new("tests/programs/main.my")))?;
+        Ok(modules)
+    }
+
+    #[test]
+    fn test_modules_load_once() {
+        let modules =
+            load("import \"modules/geometry.my\"; import \"modules/../modules/math.my\";")
+                .expect("should load");
+        let file = Some(Path::new("tests/programs/main.my"));
+
+        let geometry = modules
+            .find(file, "modules/geometry.my")
+            .expect("should find geometry");
+        assert_eq!(geometry.name, "geometry");
+        assert_eq!(geometry.exports, ["area", "perimeter", "square_area"]);
+
+        // geometry imports math itself, under a different path
+        let math = modules
+            .find(file, "modules/../modules/math.my")
+            .expect("should find math");
+        assert!(Rc::ptr_eq(
+            math,
+            modules
+                .find(Some(&geometry.path), "math.my")
+                .expect("should find math from geometry")
+        ));
+        assert_eq!(math.exports, ["square", "max"]);
+        assert!(math.id < geometry.id);
+    }
+
+    #[test]
+    fn test_module_errors() {
+        assert_eq!(
+            load("import \"modules/cycle_a.my\";").err(),
+            Some(String::from(
+                "import cycle: tests/programs/modules/cycle_a.my -> \
+                 tests/programs/modules/cycle_b.my -> tests/programs/modules/cycle_a.my"
+            ))
+        );
+
+        // checked before either file is read
+        assert_eq!(
+            load("import \"p1/util.my\"; import \"p2/util.my\";").err(),
+            Some(String::from(
+                "can't import p2/util.my: util is already imported from p1/util.my"
+            ))
+        );
+
+        let missing = load("import \"modules/missing.my\";").err().unwrap();
+        assert!(
+            missing.starts_with("can't import tests/programs/modules/missing.my: "),
+            "{}",
+            missing
+        );
+    }
+}
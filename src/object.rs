@@ -0,0 +1,788 @@
+use crate::code::Instructions;
+use crate::error::{Failure, Kind, Site};
+use crate::gc::{Trace, Traced};
+use crate::regcode;
+use crate::token::Span;
+use crate::vm::Meter;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
+use std::rc::Rc;
+
+#[derive(Debug, Clone)]
+pub enum Object {
+    Integer(i128),
+    Boolean(bool),
+    Null,
+    String(Rc<str>),
+    Error(Rc<Exception>),
+    GcStats(GcStats),
+    CompiledFunction(Rc<CompiledFunction>),
+    Closure(Rc<Closure>),
+    RegFunction(Rc<regcode::Function>),
+    RegClosure(Rc<regcode::Closure>),
+    Builtin(&'static Builtin),
+    Native(Rc<Native>),
+    Module(Rc<Module>),
+    Array(Array<Object>),
+    Hash(Hash<Object>),
+    /// A local that a function shares with the closures made in it, so
+    /// they see each other's assignments. It's never a value of its own:
+    /// reading the local reads what's in it.
+    Cell(Rc<RefCell<Object>>),
+}
+
+impl Object {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Object::Integer(_) => "INTEGER",
+            Object::Boolean(_) => "BOOLEAN",
+            Object::Null => "NULL",
+            Object::String(_) => "STRING",
+            Object::Error(_) => "ERROR",
+            Object::GcStats(_) => "GC_STATS",
+            Object::CompiledFunction(_)
+            | Object::Closure(_)
+            | Object::RegFunction(_)
+            | Object::RegClosure(_) => "FUNCTION",
+            Object::Builtin(_) | Object::Native(_) => "BUILTIN",
+            Object::Module(_) => "MODULE",
+            Object::Array(_) => "ARRAY",
+            Object::Hash(_) => "HASH",
+            Object::Cell(c) => c.borrow().type_name(),
+        }
+    }
+
+    /// Everything but `false` and `null` is truthy.
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, Object::Boolean(false) | Object::Null)
+    }
+}
+
+impl PartialEq for Object {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Object::Integer(l), Object::Integer(r)) => l == r,
+            (Object::Boolean(l), Object::Boolean(r)) => l == r,
+            (Object::Null, Object::Null) => true,
+            (Object::String(l), Object::String(r)) => l == r,
+            (Object::Error(l), Object::Error(r)) => Rc::ptr_eq(l, r),
+            (Object::GcStats(l), Object::GcStats(r)) => l == r,
+            (Object::CompiledFunction(l), Object::CompiledFunction(r)) => Rc::ptr_eq(l, r),
+            (Object::Closure(l), Object::Closure(r)) => Rc::ptr_eq(l, r),
+            (Object::RegFunction(l), Object::RegFunction(r)) => Rc::ptr_eq(l, r),
+            (Object::RegClosure(l), Object::RegClosure(r)) => Rc::ptr_eq(l, r),
+            (Object::Builtin(l), Object::Builtin(r)) => std::ptr::eq(*l, *r),
+            (Object::Native(l), Object::Native(r)) => Rc::ptr_eq(l, r),
+            (Object::Module(l), Object::Module(r)) => Rc::ptr_eq(l, r),
+            (Object::Array(l), Object::Array(r)) => Rc::ptr_eq(l, r),
+            (Object::Hash(l), Object::Hash(r)) => Rc::ptr_eq(l, r),
+            (Object::Cell(l), Object::Cell(r)) => Rc::ptr_eq(l, r),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Object::Integer(i) => write!(f, "{}", i),
+            Object::Boolean(b) => write!(f, "{}", b),
+            Object::Null => write!(f, "null"),
+            Object::String(s) => write!(f, "{}", s),
+            Object::Error(e) => write!(f, "<error: {}>", e.message),
+            Object::GcStats(s) => write!(f, "{}", s),
+            Object::CompiledFunction(_)
+            | Object::Closure(_)
+            | Object::RegFunction(_)
+            | Object::RegClosure(_) => write!(f, "<function>"),
+            Object::Builtin(b) => write!(f, "<builtin {}>", b.name),
+            Object::Native(n) => write!(f, "<builtin {}>", n.name),
+            Object::Module(m) => write!(f, "<module {}>", m.name),
+            Object::Array(a) => fmt_array(f, a),
+            Object::Hash(h) => fmt_hash(f, h),
+            Object::Cell(c) => write!(f, "{}", c.borrow()),
+        }
+    }
+}
+
+impl Traced<'static> for Object {
+    fn node(&self) -> Option<Rc<dyn Trace<'static>>> {
+        match self {
+            Object::Array(a) => Some(a.clone()),
+            Object::Hash(h) => Some(h.clone()),
+            Object::Cell(c) => Some(c.clone()),
+            Object::Closure(c) => Some(c.clone()),
+            Object::RegClosure(c) => Some(c.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// A cell, which is emptied by setting it to null.
+impl Trace<'static> for RefCell<Object> {
+    fn trace(&self, f: &mut dyn FnMut(Rc<dyn Trace<'static>>)) -> bool {
+        match self.try_borrow() {
+            Ok(value) => {
+                value.node().map(f);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn clear(&self) -> bool {
+        let value = std::mem::replace(&mut *self.borrow_mut(), Object::Null);
+        drop(value);
+        true
+    }
+}
+
+impl Trace<'static> for Closure {
+    fn trace(&self, f: &mut dyn FnMut(Rc<dyn Trace<'static>>)) -> bool {
+        self.free.iter().filter_map(Traced::node).for_each(f);
+        true
+    }
+
+    fn clear(&self) -> bool {
+        false
+    }
+}
+
+impl Trace<'static> for regcode::Closure {
+    fn trace(&self, f: &mut dyn FnMut(Rc<dyn Trace<'static>>)) -> bool {
+        self.free.iter().filter_map(Traced::node).for_each(f);
+        true
+    }
+
+    fn clear(&self) -> bool {
+        false
+    }
+}
+
+impl Element for Object {
+    fn integer(i: i128) -> Self {
+        Object::Integer(i)
+    }
+
+    fn null() -> Self {
+        Object::Null
+    }
+
+    fn gc_stats(stats: GcStats) -> Self {
+        Object::GcStats(stats)
+    }
+
+    fn type_name(&self) -> &'static str {
+        Object::type_name(self)
+    }
+
+    fn array(&self) -> Option<&Array<Self>> {
+        match self {
+            Object::Array(a) => Some(a),
+            _ => None,
+        }
+    }
+
+    fn hash(&self) -> Option<&Hash<Self>> {
+        match self {
+            Object::Hash(h) => Some(h),
+            _ => None,
+        }
+    }
+
+    fn key(&self) -> Option<HashKey> {
+        match self {
+            Object::Integer(i) => Some(HashKey::Integer(*i)),
+            Object::Boolean(b) => Some(HashKey::Boolean(*b)),
+            Object::String(s) => Some(HashKey::String(s.clone())),
+            _ => None,
+        }
+    }
+
+    fn from_key(key: &HashKey) -> Self {
+        match key {
+            HashKey::Integer(i) => Object::Integer(*i),
+            HashKey::Boolean(b) => Object::Boolean(*b),
+            HashKey::String(s) => Object::String(s.clone()),
+        }
+    }
+}
+
+/// An array's elements. Copying an array copies the reference, so every
+/// copy sees the same elements, and arrays are only equal to themselves.
+pub type Array<V> = Rc<RefCell<Vec<V>>>;
+
+/// A hash's entries, shared by its copies like an array's elements.
+pub type Hash<V> = Rc<RefCell<Table<V>>>;
+
+/// What a hash can be keyed by: the values that can't change.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum HashKey {
+    Integer(i128),
+    Boolean(bool),
+    String(Rc<str>),
+}
+
+impl fmt::Display for HashKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HashKey::Integer(i) => write!(f, "{}", i),
+            HashKey::Boolean(b) => write!(f, "{}", b),
+            HashKey::String(s) => write!(f, "\"{}\"", s),
+        }
+    }
+}
+
+/// The entries of a hash, in the order their keys were first added, which
+/// is the order `for` goes over them in.
+#[derive(Debug)]
+pub struct Table<V> {
+    entries: Vec<(HashKey, V)>,
+    index: HashMap<HashKey, usize>,
+}
+
+impl<V> Default for Table<V> {
+    fn default() -> Self {
+        Table {
+            entries: Vec::new(),
+            index: HashMap::new(),
+        }
+    }
+}
+
+impl<V> Table<V> {
+    pub fn get(&self, key: &HashKey) -> Option<&V> {
+        Some(&self.entries[*self.index.get(key)?].1)
+    }
+
+    /// Sets the value of `key`, which keeps its place if it's already in
+    /// the table.
+    pub fn insert(&mut self, key: HashKey, value: V) {
+        match self.index.get(&key) {
+            Some(&i) => self.entries[i].1 = value,
+            None => {
+                self.index.insert(key.clone(), self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn entries(&self) -> &[(HashKey, V)] {
+        &self.entries
+    }
+}
+
+/// What arrays, hashes and builtins need of the values they hold. The
+/// evaluator's values aren't `Object`s, so what works on them is written
+/// once for both.
+pub trait Element: Clone + fmt::Display {
+    fn integer(i: i128) -> Self;
+    fn null() -> Self;
+    fn gc_stats(stats: GcStats) -> Self;
+    fn type_name(&self) -> &'static str;
+    fn array(&self) -> Option<&Array<Self>>;
+    fn hash(&self) -> Option<&Hash<Self>>;
+    /// The value as a hash key, if it can be one.
+    fn key(&self) -> Option<HashKey>;
+    fn from_key(key: &HashKey) -> Self;
+}
+
+/// `value` as a hash key, or an error saying it can't be one.
+pub fn hash_key<V: Element>(value: &V) -> Result<HashKey, Failure> {
+    value.key().ok_or_else(|| {
+        Failure::new(
+            Kind::Type,
+            format!("unusable as hash key: {}", value.type_name()),
+        )
+    })
+}
+
+/// Where `index` is in an array of `len` elements, which it has to be.
+fn position<V: Element>(index: &V, len: usize) -> Result<usize, Failure> {
+    match index.key() {
+        Some(HashKey::Integer(i)) => {
+            usize::try_from(i).ok().filter(|&i| i < len).ok_or_else(|| {
+                Failure::new(Kind::Range, format!("index out of range: {} of {}", i, len))
+            })
+        }
+        _ => Err(Failure::new(
+            Kind::Type,
+            format!("array index must be an integer: {}", index.type_name()),
+        )),
+    }
+}
+
+/// `left[index]`: the element of an array, which has to be there, or the
+/// value of a key in a hash, which is null when it isn't.
+pub fn index<V: Element>(left: &V, index: &V) -> Result<V, Failure> {
+    if let Some(a) = left.array() {
+        let a = a.borrow();
+        return Ok(a[position(index, a.len())?].clone());
+    }
+    match left.hash() {
+        Some(h) => Ok(h
+            .borrow()
+            .get(&hash_key(index)?)
+            .cloned()
+            .unwrap_or_else(V::null)),
+        None => Err(Failure::new(
+            Kind::Type,
+            format!("index operator not supported: {}", left.type_name()),
+        )),
+    }
+}
+
+/// `left[index] = value`: replaces an element of an array, which has to be
+/// there already, or adds or replaces the value of a key in a hash.
+pub(crate) fn set_index<V: Element>(
+    meter: &mut Meter,
+    left: &V,
+    index: &V,
+    value: V,
+) -> Result<(), Failure> {
+    if let Some(a) = left.array() {
+        let mut a = a.borrow_mut();
+        let i = position(index, a.len())?;
+        a[i] = value;
+        return Ok(());
+    }
+    match left.hash() {
+        Some(h) => {
+            let key = hash_key(index)?;
+            let mut h = h.borrow_mut();
+            if h.get(&key).is_none() {
+                meter.allocate(2 * std::mem::size_of::<V>())?;
+            }
+            h.insert(key, value);
+            Ok(())
+        }
+        None => Err(Failure::new(
+            Kind::Type,
+            format!("index assignment not supported: {}", left.type_name()),
+        )),
+    }
+}
+
+/// What a `for` loop over `items` binds at `position`: the element of an
+/// array or the key of a hash, or `None` once it's past the end. The loop
+/// looks again each time round, so it sees what the body adds.
+pub fn nth<V: Element>(items: &V, position: usize) -> Result<Option<V>, Failure> {
+    if let Some(a) = items.array() {
+        return Ok(a.borrow().get(position).cloned());
+    }
+    match items.hash() {
+        Some(h) => Ok(h
+            .borrow()
+            .entries
+            .get(position)
+            .map(|(k, _)| V::from_key(k))),
+        None => Err(Failure::new(
+            Kind::Type,
+            format!("unsupported type for iteration: {}", items.type_name()),
+        )),
+    }
+}
+
+/// Makes a hash of `pairs`, the later of two equal keys winning.
+pub fn make_hash<V: Element>(pairs: impl IntoIterator<Item = (V, V)>) -> Result<Hash<V>, Failure> {
+    let mut table = Table::default();
+    for (key, value) in pairs {
+        table.insert(hash_key(&key)?, value);
+    }
+    Ok(Rc::new(RefCell::new(table)))
+}
+
+thread_local! {
+    /// The collections being printed, so that one holding itself prints
+    /// as `[...]` instead of forever.
+    static PRINTING: RefCell<Vec<*const ()>> = const { RefCell::new(Vec::new()) };
+}
+
+fn fmt_nested<T>(
+    f: &mut fmt::Formatter<'_>,
+    collection: &Rc<T>,
+    empty: &str,
+    inside: impl FnOnce(&mut fmt::Formatter<'_>) -> fmt::Result,
+) -> fmt::Result {
+    let ptr = Rc::as_ptr(collection) as *const ();
+    if PRINTING.with(|p| p.borrow().contains(&ptr)) {
+        return write!(f, "{}", empty);
+    }
+    PRINTING.with(|p| p.borrow_mut().push(ptr));
+    let result = inside(f);
+    PRINTING.with(|p| p.borrow_mut().pop());
+    result
+}
+
+/// Prints an element of a collection, which quotes it if it's a string.
+fn fmt_element<V: Element>(f: &mut fmt::Formatter<'_>, value: &V) -> fmt::Result {
+    match value.key() {
+        Some(key) => write!(f, "{}", key),
+        None => write!(f, "{}", value),
+    }
+}
+
+pub fn fmt_array<V: Element>(f: &mut fmt::Formatter<'_>, array: &Array<V>) -> fmt::Result {
+    fmt_nested(f, array, "[...]", |f| {
+        write!(f, "[")?;
+        for (i, v) in array.borrow().iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            fmt_element(f, v)?;
+        }
+        write!(f, "]")
+    })
+}
+
+pub fn fmt_hash<V: Element>(f: &mut fmt::Formatter<'_>, hash: &Hash<V>) -> fmt::Result {
+    fmt_nested(f, hash, "{...}", |f| {
+        write!(f, "{{")?;
+        for (i, (k, v)) in hash.borrow().entries.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}: ", k)?;
+            fmt_element(f, v)?;
+        }
+        write!(f, "}}")
+    })
+}
+
+#[derive(Debug, PartialEq)]
+pub struct CompiledFunction {
+    pub instructions: Instructions,
+    pub num_locals: usize,
+    pub num_parameters: usize,
+    /// The name `let` bound it to, for stack traces.
+    pub name: Option<String>,
+    /// The expressions the instructions that can fail came from, by the
+    /// position just after each instruction, in order. That's where the
+    /// frame's `ip` is when it fails, or when it's calling.
+    pub sites: Vec<(usize, Site)>,
+}
+
+impl CompiledFunction {
+    /// The expression of the instruction ending at `position`, if it can
+    /// fail.
+    pub fn site(&self, position: usize) -> Option<&Site> {
+        find_site(&self.sites, position)
+    }
+}
+
+pub(crate) fn find_site(sites: &[(usize, Site)], position: usize) -> Option<&Site> {
+    let idx = sites.binary_search_by_key(&position, |(p, _)| *p).ok()?;
+    Some(&sites[idx].1)
+}
+
+/// What integer arithmetic does when the result doesn't fit in an `i128`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Overflow {
+    /// Fail with `INTEGER_OVERFLOW`.
+    #[default]
+    Error,
+    /// Wrap around in two's complement, like Rust's `wrapping_*` methods.
+    Wrap,
+}
+
+pub const INTEGER_OVERFLOW: &str = "integer overflow";
+
+impl Overflow {
+    /// `checked(l, r)`, or `wrapping(l, r)` when wrapping. Division by zero
+    /// is for the caller to rule out first.
+    pub fn apply(
+        self,
+        l: i128,
+        r: i128,
+        checked: fn(i128, i128) -> Option<i128>,
+        wrapping: fn(i128, i128) -> i128,
+    ) -> Result<i128, Failure> {
+        match self {
+            Overflow::Error => {
+                checked(l, r).ok_or_else(|| Failure::new(Kind::Overflow, INTEGER_OVERFLOW))
+            }
+            Overflow::Wrap => Ok(wrapping(l, r)),
+        }
+    }
+}
+
+/// `l << r` for a shift amount already known to be in `0..128`, or `None`
+/// when bits, the sign included, are shifted out.
+pub fn checked_shl(l: i128, r: i128) -> Option<i128> {
+    let shifted = l << r;
+    (shifted >> r == l).then_some(shifted)
+}
+
+/// An error from integer arithmetic, which says where the operator that
+/// failed is when that's known.
+pub fn arithmetic_error(failure: Failure, at: Option<impl fmt::Display>) -> Failure {
+    match at {
+        Some(at) => Failure::new(failure.kind, format!("{} at {}", failure.message, at)),
+        None => failure,
+    }
+}
+
+/// An error as `catch` sees it, whether it came from running the code or
+/// from `throw`.
+#[derive(Debug, PartialEq)]
+pub struct Exception {
+    pub message: String,
+    /// What sort of error it is, as whatever raised it said, or
+    /// `Kind::Thrown` for a value that was thrown.
+    pub kind: Kind,
+    /// Where it happened, when the source had locations.
+    pub span: Option<Span>,
+}
+
+impl Exception {
+    /// The member `name`. The span is a string like an error's location,
+    /// or null when it isn't known.
+    pub fn member(&self, name: &str) -> Result<Object, Failure> {
+        match name {
+            "message" => Ok(Object::String(Rc::from(self.message.as_str()))),
+            "kind" => Ok(Object::String(Rc::from(self.kind.name()))),
+            "span" => match &self.span {
+                Some(span) => Ok(Object::String(Rc::from(span.to_string()))),
+                None => Ok(Object::Null),
+            },
+            _ => Err(Failure::new(
+                Kind::Name,
+                format!("error has no member {}", name),
+            )),
+        }
+    }
+}
+
+/// What `gc_stats()` returns: how the collector has done so far. It counts
+/// what could end up in a cycle: arrays and hashes on every engine, and
+/// environments in the evaluator or the cells closures share on the
+/// machines.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct GcStats {
+    /// How many times the collector has run.
+    pub collections: usize,
+    /// Values made, whether they're still around or not.
+    pub allocated: usize,
+    /// Values the collector freed, which reference counting couldn't.
+    pub freed: usize,
+    /// Values still around after the last collection.
+    pub live: usize,
+}
+
+impl GcStats {
+    pub fn member(&self, name: &str) -> Result<Object, Failure> {
+        let n = match name {
+            "collections" => self.collections,
+            "allocated" => self.allocated,
+            "freed" => self.freed,
+            "live" => self.live,
+            _ => {
+                return Err(Failure::new(
+                    Kind::Name,
+                    format!("gc stats have no member {}", name),
+                ))
+            }
+        };
+        Ok(Object::Integer(n as i128))
+    }
+}
+
+impl fmt::Display for GcStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "<gc collections={} allocated={} freed={} live={}>",
+            self.collections, self.allocated, self.freed, self.live
+        )
+    }
+}
+
+/// When the collector runs: once `threshold` values have been made
+/// since it last ran, or `growth` times as many as were live then, if
+/// that's more. Growing with the heap keeps the time spent collecting
+/// proportional to the time spent allocating.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GcConfig {
+    pub threshold: usize,
+    pub growth: usize,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        GcConfig {
+            threshold: 10_000,
+            growth: 2,
+        }
+    }
+}
+
+impl GcConfig {
+    /// How many values to make before the next collection, when
+    /// `live` were left by the last one.
+    pub fn next(&self, live: usize) -> usize {
+        self.threshold.max(live.saturating_mul(self.growth))
+    }
+}
+
+/// A function together with the free variables it closed over.
+#[derive(Debug)]
+pub struct Closure {
+    pub func: Rc<CompiledFunction>,
+    pub free: Vec<Object>,
+}
+
+/// An imported file. Its bindings live in globals, so reading one reads
+/// whatever the global holds at the time.
+#[derive(Debug)]
+pub struct Module {
+    pub name: String,
+    /// The global slot of each binding, by name.
+    pub exports: Vec<(String, usize)>,
+}
+
+impl Module {
+    /// The global slot of the binding `name`.
+    pub fn slot(&self, name: &str) -> Result<usize, Failure> {
+        match self.exports.iter().find(|(n, _)| n == name) {
+            Some((_, slot)) => Ok(*slot),
+            None => Err(Failure::new(
+                Kind::Name,
+                format!("module {} has no member {}", self.name, name),
+            )),
+        }
+    }
+}
+
+pub type BuiltinFunction<V = Object> = fn(&mut Host, &[V]) -> Result<V, Failure>;
+
+/// What a builtin can see of the engine running it.
+pub struct Host<'h> {
+    pub out: &'h mut dyn Write,
+    pub gc: GcStats,
+    /// What the builtin allocates counts against `Limits::max_heap`.
+    pub(crate) meter: &'h mut Meter,
+}
+
+pub struct Builtin {
+    pub name: &'static str,
+    pub func: BuiltinFunction,
+}
+
+impl fmt::Debug for Builtin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Builtin({})", self.name)
+    }
+}
+
+pub type NativeFunction = dyn Fn(&[Object]) -> Result<Object, Failure>;
+
+/// A function the host registered with `Interpreter::register_fn`.
+pub struct Native {
+    pub name: String,
+    pub num_parameters: usize,
+    pub func: Box<NativeFunction>,
+}
+
+impl Native {
+    /// Calls the function, checking the number of arguments first like
+    /// calls to Monkey functions do.
+    pub fn call(&self, args: &[Object]) -> Result<Object, Failure> {
+        if args.len() != self.num_parameters {
+            return Err(Failure::wrong_arguments(self.num_parameters, args.len()));
+        }
+        (self.func)(args)
+    }
+}
+
+impl fmt::Debug for Native {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Native({})", self.name)
+    }
+}
+
+/// Builtins are looked up by their index here, so only ever append.
+pub static BUILTINS: [Builtin; 4] = [
+    Builtin {
+        name: "puts",
+        func: puts,
+    },
+    Builtin {
+        name: "gc_stats",
+        func: gc_stats,
+    },
+    Builtin {
+        name: "len",
+        func: len,
+    },
+    Builtin {
+        name: "push",
+        func: push,
+    },
+];
+
+/// The function of `BUILTINS[idx]` for an engine whose values aren't
+/// `Object`s.
+pub fn builtin<V: Element>(idx: usize) -> BuiltinFunction<V> {
+    let funcs: [BuiltinFunction<V>; 4] = [puts, gc_stats, len, push];
+    funcs[idx]
+}
+
+fn puts<V: Element>(host: &mut Host, args: &[V]) -> Result<V, Failure> {
+    for arg in args {
+        writeln!(host.out, "{}", arg).map_err(|e| e.to_string())?;
+    }
+    Ok(V::null())
+}
+
+fn gc_stats<V: Element>(host: &mut Host, args: &[V]) -> Result<V, Failure> {
+    if !args.is_empty() {
+        return Err(Failure::wrong_arguments(0, args.len()));
+    }
+    Ok(V::gc_stats(host.gc))
+}
+
+/// `len(xs)`: how many elements an array has, or entries a hash has.
+fn len<V: Element>(_: &mut Host, args: &[V]) -> Result<V, Failure> {
+    let [items] = args else {
+        return Err(Failure::wrong_arguments(1, args.len()));
+    };
+    let n = match (items.array(), items.hash()) {
+        (Some(a), _) => a.borrow().len(),
+        (_, Some(h)) => h.borrow().len(),
+        _ => {
+            return Err(Failure::new(
+                Kind::Type,
+                format!("argument to len not supported: {}", items.type_name()),
+            ))
+        }
+    };
+    Ok(V::integer(n as i128))
+}
+
+/// `push(xs, x)`: adds `x` to the end of the array `xs`, in place.
+fn push<V: Element>(host: &mut Host, args: &[V]) -> Result<V, Failure> {
+    let [items, value] = args else {
+        return Err(Failure::wrong_arguments(2, args.len()));
+    };
+    match items.array() {
+        Some(a) => {
+            host.meter.allocate(std::mem::size_of::<V>())?;
+            a.borrow_mut().push(value.clone());
+            Ok(V::null())
+        }
+        None => Err(Failure::new(
+            Kind::Type,
+            format!("argument to push must be an array: {}", items.type_name()),
+        )),
+    }
+}
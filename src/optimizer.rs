@@ -0,0 +1,424 @@
+use crate::ast::{
+    self, BooleanInternal, Expression, ExpressionInternal, IntegerInternal, Program, Statement,
+};
+use crate::object;
+use crate::token::{Location, Token, TokenKind};
+
+/// Folds constant integer and boolean operations and drops `if` branches
+/// whose condition is a literal.
+///
+/// Folded literals keep the location their expression started at, so
+/// diagnostics still point at the original source. Arithmetic that would
+/// overflow or divide by zero is left alone for the runtime to report.
+pub fn optimize(program: &mut Program) {
+    program.statements = statements(std::mem::take(&mut program.statements));
+}
+
+/// Where `e` starts in the source.
+fn start<'a>(e: &Expression<'a>) -> Option<Location<'a>> {
+    match e {
+        Expression::Infix(i) => start(i.left()),
+        Expression::Call(i) => start(i.function()),
+        Expression::Member(i) => start(i.object()),
+        Expression::Index(i) => start(i.left()),
+        Expression::IndexAssign(i) => start(i.target().left()),
+        Expression::Assign(i) => i.name().token().local().cloned(),
+        _ => e.token().local().cloned(),
+    }
+}
+
+fn integer<'a>(value: i128, local: Option<Location<'a>>) -> Expression<'a> {
+    Expression::Integer(IntegerInternal::new(
+        Token::new(TokenKind::INT(value), local),
+        value,
+    ))
+}
+
+fn boolean<'a>(value: bool, local: Option<Location<'a>>) -> Expression<'a> {
+    let kind = if value {
+        TokenKind::TRUE
+    } else {
+        TokenKind::FALSE
+    };
+    Expression::Boolean(BooleanInternal::new(Token::new(kind, local), value))
+}
+
+/// The truthiness of `e` if it's a literal. Everything but `false` is
+/// truthy, so integers count as true.
+fn constant_condition(e: &Expression) -> Option<bool> {
+    match e {
+        Expression::Boolean(b) => Some(b.value()),
+        Expression::Integer(_) => Some(true),
+        _ => None,
+    }
+}
+
+fn statements<'a>(input: Vec<Statement<'a>>) -> Vec<Statement<'a>> {
+    let mut out = Vec::with_capacity(input.len());
+    let count = input.len();
+
+    for (idx, mut stmt) in input.into_iter().enumerate() {
+        statement(&mut stmt);
+
+        // blocks don't open a scope, so an `if` statement with a constant
+        // condition can be replaced by the statements of the branch taken
+        let is_last = idx + 1 == count;
+        match stmt {
+            Statement::Expression(mut i) if is_constant_if(&i) => {
+                let e = match i.expression_mut() {
+                    Some(Expression::If(e)) => e,
+                    _ => unreachable!("checked by is_constant_if"),
+                };
+                let taken = constant_condition(e.condition()).unwrap_or(true);
+                let branch_len = if taken {
+                    Some(e.consequence().statements.len())
+                } else {
+                    e.alternative().map(|b| b.statements.len())
+                };
+
+                // the last statement gives the block its value, and when the
+                // branch taken is empty that value is null, which we have no
+                // literal for, so keep the `if` but empty out the dead branch
+                if is_last && branch_len.unwrap_or(0) == 0 {
+                    if taken {
+                        if let Some(alt) = e.alternative_mut() {
+                            alt.statements.clear();
+                        }
+                    } else {
+                        e.consequence_mut().statements.clear();
+                    }
+                    out.push(Statement::Expression(i));
+                    continue;
+                }
+
+                if let Some(Expression::If(e)) = i.into_expression() {
+                    let (consequence, alternative) = e.into_branches();
+                    if taken {
+                        out.extend(consequence.statements);
+                    } else if let Some(alt) = alternative {
+                        out.extend(alt.statements);
+                    }
+                }
+            }
+            stmt => out.push(stmt),
+        }
+    }
+
+    out
+}
+
+fn is_constant_if(i: &ExpressionInternal) -> bool {
+    match i.expression() {
+        Some(Expression::If(e)) => constant_condition(e.condition()).is_some(),
+        _ => false,
+    }
+}
+
+fn statement(stmt: &mut Statement) {
+    ast::deeper(|| {
+        let expr = match stmt {
+            Statement::Let(i) => i.value_mut(),
+            Statement::Return(i) => i.return_value_mut(),
+            Statement::Expression(i) => i.expression_mut(),
+            Statement::While(i) => {
+                let body = i.body_mut();
+                body.statements = statements(std::mem::take(&mut body.statements));
+                Some(i.condition_mut())
+            }
+            Statement::For(i) => {
+                let body = i.body_mut();
+                body.statements = statements(std::mem::take(&mut body.statements));
+                expression(i.start_mut());
+                i.end_mut()
+            }
+            Statement::Throw(i) => Some(i.value_mut()),
+            Statement::Import(_) | Statement::Break(_) | Statement::Continue(_) => None,
+        };
+
+        if let Some(e) = expr {
+            expression(e);
+        }
+    })
+}
+
+fn expression<'a>(e: &mut Expression<'a>) {
+    ast::deeper(|| match e {
+        Expression::Identifier(_)
+        | Expression::Integer(_)
+        | Expression::Boolean(_)
+        | Expression::String(_) => {}
+        Expression::Prefix(i) => {
+            expression(i.right_mut());
+
+            let folded = match (i.operator(), i.right()) {
+                ("-", Expression::Integer(r)) => {
+                    r.value().checked_neg().map(|v| integer(v, start(e)))
+                }
+                ("~", Expression::Integer(r)) => Some(integer(!r.value(), start(e))),
+                ("!", Expression::Boolean(r)) => Some(boolean(!r.value(), start(e))),
+                _ => None,
+            };
+            if let Some(f) = folded {
+                replace(e, f);
+            }
+        }
+        Expression::Infix(i) => {
+            expression(i.left_mut());
+            expression(i.right_mut());
+
+            let local = start(e);
+            let folded = match e {
+                Expression::Infix(i) => fold_infix(i.left(), i.operator(), i.right(), local),
+                _ => None,
+            };
+            if let Some(f) = folded {
+                replace(e, f);
+            }
+        }
+        Expression::If(i) => {
+            expression(i.condition_mut());
+            let consequence = i.consequence_mut();
+            consequence.statements = statements(std::mem::take(&mut consequence.statements));
+            if let Some(alt) = i.alternative_mut() {
+                alt.statements = statements(std::mem::take(&mut alt.statements));
+            }
+
+            // in an expression, a taken branch can only stand in for the
+            // `if` when it is a single expression
+            if let Some(taken) = constant_condition(i.condition()) {
+                let branch = if taken {
+                    Some(i.consequence_mut())
+                } else {
+                    i.alternative_mut()
+                };
+                if let Some(b) = branch {
+                    if let [Statement::Expression(_)] = b.statements.as_slice() {
+                        if let Some(Statement::Expression(s)) = b.statements.pop() {
+                            if let Some(inner) = s.into_expression() {
+                                *e = inner;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Expression::Try(i) => {
+            let body = i.body_mut();
+            body.statements = statements(std::mem::take(&mut body.statements));
+            let handler = i.handler_mut();
+            handler.statements = statements(std::mem::take(&mut handler.statements));
+            if let Some(finally) = i.finally_mut() {
+                finally.statements = statements(std::mem::take(&mut finally.statements));
+            }
+        }
+        Expression::Function(i) | Expression::Macro(i) => {
+            let body = i.body_mut();
+            body.statements = statements(std::mem::take(&mut body.statements));
+        }
+        Expression::Call(i) => {
+            expression(i.function_mut());
+            for arg in i.arguments_mut() {
+                expression(arg);
+            }
+        }
+        Expression::Array(i) => {
+            for element in i.elements_mut() {
+                expression(element);
+            }
+        }
+        Expression::Hash(i) => {
+            for (key, value) in i.pairs_mut() {
+                expression(key);
+                expression(value);
+            }
+        }
+        Expression::Index(i) => {
+            expression(i.left_mut());
+            expression(i.index_mut());
+        }
+        Expression::Member(i) => expression(i.object_mut()),
+        Expression::Assign(i) => expression(i.value_mut()),
+        Expression::IndexAssign(i) => {
+            let target = i.target_mut();
+            expression(target.left_mut());
+            expression(target.index_mut());
+            expression(i.value_mut());
+        }
+    })
+}
+
+/// Replaces `e` with the literal it folds to, which keeps `e` for errors
+/// in the expressions around it to quote.
+fn replace<'a>(e: &mut Expression<'a>, literal: Expression<'a>) {
+    let original = std::mem::replace(e, literal);
+    e.fold(original);
+}
+
+fn fold_infix<'a>(
+    left: &Expression,
+    operator: &str,
+    right: &Expression,
+    local: Option<Location<'a>>,
+) -> Option<Expression<'a>> {
+    // a falsy left decides `&&` and a truthy one `||`, whatever the right is,
+    // and otherwise the right gives the result
+    if operator == "&&" || operator == "||" {
+        let decided = constant_condition(left)? == (operator == "||");
+        let value = if decided {
+            operator == "||"
+        } else {
+            constant_condition(right)?
+        };
+        return Some(boolean(value, local));
+    }
+
+    match (left, right) {
+        (Expression::Integer(l), Expression::Integer(r)) => {
+            let (l, r) = (l.value(), r.value());
+            match operator {
+                "+" => l.checked_add(r).map(|v| integer(v, local)),
+                "-" => l.checked_sub(r).map(|v| integer(v, local)),
+                "*" => l.checked_mul(r).map(|v| integer(v, local)),
+                "/" => l.checked_div(r).map(|v| integer(v, local)),
+                "%" => l.checked_rem(r).map(|v| integer(v, local)),
+                "&" => Some(integer(l & r, local)),
+                "|" => Some(integer(l | r, local)),
+                "^" => Some(integer(l ^ r, local)),
+                // out of range shifts are left to fail at runtime
+                "<<" if (0..128).contains(&r) => {
+                    object::checked_shl(l, r).map(|v| integer(v, local))
+                }
+                ">>" => u32::try_from(r)
+                    .ok()
+                    .and_then(|r| l.checked_shr(r))
+                    .map(|v| integer(v, local)),
+                "<" => Some(boolean(l < r, local)),
+                ">" => Some(boolean(l > r, local)),
+                "<=" => Some(boolean(l <= r, local)),
+                ">=" => Some(boolean(l >= r, local)),
+                "==" => Some(boolean(l == r, local)),
+                "!=" => Some(boolean(l != r, local)),
+                _ => None,
+            }
+        }
+        (Expression::Boolean(l), Expression::Boolean(r)) => match operator {
+            "==" => Some(boolean(l.value() == r.value(), local)),
+            "!=" => Some(boolean(l.value() != r.value(), local)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Site;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn optimized(input: &str) -> String {
+        let l = Lexer::new(input, true, None);
+        let mut p = Parser::new(l);
+        let mut program = p.parse_program().expect("Program should be Some here");
+        assert_eq!(p.errors().len(), 0);
+
+        optimize(&mut program);
+        program.to_string()
+    }
+
+    #[test]
+    fn test_fold_constants() {
+        let tests = [
+            ("2 * 3 + 1", "7"),
+            ("-(4 - 10) / 2", "3"),
+            ("!true", "false"),
+            ("1 + 2 == 3 != false", "true"),
+            ("x + 2 * 3", "(x + 6)"),
+            ("f(1 + 1, 10 > 2)", "f(2, true)"),
+            ("x -= 4 / 2", "(x -= 2)"),
+            ("7 % 3 <= 1 == 10 >= 11", "false"),
+            ("~5 & 255 | 1 << 10 ^ -16 >> 2", "-1026"),
+            ("false && f() || 1 && x", "(false || (1 && x))"),
+            ("true || f()", "true"),
+            ("x && 1 || 2 && false", "((x && 1) || false)"),
+            (
+                "let a = fn(x) { x * (2 - 1) };",
+                "let a = fn(x) { (x * 1) };",
+            ),
+        ];
+
+        for (input, expected) in tests {
+            assert_eq!(optimized(input), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_leaves_runtime_errors_alone() {
+        assert_eq!(optimized("1 / 0"), "(1 / 0)");
+        assert_eq!(optimized("1 << 128"), "(1 << 128)");
+        assert_eq!(optimized("3 << 127"), "(3 << 127)");
+        assert_eq!(optimized("1 >> -1"), "(1 >> -1)");
+        assert_eq!(
+            optimized("170141183460469231731687303715884105727 + 1"),
+            "(170141183460469231731687303715884105727 + 1)"
+        );
+    }
+
+    #[test]
+    fn test_errors_quote_the_source() {
+        let tests = [
+            ("-(1 > 2)", "-(1 > 2)"),
+            ("grow(1 << 126)", "grow(1 << 126)"),
+            ("f(2 * 3 + 1, !true)", "f(2 * 3 + 1, !true)"),
+            ("(1 + 2)(x)", "(1 + 2)(x)"),
+        ];
+
+        for (input, expected) in tests {
+            let mut p = Parser::new(Lexer::new(input, true, None));
+            let mut program = p.parse_program().expect("Program should be Some here");
+            optimize(&mut program);
+            let e = program.statements[0].expressions()[0];
+            assert_eq!(Site::of(e).expression, expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_remove_constant_branches() {
+        let tests = [
+            ("if (1 < 2) { let x = 1; x } else { 3 }; y", "let x = 1;xy"),
+            ("if (false) { 1 }; y", "y"),
+            ("let v = if (!false) { 10 } else { 20 };", "let v = 10;"),
+            ("let v = if (false) { 10 };", "let v = if false { 10 };"),
+            ("if (false) { 1 }", "if false {  }"),
+            ("if (true) { } else { 1 }", "if true {  } else {  }"),
+            (
+                "if (c) { if (true) { a } } else { b }",
+                "if c { a } else { b }",
+            ),
+        ];
+
+        for (input, expected) in tests {
+            assert_eq!(optimized(input), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_folded_literals_keep_their_span() {
+        let path = std::path::Path::new("fold.my");
+        let l = Lexer::new("let x =\n  2 * 3;", false, Some(path));
+        let mut p = Parser::new(l);
+        let mut program = p.parse_program().expect("Program should be Some here");
+
+        optimize(&mut program);
+
+        match &program.statements[0] {
+            Statement::Let(i) => {
+                let value = i.value().expect("let should have a value");
+                assert_eq!(value.to_string(), "6");
+                assert_eq!(value.token().local(), Some(&Location::new(2, 3, path)));
+            }
+            _ => panic!("expected let statement but got something else"),
+        }
+    }
+}
@@ -1,37 +1,178 @@
 use crate::ast;
 use crate::lexer::Lexer;
-use crate::token::{Token, TokenKind};
+use crate::token::{self, Comment, Location, Token, TokenKind};
+use std::fmt;
+use std::path::Path;
+
+/// How deeply expressions and blocks may nest. Every expression, operator
+/// and block counts a level, and the tree is never deeper than that.
+pub const MAX_NESTING: usize = 1000;
+
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub enum Precedence {
+    Lowest,
+    Assign,      // = or +=
+    Or,          // ||
+    And,         // &&
+    Equals,      // ==
+    LessGreater, // > or <=
+    BitOr,       // |
+    BitXor,      // ^
+    BitAnd,      // &
+    Shift,       // << or >>
+    Sum,         // +
+    Product,     // * or %
+    Prefix,      // -X or !X
+    Call,        // myFunction(X)
+    Index,       // array[index]
+    Member,      // module.name
+}
+
+impl Precedence {
+    pub fn of(t: &TokenKind) -> Precedence {
+        match t {
+            TokenKind::ASSIGN
+            | TokenKind::PLUSEQ
+            | TokenKind::MINUSEQ
+            | TokenKind::ASTERISKEQ
+            | TokenKind::SLASHEQ => Precedence::Assign,
+            TokenKind::OR => Precedence::Or,
+            TokenKind::AND => Precedence::And,
+            TokenKind::EQ | TokenKind::NEQ => Precedence::Equals,
+            TokenKind::LT | TokenKind::GT | TokenKind::LTE | TokenKind::GTE => {
+                Precedence::LessGreater
+            }
+            TokenKind::PIPE => Precedence::BitOr,
+            TokenKind::CARET => Precedence::BitXor,
+            TokenKind::AMPERSAND => Precedence::BitAnd,
+            TokenKind::SHL | TokenKind::SHR => Precedence::Shift,
+            TokenKind::PLUS | TokenKind::MINUS => Precedence::Sum,
+            TokenKind::SLASH | TokenKind::ASTERISK | TokenKind::PERCENT => Precedence::Product,
+            TokenKind::LPAREN => Precedence::Call,
+            TokenKind::LBRACKET => Precedence::Index,
+            TokenKind::DOT => Precedence::Member,
+            _ => Precedence::Lowest,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ParseError<'a> {
+    pub message: String,
+    local: Option<Location<'a>>,
+    len: usize,
+}
+
+impl<'a> ParseError<'a> {
+    /// An error about `token`.
+    pub fn new(message: String, token: &Token<'a>) -> ParseError<'a> {
+        ParseError {
+            message,
+            local: token.local().cloned(),
+            len: token.source_len(),
+        }
+    }
+
+    /// Where the offending token starts, when parsing a file.
+    pub fn local(&self) -> Option<&Location<'a>> {
+        self.local.as_ref()
+    }
+
+    /// How long the offending token is in the source.
+    pub fn source_len(&self) -> usize {
+        self.len
+    }
+}
+
+impl fmt::Display for ParseError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.local {
+            Some(l) => {
+                let (row, col, _) = l.get();
+                write!(f, "{}:{}: {}", row, col, self.message)
+            }
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
 
 pub struct Parser<'a> {
     lex: Lexer<'a>,
     cur_token: Token<'a>,
     peek_token: Token<'a>,
-    errors: Vec<String>,
+    errors: Vec<ParseError<'a>>,
+    /// How many blocks deep we are. Imports only go at the top level.
+    depth: usize,
+    /// How many loops deep we are in the current function. `break` and
+    /// `continue` only go inside one.
+    loops: usize,
+    /// How deeply the expressions and blocks being parsed nest, so that
+    /// neither the parser nor anything walking the tree runs out of stack.
+    nesting: usize,
+    /// How many errors there were once nesting went past `MAX_NESTING`.
+    /// The errors after that only come from giving up, so they're dropped.
+    too_deep: Option<usize>,
 }
 
 impl<'a> Parser<'a> {
-    pub fn new(lex: Lexer) -> Parser {
+    pub fn new(lex: Lexer<'a>) -> Parser<'a> {
         let mut p = Parser {
             lex,
             cur_token: Token::new(TokenKind::EOF, None),
             peek_token: Token::new(TokenKind::EOF, None),
             errors: Vec::new(),
+            depth: 0,
+            loops: 0,
+            nesting: 0,
+            too_deep: None,
         };
         p.next_token();
         p.next_token();
         p
     }
 
-    pub fn errors(&self) -> &[String] {
+    pub fn errors(&self) -> &[ParseError<'a>] {
         &self.errors
     }
 
+    /// Comments skipped by the lexer so far, in source order.
+    pub fn comments(&self) -> &[Comment<'a>] {
+        self.lex.comments()
+    }
+
     fn peek_error(&mut self, t: &TokenKind) {
         let msg = format!(
             "expected next token to be {}, got {} instead",
             t, self.peek_token.ttype
         );
-        self.errors.push(msg);
+        self.errors.push(ParseError::new(msg, &self.peek_token));
+    }
+
+    fn no_prefix_parse_error(&mut self) {
+        let msg = format!(
+            "no prefix parse function for {} found",
+            self.cur_token.ttype
+        );
+        self.errors.push(ParseError::new(msg, &self.cur_token));
+    }
+
+    /// Goes one level deeper, or gives up on the rest of the input if
+    /// that's too deep. Callers put `nesting` back when they're done.
+    fn nest(&mut self) -> Option<()> {
+        self.nesting += 1;
+        if self.nesting <= MAX_NESTING {
+            return Some(());
+        }
+
+        if self.too_deep.is_none() {
+            let msg = format!("nested more than {} levels deep", MAX_NESTING);
+            self.errors.push(ParseError::new(msg, &self.cur_token));
+            self.too_deep = Some(self.errors.len());
+        }
+        while !self.cur_token_is(&TokenKind::EOF) {
+            self.next_token();
+        }
+        None
     }
 
     fn next_token(&mut self) {
@@ -46,6 +187,7 @@ impl<'a> Parser<'a> {
     fn peek_token_is(&self, t: &TokenKind) -> bool {
         match t {
             TokenKind::IDENT(_) => matches!(self.peek_token.ttype, TokenKind::IDENT(_)),
+            TokenKind::STRING(_) => matches!(self.peek_token.ttype, TokenKind::STRING(_)),
             _ => self.peek_token.ttype == *t,
         }
     }
@@ -60,19 +202,515 @@ impl<'a> Parser<'a> {
         }
     }
 
+    fn peek_precedence(&self) -> Precedence {
+        Precedence::of(&self.peek_token.ttype)
+    }
+
+    fn cur_precedence(&self) -> Precedence {
+        Precedence::of(&self.cur_token.ttype)
+    }
+
+    fn parse_identifier(&self) -> ast::Expression<'a> {
+        ast::Expression::Identifier(ast::Identifier::new(
+            self.cur_token.clone(),
+            self.cur_token.literal.clone(),
+        ))
+    }
+
+    fn parse_integer_literal(&self) -> Option<ast::Expression<'a>> {
+        match self.cur_token.ttype {
+            TokenKind::INT(value) => Some(ast::Expression::Integer(ast::IntegerInternal::new(
+                self.cur_token.clone(),
+                value,
+            ))),
+            _ => None,
+        }
+    }
+
+    fn parse_boolean(&self) -> ast::Expression<'a> {
+        ast::Expression::Boolean(ast::BooleanInternal::new(
+            self.cur_token.clone(),
+            self.cur_token_is(&TokenKind::TRUE),
+        ))
+    }
+
+    fn parse_string_literal(&self) -> ast::Expression<'a> {
+        ast::Expression::String(ast::StringInternal::new(self.cur_token.clone()))
+    }
+
+    fn parse_prefix_expression(&mut self) -> Option<ast::Expression<'a>> {
+        let token = self.cur_token.clone();
+        let operator = token.literal.clone();
+
+        self.next_token();
+
+        let right = self.parse_expression(Precedence::Prefix)?;
+
+        Some(ast::Expression::Prefix(ast::PrefixInternal::new(
+            token, operator, right,
+        )))
+    }
+
+    fn parse_infix_expression(&mut self, left: ast::Expression<'a>) -> Option<ast::Expression<'a>> {
+        let token = self.cur_token.clone();
+        let operator = token.literal.clone();
+        let precedence = self.cur_precedence();
+
+        self.next_token();
+
+        let right = self.parse_expression(precedence)?;
+
+        Some(ast::Expression::Infix(ast::InfixInternal::new(
+            token, left, operator, right,
+        )))
+    }
+
+    fn parse_assign_expression(
+        &mut self,
+        left: ast::Expression<'a>,
+    ) -> Option<ast::Expression<'a>> {
+        let token = self.cur_token.clone();
+
+        match left {
+            ast::Expression::Identifier(name) => {
+                let value = self.parse_assigned_value()?;
+                Some(ast::Expression::Assign(ast::AssignInternal::new(
+                    token, name, value,
+                )))
+            }
+            ast::Expression::Index(target) => {
+                let value = self.parse_assigned_value()?;
+                Some(ast::Expression::IndexAssign(ast::IndexAssignInternal::new(
+                    token, target, value,
+                )))
+            }
+            left => {
+                let msg = format!("can't assign to {}", left);
+                self.errors.push(ParseError::new(msg, &token));
+                None
+            }
+        }
+    }
+
+    fn parse_assigned_value(&mut self) -> Option<ast::Expression<'a>> {
+        self.next_token();
+
+        // parsed at the lowest precedence so that `a = b = 1` assigns both
+        self.parse_expression(Precedence::Lowest)
+    }
+
+    fn parse_grouped_expression(&mut self) -> Option<ast::Expression<'a>> {
+        self.next_token();
+
+        let exp = self.parse_expression(Precedence::Lowest);
+
+        if !self.expect_peek(&TokenKind::RPAREN) {
+            return None;
+        }
+
+        exp
+    }
+
+    fn parse_block_statement(&mut self) -> ast::Block<'a> {
+        let token = self.cur_token.clone();
+        let mut statements = Vec::new();
+
+        self.next_token();
+        self.depth += 1;
+        let nesting = self.nesting;
+        let _ = self.nest();
+
+        while !self.cur_token_is(&TokenKind::RBRACE) && !self.cur_token_is(&TokenKind::EOF) {
+            if let Some(s) = ast::deeper(|| self.parse_statement()) {
+                statements.push(s);
+            }
+            self.next_token();
+        }
+
+        self.nesting = nesting;
+        self.depth -= 1;
+        ast::Block::new(token, statements)
+    }
+
+    fn parse_if_expression(&mut self) -> Option<ast::Expression<'a>> {
+        let token = self.cur_token.clone();
+
+        if !self.expect_peek(&TokenKind::LPAREN) {
+            return None;
+        }
+
+        self.next_token();
+        let condition = self.parse_expression(Precedence::Lowest)?;
+
+        if !self.expect_peek(&TokenKind::RPAREN) {
+            return None;
+        }
+
+        if !self.expect_peek(&TokenKind::LBRACE) {
+            return None;
+        }
+
+        let consequence = self.parse_block_statement();
+
+        let alternative = if self.peek_token_is(&TokenKind::ELSE) {
+            self.next_token();
+
+            if !self.expect_peek(&TokenKind::LBRACE) {
+                return None;
+            }
+
+            Some(self.parse_block_statement())
+        } else {
+            None
+        };
+
+        Some(ast::Expression::If(ast::IfInternal::new(
+            token,
+            condition,
+            consequence,
+            alternative,
+        )))
+    }
+
+    fn parse_try_expression(&mut self) -> Option<ast::Expression<'a>> {
+        let token = self.cur_token.clone();
+
+        if !self.expect_peek(&TokenKind::LBRACE) {
+            return None;
+        }
+
+        let body = self.parse_block_statement();
+
+        if !self.expect_peek(&TokenKind::CATCH) {
+            return None;
+        }
+
+        if !self.expect_peek(&TokenKind::LPAREN) {
+            return None;
+        }
+
+        if !self.expect_peek(&TokenKind::IDENT(String::from("/*something*/"))) {
+            return None;
+        }
+
+        let name = ast::Identifier::new(self.cur_token.clone(), self.cur_token.literal.clone());
+
+        if !self.expect_peek(&TokenKind::RPAREN) {
+            return None;
+        }
+
+        if !self.expect_peek(&TokenKind::LBRACE) {
+            return None;
+        }
+
+        let handler = self.parse_block_statement();
+
+        let finally = if self.peek_token_is(&TokenKind::FINALLY) {
+            self.next_token();
+
+            if !self.expect_peek(&TokenKind::LBRACE) {
+                return None;
+            }
+
+            Some(self.parse_block_statement())
+        } else {
+            None
+        };
+
+        Some(ast::Expression::Try(Box::new(ast::TryInternal::new(
+            token, body, name, handler, finally,
+        ))))
+    }
+
+    fn parse_function_parameters(&mut self) -> Option<Vec<ast::Identifier<'a>>> {
+        let mut identifiers = Vec::new();
+
+        if self.peek_token_is(&TokenKind::RPAREN) {
+            self.next_token();
+            return Some(identifiers);
+        }
+
+        if !self.expect_peek(&TokenKind::IDENT(String::from("/*something*/"))) {
+            return None;
+        }
+
+        self.push_parameter(&mut identifiers);
+
+        while self.peek_token_is(&TokenKind::COMMA) {
+            self.next_token();
+
+            if !self.expect_peek(&TokenKind::IDENT(String::from("/*something*/"))) {
+                return None;
+            }
+
+            self.push_parameter(&mut identifiers);
+        }
+
+        if !self.expect_peek(&TokenKind::RPAREN) {
+            return None;
+        }
+
+        Some(identifiers)
+    }
+
+    /// Adds the parameter at the current token, which can't share its name
+    /// with one before it.
+    fn push_parameter(&mut self, identifiers: &mut Vec<ast::Identifier<'a>>) {
+        let name = &self.cur_token.literal;
+        if identifiers.iter().any(|i| i.value() == name) {
+            let msg = format!("duplicate parameter: {}", name);
+            self.errors.push(ParseError::new(msg, &self.cur_token));
+        }
+        identifiers.push(ast::Identifier::new(
+            self.cur_token.clone(),
+            self.cur_token.literal.clone(),
+        ));
+    }
+
+    /// A macro literal is written like a function, with `macro` for `fn`.
+    fn parse_function_literal(&mut self) -> Option<ast::Expression<'a>> {
+        let token = self.cur_token.clone();
+
+        if !self.expect_peek(&TokenKind::LPAREN) {
+            return None;
+        }
+
+        let parameters = self.parse_function_parameters()?;
+
+        if !self.expect_peek(&TokenKind::LBRACE) {
+            return None;
+        }
+
+        // a loop around the function isn't one `break` could leave
+        let loops = std::mem::take(&mut self.loops);
+        let body = self.parse_block_statement();
+        self.loops = loops;
+
+        let function = ast::FunctionInternal::new(token, parameters, body);
+        match function.token().ttype {
+            TokenKind::MACRO => Some(ast::Expression::Macro(function)),
+            _ => Some(ast::Expression::Function(function)),
+        }
+    }
+
+    /// Expressions separated by commas, up to `end`, as in a call or an
+    /// array literal.
+    fn parse_expression_list(&mut self, end: &TokenKind) -> Option<Vec<ast::Expression<'a>>> {
+        let mut list = Vec::new();
+
+        if self.peek_token_is(end) {
+            self.next_token();
+            return Some(list);
+        }
+
+        self.next_token();
+        list.push(self.parse_expression(Precedence::Lowest)?);
+
+        while self.peek_token_is(&TokenKind::COMMA) {
+            self.next_token();
+            self.next_token();
+            list.push(self.parse_expression(Precedence::Lowest)?);
+        }
+
+        if !self.expect_peek(end) {
+            return None;
+        }
+
+        Some(list)
+    }
+
+    fn parse_call_expression(
+        &mut self,
+        function: ast::Expression<'a>,
+    ) -> Option<ast::Expression<'a>> {
+        let token = self.cur_token.clone();
+        let arguments = self.parse_expression_list(&TokenKind::RPAREN)?;
+
+        Some(ast::Expression::Call(ast::CallInternal::new(
+            token, function, arguments,
+        )))
+    }
+
+    fn parse_member_expression(
+        &mut self,
+        object: ast::Expression<'a>,
+    ) -> Option<ast::Expression<'a>> {
+        let token = self.cur_token.clone();
+
+        if !self.expect_peek(&TokenKind::IDENT(String::from("/*something*/"))) {
+            return None;
+        }
+
+        let name = ast::Identifier::new(self.cur_token.clone(), self.cur_token.literal.clone());
+        Some(ast::Expression::Member(ast::MemberInternal::new(
+            token, object, name,
+        )))
+    }
+
+    fn parse_array_literal(&mut self) -> Option<ast::Expression<'a>> {
+        let token = self.cur_token.clone();
+        let elements = self.parse_expression_list(&TokenKind::RBRACKET)?;
+
+        Some(ast::Expression::Array(ast::ArrayInternal::new(
+            token, elements,
+        )))
+    }
+
+    fn parse_hash_literal(&mut self) -> Option<ast::Expression<'a>> {
+        let token = self.cur_token.clone();
+        let mut pairs = Vec::new();
+
+        while !self.peek_token_is(&TokenKind::RBRACE) {
+            self.next_token();
+            let key = self.parse_expression(Precedence::Lowest)?;
+
+            if !self.expect_peek(&TokenKind::COLON) {
+                return None;
+            }
+
+            self.next_token();
+            let value = self.parse_expression(Precedence::Lowest)?;
+            pairs.push((key, value));
+
+            if !self.peek_token_is(&TokenKind::RBRACE) && !self.expect_peek(&TokenKind::COMMA) {
+                return None;
+            }
+        }
+
+        self.next_token();
+        Some(ast::Expression::Hash(ast::HashInternal::new(token, pairs)))
+    }
+
+    fn parse_index_expression(&mut self, left: ast::Expression<'a>) -> Option<ast::Expression<'a>> {
+        let token = self.cur_token.clone();
+
+        self.next_token();
+        let index = self.parse_expression(Precedence::Lowest)?;
+
+        if !self.expect_peek(&TokenKind::RBRACKET) {
+            return None;
+        }
+
+        Some(ast::Expression::Index(ast::IndexInternal::new(
+            token, left, index,
+        )))
+    }
+
+    fn parse_expression(&mut self, precedence: Precedence) -> Option<ast::Expression<'a>> {
+        let nesting = self.nesting;
+        let exp = ast::deeper(|| self.parse_nested_expression(precedence));
+        self.nesting = nesting;
+        exp
+    }
+
+    /// Every operator applied here wraps `left` one level deeper, so each
+    /// counts towards the nesting as well.
+    fn parse_nested_expression(&mut self, precedence: Precedence) -> Option<ast::Expression<'a>> {
+        self.nest()?;
+        let mut left = match self.cur_token.ttype {
+            TokenKind::IDENT(_) => self.parse_identifier(),
+            TokenKind::INT(_) => self.parse_integer_literal()?,
+            TokenKind::TRUE | TokenKind::FALSE => self.parse_boolean(),
+            TokenKind::STRING(_) => self.parse_string_literal(),
+            TokenKind::BANG | TokenKind::MINUS | TokenKind::TILDE => {
+                self.parse_prefix_expression()?
+            }
+            TokenKind::LPAREN => self.parse_grouped_expression()?,
+            TokenKind::LBRACKET => self.parse_array_literal()?,
+            TokenKind::LBRACE => self.parse_hash_literal()?,
+            TokenKind::IF => self.parse_if_expression()?,
+            TokenKind::FUNCTION | TokenKind::MACRO => self.parse_function_literal()?,
+            TokenKind::TRY => self.parse_try_expression()?,
+            // the rest still parses as if it were a literal, so this is the
+            // only error it causes
+            TokenKind::ILLEGAL
+                if self
+                    .cur_token
+                    .literal
+                    .starts_with(|c: char| c.is_ascii_digit()) =>
+            {
+                let msg = format!("integer literal out of range: {}", self.cur_token.literal);
+                self.errors.push(ParseError::new(msg, &self.cur_token));
+                ast::Expression::Integer(ast::IntegerInternal::new(self.cur_token.clone(), 0))
+            }
+            _ => {
+                self.no_prefix_parse_error();
+                return None;
+            }
+        };
+
+        while !self.peek_token_is(&TokenKind::SEMICOLON) && precedence < self.peek_precedence() {
+            self.nest()?;
+            left = match self.peek_token.ttype {
+                TokenKind::PLUS
+                | TokenKind::MINUS
+                | TokenKind::SLASH
+                | TokenKind::ASTERISK
+                | TokenKind::PERCENT
+                | TokenKind::EQ
+                | TokenKind::NEQ
+                | TokenKind::LT
+                | TokenKind::GT
+                | TokenKind::LTE
+                | TokenKind::GTE
+                | TokenKind::AND
+                | TokenKind::OR
+                | TokenKind::AMPERSAND
+                | TokenKind::PIPE
+                | TokenKind::CARET
+                | TokenKind::SHL
+                | TokenKind::SHR => {
+                    self.next_token();
+                    self.parse_infix_expression(left)?
+                }
+                TokenKind::LPAREN => {
+                    self.next_token();
+                    self.parse_call_expression(left)?
+                }
+                TokenKind::LBRACKET => {
+                    self.next_token();
+                    self.parse_index_expression(left)?
+                }
+                TokenKind::DOT => {
+                    self.next_token();
+                    self.parse_member_expression(left)?
+                }
+                TokenKind::ASSIGN
+                | TokenKind::PLUSEQ
+                | TokenKind::MINUSEQ
+                | TokenKind::ASTERISKEQ
+                | TokenKind::SLASHEQ => {
+                    self.next_token();
+                    self.parse_assign_expression(left)?
+                }
+                _ => return Some(left),
+            };
+        }
+
+        Some(left)
+    }
+
     fn parse_return_statement(&mut self) -> Option<ast::Statement<'a>> {
-        let stmt = ast::Statement::Return(ast::ReturnInternal::init(self.cur_token.clone(), None));
+        let token = self.cur_token.clone();
 
         self.next_token();
 
-        //
-        //TODO: we're skipping expressions until we
-        // encounter a semicolon
-        while !self.cur_token_is(&TokenKind::SEMICOLON) {
+        if self.cur_token_is(&TokenKind::SEMICOLON) {
+            return Some(ast::Statement::Return(ast::ReturnInternal::init(
+                token, None,
+            )));
+        }
+
+        let return_value = Some(self.parse_expression(Precedence::Lowest)?);
+
+        if self.peek_token_is(&TokenKind::SEMICOLON) {
             self.next_token();
         }
 
-        Some(stmt)
+        Some(ast::Statement::Return(ast::ReturnInternal::init(
+            token,
+            return_value,
+        )))
     }
 
     fn parse_let_statement(&mut self) -> Option<ast::Statement<'a>> {
@@ -91,27 +729,197 @@ impl<'a> Parser<'a> {
             return None;
         }
 
-        while !self.cur_token_is(&TokenKind::SEMICOLON) {
+        self.next_token();
+
+        internal.change_value(self.parse_expression(Precedence::Lowest)?);
+
+        if self.peek_token_is(&TokenKind::SEMICOLON) {
             self.next_token();
         }
 
         Some(ast::Statement::Let(internal))
     }
 
-    fn parse_statement<'b>(&mut self) -> Option<ast::Statement<'b>>
-    where
-        'a: 'b,
-    {
-        match self.cur_token.ttype {
-            TokenKind::LET => {
-                let stmt = self.parse_let_statement();
-                stmt.map(|i| i)
+    fn parse_expression_statement(&mut self) -> Option<ast::Statement<'a>> {
+        let token = self.cur_token.clone();
+
+        let expression = self.parse_expression(Precedence::Lowest)?;
+
+        if self.peek_token_is(&TokenKind::SEMICOLON) {
+            self.next_token();
+        }
+
+        Some(ast::Statement::Expression(ast::ExpressionInternal::init(
+            token,
+            Some(expression),
+        )))
+    }
+
+    fn parse_import_statement(&mut self) -> Option<ast::Statement<'a>> {
+        let token = self.cur_token.clone();
+
+        if self.depth > 0 {
+            self.errors.push(ParseError::new(
+                String::from("import is only allowed at the top level"),
+                &token,
+            ));
+            return None;
+        }
+
+        if !self.expect_peek(&TokenKind::STRING(String::from("/*path*/"))) {
+            return None;
+        }
+
+        // the module is named after its file, so that has to make a name
+        let path = self.cur_token.literal.clone();
+        let stem = Path::new(&path).file_stem().and_then(|s| s.to_str());
+        let name = match stem.map(token::lookup_ident) {
+            Some(TokenKind::IDENT(name))
+                if !name.is_empty() && name.chars().all(|c| c.is_alphabetic() || c == '_') =>
+            {
+                name
             }
-            TokenKind::RETURN => {
-                let stmt = self.parse_return_statement();
-                stmt.map(|i| i)
+            _ => {
+                let msg = format!("can't import {}: its file name isn't an identifier", path);
+                self.errors.push(ParseError::new(msg, &self.cur_token));
+                if self.peek_token_is(&TokenKind::SEMICOLON) {
+                    self.next_token();
+                }
+                return None;
             }
-            _ => None,
+        };
+        let name = ast::Identifier::new(self.cur_token.clone(), name);
+
+        if self.peek_token_is(&TokenKind::SEMICOLON) {
+            self.next_token();
+        }
+
+        Some(ast::Statement::Import(ast::ImportInternal::new(
+            token, path, name,
+        )))
+    }
+
+    /// The body of a loop, with the current token on its `{`.
+    fn parse_loop_body(&mut self) -> ast::Block<'a> {
+        self.loops += 1;
+        let body = self.parse_block_statement();
+        self.loops -= 1;
+        body
+    }
+
+    fn parse_while_statement(&mut self) -> Option<ast::Statement<'a>> {
+        let token = self.cur_token.clone();
+
+        if !self.expect_peek(&TokenKind::LPAREN) {
+            return None;
+        }
+
+        self.next_token();
+        let condition = self.parse_expression(Precedence::Lowest)?;
+
+        if !self.expect_peek(&TokenKind::RPAREN) {
+            return None;
+        }
+
+        if !self.expect_peek(&TokenKind::LBRACE) {
+            return None;
+        }
+
+        let body = self.parse_loop_body();
+
+        Some(ast::Statement::While(ast::WhileInternal::new(
+            token, condition, body,
+        )))
+    }
+
+    fn parse_for_statement(&mut self) -> Option<ast::Statement<'a>> {
+        let token = self.cur_token.clone();
+
+        if !self.expect_peek(&TokenKind::LPAREN) {
+            return None;
+        }
+
+        if !self.expect_peek(&TokenKind::IDENT(String::from("/*something*/"))) {
+            return None;
+        }
+
+        let variable = ast::Identifier::new(self.cur_token.clone(), self.cur_token.literal.clone());
+
+        if !self.expect_peek(&TokenKind::IN) {
+            return None;
+        }
+
+        self.next_token();
+        let start = self.parse_expression(Precedence::Lowest)?;
+
+        // without an end, it goes over the elements of an array or the
+        // keys of a hash
+        let end = if self.peek_token_is(&TokenKind::DOTDOT) {
+            self.next_token();
+            self.next_token();
+            Some(self.parse_expression(Precedence::Lowest)?)
+        } else {
+            None
+        };
+
+        if !self.expect_peek(&TokenKind::RPAREN) {
+            return None;
+        }
+
+        if !self.expect_peek(&TokenKind::LBRACE) {
+            return None;
+        }
+
+        let body = self.parse_loop_body();
+
+        Some(ast::Statement::For(ast::ForInternal::new(
+            token, variable, start, end, body,
+        )))
+    }
+
+    /// `break` or `continue`, which only make sense inside a loop.
+    fn parse_loop_control(&mut self) -> Option<ast::Statement<'a>> {
+        let token = self.cur_token.clone();
+
+        if self.peek_token_is(&TokenKind::SEMICOLON) {
+            self.next_token();
+        }
+
+        if self.loops == 0 {
+            let msg = format!("{} outside of a loop", token.literal.to_lowercase());
+            self.errors.push(ParseError::new(msg, &token));
+            return None;
+        }
+
+        match token.ttype {
+            TokenKind::BREAK => Some(ast::Statement::Break(token)),
+            _ => Some(ast::Statement::Continue(token)),
+        }
+    }
+
+    fn parse_throw_statement(&mut self) -> Option<ast::Statement<'a>> {
+        let token = self.cur_token.clone();
+
+        self.next_token();
+        let value = self.parse_expression(Precedence::Lowest)?;
+
+        if self.peek_token_is(&TokenKind::SEMICOLON) {
+            self.next_token();
+        }
+
+        Some(ast::Statement::Throw(ast::ThrowInternal::new(token, value)))
+    }
+
+    fn parse_statement(&mut self) -> Option<ast::Statement<'a>> {
+        match self.cur_token.ttype {
+            TokenKind::LET => self.parse_let_statement(),
+            TokenKind::RETURN => self.parse_return_statement(),
+            TokenKind::IMPORT => self.parse_import_statement(),
+            TokenKind::WHILE => self.parse_while_statement(),
+            TokenKind::FOR => self.parse_for_statement(),
+            TokenKind::BREAK | TokenKind::CONTINUE => self.parse_loop_control(),
+            TokenKind::THROW => self.parse_throw_statement(),
+            _ => self.parse_expression_statement(),
         }
     }
 
@@ -128,6 +936,9 @@ impl<'a> Parser<'a> {
             self.next_token();
         }
 
+        if let Some(n) = self.too_deep {
+            self.errors.truncate(n);
+        }
         Some(program)
     }
 }
@@ -230,4 +1041,319 @@ mod tests {
             check_return_statement(stmt)
         }
     }
+
+    #[test]
+    fn test_operator_precedence_parsing() {
+        let tests = [
+            ("-a * b", "((-a) * b)"),
+            ("!-a", "(!(-a))"),
+            ("a + b - c", "((a + b) - c)"),
+            ("a + b * c + d / e - f", "(((a + (b * c)) + (d / e)) - f)"),
+            ("5 > 4 == 3 < 4", "((5 > 4) == (3 < 4))"),
+            (
+                "3 + 4 * 5 == 3 * 1 + 4 * 5",
+                "((3 + (4 * 5)) == ((3 * 1) + (4 * 5)))",
+            ),
+            ("true != false", "(true != false)"),
+            ("(5 + 5) * 2", "((5 + 5) * 2)"),
+            ("-(5 + 5)", "(-(5 + 5))"),
+            ("a + add(b * c) + d", "((a + add((b * c))) + d)"),
+            (
+                "add(a, b, 1, 2 * 3, 4 + 5, add(6, 7 * 8))",
+                "add(a, b, 1, (2 * 3), (4 + 5), add(6, (7 * 8)))",
+            ),
+            ("-lib.x * lib.f(1).y", "((-lib.x) * lib.f(1).y)"),
+            ("a <= b == c >= d % 2", "((a <= b) == (c >= (d % 2)))"),
+            ("a || b && c == d || e", "((a || (b && (c == d))) || e)"),
+            ("x = a && !b", "(x = (a && (!b)))"),
+            (
+                "a | b ^ c & d << 1 + 2 == e",
+                "((a | (b ^ (c & (d << (1 + 2))))) == e)",
+            ),
+            ("~a >> 2 & b < c", "((((~a) >> 2) & b) < c)"),
+        ];
+
+        for (input, expected) in tests {
+            let l = Lexer::new(input, true, None);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().expect("Program should be Some here");
+
+            assert_eq!(p.errors().len(), 0, "{:?}", p.errors());
+            assert_eq!(program.to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn test_if_and_function_expressions() {
+        let input = "let max = fn(x, y) { if (x > y) { x } else { y } };
+        max(1, 2);";
+
+        let l = Lexer::new(input, true, None);
+        let mut p = Parser::new(l);
+        let program = p.parse_program().expect("Program should be Some here");
+
+        assert_eq!(p.errors().len(), 0, "{:?}", p.errors());
+        assert_eq!(program.statements.len(), 2);
+
+        let function = match &program.statements[0] {
+            ast::Statement::Let(i) => match i.value() {
+                Some(ast::Expression::Function(f)) => f,
+                _ => panic!("expected a function literal"),
+            },
+            _ => panic!("expected let statement but got something else"),
+        };
+
+        let params: Vec<&str> = function
+            .parameters()
+            .iter()
+            .map(|p| p.value().as_str())
+            .collect();
+        assert_eq!(params, ["x", "y"]);
+
+        match &function.body().statements[0] {
+            ast::Statement::Expression(i) => match i.expression() {
+                Some(ast::Expression::If(e)) => {
+                    assert_eq!(e.condition().to_string(), "(x > y)");
+                    assert_eq!(e.consequence().to_string(), "x");
+                    assert_eq!(e.alternative().expect("expected else").to_string(), "y");
+                }
+                _ => panic!("expected an if expression"),
+            },
+            _ => panic!("expected expression statement but got something else"),
+        }
+
+        assert_eq!(program.statements[1].to_string(), "max(1, 2)");
+    }
+
+    #[test]
+    fn test_macro_literals() {
+        let input = "let unless = macro(c, body) { quote(if (!(unquote(c))) { unquote(body) }) };";
+
+        let l = Lexer::new(input, true, None);
+        let mut p = Parser::new(l);
+        let program = p.parse_program().expect("Program should be Some here");
+
+        assert_eq!(p.errors().len(), 0, "{:?}", p.errors());
+        match &program.statements[0] {
+            ast::Statement::Let(i) => match i.value() {
+                Some(ast::Expression::Macro(m)) => assert_eq!(m.parameters().len(), 2),
+                _ => panic!("expected a macro literal"),
+            },
+            _ => panic!("expected let statement but got something else"),
+        }
+        assert_eq!(
+            program.to_string(),
+            "let unless = macro(c, body) { quote(if (!unquote(c)) { unquote(body) }) };"
+        );
+    }
+
+    #[test]
+    fn test_import_statements() {
+        let l = Lexer::new("import \"lib/str_util.my\"; str_util.max", true, None);
+        let mut p = Parser::new(l);
+        let program = p.parse_program().expect("Program should be Some here");
+
+        assert_eq!(p.errors().len(), 0, "{:?}", p.errors());
+        match &program.statements[0] {
+            ast::Statement::Import(i) => {
+                assert_eq!(i.path(), "lib/str_util.my");
+                assert_eq!(i.name().value(), "str_util");
+            }
+            _ => panic!("expected import statement but got something else"),
+        }
+
+        let errors = |input| {
+            let mut p = Parser::new(Lexer::new(input, true, None));
+            p.parse_program();
+            p.errors()
+                .iter()
+                .map(|e| e.message.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            errors("import \"d/my-lib.my\"; 1"),
+            ["can't import d/my-lib.my: its file name isn't an identifier"]
+        );
+        assert_eq!(
+            errors("fn() { import \"lib.my\"; }"),
+            ["import is only allowed at the top level"]
+        );
+    }
+
+    #[test]
+    fn test_loop_statements() {
+        let input = "while (x > 0) { break; }\n\
+            for (i in 1 + 1..n * 2) { if (i > 2) { continue; } puts(i); }";
+        let l = Lexer::new(input, true, None);
+        let mut p = Parser::new(l);
+        let program = p.parse_program().expect("Program should be Some here");
+
+        assert_eq!(p.errors().len(), 0, "{:?}", p.errors());
+        assert_eq!(
+            program.statements[0].to_string(),
+            "while (x > 0) { break; }"
+        );
+        assert_eq!(
+            program.statements[1].to_string(),
+            "for i in (1 + 1)..(n * 2) { if (i > 2) { continue; }puts(i) }"
+        );
+
+        let l = Lexer::new("for (x in [1, 2]) { } for (k in h) { }", true, None);
+        let mut p = Parser::new(l);
+        let program = p.parse_program().expect("Program should be Some here");
+        assert_eq!(p.errors().len(), 0, "{:?}", p.errors());
+        assert_eq!(program.to_string(), "for x in [1, 2] {  }for k in h {  }");
+
+        let errors = |input| {
+            let mut p = Parser::new(Lexer::new(input, true, None));
+            p.parse_program();
+            p.errors()
+                .iter()
+                .map(|e| e.message.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(errors("break;"), ["break outside of a loop"]);
+        assert_eq!(errors("continue"), ["continue outside of a loop"]);
+        assert_eq!(
+            errors("while (true) { fn() { continue; } }"),
+            ["continue outside of a loop"]
+        );
+        assert_eq!(
+            errors("for (i in 0 xs) { }")[0],
+            "expected next token to be RPAREN, got IDENT = xs instead"
+        );
+    }
+
+    #[test]
+    fn test_try_expressions() {
+        let input = "let n = try { parse(\"12\") } catch (e) { 0 };\n\
+            try { throw \"bad\"; } catch (err) { err.kind } finally { done() }";
+        let l = Lexer::new(input, true, None);
+        let mut p = Parser::new(l);
+        let program = p.parse_program().expect("Program should be Some here");
+
+        assert_eq!(p.errors().len(), 0, "{:?}", p.errors());
+        assert_eq!(
+            program.statements[0].to_string(),
+            "let n = try { parse(\"12\") } catch (e) { 0 };"
+        );
+        assert_eq!(
+            program.statements[1].to_string(),
+            "try { throw \"bad\"; } catch (err) { err.kind } finally { done() }"
+        );
+
+        let errors = |input| {
+            let mut p = Parser::new(Lexer::new(input, true, None));
+            p.parse_program();
+            p.errors()[0].message.clone()
+        };
+        assert_eq!(
+            errors("try { 1 } finally { 2 }"),
+            "expected next token to be CATCH, got FINALLY instead"
+        );
+        assert_eq!(
+            errors("try { 1 } catch { 2 }"),
+            "expected next token to be LPAREN, got LBRACE instead"
+        );
+    }
+
+    #[test]
+    fn test_collections() {
+        let tests = [
+            ("[]", "[]"),
+            ("[1, 2 * 2, f(3)]", "[1, (2 * 2), f(3)]"),
+            ("{}", "{}"),
+            (
+                "{\"one\": 1, 2: [3], true: {}}",
+                "{\"one\": 1, 2: [3], true: {}}",
+            ),
+            ("a[1 + 1] * b", "((a[(1 + 1)]) * b)"),
+            ("-xs[0][1]", "(-((xs[0])[1]))"),
+            ("f(a)[0]", "(f(a)[0])"),
+            ("lib.xs[0].y", "(lib.xs[0]).y"),
+        ];
+
+        for (input, expected) in tests {
+            let l = Lexer::new(input, true, None);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().expect("Program should be Some here");
+            assert_eq!(p.errors().len(), 0, "{:?}", p.errors());
+            assert_eq!(program.to_string(), expected);
+        }
+
+        let errors = |input| {
+            let mut p = Parser::new(Lexer::new(input, true, None));
+            p.parse_program();
+            p.errors()[0].message.clone()
+        };
+        assert_eq!(
+            errors("{1 2}"),
+            "expected next token to be COLON, got INT = 2 instead"
+        );
+        assert_eq!(
+            errors("[1, 2"),
+            "expected next token to be RBRACKET, got EOF instead"
+        );
+    }
+
+    #[test]
+    fn test_assignments() {
+        let tests = [
+            ("x = 5", "(x = 5)"),
+            ("x += 1 * 2", "(x += (1 * 2))"),
+            ("a = b -= c == d", "(a = (b -= (c == d)))"),
+            ("f(x /= 2, y *= 3)", "f((x /= 2), (y *= 3))"),
+            ("a[0] = b[1] += 2", "(a[0] = (b[1] += 2))"),
+            ("h[k][0] -= 1", "((h[k])[0] -= 1)"),
+        ];
+
+        for (input, expected) in tests {
+            let l = Lexer::new(input, true, None);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().expect("Program should be Some here");
+            assert_eq!(p.errors().len(), 0, "{:?}", p.errors());
+            assert_eq!(program.to_string(), expected);
+        }
+
+        let mut p = Parser::new(Lexer::new("a + b = 1", true, None));
+        p.parse_program();
+        assert_eq!(p.errors()[0].message, "can't assign to (a + b)");
+    }
+
+    #[test]
+    fn test_parser_errors() {
+        let l = Lexer::new("let = 5; let x 5;", true, None);
+        let mut p = Parser::new(l);
+        p.parse_program();
+
+        assert_eq!(
+            p.errors()[0].message,
+            "expected next token to be IDENT = /*something*/, got ASSIGN instead"
+        );
+        assert_eq!(
+            p.errors()[2].message,
+            "expected next token to be ASSIGN, got INT = 5 instead"
+        );
+
+        let l = Lexer::new(
+            "let x = 1 + 999999999999999999999999999999999999999999 * 2;",
+            true,
+            None,
+        );
+        let mut p = Parser::new(l);
+        p.parse_program();
+
+        let errors: Vec<&str> = p.errors().iter().map(|e| e.message.as_str()).collect();
+        assert_eq!(
+            errors,
+            ["integer literal out of range: 999999999999999999999999999999999999999999"]
+        );
+
+        let l = Lexer::new("let f = fn(a, b, a) { a }; f(1, 2, 3)", true, None);
+        let mut p = Parser::new(l);
+        p.parse_program();
+
+        let errors: Vec<&str> = p.errors().iter().map(|e| e.message.as_str()).collect();
+        assert_eq!(errors, ["duplicate parameter: a"]);
+    }
 }
@@ -0,0 +1,460 @@
+use crate::error::Site;
+use crate::object::{self, Object};
+use std::fmt::Write;
+use std::rc::Rc;
+
+/// A register of the current frame. The compiler first numbers registers
+/// virtually, then `regcompiler` allocates them to as few real ones as it
+/// can.
+pub type Reg = u32;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instr {
+    LoadConstant {
+        dst: Reg,
+        idx: u32,
+    },
+    LoadTrue {
+        dst: Reg,
+    },
+    LoadFalse {
+        dst: Reg,
+    },
+    LoadNull {
+        dst: Reg,
+    },
+    Move {
+        dst: Reg,
+        src: Reg,
+    },
+
+    Add {
+        dst: Reg,
+        lhs: Reg,
+        rhs: Reg,
+    },
+    Sub {
+        dst: Reg,
+        lhs: Reg,
+        rhs: Reg,
+    },
+    Mul {
+        dst: Reg,
+        lhs: Reg,
+        rhs: Reg,
+    },
+    Div {
+        dst: Reg,
+        lhs: Reg,
+        rhs: Reg,
+    },
+    Mod {
+        dst: Reg,
+        lhs: Reg,
+        rhs: Reg,
+    },
+    BitAnd {
+        dst: Reg,
+        lhs: Reg,
+        rhs: Reg,
+    },
+    BitOr {
+        dst: Reg,
+        lhs: Reg,
+        rhs: Reg,
+    },
+    BitXor {
+        dst: Reg,
+        lhs: Reg,
+        rhs: Reg,
+    },
+    ShiftLeft {
+        dst: Reg,
+        lhs: Reg,
+        rhs: Reg,
+    },
+    ShiftRight {
+        dst: Reg,
+        lhs: Reg,
+        rhs: Reg,
+    },
+
+    Equal {
+        dst: Reg,
+        lhs: Reg,
+        rhs: Reg,
+    },
+    NotEqual {
+        dst: Reg,
+        lhs: Reg,
+        rhs: Reg,
+    },
+    GreaterThan {
+        dst: Reg,
+        lhs: Reg,
+        rhs: Reg,
+    },
+    GreaterEqual {
+        dst: Reg,
+        lhs: Reg,
+        rhs: Reg,
+    },
+
+    Minus {
+        dst: Reg,
+        src: Reg,
+    },
+    Bang {
+        dst: Reg,
+        src: Reg,
+    },
+    BitNot {
+        dst: Reg,
+        src: Reg,
+    },
+
+    Jump {
+        target: u32,
+    },
+    JumpNotTruthy {
+        cond: Reg,
+        target: u32,
+    },
+
+    GetGlobal {
+        dst: Reg,
+        idx: u32,
+    },
+    SetGlobal {
+        idx: u32,
+        src: Reg,
+    },
+    GetBuiltin {
+        dst: Reg,
+        idx: u32,
+    },
+    GetFree {
+        dst: Reg,
+        idx: u32,
+    },
+    CurrentClosure {
+        dst: Reg,
+    },
+    /// Puts the value in `reg` in a cell, which closures share with the
+    /// function instead of copying the value.
+    MakeCell {
+        reg: Reg,
+    },
+    GetCell {
+        dst: Reg,
+        cell: Reg,
+    },
+    SetCell {
+        cell: Reg,
+        src: Reg,
+    },
+    /// Reads the binding of the module in `obj` named `members[name]`.
+    GetMember {
+        dst: Reg,
+        obj: Reg,
+        name: u32,
+    },
+    /// Fails unless the bounds of a `for` loop are both integers.
+    CheckRange {
+        start: Reg,
+        end: Reg,
+    },
+
+    /// Makes a closure of the function constant `func`, capturing the
+    /// values in `free`.
+    Closure {
+        dst: Reg,
+        func: u32,
+        free: Box<[Reg]>,
+    },
+    Call {
+        dst: Reg,
+        func: Reg,
+        args: Box<[Reg]>,
+    },
+    /// Calls `func` in place of the running function, returning whatever
+    /// it returns.
+    TailCall {
+        func: Reg,
+        args: Box<[Reg]>,
+    },
+    Return {
+        src: Reg,
+    },
+    ReturnNull,
+
+    /// Goes to `target`, which starts with a `Catch`, when something fails
+    /// before the matching `PopHandler`.
+    SetHandler {
+        target: u32,
+    },
+    PopHandler,
+    /// Puts the error that was just caught in `dst`.
+    Catch {
+        dst: Reg,
+    },
+    /// Fails with the value in `src`.
+    Throw {
+        src: Reg,
+    },
+
+    Array {
+        dst: Reg,
+        elements: Box<[Reg]>,
+    },
+    /// Makes a hash of `pairs`, each a key then its value.
+    Hash {
+        dst: Reg,
+        pairs: Box<[Reg]>,
+    },
+    Index {
+        dst: Reg,
+        left: Reg,
+        index: Reg,
+    },
+    /// Stores `src` at `index` in the array or hash in `left`.
+    SetIndex {
+        left: Reg,
+        index: Reg,
+        src: Reg,
+    },
+    /// Puts what's at `position` in the array or hash a `for` goes over in
+    /// `dst`, or goes to `target` past the end.
+    Next {
+        dst: Reg,
+        items: Reg,
+        position: Reg,
+        target: u32,
+    },
+}
+
+impl Instr {
+    /// Calls `f` on every register the instruction reads or writes.
+    pub fn registers_mut(&mut self, mut f: impl FnMut(&mut Reg)) {
+        match self {
+            Instr::LoadConstant { dst, .. }
+            | Instr::LoadTrue { dst }
+            | Instr::LoadFalse { dst }
+            | Instr::LoadNull { dst }
+            | Instr::GetGlobal { dst, .. }
+            | Instr::GetBuiltin { dst, .. }
+            | Instr::GetFree { dst, .. }
+            | Instr::CurrentClosure { dst }
+            | Instr::MakeCell { reg: dst }
+            | Instr::Catch { dst } => f(dst),
+            Instr::Move { dst, src }
+            | Instr::Minus { dst, src }
+            | Instr::Bang { dst, src }
+            | Instr::BitNot { dst, src } => {
+                f(dst);
+                f(src);
+            }
+            Instr::GetMember { dst, obj, .. } | Instr::GetCell { dst, cell: obj } => {
+                f(dst);
+                f(obj);
+            }
+            Instr::Add { dst, lhs, rhs }
+            | Instr::Sub { dst, lhs, rhs }
+            | Instr::Mul { dst, lhs, rhs }
+            | Instr::Div { dst, lhs, rhs }
+            | Instr::Mod { dst, lhs, rhs }
+            | Instr::BitAnd { dst, lhs, rhs }
+            | Instr::BitOr { dst, lhs, rhs }
+            | Instr::BitXor { dst, lhs, rhs }
+            | Instr::ShiftLeft { dst, lhs, rhs }
+            | Instr::ShiftRight { dst, lhs, rhs }
+            | Instr::Equal { dst, lhs, rhs }
+            | Instr::NotEqual { dst, lhs, rhs }
+            | Instr::GreaterThan { dst, lhs, rhs }
+            | Instr::GreaterEqual { dst, lhs, rhs }
+            | Instr::Index {
+                dst,
+                left: lhs,
+                index: rhs,
+            }
+            | Instr::Next {
+                dst,
+                items: lhs,
+                position: rhs,
+                ..
+            }
+            | Instr::SetIndex {
+                left: dst,
+                index: lhs,
+                src: rhs,
+            } => {
+                f(dst);
+                f(lhs);
+                f(rhs);
+            }
+            Instr::CheckRange { start, end }
+            | Instr::SetCell {
+                cell: start,
+                src: end,
+            } => {
+                f(start);
+                f(end);
+            }
+            Instr::JumpNotTruthy { cond, .. } => f(cond),
+            Instr::SetGlobal { src, .. } | Instr::Return { src } | Instr::Throw { src } => f(src),
+            Instr::Closure { dst, free, .. }
+            | Instr::Array {
+                dst,
+                elements: free,
+            }
+            | Instr::Hash { dst, pairs: free } => {
+                f(dst);
+                free.iter_mut().for_each(f);
+            }
+            Instr::Call { dst, func, args } => {
+                f(dst);
+                f(func);
+                args.iter_mut().for_each(f);
+            }
+            Instr::TailCall { func, args } => {
+                f(func);
+                args.iter_mut().for_each(f);
+            }
+            Instr::Jump { .. }
+            | Instr::ReturnNull
+            | Instr::SetHandler { .. }
+            | Instr::PopHandler => (),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Function {
+    pub code: Vec<Instr>,
+    /// Registers a frame of this function needs. The parameters come first.
+    pub num_registers: usize,
+    pub num_parameters: usize,
+    /// The name `let` bound it to, for stack traces.
+    pub name: Option<String>,
+    /// The expressions the instructions that can fail came from, by index,
+    /// in order.
+    pub sites: Vec<(usize, Site)>,
+}
+
+impl Function {
+    /// The expression of the instruction at `idx`, if it can fail.
+    pub fn site(&self, idx: usize) -> Option<&Site> {
+        object::find_site(&self.sites, idx)
+    }
+}
+
+#[derive(Debug)]
+pub struct Closure {
+    pub func: Rc<Function>,
+    pub free: Vec<Object>,
+}
+
+pub struct Bytecode {
+    pub main: Function,
+    pub constants: Vec<Object>,
+    /// Names of the globals by slot, for error messages.
+    pub globals: Vec<String>,
+    /// The names `Instr::GetMember` looks up.
+    pub members: Vec<String>,
+}
+
+fn regs(regs: &[Reg]) -> String {
+    let names: Vec<String> = regs.iter().map(|r| format!("r{}", r)).collect();
+    names.join(" ")
+}
+
+/// One instruction per line, prefixed with its index.
+pub fn disassemble(code: &[Instr]) -> String {
+    let mut out = String::new();
+
+    for (idx, ins) in code.iter().enumerate() {
+        let _ = write!(out, "{:04} ", idx);
+        let _ = match ins {
+            Instr::LoadConstant { dst, idx } => write!(out, "LoadConstant r{} {}", dst, idx),
+            Instr::LoadTrue { dst } => write!(out, "LoadTrue r{}", dst),
+            Instr::LoadFalse { dst } => write!(out, "LoadFalse r{}", dst),
+            Instr::LoadNull { dst } => write!(out, "LoadNull r{}", dst),
+            Instr::Move { dst, src } => write!(out, "Move r{} r{}", dst, src),
+            Instr::Add { dst, lhs, rhs } => write!(out, "Add r{} r{} r{}", dst, lhs, rhs),
+            Instr::Sub { dst, lhs, rhs } => write!(out, "Sub r{} r{} r{}", dst, lhs, rhs),
+            Instr::Mul { dst, lhs, rhs } => write!(out, "Mul r{} r{} r{}", dst, lhs, rhs),
+            Instr::Div { dst, lhs, rhs } => write!(out, "Div r{} r{} r{}", dst, lhs, rhs),
+            Instr::Mod { dst, lhs, rhs } => write!(out, "Mod r{} r{} r{}", dst, lhs, rhs),
+            Instr::BitAnd { dst, lhs, rhs } => write!(out, "BitAnd r{} r{} r{}", dst, lhs, rhs),
+            Instr::BitOr { dst, lhs, rhs } => write!(out, "BitOr r{} r{} r{}", dst, lhs, rhs),
+            Instr::BitXor { dst, lhs, rhs } => write!(out, "BitXor r{} r{} r{}", dst, lhs, rhs),
+            Instr::ShiftLeft { dst, lhs, rhs } => {
+                write!(out, "ShiftLeft r{} r{} r{}", dst, lhs, rhs)
+            }
+            Instr::ShiftRight { dst, lhs, rhs } => {
+                write!(out, "ShiftRight r{} r{} r{}", dst, lhs, rhs)
+            }
+            Instr::Equal { dst, lhs, rhs } => write!(out, "Equal r{} r{} r{}", dst, lhs, rhs),
+            Instr::NotEqual { dst, lhs, rhs } => {
+                write!(out, "NotEqual r{} r{} r{}", dst, lhs, rhs)
+            }
+            Instr::GreaterThan { dst, lhs, rhs } => {
+                write!(out, "GreaterThan r{} r{} r{}", dst, lhs, rhs)
+            }
+            Instr::GreaterEqual { dst, lhs, rhs } => {
+                write!(out, "GreaterEqual r{} r{} r{}", dst, lhs, rhs)
+            }
+            Instr::Minus { dst, src } => write!(out, "Minus r{} r{}", dst, src),
+            Instr::BitNot { dst, src } => write!(out, "BitNot r{} r{}", dst, src),
+            Instr::Bang { dst, src } => write!(out, "Bang r{} r{}", dst, src),
+            Instr::Jump { target } => write!(out, "Jump {}", target),
+            Instr::JumpNotTruthy { cond, target } => {
+                write!(out, "JumpNotTruthy r{} {}", cond, target)
+            }
+            Instr::GetGlobal { dst, idx } => write!(out, "GetGlobal r{} {}", dst, idx),
+            Instr::SetGlobal { idx, src } => write!(out, "SetGlobal {} r{}", idx, src),
+            Instr::GetBuiltin { dst, idx } => write!(out, "GetBuiltin r{} {}", dst, idx),
+            Instr::GetFree { dst, idx } => write!(out, "GetFree r{} {}", dst, idx),
+            Instr::CurrentClosure { dst } => write!(out, "CurrentClosure r{}", dst),
+            Instr::MakeCell { reg } => write!(out, "MakeCell r{}", reg),
+            Instr::GetCell { dst, cell } => write!(out, "GetCell r{} r{}", dst, cell),
+            Instr::SetCell { cell, src } => write!(out, "SetCell r{} r{}", cell, src),
+            Instr::GetMember { dst, obj, name } => {
+                write!(out, "GetMember r{} r{} {}", dst, obj, name)
+            }
+            Instr::CheckRange { start, end } => write!(out, "CheckRange r{} r{}", start, end),
+            Instr::Closure { dst, func, free } => {
+                write!(out, "Closure r{} {} [{}]", dst, func, regs(free))
+            }
+            Instr::Call { dst, func, args } => {
+                write!(out, "Call r{} r{} [{}]", dst, func, regs(args))
+            }
+            Instr::TailCall { func, args } => {
+                write!(out, "TailCall r{} [{}]", func, regs(args))
+            }
+            Instr::Return { src } => write!(out, "Return r{}", src),
+            Instr::ReturnNull => write!(out, "ReturnNull"),
+            Instr::SetHandler { target } => write!(out, "SetHandler {}", target),
+            Instr::PopHandler => write!(out, "PopHandler"),
+            Instr::Catch { dst } => write!(out, "Catch r{}", dst),
+            Instr::Throw { src } => write!(out, "Throw r{}", src),
+            Instr::Array { dst, elements } => write!(out, "Array r{} [{}]", dst, regs(elements)),
+            Instr::Hash { dst, pairs } => write!(out, "Hash r{} [{}]", dst, regs(pairs)),
+            Instr::Index { dst, left, index } => {
+                write!(out, "Index r{} r{} r{}", dst, left, index)
+            }
+            Instr::SetIndex { left, index, src } => {
+                write!(out, "SetIndex r{} r{} r{}", left, index, src)
+            }
+            Instr::Next {
+                dst,
+                items,
+                position,
+                target,
+            } => write!(out, "Next r{} r{} r{} {}", dst, items, position, target),
+        };
+        out.push('\n');
+    }
+
+    out
+}
@@ -0,0 +1,1350 @@
+use crate::ast::{
+    self, Block, CallInternal, Expression, ForInternal, FunctionInternal, ImportInternal, Program,
+    Statement, TryInternal,
+};
+use crate::error::Site;
+use crate::macros;
+use crate::module::Modules;
+use crate::object::{self, Object, BUILTINS};
+use crate::regcode::{Bytecode, Function, Instr, Reg};
+use crate::symbol_table::{self, Symbol, SymbolScope, SymbolTable};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// The function being compiled, in virtual registers.
+#[derive(Default)]
+struct FunctionScope {
+    code: Vec<Instr>,
+    next_reg: Reg,
+    /// Register of each local, indexed like `SymbolScope::Local` symbols.
+    locals: Vec<Reg>,
+    num_parameters: usize,
+    name: Option<String>,
+    sites: Vec<(usize, Site)>,
+    /// The `try`s being compiled, innermost last.
+    tries: Vec<Try>,
+}
+
+/// The jumps a loop's `break` and `continue` statements leave to be patched.
+#[derive(Default)]
+struct Loop {
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
+}
+
+/// The ways out of a block other than reaching its end or failing.
+#[derive(Clone, Copy, PartialEq)]
+enum Exit {
+    Return,
+    Break,
+    Continue,
+}
+
+/// How a `finally` block was reached, like `compiler`'s.
+const REACHED_END: i128 = 0;
+const RETHROWING: i128 = 1;
+
+impl Exit {
+    fn code(self) -> i128 {
+        match self {
+            Exit::Return => 2,
+            Exit::Break => 3,
+            Exit::Continue => 4,
+        }
+    }
+}
+
+/// Which block of a `try` is being compiled, like `compiler`'s.
+#[derive(Clone, Copy, PartialEq)]
+enum TryPart {
+    Body,
+    Catch,
+    Finally,
+}
+
+/// A `try` being compiled, which a `return`, `break` or `continue` inside
+/// it has to leave properly.
+struct Try {
+    part: TryPart,
+    /// Loops already being compiled when it started.
+    loops: usize,
+    /// For a `try` with a `finally`, the registers that keep the value it's
+    /// leaving with and how it's leaving while the `finally` runs.
+    slots: Option<(Reg, Reg)>,
+    /// The jumps to the `finally` block, and the exits they were taking.
+    finally_jumps: Vec<usize>,
+    exits: Vec<Exit>,
+}
+
+/// Compiles a program for `regvm`. It has the same scoping rules as
+/// `compiler::Compiler`, and shares its `SymbolTable`.
+pub struct RegCompiler {
+    constants: Vec<Object>,
+    symbols: SymbolTable,
+    scopes: Vec<FunctionScope>,
+    members: Vec<String>,
+    loops: Vec<Loop>,
+
+    modules: Modules,
+    file: Option<PathBuf>,
+    loaded: HashMap<usize, Rc<object::Module>>,
+}
+
+impl Default for RegCompiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RegCompiler {
+    pub fn new() -> RegCompiler {
+        let mut symbols = SymbolTable::new();
+        for (idx, builtin) in BUILTINS.iter().enumerate() {
+            symbols.define_builtin(idx, builtin.name);
+        }
+
+        RegCompiler {
+            constants: Vec::new(),
+            symbols,
+            scopes: vec![FunctionScope::default()],
+            members: Vec::new(),
+            loops: Vec::new(),
+            modules: Modules::new(),
+            file: None,
+            loaded: HashMap::new(),
+        }
+    }
+
+    /// Sets the file being compiled, like `Compiler::set_file`.
+    pub fn set_file(&mut self, file: Option<&Path>) {
+        self.file = file.map(Path::to_path_buf);
+    }
+
+    pub fn compile(&mut self, program: &Program) -> Result<(), String> {
+        self.modules.load(program, self.file.as_deref())?;
+        self.statements(&program.statements)
+    }
+
+    fn statements(&mut self, statements: &[Statement]) -> Result<(), String> {
+        for stmt in statements {
+            let name = match stmt {
+                Statement::Let(i) => i.name(),
+                Statement::Import(i) => Some(i.name()),
+                _ => None,
+            };
+            if let Some(name) = name {
+                self.symbols.define(name.value());
+            }
+        }
+
+        for stmt in statements {
+            self.statement(stmt)?;
+        }
+
+        Ok(())
+    }
+
+    /// The module `i` imports, compiling its code the first time.
+    fn import(&mut self, i: &ImportInternal) -> Result<Rc<object::Module>, String> {
+        let module = match self.modules.find(self.file.as_deref(), i.path()) {
+            Some(m) => m.clone(),
+            None => return Err(format!("module {} isn't loaded", i.path())),
+        };
+        if let Some(compiled) = self.loaded.get(&module.id) {
+            return Ok(compiled.clone());
+        }
+
+        self.symbols.enter_module(&[]);
+        let importer = self.file.replace(module.path.clone());
+        let compiled = self.statements(&module.program.statements);
+        let exports = module
+            .exports
+            .iter()
+            .filter_map(|name| Some((name.clone(), self.symbols.global(name)?)))
+            .collect();
+        self.file = importer;
+        self.symbols.leave_module();
+        compiled?;
+
+        let compiled = Rc::new(object::Module {
+            name: module.name.clone(),
+            exports,
+        });
+        self.loaded.insert(module.id, compiled.clone());
+        Ok(compiled)
+    }
+
+    pub fn bytecode(mut self) -> Bytecode {
+        let scope = self.scopes.pop().expect("there is always a main scope");
+        Bytecode {
+            main: allocate(scope),
+            constants: self.constants,
+            globals: self.symbols.global_names(),
+            members: self.members,
+        }
+    }
+
+    fn scope(&mut self) -> &mut FunctionScope {
+        self.scopes
+            .last_mut()
+            .expect("there is always a main scope")
+    }
+
+    fn new_reg(&mut self) -> Reg {
+        let scope = self.scope();
+        scope.next_reg += 1;
+        scope.next_reg - 1
+    }
+
+    fn emit(&mut self, ins: Instr) -> usize {
+        let code = &mut self.scope().code;
+        code.push(ins);
+        code.len() - 1
+    }
+
+    /// Notes that the instruction just emitted can fail, and came from
+    /// `site`.
+    fn mark(&mut self, site: Site) {
+        let scope = self.scope();
+        scope.sites.push((scope.code.len() - 1, site));
+    }
+
+    /// Emits the infix operator `op` of the expression `e`.
+    fn emit_infix(
+        &mut self,
+        op: &str,
+        dst: Reg,
+        lhs: Reg,
+        rhs: Reg,
+        e: &Expression,
+    ) -> Result<(), String> {
+        let ins = infix(op, dst, lhs, rhs)?;
+        // anything can be compared for equality
+        let can_fail = !matches!(ins, Instr::Equal { .. } | Instr::NotEqual { .. });
+        self.emit(ins);
+        if can_fail {
+            self.mark(Site::of(e));
+        }
+        Ok(())
+    }
+
+    fn add_constant(&mut self, obj: Object) -> u32 {
+        self.constants.push(obj);
+        (self.constants.len() - 1) as u32
+    }
+
+    /// Points the jump at `position` to the next instruction.
+    fn patch_jump(&mut self, position: usize) {
+        let here = self.scope().code.len();
+        self.patch_jump_to(position, here);
+    }
+
+    fn patch_jump_to(&mut self, position: usize, to: usize) {
+        match &mut self.scope().code[position] {
+            Instr::Jump { target }
+            | Instr::JumpNotTruthy { target, .. }
+            | Instr::SetHandler { target }
+            | Instr::Next { target, .. } => *target = to as u32,
+            ins => unreachable!("{:?} isn't a jump", ins),
+        }
+    }
+
+    /// Gives the next local the register `reg`, or moves `reg` into the
+    /// local's register if `symbol` was defined before.
+    fn bind_local(&mut self, symbol: &Symbol, reg: Reg) {
+        if symbol.cell {
+            let cell = self.load_slot(symbol, None);
+            self.emit(Instr::SetCell { cell, src: reg });
+            return;
+        }
+
+        let scope = self.scope();
+        if symbol.index == scope.locals.len() {
+            scope.locals.push(reg);
+        } else {
+            let dst = scope.locals[symbol.index];
+            self.emit(Instr::Move { dst, src: reg });
+        }
+    }
+
+    /// Loads `s` into `dst`, or for a local with no `dst` given, just returns
+    /// its register.
+    fn load_symbol(&mut self, s: &Symbol, dst: Option<Reg>) -> Reg {
+        if !s.cell {
+            return self.load_slot(s, dst);
+        }
+        let cell = self.load_slot(s, None);
+        let dst = dst.unwrap_or_else(|| self.new_reg());
+        self.emit(Instr::GetCell { dst, cell });
+        dst
+    }
+
+    /// Like `load_symbol`, but loads the cell of a cell.
+    fn load_slot(&mut self, s: &Symbol, dst: Option<Reg>) -> Reg {
+        if s.scope == SymbolScope::Local {
+            let local = self.scope().locals[s.index];
+            return match dst {
+                Some(dst) if dst != local => {
+                    self.emit(Instr::Move { dst, src: local });
+                    dst
+                }
+                _ => local,
+            };
+        }
+
+        let dst = dst.unwrap_or_else(|| self.new_reg());
+        let idx = s.index as u32;
+        self.emit(match s.scope {
+            SymbolScope::Global => Instr::GetGlobal { dst, idx },
+            SymbolScope::Builtin => Instr::GetBuiltin { dst, idx },
+            SymbolScope::Free => Instr::GetFree { dst, idx },
+            SymbolScope::Function => Instr::CurrentClosure { dst },
+            SymbolScope::Local => unreachable!("handled above"),
+        });
+        dst
+    }
+
+    fn statement(&mut self, stmt: &Statement) -> Result<(), String> {
+        ast::deeper(|| {
+            match stmt {
+                Statement::Expression(i) => {
+                    if let Some(e) = i.expression() {
+                        self.expression(e, None)?;
+                    }
+                }
+                Statement::Let(i) => {
+                    let name = match i.name() {
+                        Some(n) => n.value(),
+                        None => return Ok(()),
+                    };
+
+                    // the name isn't in scope yet while compiling the value, so
+                    // writing straight into a fresh register is safe
+                    let reg = self.new_reg();
+                    match i.value() {
+                        Some(Expression::Function(f)) => self.function(f, Some(name), reg)?,
+                        Some(value) => {
+                            self.expression(value, Some(reg))?;
+                        }
+                        None => {
+                            self.emit(Instr::LoadNull { dst: reg });
+                        }
+                    }
+
+                    let symbol = self.symbols.define(name);
+                    if symbol.scope == SymbolScope::Global {
+                        self.emit(Instr::SetGlobal {
+                            idx: symbol.index as u32,
+                            src: reg,
+                        });
+                    } else {
+                        self.bind_local(&symbol, reg);
+                    }
+                }
+                Statement::Return(i) => {
+                    // a `try` has to see what the call does, so a call in one
+                    // isn't the last thing the function does
+                    let tail = self.scopes.len() > 1 && self.scope().tries.is_empty();
+                    let src = match i.return_value() {
+                        Some(value) if tail => return self.tail_return(value),
+                        Some(value) => self.expression(value, None)?,
+                        None => {
+                            let dst = self.new_reg();
+                            self.emit(Instr::LoadNull { dst });
+                            dst
+                        }
+                    };
+                    self.exit(Exit::Return, Some(src));
+                }
+                Statement::Import(i) => {
+                    let module = self.import(i)?;
+                    let src = self.new_reg();
+                    let idx = self.add_constant(Object::Module(module));
+                    self.emit(Instr::LoadConstant { dst: src, idx });
+
+                    let symbol = self.symbols.define(i.name().value());
+                    self.emit(Instr::SetGlobal {
+                        idx: symbol.index as u32,
+                        src,
+                    });
+                }
+                Statement::While(i) => {
+                    let start = self.scope().code.len();
+                    let cond = self.expression(i.condition(), None)?;
+                    let exit = self.emit(Instr::JumpNotTruthy { cond, target: 0 });
+
+                    let body = self.loop_body(i.body())?;
+                    self.emit(Instr::Jump {
+                        target: start as u32,
+                    });
+                    self.end_loop(body, exit, start);
+                }
+                Statement::For(i) => {
+                    let end = match i.end() {
+                        Some(end) => end,
+                        None => return self.for_each(i),
+                    };
+                    // both bounds are copied, so that rebinding a local they
+                    // came from doesn't change how often the loop runs
+                    let counter = self.new_reg();
+                    self.expression(i.start(), Some(counter))?;
+                    let end_reg = self.new_reg();
+                    self.expression(end, Some(end_reg))?;
+                    self.emit(Instr::CheckRange {
+                        start: counter,
+                        end: end_reg,
+                    });
+                    self.mark(Site::range(i.token(), i.start(), end));
+
+                    let start = self.scope().code.len();
+                    let cond = self.new_reg();
+                    self.emit(Instr::GreaterThan {
+                        dst: cond,
+                        lhs: end_reg,
+                        rhs: counter,
+                    });
+                    let exit = self.emit(Instr::JumpNotTruthy { cond, target: 0 });
+
+                    self.bind_variable(i, counter);
+
+                    let body = self.loop_body(i.body())?;
+                    let next = self.step(counter, start);
+                    self.end_loop(body, exit, next);
+                }
+                Statement::Break(_) | Statement::Continue(_) => {
+                    if self.loops.is_empty() {
+                        return Err(format!(
+                            "{} outside of a loop",
+                            stmt.token().literal.to_lowercase()
+                        ));
+                    }
+                    match stmt {
+                        Statement::Break(_) => self.exit(Exit::Break, None),
+                        _ => self.exit(Exit::Continue, None),
+                    }
+                }
+                Statement::Throw(i) => {
+                    let src = self.expression(i.value(), None)?;
+                    self.emit(Instr::Throw { src });
+                    self.mark(Site::throw(i.token(), i.value()));
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    /// `for (x in items)`, which counts through the positions of the
+    /// array or hash, looking it up afresh each time.
+    fn for_each(&mut self, i: &ForInternal) -> Result<(), String> {
+        // copied like a range's bounds
+        let items = self.new_reg();
+        self.expression(i.start(), Some(items))?;
+        let position = self.new_reg();
+        let idx = self.add_constant(Object::Integer(0));
+        self.emit(Instr::LoadConstant { dst: position, idx });
+
+        let start = self.scope().code.len();
+        let item = self.new_reg();
+        let exit = self.emit(Instr::Next {
+            dst: item,
+            items,
+            position,
+            target: 0,
+        });
+        self.mark(Site::of(i.start()));
+        self.bind_variable(i, item);
+
+        let body = self.loop_body(i.body())?;
+        let next = self.step(position, start);
+        self.end_loop(body, exit, next);
+        Ok(())
+    }
+
+    /// Binds the variable of the `for` loop `i` to a copy of what's in
+    /// `src`, so that rebinding it doesn't change how the loop goes.
+    fn bind_variable(&mut self, i: &ForInternal, src: Reg) {
+        let symbol = self.symbols.define(i.variable().value());
+        if symbol.scope == SymbolScope::Global {
+            self.emit(Instr::SetGlobal {
+                idx: symbol.index as u32,
+                src,
+            });
+        } else {
+            let reg = self.new_reg();
+            self.emit(Instr::Move { dst: reg, src });
+            self.bind_local(&symbol, reg);
+        }
+    }
+
+    /// Adds one to a `for` loop's `counter` and goes back to the `start` of
+    /// the loop, returning where that begins, which is where `continue`
+    /// goes.
+    fn step(&mut self, counter: Reg, start: usize) -> usize {
+        let next = self.scope().code.len();
+        let one = self.new_reg();
+        let idx = self.add_constant(Object::Integer(1));
+        self.emit(Instr::LoadConstant { dst: one, idx });
+        self.emit(Instr::Add {
+            dst: counter,
+            lhs: counter,
+            rhs: one,
+        });
+        self.emit(Instr::Jump {
+            target: start as u32,
+        });
+        next
+    }
+
+    /// Leaves by `exit`, returning `value` if it's a `return`.
+    fn exit(&mut self, exit: Exit, value: Option<Reg>) {
+        if self.leave_tries(exit, value) {
+            return;
+        }
+
+        if exit == Exit::Return {
+            let src = value.expect("a return has a value");
+            self.emit(Instr::Return { src });
+            return;
+        }
+
+        let jump = self.emit(Instr::Jump { target: 0 });
+        let l = self.loops.last_mut().expect("checked by the caller");
+        match exit {
+            Exit::Break => l.breaks.push(jump),
+            _ => l.continues.push(jump),
+        }
+    }
+
+    /// Pops the handlers of the `try`s that `exit` jumps out of, like
+    /// `Compiler::leave_tries`, returning true if one with a `finally` took
+    /// the exit over.
+    fn leave_tries(&mut self, exit: Exit, value: Option<Reg>) -> bool {
+        let loops = self.loops.len();
+
+        for idx in (0..self.scope().tries.len()).rev() {
+            let t = &self.scope().tries[idx];
+            if exit != Exit::Return && t.loops < loops {
+                break;
+            }
+            let (part, slots) = (t.part, t.slots);
+
+            if part == TryPart::Body || (part == TryPart::Catch && slots.is_some()) {
+                self.emit(Instr::PopHandler);
+            }
+            let (slot, how) = match slots {
+                Some(slots) if part != TryPart::Finally => slots,
+                _ => continue,
+            };
+
+            if let Some(src) = value {
+                self.emit(Instr::Move { dst: slot, src });
+            }
+            self.set_exit_code(how, exit.code());
+            let jump = self.emit(Instr::Jump { target: 0 });
+
+            let t = &mut self.scope().tries[idx];
+            t.finally_jumps.push(jump);
+            if !t.exits.contains(&exit) {
+                t.exits.push(exit);
+            }
+            return true;
+        }
+
+        false
+    }
+
+    fn set_exit_code(&mut self, how: Reg, code: i128) {
+        let idx = self.add_constant(Object::Integer(code));
+        self.emit(Instr::LoadConstant { dst: how, idx });
+    }
+
+    /// Jumps past what follows unless `how` says the `finally` block was
+    /// reached with `code`. Returns the jump to patch.
+    fn unless_exit_code(&mut self, how: Reg, code: i128) -> usize {
+        let expected = self.new_reg();
+        self.set_exit_code(expected, code);
+        let cond = self.new_reg();
+        self.emit(Instr::Equal {
+            dst: cond,
+            lhs: how,
+            rhs: expected,
+        });
+        self.emit(Instr::JumpNotTruthy { cond, target: 0 })
+    }
+
+    /// Compiles the `try` `e` into `dst`, the way `Compiler::try_catch`
+    /// does, with registers for the `finally` block's hidden slots.
+    fn try_catch(&mut self, i: &TryInternal, e: &Expression, dst: Reg) -> Result<(), String> {
+        let slots = i.finally().map(|_| (self.new_reg(), self.new_reg()));
+
+        let set_handler = self.emit(Instr::SetHandler { target: 0 });
+        let t = Try {
+            part: TryPart::Body,
+            loops: self.loops.len(),
+            slots,
+            finally_jumps: Vec::new(),
+            exits: Vec::new(),
+        };
+        self.scope().tries.push(t);
+
+        self.block_value(i.body(), dst)?;
+        self.emit(Instr::PopHandler);
+        let done = self.emit(Instr::Jump { target: 0 });
+
+        self.patch_jump(set_handler);
+        let error = self.new_reg();
+        self.emit(Instr::Catch { dst: error });
+        let symbol = self.symbols.define(i.name().value());
+        if symbol.scope == SymbolScope::Global {
+            self.emit(Instr::SetGlobal {
+                idx: symbol.index as u32,
+                src: error,
+            });
+        } else {
+            self.bind_local(&symbol, error);
+        }
+        self.current_try().part = TryPart::Catch;
+
+        let rethrow = slots.map(|_| self.emit(Instr::SetHandler { target: 0 }));
+        self.block_value(i.handler(), dst)?;
+        if rethrow.is_some() {
+            self.emit(Instr::PopHandler);
+        }
+        self.patch_jump(done);
+
+        let (finally, (slot, how), rethrow) = match (i.finally(), slots, rethrow) {
+            (Some(finally), Some(slots), Some(rethrow)) => (finally, slots, rethrow),
+            _ => {
+                self.scope().tries.pop();
+                return Ok(());
+            }
+        };
+
+        self.emit(Instr::Move {
+            dst: slot,
+            src: dst,
+        });
+        self.set_exit_code(how, REACHED_END);
+        let reached_end = self.emit(Instr::Jump { target: 0 });
+
+        self.patch_jump(rethrow);
+        self.emit(Instr::Catch { dst: slot });
+        self.set_exit_code(how, RETHROWING);
+
+        self.patch_jump(reached_end);
+        let jumps = std::mem::take(&mut self.current_try().finally_jumps);
+        for jump in jumps {
+            self.patch_jump(jump);
+        }
+        self.current_try().part = TryPart::Finally;
+        for stmt in &finally.statements {
+            self.statement(stmt)?;
+        }
+
+        let t = self.scope().tries.pop().expect("we pushed this try");
+        for exit in t.exits {
+            let skip = self.unless_exit_code(how, exit.code());
+            self.exit(exit, Some(slot).filter(|_| exit == Exit::Return));
+            self.patch_jump(skip);
+        }
+
+        let skip = self.unless_exit_code(how, RETHROWING);
+        self.emit(Instr::Throw { src: slot });
+        self.mark(Site::of(e));
+        self.patch_jump(skip);
+
+        self.emit(Instr::Move { dst, src: slot });
+        Ok(())
+    }
+
+    fn current_try(&mut self) -> &mut Try {
+        self.scope()
+            .tries
+            .last_mut()
+            .expect("only called while compiling a try")
+    }
+
+    /// Compiles the body of a loop, returning the jumps out of it.
+    fn loop_body(&mut self, body: &Block) -> Result<Loop, String> {
+        self.loops.push(Loop::default());
+        let compiled = body.statements.iter().try_for_each(|s| self.statement(s));
+        let body = self.loops.pop().expect("we pushed this loop");
+        compiled.map(|_| body)
+    }
+
+    /// Points the jumps out of a loop that ends here, given the one taken
+    /// when its condition fails and where `continue` goes.
+    fn end_loop(&mut self, body: Loop, exit: usize, next: usize) {
+        self.patch_jump(exit);
+        for jump in body.breaks {
+            self.patch_jump(jump);
+        }
+        for jump in body.continues {
+            self.patch_jump_to(jump, next);
+        }
+    }
+
+    /// Compiles a block whose value ends up in `dst`, which is null unless
+    /// the block ends in an expression.
+    fn block_value(&mut self, block: &Block, dst: Reg) -> Result<(), String> {
+        let (last, rest) = match block.statements.split_last() {
+            Some(split) => split,
+            None => {
+                self.emit(Instr::LoadNull { dst });
+                return Ok(());
+            }
+        };
+
+        for stmt in rest {
+            self.statement(stmt)?;
+        }
+
+        match last {
+            Statement::Expression(i) if i.expression().is_some() => {
+                self.expression(i.expression().expect("checked"), Some(dst))?;
+            }
+            _ => {
+                self.statement(last)?;
+                self.emit(Instr::LoadNull { dst });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the value of `block`, with the expression it ends in in
+    /// tail position.
+    fn tail_block(&mut self, block: &Block) -> Result<(), String> {
+        match block.statements.split_last() {
+            Some((Statement::Expression(i), rest)) if i.expression().is_some() => {
+                for stmt in rest {
+                    self.statement(stmt)?;
+                }
+                self.tail_return(i.expression().expect("checked"))
+            }
+            _ => {
+                let src = self.new_reg();
+                self.block_value(block, src)?;
+                self.emit(Instr::Return { src });
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns the value of `e`. A call is made in place of the running
+    /// function, and so is one that's the value of a branch of an `if`.
+    fn tail_return(&mut self, e: &Expression) -> Result<(), String> {
+        match e {
+            Expression::Call(i) => {
+                let (func, args) = self.call_operands(i)?;
+                self.emit(Instr::TailCall { func, args });
+                self.mark(Site::of(e));
+            }
+            Expression::If(i) => {
+                let cond = self.expression(i.condition(), None)?;
+                let jump_not_truthy = self.emit(Instr::JumpNotTruthy { cond, target: 0 });
+                // both branches return, so there's nothing to jump over
+                self.tail_block(i.consequence())?;
+                self.patch_jump(jump_not_truthy);
+                match i.alternative() {
+                    Some(alt) => self.tail_block(alt)?,
+                    None => {
+                        let src = self.new_reg();
+                        self.emit(Instr::LoadNull { dst: src });
+                        self.emit(Instr::Return { src });
+                    }
+                }
+            }
+            _ => {
+                let src = self.expression(e, None)?;
+                self.emit(Instr::Return { src });
+            }
+        }
+        Ok(())
+    }
+
+    /// Compiles the function and arguments of a call.
+    fn call_operands(&mut self, i: &CallInternal) -> Result<(Reg, Box<[Reg]>), String> {
+        let arguments: Vec<&Expression> = i.arguments().iter().collect();
+        let func = self.operand(i.function(), &arguments)?;
+        Ok((func, self.operands(&arguments)?))
+    }
+
+    /// Compiles expressions evaluated one after the other, like the
+    /// arguments of a call or the elements of an array.
+    fn operands(&mut self, es: &[&Expression]) -> Result<Box<[Reg]>, String> {
+        let mut regs = Vec::with_capacity(es.len());
+        for (idx, e) in es.iter().enumerate() {
+            regs.push(self.operand(e, &es[idx + 1..])?);
+        }
+        Ok(regs.into_boxed_slice())
+    }
+
+    fn function(
+        &mut self,
+        f: &FunctionInternal,
+        name: Option<&str>,
+        dst: Reg,
+    ) -> Result<(), String> {
+        self.scopes.push(FunctionScope {
+            num_parameters: f.parameters().len(),
+            name: name.map(String::from),
+            ..Default::default()
+        });
+        self.symbols.push();
+
+        if let Some(name) = name {
+            self.symbols.define_function_name(name);
+        }
+
+        for p in f.parameters() {
+            let symbol = self.symbols.define(p.value());
+            let reg = self.new_reg();
+            self.bind_local(&symbol, reg);
+        }
+        for slot in self.symbols.define_cells(symbol_table::cells(f)) {
+            if slot == self.scope().locals.len() {
+                let reg = self.new_reg();
+                self.scope().locals.push(reg);
+            }
+            let reg = self.scope().locals[slot];
+            self.emit(Instr::MakeCell { reg });
+        }
+
+        let statements = &f.body().statements;
+        match statements.split_last() {
+            Some((Statement::Expression(i), rest)) if i.expression().is_some() => {
+                for stmt in rest {
+                    self.statement(stmt)?;
+                }
+                self.tail_return(i.expression().expect("checked"))?;
+            }
+            _ => {
+                for stmt in statements {
+                    self.statement(stmt)?;
+                }
+                if !matches!(self.scope().code.last(), Some(Instr::Return { .. })) {
+                    self.emit(Instr::ReturnNull);
+                }
+            }
+        }
+
+        let (free, _) = self.symbols.pop();
+        let scope = self.scopes.pop().expect("we pushed this scope");
+
+        let free = free.iter().map(|s| self.load_slot(s, None)).collect();
+        let func = self.add_constant(Object::RegFunction(Rc::new(allocate(scope))));
+        self.emit(Instr::Closure { dst, func, free });
+
+        Ok(())
+    }
+
+    /// Compiles `e` into `dst`, or a register of its choosing if that's
+    /// `None`, returning the register it used.
+    fn expression(&mut self, e: &Expression, dst: Option<Reg>) -> Result<Reg, String> {
+        ast::deeper(|| {
+            if let Expression::Identifier(i) = e {
+                let s = match self.symbols.resolve(i.value()) {
+                    Some(s) => s,
+                    None => return Err(format!("undefined variable {}", i.value())),
+                };
+                let reg = self.load_symbol(&s, dst);
+                // only a global can be read before it's set
+                if s.scope == SymbolScope::Global {
+                    self.mark(Site::of(e));
+                }
+                return Ok(reg);
+            }
+
+            let dst = dst.unwrap_or_else(|| self.new_reg());
+
+            match e {
+                Expression::Integer(i) => {
+                    let idx = self.add_constant(Object::Integer(i.value()));
+                    self.emit(Instr::LoadConstant { dst, idx });
+                }
+                Expression::Boolean(b) => {
+                    if b.value() {
+                        self.emit(Instr::LoadTrue { dst });
+                    } else {
+                        self.emit(Instr::LoadFalse { dst });
+                    }
+                }
+                Expression::Prefix(i) => {
+                    let src = self.expression(i.right(), None)?;
+                    match i.operator() {
+                        "!" => self.emit(Instr::Bang { dst, src }),
+                        "-" => self.emit(Instr::Minus { dst, src }),
+                        "~" => self.emit(Instr::BitNot { dst, src }),
+                        op => return Err(format!("unknown operator {}", op)),
+                    };
+                    if i.operator() != "!" {
+                        self.mark(Site::of(e));
+                    }
+                }
+                Expression::Infix(i) if matches!(i.operator(), "&&" | "||") => {
+                    let cond = self.expression(i.left(), None)?;
+                    let jump_not_truthy = self.emit(Instr::JumpNotTruthy { cond, target: 0 });
+
+                    if i.operator() == "||" {
+                        self.emit(Instr::LoadTrue { dst });
+                    } else {
+                        self.truthiness(i.right(), dst)?;
+                    }
+                    let jump = self.emit(Instr::Jump { target: 0 });
+
+                    self.patch_jump(jump_not_truthy);
+                    if i.operator() == "||" {
+                        self.truthiness(i.right(), dst)?;
+                    } else {
+                        self.emit(Instr::LoadFalse { dst });
+                    }
+                    self.patch_jump(jump);
+                }
+                Expression::Infix(i) => {
+                    let lhs = self.operand(i.left(), &[i.right()])?;
+                    let rhs = self.expression(i.right(), None)?;
+                    self.emit_infix(i.operator(), dst, lhs, rhs, e)?;
+                }
+                Expression::If(i) => {
+                    let cond = self.expression(i.condition(), None)?;
+                    let jump_not_truthy = self.emit(Instr::JumpNotTruthy { cond, target: 0 });
+
+                    self.block_value(i.consequence(), dst)?;
+                    let jump = self.emit(Instr::Jump { target: 0 });
+
+                    self.patch_jump(jump_not_truthy);
+                    match i.alternative() {
+                        Some(alt) => self.block_value(alt, dst)?,
+                        None => {
+                            self.emit(Instr::LoadNull { dst });
+                        }
+                    }
+                    self.patch_jump(jump);
+                }
+                Expression::String(s) => {
+                    let idx = self.add_constant(Object::String(Rc::from(s.value())));
+                    self.emit(Instr::LoadConstant { dst, idx });
+                }
+                Expression::Try(i) => self.try_catch(i, e, dst)?,
+                Expression::Function(f) => self.function(f, None, dst)?,
+                Expression::Macro(_) => return Err(String::from(macros::STRAY_MACRO)),
+                Expression::Call(i) => {
+                    let (func, args) = self.call_operands(i)?;
+                    self.emit(Instr::Call { dst, func, args });
+                    self.mark(Site::of(e));
+                }
+                Expression::Assign(i) => {
+                    let symbol = self.symbols.resolve_assignment(i.name().value())?;
+                    match i.infix_operator() {
+                        Some(op) => {
+                            // the value could rebind the local before it's read
+                            let lhs = if binds_locals(i.value()) {
+                                let copy = self.new_reg();
+                                self.load_symbol(&symbol, Some(copy))
+                            } else {
+                                self.load_symbol(&symbol, None)
+                            };
+                            if symbol.scope == SymbolScope::Global {
+                                self.mark(Site::of(e));
+                            }
+                            let rhs = self.expression(i.value(), None)?;
+                            self.emit_infix(op, dst, lhs, rhs, e)?;
+                        }
+                        None => {
+                            self.expression(i.value(), Some(dst))?;
+                        }
+                    }
+
+                    if symbol.scope == SymbolScope::Global {
+                        self.emit(Instr::SetGlobal {
+                            idx: symbol.index as u32,
+                            src: dst,
+                        });
+                    } else {
+                        self.bind_local(&symbol, dst);
+                    }
+                }
+                Expression::Array(i) => {
+                    let elements: Vec<&Expression> = i.elements().iter().collect();
+                    let elements = self.operands(&elements)?;
+                    self.emit(Instr::Array { dst, elements });
+                }
+                Expression::Hash(i) => {
+                    let pairs: Vec<&Expression> =
+                        i.pairs().iter().flat_map(|(k, v)| [k, v]).collect();
+                    let pairs = self.operands(&pairs)?;
+                    self.emit(Instr::Hash { dst, pairs });
+                    self.mark(Site::of(e));
+                }
+                Expression::Index(i) => {
+                    let left = self.operand(i.left(), &[i.index()])?;
+                    let index = self.expression(i.index(), None)?;
+                    self.emit(Instr::Index { dst, left, index });
+                    self.mark(Site::of(e));
+                }
+                Expression::IndexAssign(i) => {
+                    let target = i.target();
+                    let left = self.operand(target.left(), &[target.index(), i.value()])?;
+                    let index = self.operand(target.index(), &[i.value()])?;
+                    match i.infix_operator() {
+                        Some(op) => {
+                            let current = self.new_reg();
+                            self.emit(Instr::Index {
+                                dst: current,
+                                left,
+                                index,
+                            });
+                            self.mark(Site::of(e));
+                            let rhs = self.expression(i.value(), None)?;
+                            self.emit_infix(op, dst, current, rhs, e)?;
+                        }
+                        None => {
+                            self.expression(i.value(), Some(dst))?;
+                        }
+                    }
+                    self.emit(Instr::SetIndex {
+                        left,
+                        index,
+                        src: dst,
+                    });
+                    self.mark(Site::of(e));
+                }
+                Expression::Member(i) => {
+                    let obj = self.expression(i.object(), None)?;
+                    let name = i.name().value();
+                    let name = match self.members.iter().position(|m| m == name) {
+                        Some(idx) => idx,
+                        None => {
+                            self.members.push(name.clone());
+                            self.members.len() - 1
+                        }
+                    } as u32;
+                    self.emit(Instr::GetMember { dst, obj, name });
+                    self.mark(Site::of(e));
+                }
+                Expression::Identifier(_) => unreachable!("handled above"),
+            }
+
+            Ok(dst)
+        })
+    }
+
+    /// Puts whether `e` is truthy in `dst`, for `&&` and `||`.
+    fn truthiness(&mut self, e: &Expression, dst: Reg) -> Result<(), String> {
+        let src = self.expression(e, None)?;
+        self.emit(Instr::Bang { dst, src });
+        self.emit(Instr::Bang { dst, src: dst });
+        Ok(())
+    }
+
+    /// Compiles an operand that `later` operands are evaluated after. A
+    /// local is normally used in place, but if one of those could rebind it
+    /// the operand gets a copy of its current value instead.
+    fn operand(&mut self, e: &Expression, later: &[&Expression]) -> Result<Reg, String> {
+        if later.iter().any(|l| binds_locals(l)) {
+            let dst = self.new_reg();
+            self.expression(e, Some(dst))
+        } else {
+            self.expression(e, None)
+        }
+    }
+}
+
+/// The instruction for the infix operator `op`.
+fn infix(op: &str, dst: Reg, lhs: Reg, rhs: Reg) -> Result<Instr, String> {
+    Ok(match op {
+        "+" => Instr::Add { dst, lhs, rhs },
+        "-" => Instr::Sub { dst, lhs, rhs },
+        "*" => Instr::Mul { dst, lhs, rhs },
+        "/" => Instr::Div { dst, lhs, rhs },
+        "%" => Instr::Mod { dst, lhs, rhs },
+        "&" => Instr::BitAnd { dst, lhs, rhs },
+        "|" => Instr::BitOr { dst, lhs, rhs },
+        "^" => Instr::BitXor { dst, lhs, rhs },
+        "<<" => Instr::ShiftLeft { dst, lhs, rhs },
+        ">>" => Instr::ShiftRight { dst, lhs, rhs },
+        ">" => Instr::GreaterThan { dst, lhs, rhs },
+        "<" => Instr::GreaterThan {
+            dst,
+            lhs: rhs,
+            rhs: lhs,
+        },
+        ">=" => Instr::GreaterEqual { dst, lhs, rhs },
+        "<=" => Instr::GreaterEqual {
+            dst,
+            lhs: rhs,
+            rhs: lhs,
+        },
+        "==" => Instr::Equal { dst, lhs, rhs },
+        "!=" => Instr::NotEqual { dst, lhs, rhs },
+        op => return Err(format!("unknown operator {}", op)),
+    })
+}
+
+/// Whether evaluating `e` could rebind a local of the enclosing function,
+/// which assignments do, and so do `let`s in the blocks of an if and in
+/// loops there, and a `catch`.
+pub fn binds_locals(e: &Expression) -> bool {
+    match e {
+        Expression::Assign(_) | Expression::Try(_) => true,
+        Expression::Identifier(_)
+        | Expression::Integer(_)
+        | Expression::Boolean(_)
+        | Expression::String(_) => false,
+        Expression::Function(_) | Expression::Macro(_) => false,
+        Expression::Prefix(i) => binds_locals(i.right()),
+        Expression::Member(i) => binds_locals(i.object()),
+        Expression::Array(i) => i.elements().iter().any(binds_locals),
+        Expression::Hash(i) => i
+            .pairs()
+            .iter()
+            .any(|(k, v)| binds_locals(k) || binds_locals(v)),
+        Expression::Index(i) => binds_locals(i.left()) || binds_locals(i.index()),
+        Expression::IndexAssign(i) => {
+            binds_locals(i.target().left())
+                || binds_locals(i.target().index())
+                || binds_locals(i.value())
+        }
+        Expression::Infix(i) => binds_locals(i.left()) || binds_locals(i.right()),
+        Expression::If(i) => {
+            binds_locals(i.condition())
+                || block_binds_locals(i.consequence())
+                || i.alternative().is_some_and(block_binds_locals)
+        }
+        Expression::Call(i) => binds_locals(i.function()) || i.arguments().iter().any(binds_locals),
+    }
+}
+
+fn block_binds_locals(block: &Block) -> bool {
+    block.statements.iter().any(|s| match s {
+        Statement::Let(_) | Statement::Import(_) | Statement::For(_) => true,
+        Statement::While(i) => binds_locals(i.condition()) || block_binds_locals(i.body()),
+        Statement::Break(_) | Statement::Continue(_) => false,
+        Statement::Return(i) => i.return_value().is_some_and(binds_locals),
+        Statement::Throw(i) => binds_locals(i.value()),
+        Statement::Expression(i) => i.expression().is_some_and(binds_locals),
+    })
+}
+
+/// A virtual register's lifetime, from its first to its last appearance in
+/// the code.
+struct Interval {
+    reg: Reg,
+    start: usize,
+    end: usize,
+    /// Locals live from the start of the function, so that one that's read
+    /// before it's bound (declared in an if branch that didn't run, say)
+    /// is still null rather than a leftover temporary.
+    local: bool,
+}
+
+/// Linear scan register allocation. A register is live over the whole span
+/// between its first and last appearance, and any register free by then can
+/// be reused. Loops stretch that span: one that's live going into a loop
+/// stays live until the jump back, since it's read again next time round.
+fn allocate(mut scope: FunctionScope) -> Function {
+    let num_virtual = scope.next_reg as usize;
+    let mut first = vec![usize::MAX; num_virtual];
+    let mut last = vec![0; num_virtual];
+
+    for (pos, ins) in scope.code.iter_mut().enumerate() {
+        ins.registers_mut(|r| {
+            let r = *r as usize;
+            first[r] = first[r].min(pos);
+            last[r] = last[r].max(pos);
+        });
+    }
+
+    let mut is_local = vec![false; num_virtual];
+    for r in &scope.locals {
+        is_local[*r as usize] = true;
+    }
+
+    let mut intervals: Vec<Interval> = (0..num_virtual)
+        .filter(|r| first[*r] != usize::MAX || is_local[*r])
+        .map(|r| Interval {
+            reg: r as Reg,
+            start: if is_local[r] { 0 } else { first[r] },
+            end: last[r],
+            local: is_local[r],
+        })
+        .collect();
+
+    let back_edges: Vec<(usize, usize)> = scope
+        .code
+        .iter()
+        .enumerate()
+        .filter_map(|(pos, ins)| match ins {
+            Instr::Jump { target } if *target as usize <= pos => Some((*target as usize, pos)),
+            _ => None,
+        })
+        .collect();
+    // stretching one loop can make a register live into another
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (head, jump) in &back_edges {
+            for i in intervals.iter_mut() {
+                if (i.start < *head || i.local) && i.end >= *head && i.end < *jump {
+                    i.end = *jump;
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    intervals.sort_by_key(|i| (i.start, !i.local));
+
+    let mut physical = vec![0; num_virtual];
+    let mut active: Vec<(usize, Reg)> = Vec::new();
+    let mut free: BinaryHeap<Reverse<Reg>> = BinaryHeap::new();
+    let mut num_registers = scope.num_parameters as Reg;
+
+    for interval in intervals {
+        // a temporary can take the register of one last read by the
+        // instruction that writes it, as operands are read first
+        active.retain(|(end, reg)| {
+            let expired = *end < interval.start || (*end == interval.start && !interval.local);
+            if expired {
+                free.push(Reverse(*reg));
+            }
+            !expired
+        });
+
+        // the parameters are where the caller put the arguments
+        let reg = if (interval.reg as usize) < scope.num_parameters {
+            interval.reg
+        } else {
+            match free.pop() {
+                Some(Reverse(r)) => r,
+                None => {
+                    num_registers += 1;
+                    num_registers - 1
+                }
+            }
+        };
+
+        physical[interval.reg as usize] = reg;
+        active.push((interval.end, reg));
+    }
+
+    for ins in scope.code.iter_mut() {
+        ins.registers_mut(|r| *r = physical[*r as usize]);
+    }
+
+    Function {
+        code: scope.code,
+        num_registers: num_registers as usize,
+        num_parameters: scope.num_parameters,
+        name: scope.name,
+        sites: scope.sites,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use crate::regcode::disassemble;
+
+    fn compile(input: &str) -> Bytecode {
+        let l = Lexer::new(input, true, None);
+        let mut p = Parser::new(l);
+        let program = p.parse_program().expect("Program should be Some here");
+        assert_eq!(p.errors().len(), 0, "{:?}", p.errors());
+
+        let mut c = RegCompiler::new();
+        c.compile(&program).expect("program should compile");
+        c.bytecode()
+    }
+
+    fn function(obj: &Object) -> &Function {
+        match obj {
+            Object::RegFunction(f) => f,
+            _ => panic!("expected a compiled function, got {:?}", obj),
+        }
+    }
+
+    #[test]
+    fn test_registers_are_reused() {
+        let bytecode = compile("let f = fn(a, b) { let c = a * b; (c + 1) * (c - 2) < a };");
+        let f = function(&bytecode.constants[2]);
+
+        // b is dead after the first instruction and c after the Sub, so
+        // their registers are taken over
+        let expected = "0000 Mul r2 r0 r1\n\
+            0001 LoadConstant r1 0\n\
+            0002 Add r1 r2 r1\n\
+            0003 LoadConstant r3 1\n\
+            0004 Sub r2 r2 r3\n\
+            0005 Mul r1 r1 r2\n\
+            0006 GreaterThan r0 r0 r1\n\
+            0007 Return r0\n";
+
+        assert_eq!(disassemble(&f.code), expected);
+        assert_eq!(f.num_registers, 4);
+        assert_eq!(f.num_parameters, 2);
+
+        let main = "0000 Closure r0 2 []\n\
+            0001 SetGlobal 0 r0\n";
+        assert_eq!(disassemble(&bytecode.main.code), main);
+    }
+
+    #[test]
+    fn test_loops_keep_registers_live() {
+        let bytecode = compile("fn(n) { let t = 0; for (i in 0..n) { let t = t + i * 2; } t }");
+        // the counter in r3 and the end in r0 are read again after the
+        // jump back, so nothing in the body may take their registers
+        let expected = "0000 LoadConstant r1 0\n\
+            0001 LoadConstant r3 1\n\
+            0002 Move r0 r0\n\
+            0003 CheckRange r3 r0\n\
+            0004 GreaterThan r4 r0 r3\n\
+            0005 JumpNotTruthy r4 14\n\
+            0006 Move r2 r3\n\
+            0007 LoadConstant r4 2\n\
+            0008 Mul r4 r2 r4\n\
+            0009 Add r4 r1 r4\n\
+            0010 Move r1 r4\n\
+            0011 LoadConstant r4 3\n\
+            0012 Add r3 r3 r4\n\
+            0013 Jump 4\n\
+            0014 Return r1\n";
+        assert_eq!(
+            disassemble(&function(&bytecode.constants[4]).code),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_closures_and_conditionals() {
+        let bytecode =
+            compile("fn(x) { let y = if (x) { let x = 1; x } else { 2 }; fn() { x + y } }");
+
+        // rebinding `x` puts it in a cell, which the closure shares
+        let inner = "0000 GetFree r0 0\n\
+            0001 GetCell r0 r0\n\
+            0002 GetFree r1 1\n\
+            0003 Add r0 r0 r1\n\
+            0004 Return r0\n";
+        assert_eq!(disassemble(&function(&bytecode.constants[2]).code), inner);
+
+        // the inner `let x` rebinds the parameter, as with the stack vm
+        let outer = "0000 MakeCell r0\n\
+            0001 GetCell r2 r0\n\
+            0002 JumpNotTruthy r2 7\n\
+            0003 LoadConstant r2 0\n\
+            0004 SetCell r0 r2\n\
+            0005 GetCell r1 r0\n\
+            0006 Jump 8\n\
+            0007 LoadConstant r1 1\n\
+            0008 Closure r0 2 [r0 r1]\n\
+            0009 Return r0\n";
+        assert_eq!(disassemble(&function(&bytecode.constants[3]).code), outer);
+    }
+}
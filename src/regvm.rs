@@ -0,0 +1,817 @@
+use crate::error::{self, Error, Failure, Kind, TraceFrame};
+use crate::gc::Heap;
+use crate::object::{self, Exception, GcConfig, Host, Object, Overflow, BUILTINS};
+use crate::regcode::{Bytecode, Closure, Function, Instr, Reg};
+use crate::vm::{InterruptHandle, Limits, Meter, MAX_FRAMES};
+use std::cell::RefCell;
+use std::io::Write;
+use std::rc::Rc;
+
+struct Frame {
+    closure: Rc<Closure>,
+    ip: usize,
+    /// Where the frame's registers start.
+    base: usize,
+    /// The caller's register the return value goes in.
+    return_to: Reg,
+}
+
+/// Where `Instr::SetHandler` said to go when something fails, in the frame
+/// that set it.
+struct Handler {
+    frames: usize,
+    target: usize,
+}
+
+/// Runs bytecode from `regcompiler`. It behaves like `vm::Vm`, errors and
+/// all, but keeps values in registers instead of pushing and popping them.
+pub struct RegVm {
+    constants: Vec<Object>,
+    globals: Vec<Option<Object>>,
+    global_names: Vec<String>,
+    members: Vec<String>,
+
+    registers: Vec<Object>,
+    frames: Vec<Frame>,
+    handlers: Vec<Handler>,
+    /// What was thrown, when the error in flight came from `Instr::Throw`.
+    thrown: Option<Rc<Exception>>,
+    /// The error `Instr::Catch` picks up.
+    caught: Option<Rc<Exception>>,
+
+    out: Box<dyn Write>,
+    overflow: Overflow,
+    meter: Meter,
+    heap: Heap<'static>,
+}
+
+impl RegVm {
+    pub fn new(bytecode: Bytecode) -> RegVm {
+        let main = Rc::new(Closure {
+            func: Rc::new(bytecode.main),
+            free: Vec::new(),
+        });
+
+        RegVm {
+            constants: bytecode.constants,
+            globals: vec![None; bytecode.globals.len()],
+            global_names: bytecode.globals,
+            members: bytecode.members,
+            registers: vec![Object::Null; main.func.num_registers],
+            frames: vec![Frame {
+                closure: main,
+                ip: 0,
+                base: 0,
+                return_to: 0,
+            }],
+            handlers: Vec::new(),
+            thrown: None,
+            caught: None,
+            out: Box::new(std::io::stdout()),
+            overflow: Overflow::default(),
+            meter: Meter::default(),
+            heap: Heap::default(),
+        }
+    }
+
+    /// Sends what `puts` prints to `out` rather than stdout.
+    pub fn set_output(&mut self, out: Box<dyn Write>) {
+        self.out = out;
+    }
+
+    /// Makes integer arithmetic wrap around instead of failing when it
+    /// overflows.
+    pub fn set_overflow(&mut self, overflow: Overflow) {
+        self.overflow = overflow;
+    }
+
+    /// Limits what `run` may use, like `Vm::set_limits`.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.meter.set_limits(limits);
+    }
+
+    /// Changes when the collector runs, like `Vm::set_gc`.
+    pub fn set_gc(&mut self, config: GcConfig) {
+        self.heap.set_config(config);
+    }
+
+    /// A handle that interrupts this vm.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.meter.interrupt_handle()
+    }
+
+    /// Makes `handle` interrupt this vm, instead of its own.
+    pub fn set_interrupt_handle(&mut self, handle: InterruptHandle) {
+        self.meter.set_interrupt_handle(handle);
+    }
+
+    /// Runs the bytecode to the end, like `Vm::run`.
+    pub fn run(&mut self) -> Result<(), Error> {
+        let mut ip = self.frames.last().expect("there is always a main frame").ip;
+        self.handlers.clear();
+        self.thrown = None;
+        self.meter.start();
+
+        loop {
+            let failure = match self.execute(&mut ip) {
+                Ok(()) => return Ok(()),
+                Err(failure) => failure,
+            };
+            // the running frame's ip is only kept in `execute`
+            self.frames
+                .last_mut()
+                .expect("there is always a main frame")
+                .ip = ip;
+
+            let caught = self.thrown.is_some() || self.meter.catchable();
+            match self.handlers.pop() {
+                Some(handler) if caught => ip = self.catch(failure, handler),
+                _ => {
+                    return Err(Error {
+                        message: failure.message,
+                        kind: failure.kind,
+                        trace: self.trace(),
+                        limit: self.meter.exceeded(),
+                    })
+                }
+            }
+        }
+    }
+
+    /// Unwinds to `handler`, leaving the error for its `Instr::Catch`, and
+    /// returns where that is.
+    fn catch(&mut self, failure: Failure, handler: Handler) -> usize {
+        let exception = match self.thrown.take() {
+            Some(exception) => exception,
+            None => {
+                let frame = self.frames.last().expect("there is always a main frame");
+                let site = frame.closure.func.site(frame.ip - 1);
+                Rc::new(Exception {
+                    kind: failure.kind,
+                    message: failure.message,
+                    span: site.and_then(|s| s.span.clone()),
+                })
+            }
+        };
+        self.caught = Some(exception);
+
+        self.frames.truncate(handler.frames);
+        let frame = self.frames.last().expect("the handler's frame is left");
+        self.registers
+            .truncate(frame.base + frame.closure.func.num_registers);
+        handler.target
+    }
+
+    /// The calls in progress, innermost first.
+    fn trace(&self) -> Vec<TraceFrame> {
+        self.frames
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(idx, frame)| {
+                let func = &frame.closure.func;
+                let name = match (idx, &func.name) {
+                    (0, _) => error::MAIN,
+                    (_, Some(name)) => name,
+                    (_, None) => error::ANONYMOUS,
+                };
+                // a frame's ip is past the instruction it's on
+                let site = frame.ip.checked_sub(1).and_then(|idx| func.site(idx));
+                TraceFrame {
+                    function: name.to_string(),
+                    site: site.cloned(),
+                }
+            })
+            .collect()
+    }
+
+    fn execute(&mut self, ip: &mut usize) -> Result<(), Failure> {
+        // the running frame's state is kept in locals, and only written
+        // back to its `Frame` around calls
+        let frame = self.frames.last().expect("there is always a main frame");
+        let mut closure = frame.closure.clone();
+        let mut func: Rc<Function> = closure.func.clone();
+        let mut base = frame.base;
+
+        macro_rules! reg {
+            ($r:expr) => {
+                self.registers[base + $r as usize]
+            };
+        }
+
+        loop {
+            self.meter.step()?;
+
+            let ins = match func.code.get(*ip) {
+                Some(ins) => ins,
+                None => return Ok(()),
+            };
+            *ip += 1;
+
+            // set when a call or return changes the running frame
+            let mut switched = false;
+
+            match ins {
+                Instr::LoadConstant { dst, idx } => {
+                    reg!(*dst) = self.constants[*idx as usize].clone();
+                }
+                Instr::LoadTrue { dst } => reg!(*dst) = Object::Boolean(true),
+                Instr::LoadFalse { dst } => reg!(*dst) = Object::Boolean(false),
+                Instr::LoadNull { dst } => reg!(*dst) = Object::Null,
+                Instr::Move { dst, src } => reg!(*dst) = reg!(*src).clone(),
+                Instr::Add { dst, lhs, rhs }
+                | Instr::Sub { dst, lhs, rhs }
+                | Instr::Mul { dst, lhs, rhs }
+                | Instr::Div { dst, lhs, rhs }
+                | Instr::Mod { dst, lhs, rhs }
+                | Instr::BitAnd { dst, lhs, rhs }
+                | Instr::BitOr { dst, lhs, rhs }
+                | Instr::BitXor { dst, lhs, rhs }
+                | Instr::ShiftLeft { dst, lhs, rhs }
+                | Instr::ShiftRight { dst, lhs, rhs } => {
+                    let (l, r) = (&reg!(*lhs), &reg!(*rhs));
+                    reg!(*dst) = arithmetic(ins, l, r, self.overflow, &func, *ip - 1)?;
+                }
+                Instr::Equal { dst, lhs, rhs } => {
+                    reg!(*dst) = Object::Boolean(reg!(*lhs) == reg!(*rhs));
+                }
+                Instr::NotEqual { dst, lhs, rhs } => {
+                    reg!(*dst) = Object::Boolean(reg!(*lhs) != reg!(*rhs));
+                }
+                Instr::GreaterThan { dst, lhs, rhs } | Instr::GreaterEqual { dst, lhs, rhs } => {
+                    let result = match (&reg!(*lhs), &reg!(*rhs)) {
+                        (Object::Integer(l), Object::Integer(r)) => match ins {
+                            Instr::GreaterThan { .. } => l > r,
+                            _ => l >= r,
+                        },
+                        (l, r) => {
+                            let op = match ins {
+                                Instr::GreaterThan { .. } => ">",
+                                _ => ">=",
+                            };
+                            return Err(Failure::new(
+                                Kind::Type,
+                                format!(
+                                    "unknown operator: {} {} {}",
+                                    l.type_name(),
+                                    op,
+                                    r.type_name()
+                                ),
+                            ));
+                        }
+                    };
+                    reg!(*dst) = Object::Boolean(result);
+                }
+                Instr::Minus { dst, src } => {
+                    let result = match &reg!(*src) {
+                        Object::Integer(i) => {
+                            let negated =
+                                self.overflow
+                                    .apply(0, *i, i128::checked_sub, i128::wrapping_sub);
+                            let site = func.site(*ip - 1);
+                            let at =
+                                |e| object::arithmetic_error(e, site.and_then(|s| s.span.as_ref()));
+                            Object::Integer(negated.map_err(at)?)
+                        }
+                        obj => {
+                            return Err(Failure::new(
+                                Kind::Type,
+                                format!("unsupported type for negation: {}", obj.type_name()),
+                            ))
+                        }
+                    };
+                    reg!(*dst) = result;
+                }
+                Instr::BitNot { dst, src } => {
+                    let result = match &reg!(*src) {
+                        Object::Integer(i) => Object::Integer(!i),
+                        obj => {
+                            return Err(Failure::new(
+                                Kind::Type,
+                                format!("unsupported type for bitwise not: {}", obj.type_name()),
+                            ))
+                        }
+                    };
+                    reg!(*dst) = result;
+                }
+                Instr::Bang { dst, src } => {
+                    reg!(*dst) = Object::Boolean(!reg!(*src).is_truthy());
+                }
+                Instr::Jump { target } => *ip = *target as usize,
+                Instr::JumpNotTruthy { cond, target } => {
+                    if !reg!(*cond).is_truthy() {
+                        *ip = *target as usize;
+                    }
+                }
+                Instr::GetGlobal { dst, idx } => match &self.globals[*idx as usize] {
+                    Some(value) => reg!(*dst) = value.clone(),
+                    None => {
+                        let name = &self.global_names[*idx as usize];
+                        return Err(Failure::not_found(name));
+                    }
+                },
+                Instr::SetGlobal { idx, src } => {
+                    self.globals[*idx as usize] = Some(reg!(*src).clone());
+                }
+                Instr::GetMember { dst, obj, name } => {
+                    let name = &self.members[*name as usize];
+                    let slot = match &reg!(*obj) {
+                        Object::Module(m) => m.slot(name)?,
+                        Object::Error(e) => {
+                            reg!(*dst) = e.member(name)?;
+                            continue;
+                        }
+                        Object::GcStats(s) => {
+                            reg!(*dst) = s.member(name)?;
+                            continue;
+                        }
+                        obj => {
+                            return Err(Failure::new(
+                                Kind::Type,
+                                format!("unsupported type for member access: {}", obj.type_name()),
+                            ))
+                        }
+                    };
+                    match &self.globals[slot] {
+                        Some(value) => reg!(*dst) = value.clone(),
+                        None => return Err(Failure::not_found(name)),
+                    }
+                }
+                Instr::CheckRange { start, end } => {
+                    let (start, end) = (&reg!(*start), &reg!(*end));
+                    if !matches!((start, end), (Object::Integer(_), Object::Integer(_))) {
+                        return Err(Failure::new(
+                            Kind::Type,
+                            format!(
+                                "unsupported types for range: {} {}",
+                                start.type_name(),
+                                end.type_name()
+                            ),
+                        ));
+                    }
+                }
+                Instr::GetBuiltin { dst, idx } => {
+                    reg!(*dst) = Object::Builtin(&BUILTINS[*idx as usize]);
+                }
+                Instr::Array { dst, elements } => {
+                    self.meter
+                        .allocate(elements.len() * std::mem::size_of::<Object>())?;
+                    let elements = elements.iter().map(|r| reg!(*r).clone()).collect();
+                    let array = Rc::new(RefCell::new(elements));
+                    self.heap.track(&array);
+                    reg!(*dst) = Object::Array(array);
+                }
+                Instr::Hash { dst, pairs } => {
+                    self.meter
+                        .allocate(pairs.len() * std::mem::size_of::<Object>())?;
+                    let pairs = pairs
+                        .chunks(2)
+                        .map(|kv| (reg!(kv[0]).clone(), reg!(kv[1]).clone()));
+                    let hash = object::make_hash(pairs)?;
+                    self.heap.track(&hash);
+                    reg!(*dst) = Object::Hash(hash);
+                }
+                Instr::Index { dst, left, index } => {
+                    reg!(*dst) = object::index(&reg!(*left), &reg!(*index))?;
+                }
+                Instr::SetIndex { left, index, src } => {
+                    let value = reg!(*src).clone();
+                    object::set_index(&mut self.meter, &reg!(*left), &reg!(*index), value)?;
+                }
+                Instr::Next {
+                    dst,
+                    items,
+                    position,
+                    target,
+                } => {
+                    let position = match reg!(*position) {
+                        Object::Integer(i) => i as usize,
+                        _ => unreachable!("the compiler counts with integers"),
+                    };
+                    match object::nth(&reg!(*items), position)? {
+                        Some(item) => reg!(*dst) = item,
+                        None => *ip = *target as usize,
+                    }
+                }
+                Instr::GetFree { dst, idx } => {
+                    reg!(*dst) = closure.free[*idx as usize].clone();
+                }
+                Instr::CurrentClosure { dst } => {
+                    reg!(*dst) = Object::RegClosure(closure.clone());
+                }
+                Instr::MakeCell { reg } => {
+                    self.meter
+                        .allocate(std::mem::size_of::<RefCell<Object>>())?;
+                    let value = std::mem::replace(&mut reg!(*reg), Object::Null);
+                    let cell = Rc::new(RefCell::new(value));
+                    self.heap.track(&cell);
+                    reg!(*reg) = Object::Cell(cell);
+                }
+                Instr::GetCell { dst, cell } => {
+                    reg!(*dst) = match &reg!(*cell) {
+                        Object::Cell(cell) => cell.borrow().clone(),
+                        _ => unreachable!("only cells are read through"),
+                    };
+                }
+                Instr::SetCell { cell, src } => {
+                    let value = reg!(*src).clone();
+                    match &reg!(*cell) {
+                        Object::Cell(cell) => *cell.borrow_mut() = value,
+                        _ => unreachable!("only cells are stored through"),
+                    }
+                }
+                Instr::Closure {
+                    dst,
+                    func: idx,
+                    free,
+                } => {
+                    let func = match &self.constants[*idx as usize] {
+                        Object::RegFunction(f) => f.clone(),
+                        obj => {
+                            return Err(Failure::from(format!(
+                                "not a function: {}",
+                                obj.type_name()
+                            )))
+                        }
+                    };
+                    let size =
+                        std::mem::size_of::<Closure>() + free.len() * std::mem::size_of::<Object>();
+                    self.meter.allocate(size)?;
+                    let free = free.iter().map(|r| reg!(*r).clone()).collect();
+                    reg!(*dst) = Object::RegClosure(Rc::new(Closure { func, free }));
+                }
+                Instr::Call {
+                    dst,
+                    func: callee,
+                    args,
+                } => match reg!(*callee).clone() {
+                    Object::RegClosure(callee) => {
+                        if args.len() != callee.func.num_parameters {
+                            return Err(Failure::wrong_arguments(
+                                callee.func.num_parameters,
+                                args.len(),
+                            ));
+                        }
+                        if self.frames.len() >= MAX_FRAMES {
+                            return Err(Failure::stack_overflow());
+                        }
+                        // the main frame isn't a call
+                        self.meter.call(self.frames.len() - 1)?;
+
+                        let new_base = base + func.num_registers;
+                        for r in args.iter() {
+                            let arg = reg!(*r).clone();
+                            self.registers.push(arg);
+                        }
+                        self.registers
+                            .resize(new_base + callee.func.num_registers, Object::Null);
+
+                        self.frames.last_mut().expect("we're in a frame").ip = *ip;
+                        self.frames.push(Frame {
+                            closure: callee,
+                            ip: 0,
+                            base: new_base,
+                            return_to: *dst,
+                        });
+                        switched = true;
+                    }
+                    obj => {
+                        let args: Vec<Object> = args.iter().map(|r| reg!(*r).clone()).collect();
+                        reg!(*dst) = self.call_native(obj, &args)?;
+                    }
+                },
+                Instr::TailCall { func: callee, args } => {
+                    let args: Vec<Object> = args.iter().map(|r| reg!(*r).clone()).collect();
+                    match reg!(*callee).clone() {
+                        // the callee takes over the running frame
+                        Object::RegClosure(callee) if args.len() == callee.func.num_parameters => {
+                            self.registers.truncate(base);
+                            self.registers.extend(args);
+                            self.registers
+                                .resize(base + callee.func.num_registers, Object::Null);
+
+                            let frame = self.frames.last_mut().expect("we're in a frame");
+                            frame.closure = callee;
+                            frame.ip = 0;
+                            switched = true;
+                        }
+                        obj => {
+                            let value = self.call_native(obj, &args)?;
+                            if self.return_value(value) {
+                                return Ok(());
+                            }
+                            switched = true;
+                        }
+                    }
+                }
+                Instr::Return { .. } | Instr::ReturnNull => {
+                    let value = match ins {
+                        Instr::Return { src } => reg!(*src).clone(),
+                        _ => Object::Null,
+                    };
+
+                    if self.return_value(value) {
+                        return Ok(());
+                    }
+                    switched = true;
+                }
+                Instr::SetHandler { target } => self.handlers.push(Handler {
+                    frames: self.frames.len(),
+                    target: *target as usize,
+                }),
+                Instr::PopHandler => {
+                    self.handlers.pop();
+                }
+                Instr::Catch { dst } => {
+                    let caught = self.caught.take().expect("a handler left the error");
+                    reg!(*dst) = Object::Error(caught);
+                }
+                Instr::Throw { src } => {
+                    let exception = match &reg!(*src) {
+                        Object::Error(e) => e.clone(),
+                        value => {
+                            let site = func.site(*ip - 1);
+                            Rc::new(Exception {
+                                message: value.to_string(),
+                                kind: Kind::Thrown,
+                                span: site.and_then(|s| s.span.clone()),
+                            })
+                        }
+                    };
+                    let failure = Failure::new(exception.kind, exception.message.clone());
+                    self.thrown = Some(exception);
+                    return Err(failure);
+                }
+            }
+
+            if switched {
+                let frame = self.frames.last().expect("there is always a main frame");
+                closure = frame.closure.clone();
+                func = closure.func.clone();
+                base = frame.base;
+                *ip = frame.ip;
+            }
+        }
+    }
+
+    /// Calls anything but a closure, which needs a frame of its own.
+    fn call_native(&mut self, callee: Object, args: &[Object]) -> Result<Object, Failure> {
+        match callee {
+            Object::RegClosure(callee) => Err(Failure::wrong_arguments(
+                callee.func.num_parameters,
+                args.len(),
+            )),
+            Object::Builtin(builtin) => {
+                let mut host = Host {
+                    out: self.out.as_mut(),
+                    gc: self.heap.stats(),
+                    meter: &mut self.meter,
+                };
+                (builtin.func)(&mut host, args)
+            }
+            Object::Native(native) => native.call(args),
+            obj => Err(Failure::not_callable(obj.type_name())),
+        }
+    }
+
+    /// Returns `value` from the running frame to where its caller wanted
+    /// it, saying whether that ended the program.
+    fn return_value(&mut self, value: Object) -> bool {
+        // a top level `return` ends the program
+        if self.frames.len() == 1 {
+            return true;
+        }
+
+        let done = self.frames.pop().expect("checked there's a frame");
+        self.registers.truncate(done.base);
+
+        let caller = self.frames.last().expect("checked there's a caller");
+        self.registers[caller.base + done.return_to as usize] = value;
+        false
+    }
+}
+
+/// Applies `ins`, the instruction at `idx` in `func`. Errors from the
+/// integer arithmetic itself say where the operator is.
+fn arithmetic(
+    ins: &Instr,
+    left: &Object,
+    right: &Object,
+    overflow: Overflow,
+    func: &Function,
+    idx: usize,
+) -> Result<Object, Failure> {
+    let (l, r) = match (left, right) {
+        (Object::Integer(l), Object::Integer(r)) => (*l, *r),
+        _ => {
+            return Err(Failure::new(
+                Kind::Type,
+                format!(
+                    "unsupported types for binary operation: {} {}",
+                    left.type_name(),
+                    right.type_name()
+                ),
+            ))
+        }
+    };
+
+    let result = match ins {
+        Instr::Add { .. } => overflow.apply(l, r, i128::checked_add, i128::wrapping_add),
+        Instr::Sub { .. } => overflow.apply(l, r, i128::checked_sub, i128::wrapping_sub),
+        Instr::Mul { .. } => overflow.apply(l, r, i128::checked_mul, i128::wrapping_mul),
+        Instr::Div { .. } | Instr::Mod { .. } if r == 0 => Err(Failure::division_by_zero()),
+        Instr::Div { .. } => overflow.apply(l, r, i128::checked_div, i128::wrapping_div),
+        Instr::Mod { .. } => overflow.apply(l, r, i128::checked_rem, i128::wrapping_rem),
+        Instr::BitAnd { .. } => Ok(l & r),
+        Instr::BitOr { .. } => Ok(l | r),
+        Instr::BitXor { .. } => Ok(l ^ r),
+        Instr::ShiftLeft { .. } | Instr::ShiftRight { .. } if !(0..128).contains(&r) => {
+            Err(Failure::shift_out_of_range(r))
+        }
+        Instr::ShiftLeft { .. } => overflow.apply(l, r, object::checked_shl, |l, r| l << r),
+        Instr::ShiftRight { .. } => Ok(l >> r),
+        _ => unreachable!("only called for arithmetic"),
+    };
+
+    match result {
+        Ok(i) => Ok(Object::Integer(i)),
+        Err(e) => {
+            let site = func.site(idx);
+            Err(object::arithmetic_error(
+                e,
+                site.and_then(|s| s.span.as_ref()),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use crate::regcompiler::RegCompiler;
+    use crate::vm::LimitExceeded;
+    use std::time::Duration;
+
+    fn run(input: &str) -> Result<(), String> {
+        let l = Lexer::new(input, true, None);
+        let mut p = Parser::new(l);
+        let program = p.parse_program().expect("Program should be Some here");
+        assert_eq!(p.errors().len(), 0, "{:?}", p.errors());
+
+        let mut c = RegCompiler::new();
+        c.compile(&program)?;
+
+        let mut vm = RegVm::new(c.bytecode());
+        vm.set_output(Box::new(std::io::sink()));
+        Ok(vm.run()?)
+    }
+
+    #[test]
+    fn test_runtime_errors() {
+        let tests = [
+            (
+                "1 + true",
+                "unsupported types for binary operation: INTEGER BOOLEAN",
+            ),
+            ("fn(a) { a }()", "wrong number of arguments: want=1, got=0"),
+            ("1()", "calling non-function: INTEGER"),
+            ("10 / (5 - 5)", "division by zero"),
+            ("10 % 0", "division by zero"),
+            ("1 <= true", "unknown operator: BOOLEAN >= INTEGER"),
+            ("1 << 128", "shift amount out of range: 128"),
+            ("-1 >> -1", "shift amount out of range: -1"),
+            ("~true", "unsupported type for bitwise not: BOOLEAN"),
+            ("let f = fn(x) { 1 + f(x) }; f(1)", "stack overflow"),
+            ("f(); let f = fn() { 1 };", "identifier not found: f"),
+            (
+                "let n = 1; n.x",
+                "unsupported type for member access: INTEGER",
+            ),
+            ("true < 1", "unknown operator: INTEGER > BOOLEAN"),
+            ("let max = ~(-1 << 127); max + 1", "integer overflow"),
+            ("let f = fn(x) { x * 4 }; f(1 << 126)", "integer overflow"),
+            ("let min = -1 << 127; -min", "integer overflow"),
+            ("let min = -1 << 127; min % -1", "integer overflow"),
+            ("let f = fn(x) { x << 126 }; f(5)", "integer overflow"),
+            (
+                "for (i in 0..true) { }",
+                "unsupported types for range: INTEGER BOOLEAN",
+            ),
+        ];
+
+        for (input, expected) in tests {
+            assert_eq!(run(input), Err(String::from(expected)), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_garbage_collection() {
+        // the same cycles as the stack vm's test: a cell the closure in it
+        // refers back to, and an array that holds itself
+        let input = "let leak = fn() {\
+                         let h = fn() { 0 };\
+                         h = fn() { h() };\
+                         let xs = [0];\
+                         xs[0] = xs;\
+                     };\
+                     let keep = fn(n) {\
+                         let f = fn() { n };\
+                         let g = fn() { f() };\
+                         f = fn() { if (false) { g() } else { n } };\
+                         g\
+                     };\
+                     let kept = keep(7);\
+                     for (i in 0..1000) { leak(); }\
+                     if (kept() != 7) { throw \"kept was collected\"; }";
+        let l = Lexer::new(input, true, None);
+        let mut p = Parser::new(l);
+        let program = p.parse_program().expect("Program should be Some here");
+
+        let mut c = RegCompiler::new();
+        c.compile(&program).unwrap();
+
+        let mut vm = RegVm::new(c.bytecode());
+        vm.set_gc(GcConfig {
+            threshold: 10,
+            growth: 2,
+        });
+        vm.run().unwrap();
+
+        let stats = vm.heap.stats();
+        assert_eq!(stats.allocated, 2001);
+        assert_eq!(stats.freed, 1998);
+        assert_eq!(stats.live, 2);
+    }
+
+    #[test]
+    fn test_limits() {
+        let spin = "let spin = fn(n) { if (n == 0) { 0 } else { 1 + spin(n - 1) } };";
+        let tests = [
+            (
+                "try { spin(100) } catch (e) { 0 }",
+                Limits {
+                    fuel: Some(100),
+                    ..Limits::default()
+                },
+                Some(LimitExceeded::Fuel),
+            ),
+            (
+                "spin(100)",
+                Limits {
+                    max_depth: Some(100),
+                    ..Limits::default()
+                },
+                Some(LimitExceeded::Depth),
+            ),
+            (
+                "spin(99)",
+                Limits {
+                    max_depth: Some(100),
+                    ..Limits::default()
+                },
+                None,
+            ),
+            (
+                "let f = fn(x) { fn() { x } }; f(1); f(2); f(3)",
+                Limits {
+                    max_heap: Some(100),
+                    ..Limits::default()
+                },
+                Some(LimitExceeded::Heap),
+            ),
+            (
+                "spin(500); spin(500); spin(500); spin(500)",
+                Limits {
+                    timeout: Some(Duration::ZERO),
+                    ..Limits::default()
+                },
+                Some(LimitExceeded::Timeout),
+            ),
+            (
+                "try { throw \"out of fuel\"; } catch (e) { 0 }",
+                Limits {
+                    fuel: Some(100),
+                    ..Limits::default()
+                },
+                None,
+            ),
+        ];
+
+        for (input, limits, expected) in tests {
+            let input = format!("{}{}", spin, input);
+            let l = Lexer::new(&input, true, None);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().expect("Program should be Some here");
+
+            let mut c = RegCompiler::new();
+            c.compile(&program).expect("should compile");
+            let mut vm = RegVm::new(c.bytecode());
+            vm.set_limits(limits);
+
+            let got = vm.run().err().map(|e| e.limit);
+            assert_eq!(got, expected.map(Some), "{}", input);
+        }
+    }
+}
@@ -1,10 +1,29 @@
+use crate::ast::Statement;
+use crate::compiler::Compiler;
+use crate::gc::Heap;
 use crate::lexer::Lexer;
-use crate::token::TokenKind;
+use crate::macros::Macros;
+use crate::parser::Parser;
+use crate::vm::{InterruptHandle, Vm};
 use std::io::Write;
 
 const PROMPT: &str = ">> ";
 
 pub fn start() {
+    // these outlive a single line, so earlier lets and macros stay visible
+    let mut compiler = Compiler::new();
+    let mut globals = Vec::new();
+    let mut heap = Heap::default();
+    let mut macros = Macros::new();
+
+    // Ctrl-C stops whatever is running rather than the whole session
+    let interrupt = InterruptHandle::new();
+    let handle = interrupt.clone();
+    if let Err(e) = ctrlc::set_handler(move || handle.interrupt()) {
+        eprintln!("Error handling Ctrl-C: {}", e);
+    }
+    macros.set_interrupt_handle(interrupt.clone());
+
     loop {
         print!("{}", PROMPT);
         match std::io::stdout().flush() {
@@ -18,6 +37,7 @@ pub fn start() {
         let mut input = String::new();
 
         match std::io::stdin().read_line(&mut input) {
+            Ok(0) => break,
             Ok(_) => (),
             Err(e) => {
                 eprintln!("Error reading input: {}", e);
@@ -29,13 +49,50 @@ pub fn start() {
             break;
         }
 
-        let mut lexer = Lexer::new(&input, true, None);
+        let lexer = Lexer::new(&input, true, None);
+        let mut parser = Parser::new(lexer);
+        let mut program = parser.parse_program().unwrap_or_default();
+
+        if !parser.errors().is_empty() {
+            for e in parser.errors() {
+                eprintln!("{}", e);
+            }
+            continue;
+        }
+
+        // a Ctrl-C at the prompt shouldn't stop the next line
+        interrupt.clear();
 
-        let mut tok = lexer.next_token();
+        if let Err(e) = macros.expand(&mut program) {
+            eprintln!("Macro error: {}", e);
+            for line in e.trace_lines() {
+                eprintln!("  {}", line);
+            }
+            continue;
+        }
 
-        while tok.ttype != TokenKind::EOF {
-            println!("{:?}", tok);
-            tok = lexer.next_token();
+        let compiled = compiler.compile(&program);
+        let bytecode = compiler.take_bytecode();
+        if let Err(e) = compiled {
+            eprintln!("Compile error: {}", e);
+            continue;
         }
+
+        let mut vm = Vm::with_globals(bytecode, std::mem::take(&mut globals));
+        vm.set_heap(std::mem::take(&mut heap));
+        vm.set_interrupt_handle(interrupt.clone());
+        let result = vm.run();
+
+        if let Err(e) = result {
+            eprintln!("Runtime error: {}", e);
+            for line in e.trace_lines() {
+                eprintln!("  {}", line);
+            }
+        } else if let Some(Statement::Expression(_)) = program.statements.last() {
+            println!("{}", vm.last_popped());
+        }
+
+        heap = vm.take_heap();
+        globals = vm.into_globals();
     }
 }
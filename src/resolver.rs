@@ -0,0 +1,515 @@
+use crate::ast::{self, Block, Expression, FunctionInternal, Identifier, Program, Statement};
+use crate::object::BUILTINS;
+use crate::token::{Token, TokenKind};
+use std::sync::OnceLock;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BindingKind {
+    Let,
+    Parameter,
+    Import,
+    /// The variable of a `for` loop.
+    Loop,
+    /// The name a `catch` binds the error to.
+    Catch,
+    /// One of `object::BUILTINS`, which are bound around the program and
+    /// have no location.
+    Builtin,
+}
+
+/// A name introduced by a `let` statement, a function parameter, an
+/// `import`, a `for` loop or a `catch`.
+#[derive(Debug, Clone, Copy)]
+pub struct Binding<'p, 'a> {
+    pub name: &'p Identifier<'a>,
+    pub kind: BindingKind,
+    /// The bound expression, for `let` bindings.
+    pub value: Option<&'p Expression<'a>>,
+    /// The binding this one hides, if the name was already visible.
+    pub shadows: Option<usize>,
+}
+
+/// How a reference uses its binding.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    /// The target of `name = value`, which doesn't read it.
+    Write,
+    /// The target of a compound assignment like `name += value`.
+    ReadWrite,
+}
+
+/// A use of an identifier in an expression, and the binding it refers to
+/// as an index into `Resolution::bindings`, if there is one.
+#[derive(Debug, Clone, Copy)]
+pub struct Reference<'p, 'a> {
+    pub name: &'p Identifier<'a>,
+    pub target: Option<usize>,
+    pub access: Access,
+}
+
+#[derive(Debug, Default)]
+pub struct Resolution<'p, 'a> {
+    pub bindings: Vec<Binding<'p, 'a>>,
+    pub references: Vec<Reference<'p, 'a>>,
+}
+
+impl<'p, 'a> Resolution<'p, 'a> {
+    /// Number of references that read `bindings[idx]`.
+    pub fn uses(&self, idx: usize) -> usize {
+        self.references
+            .iter()
+            .filter(|r| r.target == Some(idx) && r.access != Access::Write)
+            .count()
+    }
+}
+
+/// Works out which binding every identifier in `program` refers to.
+///
+/// Scoping follows Monkey's environments: the program and each function body are
+/// scopes, `if` and loop blocks share the scope they're in, and a `let` is visible
+/// to the statements after it. Since functions only look names up when
+/// they're called, a name that isn't visible yet falls back to a binding
+/// later in an enclosing scope, which is what makes recursion and mutually
+/// recursive top level functions work.
+pub fn resolve<'p, 'a>(program: &'p Program<'a>) -> Resolution<'p, 'a> {
+    let mut r = Resolver {
+        resolution: Resolution::default(),
+        scopes: Vec::new(),
+    };
+
+    // the builtins are a scope of their own, around the program's
+    let mut scope = Scope {
+        visible: Vec::new(),
+        all: Vec::new(),
+    };
+    for name in builtins() {
+        let idx = r.bind(Binding {
+            name,
+            kind: BindingKind::Builtin,
+            value: None,
+            shadows: None,
+        });
+        scope.visible.push(idx);
+        scope.all.push(idx);
+    }
+    r.scopes.push(scope);
+
+    r.push_scope(&[], &program.statements);
+    r.statements(&program.statements);
+    r.scopes.pop();
+    r.scopes.pop();
+
+    r.resolution
+}
+
+/// Identifiers for the builtins to bind, which are the same for every
+/// program.
+fn builtins() -> &'static [Identifier<'static>] {
+    static NAMES: OnceLock<Vec<Identifier<'static>>> = OnceLock::new();
+    NAMES.get_or_init(|| {
+        BUILTINS
+            .iter()
+            .map(|b| {
+                let token = Token::new(TokenKind::IDENT(b.name.to_string()), None);
+                Identifier::new(token, b.name.to_string())
+            })
+            .collect()
+    })
+}
+
+struct Scope {
+    /// Bindings declared so far, in order.
+    visible: Vec<usize>,
+    /// Every binding declared anywhere in the scope.
+    all: Vec<usize>,
+}
+
+struct Resolver<'p, 'a> {
+    resolution: Resolution<'p, 'a>,
+    scopes: Vec<Scope>,
+}
+
+/// A name something in a scope binds, with what binds it and, for `let`,
+/// the bound expression.
+type Declaration<'p, 'a> = (&'p Identifier<'a>, BindingKind, Option<&'p Expression<'a>>);
+
+/// The names `let`, `import`, `for` and `catch` bind in the scope owning
+/// `statements`, including those inside `if`, `try` and loop blocks but not
+/// inside nested functions.
+fn collect_lets<'p, 'a>(statements: &'p [Statement<'a>], out: &mut Vec<Declaration<'p, 'a>>) {
+    ast::deeper(|| {
+        for stmt in statements {
+            let expr = match stmt {
+                Statement::Let(i) => {
+                    if let Some(name) = i.name() {
+                        out.push((name, BindingKind::Let, i.value()));
+                    }
+                    i.value()
+                }
+                Statement::Return(i) => i.return_value(),
+                Statement::Expression(i) => i.expression(),
+                Statement::Import(i) => {
+                    out.push((i.name(), BindingKind::Import, None));
+                    None
+                }
+                Statement::While(i) => {
+                    collect_lets(&i.body().statements, out);
+                    Some(i.condition())
+                }
+                Statement::For(i) => {
+                    out.push((i.variable(), BindingKind::Loop, None));
+                    collect_lets(&i.body().statements, out);
+                    None
+                }
+                Statement::Throw(i) => Some(i.value()),
+                Statement::Break(_) | Statement::Continue(_) => None,
+            };
+
+            // otherwise only `if` and `try` expressions can carry statements
+            // into this scope
+            match expr {
+                Some(Expression::If(i)) => {
+                    collect_lets(&i.consequence().statements, out);
+                    if let Some(alt) = i.alternative() {
+                        collect_lets(&alt.statements, out);
+                    }
+                }
+                Some(Expression::Try(i)) => {
+                    collect_lets(&i.body().statements, out);
+                    out.push((i.name(), BindingKind::Catch, None));
+                    collect_lets(&i.handler().statements, out);
+                    if let Some(finally) = i.finally() {
+                        collect_lets(&finally.statements, out);
+                    }
+                }
+                _ => {}
+            }
+        }
+    })
+}
+
+/// The names a function with `parameters` and a body of `statements`
+/// binds itself, which hide any the same in the scopes around it. The
+/// program is a function without parameters.
+pub(crate) fn bound_names(parameters: &[Identifier], statements: &[Statement]) -> Vec<String> {
+    let mut lets = Vec::new();
+    collect_lets(statements, &mut lets);
+    parameters
+        .iter()
+        .chain(lets.into_iter().map(|(name, _, _)| name))
+        .map(|name| name.value().clone())
+        .collect()
+}
+
+impl<'p, 'a> Resolver<'p, 'a> {
+    fn bind(&mut self, binding: Binding<'p, 'a>) -> usize {
+        self.resolution.bindings.push(binding);
+        self.resolution.bindings.len() - 1
+    }
+
+    fn push_scope(&mut self, parameters: &'p [Identifier<'a>], statements: &'p [Statement<'a>]) {
+        let mut scope = Scope {
+            visible: Vec::new(),
+            all: Vec::new(),
+        };
+
+        for p in parameters {
+            let shadows = self.lookup_visible(p.value());
+            let idx = self.bind(Binding {
+                name: p,
+                kind: BindingKind::Parameter,
+                value: None,
+                shadows,
+            });
+            scope.visible.push(idx);
+            scope.all.push(idx);
+        }
+
+        let mut lets = Vec::new();
+        collect_lets(statements, &mut lets);
+        for (name, kind, value) in lets {
+            let idx = self.bind(Binding {
+                name,
+                kind,
+                value,
+                shadows: None,
+            });
+            scope.all.push(idx);
+        }
+
+        self.scopes.push(scope);
+    }
+
+    fn lookup_visible(&self, name: &str) -> Option<usize> {
+        let bindings = &self.resolution.bindings;
+        let named = |idx: &&usize| bindings[**idx].name.value() == name;
+
+        for scope in self.scopes.iter().rev() {
+            if let Some(idx) = scope.visible.iter().rev().find(named) {
+                return Some(*idx);
+            }
+        }
+
+        None
+    }
+
+    fn lookup(&self, name: &str) -> Option<usize> {
+        if let Some(idx) = self.lookup_visible(name) {
+            return Some(idx);
+        }
+
+        let bindings = &self.resolution.bindings;
+        let named = |idx: &&usize| bindings[**idx].name.value() == name;
+
+        for scope in self.scopes.iter().rev() {
+            if let Some(idx) = scope.all.iter().find(named) {
+                return Some(*idx);
+            }
+        }
+
+        None
+    }
+
+    /// Makes the binding declared by `name` visible in the current scope.
+    fn declare(&mut self, name: &Identifier<'a>) {
+        let shadows = self.lookup_visible(name.value());
+        let bindings = &mut self.resolution.bindings;
+        let scope = self.scopes.last_mut().expect("there is always a scope");
+        let found = scope
+            .all
+            .iter()
+            .find(|idx| std::ptr::eq(bindings[**idx].name, name));
+        if let Some(idx) = found {
+            bindings[*idx].shadows = shadows;
+            scope.visible.push(*idx);
+        }
+    }
+
+    fn statements(&mut self, statements: &'p [Statement<'a>]) {
+        for stmt in statements {
+            self.statement(stmt);
+        }
+    }
+
+    fn statement(&mut self, stmt: &'p Statement<'a>) {
+        ast::deeper(|| match stmt {
+            Statement::Let(i) => {
+                if let Some(value) = i.value() {
+                    self.expression(value);
+                }
+                if let Some(name) = i.name() {
+                    self.declare(name);
+                }
+            }
+            Statement::Return(i) => {
+                if let Some(value) = i.return_value() {
+                    self.expression(value);
+                }
+            }
+            Statement::Expression(i) => {
+                if let Some(e) = i.expression() {
+                    self.expression(e);
+                }
+            }
+            Statement::Import(i) => self.declare(i.name()),
+            Statement::While(i) => {
+                self.expression(i.condition());
+                self.block(i.body());
+            }
+            Statement::For(i) => {
+                self.expression(i.start());
+                if let Some(end) = i.end() {
+                    self.expression(end);
+                }
+                self.declare(i.variable());
+                self.block(i.body());
+            }
+            Statement::Throw(i) => self.expression(i.value()),
+            Statement::Break(_) | Statement::Continue(_) => {}
+        })
+    }
+
+    fn block(&mut self, block: &'p Block<'a>) {
+        self.statements(&block.statements);
+    }
+
+    fn function(&mut self, f: &'p FunctionInternal<'a>) {
+        self.push_scope(f.parameters(), &f.body().statements);
+        self.block(f.body());
+        self.scopes.pop();
+    }
+
+    fn expression(&mut self, e: &'p Expression<'a>) {
+        ast::deeper(|| match e {
+            Expression::Identifier(i) => {
+                let target = self.lookup(i.value());
+                self.resolution.references.push(Reference {
+                    name: i,
+                    target,
+                    access: Access::Read,
+                });
+            }
+            Expression::Integer(_) | Expression::Boolean(_) | Expression::String(_) => {}
+            Expression::Prefix(i) => self.expression(i.right()),
+            Expression::Infix(i) => {
+                self.expression(i.left());
+                self.expression(i.right());
+            }
+            Expression::If(i) => {
+                self.expression(i.condition());
+                self.block(i.consequence());
+                if let Some(alt) = i.alternative() {
+                    self.block(alt);
+                }
+            }
+            Expression::Try(i) => {
+                self.block(i.body());
+                self.declare(i.name());
+                self.block(i.handler());
+                if let Some(finally) = i.finally() {
+                    self.block(finally);
+                }
+            }
+            Expression::Function(f) => self.function(f),
+            // what a macro's body refers to depends on the code it's
+            // expanded into, so there's nothing to resolve until then
+            Expression::Macro(_) => {}
+            Expression::Call(i) => {
+                self.expression(i.function());
+                for arg in i.arguments() {
+                    self.expression(arg);
+                }
+            }
+            Expression::Array(i) => {
+                for element in i.elements() {
+                    self.expression(element);
+                }
+            }
+            Expression::Hash(i) => {
+                for (key, value) in i.pairs() {
+                    self.expression(key);
+                    self.expression(value);
+                }
+            }
+            Expression::Index(i) => {
+                self.expression(i.left());
+                self.expression(i.index());
+            }
+            // the collection is changed in place, and its name only read
+            Expression::IndexAssign(i) => {
+                self.expression(i.target().left());
+                self.expression(i.target().index());
+                self.expression(i.value());
+            }
+            // the name after the dot is looked up in the module at runtime
+            Expression::Member(i) => self.expression(i.object()),
+            Expression::Assign(i) => {
+                let access = match i.infix_operator() {
+                    Some(_) => Access::ReadWrite,
+                    None => Access::Write,
+                };
+                let target = self.lookup(i.name().value());
+                self.resolution.references.push(Reference {
+                    name: i.name(),
+                    target,
+                    access,
+                });
+                self.expression(i.value());
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn targets(input: &str) -> Vec<(String, Option<String>)> {
+        let path = std::path::Path::new("resolve.my");
+        let l = Lexer::new(input, false, Some(path));
+        let mut p = Parser::new(l);
+        let program = p.parse_program().expect("Program should be Some here");
+        assert_eq!(p.errors().len(), 0);
+
+        let r = resolve(&program);
+        r.references
+            .iter()
+            .map(|reference| {
+                let target = reference.target.map(|idx| {
+                    let (row, col, _) = r.bindings[idx].name.token().local().unwrap().get();
+                    format!("{}:{}", row, col)
+                });
+                (reference.name.value().clone(), target)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_resolve_shadowing_and_parameters() {
+        let input = "let x = 1;\n\
+            let x = x + 1;\n\
+            let f = fn(x) { x + y };";
+
+        assert_eq!(
+            targets(input),
+            [
+                (String::from("x"), Some(String::from("1:5"))),
+                (String::from("x"), Some(String::from("3:12"))),
+                (String::from("y"), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolve_loops() {
+        let input = "let n = 3;\n\
+            for (i in 0..n) { let sq = i * i; }\n\
+            while (sq > 0) { i; }";
+
+        assert_eq!(
+            targets(input),
+            [
+                (String::from("n"), Some(String::from("1:5"))),
+                (String::from("i"), Some(String::from("2:6"))),
+                (String::from("i"), Some(String::from("2:6"))),
+                (String::from("sq"), Some(String::from("2:23"))),
+                (String::from("i"), Some(String::from("2:6"))),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolve_catch_variables() {
+        let input = "let e = 1;\n\
+            let n = try { let x = e; x } catch (e) { e.message } finally { n; };";
+
+        assert_eq!(
+            targets(input),
+            [
+                (String::from("e"), Some(String::from("1:5"))),
+                (String::from("x"), Some(String::from("2:19"))),
+                (String::from("e"), Some(String::from("2:37"))),
+                (String::from("n"), Some(String::from("2:5"))),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolve_recursion_and_forward_references() {
+        let input = "let even = fn(n) { if (n == 0) { true } else { odd(n - 1) } };\n\
+            let odd = fn(n) { even(n - 1) };";
+
+        let resolved = targets(input);
+        assert_eq!(
+            resolved[1],
+            (String::from("odd"), Some(String::from("2:5")))
+        );
+        assert_eq!(
+            resolved[3],
+            (String::from("even"), Some(String::from("1:5")))
+        );
+    }
+}
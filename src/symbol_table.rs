@@ -0,0 +1,611 @@
+use crate::ast::{self, Expression, FunctionInternal, Statement};
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SymbolScope {
+    Global,
+    Local,
+    Builtin,
+    Free,
+    /// The name a function was bound to, seen from inside itself.
+    Function,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub scope: SymbolScope,
+    pub index: usize,
+    /// Whether the slot holds a cell with the value in it, which the
+    /// function and the closures made in it share, rather than the value.
+    pub cell: bool,
+}
+
+#[derive(Default)]
+struct Table {
+    store: HashMap<String, Symbol>,
+    num_definitions: usize,
+    /// Symbols of enclosing functions this one closes over, in the order of
+    /// their `SymbolScope::Free` indexes.
+    free_symbols: Vec<Symbol>,
+    /// Locals that live in cells, by name.
+    cells: HashSet<String>,
+    /// Slots given to cells whose `let` hasn't been compiled yet.
+    reserved: HashMap<String, usize>,
+}
+
+/// Nested symbol tables, one for the global scope and one for each function
+/// being compiled, innermost last.
+pub struct SymbolTable {
+    tables: Vec<Table>,
+    /// The global tables of files whose imports are being compiled.
+    importers: Vec<Table>,
+    /// Names of the globals by slot, across every file.
+    global_names: Vec<String>,
+}
+
+impl Default for SymbolTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable {
+            tables: vec![Table::default()],
+            importers: Vec::new(),
+            global_names: Vec::new(),
+        }
+    }
+
+    /// Enters a function's scope.
+    pub fn push(&mut self) {
+        self.tables.push(Table::default());
+    }
+
+    /// Leaves a function's scope, returning the symbols it closed over and
+    /// the number of locals it defined.
+    pub fn pop(&mut self) -> (Vec<Symbol>, usize) {
+        let table = self.tables.pop().expect("can't leave the global scope");
+        (table.free_symbols, table.num_definitions)
+    }
+
+    /// Starts an imported file, which gets a global table of its own. Its
+    /// globals take slots after everyone else's, and it sees the builtins
+    /// and the globals named in `shared`.
+    pub fn enter_module(&mut self, shared: &[String]) {
+        assert!(
+            self.is_global(),
+            "imports are only compiled at the top level"
+        );
+
+        let importer = &self.tables[0];
+        let mut table = Table {
+            num_definitions: importer.num_definitions,
+            ..Table::default()
+        };
+        for symbol in importer.store.values() {
+            if symbol.scope == SymbolScope::Builtin || shared.contains(&symbol.name) {
+                table.store.insert(symbol.name.clone(), symbol.clone());
+            }
+        }
+
+        let importer = std::mem::replace(&mut self.tables[0], table);
+        self.importers.push(importer);
+    }
+
+    /// Goes back to the file that imported the one being compiled.
+    pub fn leave_module(&mut self) {
+        let importer = self.importers.pop().expect("we're in a module");
+        let module = std::mem::replace(&mut self.tables[0], importer);
+        self.tables[0].num_definitions = module.num_definitions;
+    }
+
+    pub fn is_global(&self) -> bool {
+        self.tables.len() == 1
+    }
+
+    fn current(&mut self) -> &mut Table {
+        self.tables
+            .last_mut()
+            .expect("there is always a global table")
+    }
+
+    /// Defines `name` in the current scope. Defining a name again reuses its
+    /// slot, so rebinding with `let` overwrites the old value.
+    pub fn define(&mut self, name: &str) -> Symbol {
+        let scope = if self.is_global() {
+            SymbolScope::Global
+        } else {
+            SymbolScope::Local
+        };
+
+        let table = self.current();
+        if let Some(existing) = table.store.get(name) {
+            if existing.scope == scope {
+                return existing.clone();
+            }
+        }
+
+        let index = match table.reserved.remove(name) {
+            Some(index) => index,
+            None => {
+                table.num_definitions += 1;
+                table.num_definitions - 1
+            }
+        };
+        let symbol = Symbol {
+            name: name.to_string(),
+            scope,
+            index,
+            cell: table.cells.contains(name),
+        };
+        table.store.insert(name.to_string(), symbol.clone());
+
+        if scope == SymbolScope::Global {
+            self.global_names.push(name.to_string());
+        }
+        symbol
+    }
+
+    /// Makes `names`, locals of the current function that `cells` found,
+    /// live in cells. Call it once the parameters are defined: the other
+    /// names get their slots now, ahead of their `let`s. Returns the slot
+    /// of every cell, for the function to make them in when it starts.
+    pub fn define_cells(&mut self, names: HashSet<String>) -> Vec<usize> {
+        let table = self.current();
+        let mut names: Vec<String> = names.into_iter().collect();
+        names.sort();
+
+        let mut slots = Vec::new();
+        for name in names {
+            match table.store.get_mut(&name) {
+                Some(parameter) if parameter.scope == SymbolScope::Local => {
+                    parameter.cell = true;
+                    slots.push(parameter.index);
+                }
+                _ => {
+                    slots.push(table.num_definitions);
+                    table.reserved.insert(name.clone(), table.num_definitions);
+                    table.num_definitions += 1;
+                }
+            }
+            table.cells.insert(name);
+        }
+        slots.sort();
+        slots
+    }
+
+    pub fn define_builtin(&mut self, index: usize, name: &str) -> Symbol {
+        let symbol = Symbol {
+            name: name.to_string(),
+            scope: SymbolScope::Builtin,
+            index,
+            cell: false,
+        };
+        self.tables[0]
+            .store
+            .insert(name.to_string(), symbol.clone());
+        symbol
+    }
+
+    pub fn define_function_name(&mut self, name: &str) -> Symbol {
+        let symbol = Symbol {
+            name: name.to_string(),
+            scope: SymbolScope::Function,
+            index: 0,
+            cell: false,
+        };
+        self.current()
+            .store
+            .insert(name.to_string(), symbol.clone());
+        symbol
+    }
+
+    fn define_free(&mut self, depth: usize, original: Symbol) -> Symbol {
+        let table = &mut self.tables[depth];
+        let symbol = Symbol {
+            name: original.name.clone(),
+            scope: SymbolScope::Free,
+            index: table.free_symbols.len(),
+            cell: original.cell,
+        };
+        table.free_symbols.push(original);
+        table.store.insert(symbol.name.clone(), symbol.clone());
+        symbol
+    }
+
+    pub fn resolve(&mut self, name: &str) -> Option<Symbol> {
+        let depth = self.tables.len() - 1;
+        self.resolve_at(depth, name)
+    }
+
+    fn resolve_at(&mut self, depth: usize, name: &str) -> Option<Symbol> {
+        if let Some(s) = self.tables[depth].store.get(name) {
+            return Some(s.clone());
+        }
+
+        if depth == 0 {
+            return None;
+        }
+
+        let outer = self.resolve_at(depth - 1, name)?;
+        match outer.scope {
+            SymbolScope::Global | SymbolScope::Builtin => Some(outer),
+            _ => Some(self.define_free(depth, outer)),
+        }
+    }
+
+    /// Resolves `name` as the target of an assignment, which can be a
+    /// local of the current function, a global, or a local of an enclosing
+    /// function that lives in a cell. Closures get copies of the other
+    /// locals they capture, so assigning one couldn't change the original.
+    pub fn resolve_assignment(&mut self, name: &str) -> Result<Symbol, String> {
+        let depth = self.tables.len() - 1;
+        // a function's own name is a copy of whatever an enclosing table
+        // has, and so are captured names that aren't cells
+        let found = self.tables.iter().enumerate().rev().find_map(|(d, table)| {
+            match table.store.get(name) {
+                Some(s) if s.scope == SymbolScope::Function => None,
+                Some(s) if s.scope == SymbolScope::Free && !s.cell => None,
+                Some(s) => Some((d, s.clone())),
+                None => None,
+            }
+        });
+
+        match found {
+            None => Err(format!("undefined variable {}", name)),
+            Some((_, s)) if s.scope == SymbolScope::Builtin => {
+                Err(format!("can't assign to builtin {}", name))
+            }
+            Some((d, s)) if s.scope == SymbolScope::Global || d == depth => Ok(s),
+            Some((_, s)) if s.cell => {
+                let outer = self.resolve_at(depth - 1, name).expect("it was found");
+                Ok(self.define_free(depth, outer))
+            }
+            Some(_) => Err(format!("can't assign to captured variable {}", name)),
+        }
+    }
+
+    /// The slot of the global `name`, if it has been defined.
+    pub fn global(&self, name: &str) -> Option<usize> {
+        self.tables[0]
+            .store
+            .get(name)
+            .filter(|s| s.scope == SymbolScope::Global)
+            .map(|s| s.index)
+    }
+
+    /// Names of the globals, indexed by their slot.
+    pub fn global_names(&self) -> Vec<String> {
+        self.global_names.clone()
+    }
+}
+
+/// The locals of `f` that have to live in cells: those a function inside
+/// it uses, and that can change after it has, by being assigned or bound
+/// again. Closures copy the other locals they capture, which is only right
+/// while they stay as they were.
+///
+/// Shadowing is ignored, so a name can be given that doesn't need a cell.
+/// That only costs a little speed.
+pub fn cells(f: &FunctionInternal) -> HashSet<String> {
+    let mut scan = Scan::default();
+    for p in f.parameters() {
+        scan.bind(p.value(), false);
+    }
+    scan.statements(&f.body().statements, false, false);
+
+    scan.bound
+        .into_iter()
+        .filter(|(name, times)| {
+            scan.captured.contains(name) && (*times > 1 || scan.assigned.contains(name))
+        })
+        .map(|(name, _)| name)
+        .collect()
+}
+
+#[derive(Default)]
+struct Scan {
+    /// Names the function binds, with how many times. A binding in a loop
+    /// happens any number of times, so it counts as two.
+    bound: HashMap<String, usize>,
+    /// Names used by functions inside it.
+    captured: HashSet<String>,
+    /// Names assigned anywhere in it.
+    assigned: HashSet<String>,
+}
+
+impl Scan {
+    fn bind(&mut self, name: &str, in_loop: bool) {
+        *self.bound.entry(name.to_string()).or_default() += if in_loop { 2 } else { 1 };
+    }
+
+    /// `nested` is whether the statements are in a function inside the
+    /// one being scanned, whose bindings aren't its locals.
+    fn statements(&mut self, statements: &[Statement], in_loop: bool, nested: bool) {
+        ast::deeper(|| {
+            for stmt in statements {
+                match stmt {
+                    Statement::While(i) => {
+                        self.expression(i.condition(), true, nested);
+                        self.statements(&i.body().statements, true, nested);
+                    }
+                    Statement::For(i) => {
+                        if !nested {
+                            self.bind(i.variable().value(), true);
+                        }
+                        self.expression(i.start(), in_loop, nested);
+                        if let Some(end) = i.end() {
+                            self.expression(end, in_loop, nested);
+                        }
+                        self.statements(&i.body().statements, true, nested);
+                    }
+                    _ => {
+                        if let (Statement::Let(i), false) = (stmt, nested) {
+                            if let Some(name) = i.name() {
+                                self.bind(name.value(), in_loop);
+                            }
+                        }
+                        for e in stmt.expressions() {
+                            self.expression(e, in_loop, nested);
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    fn expression(&mut self, e: &Expression, in_loop: bool, nested: bool) {
+        ast::deeper(|| {
+            match e {
+                Expression::Identifier(i) if nested => {
+                    self.captured.insert(i.value().clone());
+                }
+                Expression::Assign(i) => {
+                    self.assigned.insert(i.name().value().clone());
+                    if nested {
+                        self.captured.insert(i.name().value().clone());
+                    }
+                }
+                Expression::Function(f) => {
+                    self.statements(&f.body().statements, false, true);
+                    return;
+                }
+                Expression::If(i) => {
+                    self.expression(i.condition(), in_loop, nested);
+                    self.statements(&i.consequence().statements, in_loop, nested);
+                    if let Some(alternative) = i.alternative() {
+                        self.statements(&alternative.statements, in_loop, nested);
+                    }
+                    return;
+                }
+                Expression::Try(i) => {
+                    if !nested {
+                        self.bind(i.name().value(), in_loop);
+                    }
+                    for block in i.blocks() {
+                        self.statements(&block.statements, in_loop, nested);
+                    }
+                    return;
+                }
+                _ => {}
+            }
+            for child in e.children() {
+                self.expression(child, in_loop, nested);
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn symbol(name: &str, scope: SymbolScope, index: usize) -> Symbol {
+        Symbol {
+            name: name.to_string(),
+            scope,
+            index,
+            cell: false,
+        }
+    }
+
+    fn cell(name: &str, scope: SymbolScope, index: usize) -> Symbol {
+        Symbol {
+            cell: true,
+            ..symbol(name, scope, index)
+        }
+    }
+
+    #[test]
+    fn test_define_and_resolve_nested() {
+        let mut table = SymbolTable::new();
+        table.define("a");
+        table.define_builtin(0, "puts");
+
+        table.push();
+        table.define("b");
+
+        table.push();
+        table.define("c");
+
+        assert_eq!(
+            table.resolve("a"),
+            Some(symbol("a", SymbolScope::Global, 0))
+        );
+        assert_eq!(
+            table.resolve("puts"),
+            Some(symbol("puts", SymbolScope::Builtin, 0))
+        );
+        assert_eq!(table.resolve("b"), Some(symbol("b", SymbolScope::Free, 0)));
+        assert_eq!(table.resolve("c"), Some(symbol("c", SymbolScope::Local, 0)));
+        assert_eq!(table.resolve("d"), None);
+
+        let (free, num_locals) = table.pop();
+        assert_eq!(free, [symbol("b", SymbolScope::Local, 0)]);
+        assert_eq!(num_locals, 1);
+    }
+
+    #[test]
+    fn test_redefining_reuses_the_slot() {
+        let mut table = SymbolTable::new();
+        assert_eq!(table.define("a").index, 0);
+        assert_eq!(table.define("b").index, 1);
+        assert_eq!(table.define("a").index, 0);
+        assert_eq!(table.global_names(), ["a", "b"]);
+
+        table.push();
+        table.define_function_name("f");
+        assert_eq!(
+            table.resolve("f"),
+            Some(symbol("f", SymbolScope::Function, 0))
+        );
+        assert_eq!(table.define("f"), symbol("f", SymbolScope::Local, 0));
+    }
+
+    #[test]
+    fn test_resolve_assignment() {
+        let mut table = SymbolTable::new();
+        table.define_builtin(0, "puts");
+        table.define("f");
+
+        table.push();
+        table.define_function_name("f");
+        table.define("a");
+        table.resolve("a");
+
+        table.push();
+        table.define("b");
+        table.resolve("a");
+
+        assert_eq!(
+            table.resolve_assignment("b"),
+            Ok(symbol("b", SymbolScope::Local, 0))
+        );
+        // the outer function's name is the global it was bound to
+        assert_eq!(
+            table.resolve_assignment("f"),
+            Ok(symbol("f", SymbolScope::Global, 0))
+        );
+        assert_eq!(
+            table.resolve_assignment("a"),
+            Err(String::from("can't assign to captured variable a"))
+        );
+        assert_eq!(
+            table.resolve_assignment("puts"),
+            Err(String::from("can't assign to builtin puts"))
+        );
+        assert_eq!(
+            table.resolve_assignment("c"),
+            Err(String::from("undefined variable c"))
+        );
+    }
+
+    #[test]
+    fn test_assigning_a_cell_from_a_closure() {
+        let mut table = SymbolTable::new();
+        table.push();
+        table.define("p");
+        let names = ["c", "p"].map(String::from).into_iter().collect();
+        assert_eq!(table.define_cells(names), [0, 1]);
+        assert_eq!(table.define("x"), symbol("x", SymbolScope::Local, 2));
+        assert_eq!(table.define("c"), cell("c", SymbolScope::Local, 1));
+
+        table.push();
+        table.push();
+        assert_eq!(
+            table.resolve_assignment("c"),
+            Ok(cell("c", SymbolScope::Free, 0))
+        );
+        assert_eq!(table.resolve("p"), Some(cell("p", SymbolScope::Free, 1)));
+        let (free, _) = table.pop();
+        assert_eq!(
+            free,
+            [
+                cell("c", SymbolScope::Free, 0),
+                cell("p", SymbolScope::Free, 1)
+            ]
+        );
+        let (free, _) = table.pop();
+        assert_eq!(
+            free,
+            [
+                cell("c", SymbolScope::Local, 1),
+                cell("p", SymbolScope::Local, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cells() {
+        let cells_of = |input: &str| {
+            let mut p = Parser::new(Lexer::new(input, true, None));
+            let program = p.parse_program().expect("Program should be Some here");
+            assert_eq!(p.errors().len(), 0, "{:?}", p.errors());
+            let Expression::Function(f) = program.statements[0].expressions()[0] else {
+                panic!("not a function");
+            };
+            let mut cells: Vec<String> = cells(f).into_iter().collect();
+            cells.sort();
+            cells
+        };
+
+        // captured and assigned, by the closure or the function itself
+        assert_eq!(cells_of("fn() { let c = 0; fn() { c += 1; c } }"), ["c"]);
+        assert_eq!(cells_of("fn(a) { let f = fn() { a }; a = 2; f }"), ["a"]);
+        // captured and bound again, or bound in a loop
+        assert_eq!(
+            cells_of("fn() { let a = 1; let f = fn() { a }; let a = 2; f }"),
+            ["a"]
+        );
+        assert_eq!(
+            cells_of("fn() { for (i in 0..3) { let f = fn() { i }; } }"),
+            ["i"]
+        );
+        assert_eq!(
+            cells_of("fn() { while (true) { let x = 1; fn() { x } } }"),
+            ["x"]
+        );
+        // captured but never changed, or changed but never captured
+        assert_eq!(
+            cells_of("fn() { let a = 1; fn() { a } }"),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            cells_of("fn() { let a = 1; a = 2; a }"),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn test_modules_get_their_own_globals() {
+        let mut table = SymbolTable::new();
+        table.define_builtin(0, "puts");
+        table.define("a");
+        table.define("host");
+
+        table.enter_module(&[String::from("host")]);
+        assert_eq!(table.resolve("a"), None);
+        assert_eq!(
+            table.resolve("host"),
+            Some(symbol("host", SymbolScope::Global, 1))
+        );
+        assert_eq!(
+            table.resolve("puts"),
+            Some(symbol("puts", SymbolScope::Builtin, 0))
+        );
+        assert_eq!(table.define("a").index, 2);
+        table.leave_module();
+
+        assert_eq!(table.define("b").index, 3);
+        assert_eq!(
+            table.resolve("a"),
+            Some(symbol("a", SymbolScope::Global, 0))
+        );
+        assert_eq!(table.global_names(), ["a", "host", "a", "b"]);
+    }
+}
@@ -3,6 +3,7 @@ use std::borrow::Borrow;
 use std::fmt;
 use std::fmt::Display;
 use std::path::Path;
+use std::rc::Rc;
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum TokenKind {
@@ -12,6 +13,7 @@ pub enum TokenKind {
     // Identifiers + literals
     IDENT(String),
     INT(i128),
+    STRING(String),
 
     // Operators
     ASSIGN,
@@ -20,21 +22,44 @@ pub enum TokenKind {
     BANG,
     ASTERISK,
     SLASH,
+    PERCENT,
+
+    PLUSEQ,
+    MINUSEQ,
+    ASTERISKEQ,
+    SLASHEQ,
 
     LT,
     GT,
+    LTE,
+    GTE,
 
     EQ,
     NEQ,
 
+    AND,
+    OR,
+
+    AMPERSAND,
+    PIPE,
+    CARET,
+    TILDE,
+    SHL,
+    SHR,
+
     // Delimiters
     COMMA,
     SEMICOLON,
+    DOT,
+    DOTDOT,
+    COLON,
 
     LPAREN,
     RPAREN,
     LBRACE,
     RBRACE,
+    LBRACKET,
+    RBRACKET,
 
     // Keywords
     FUNCTION,
@@ -44,6 +69,17 @@ pub enum TokenKind {
     IF,
     ELSE,
     RETURN,
+    IMPORT,
+    WHILE,
+    FOR,
+    IN,
+    BREAK,
+    CONTINUE,
+    TRY,
+    CATCH,
+    FINALLY,
+    THROW,
+    MACRO,
 }
 
 impl Display for TokenKind {
@@ -55,6 +91,7 @@ impl Display for TokenKind {
             // Identifiers + literals
             TokenKind::IDENT(inner_string) => "IDENT = ".to_owned() + inner_string,
             TokenKind::INT(inner_int) => "INT = ".to_owned() + inner_int.to_string().borrow(),
+            TokenKind::STRING(inner_string) => "STRING = ".to_owned() + inner_string,
 
             // Operators
             TokenKind::ASSIGN => "ASSIGN".to_string(),
@@ -63,21 +100,44 @@ impl Display for TokenKind {
             TokenKind::BANG => "BANG".to_string(),
             TokenKind::ASTERISK => "ASTERISK".to_string(),
             TokenKind::SLASH => "SLASH".to_string(),
+            TokenKind::PERCENT => "PERCENT".to_string(),
+
+            TokenKind::PLUSEQ => "PLUSEQ".to_string(),
+            TokenKind::MINUSEQ => "MINUSEQ".to_string(),
+            TokenKind::ASTERISKEQ => "ASTERISKEQ".to_string(),
+            TokenKind::SLASHEQ => "SLASHEQ".to_string(),
 
             TokenKind::LT => "LT".to_string(),
             TokenKind::GT => "GT".to_string(),
+            TokenKind::LTE => "LTE".to_string(),
+            TokenKind::GTE => "GTE".to_string(),
 
             TokenKind::EQ => "EQ".to_string(),
             TokenKind::NEQ => "NEQ".to_string(),
 
+            TokenKind::AND => "AND".to_string(),
+            TokenKind::OR => "OR".to_string(),
+
+            TokenKind::AMPERSAND => "AMPERSAND".to_string(),
+            TokenKind::PIPE => "PIPE".to_string(),
+            TokenKind::CARET => "CARET".to_string(),
+            TokenKind::TILDE => "TILDE".to_string(),
+            TokenKind::SHL => "SHL".to_string(),
+            TokenKind::SHR => "SHR".to_string(),
+
             // Delimiters
             TokenKind::COMMA => "COMMA".to_string(),
             TokenKind::SEMICOLON => "SEMICOLON".to_string(),
+            TokenKind::DOT => "DOT".to_string(),
+            TokenKind::DOTDOT => "DOTDOT".to_string(),
+            TokenKind::COLON => "COLON".to_string(),
 
             TokenKind::LPAREN => "LPAREN".to_string(),
             TokenKind::RPAREN => "RPAREN".to_string(),
             TokenKind::LBRACE => "LBRACE".to_string(),
             TokenKind::RBRACE => "RBRACE".to_string(),
+            TokenKind::LBRACKET => "LBRACKET".to_string(),
+            TokenKind::RBRACKET => "RBRACKET".to_string(),
 
             // Keywords
             TokenKind::FUNCTION => "FUNCTION".to_string(),
@@ -87,13 +147,24 @@ impl Display for TokenKind {
             TokenKind::IF => "IF".to_string(),
             TokenKind::ELSE => "ELSE".to_string(),
             TokenKind::RETURN => "RETURN".to_string(),
+            TokenKind::IMPORT => "IMPORT".to_string(),
+            TokenKind::WHILE => "WHILE".to_string(),
+            TokenKind::FOR => "FOR".to_string(),
+            TokenKind::IN => "IN".to_string(),
+            TokenKind::BREAK => "BREAK".to_string(),
+            TokenKind::CONTINUE => "CONTINUE".to_string(),
+            TokenKind::TRY => "TRY".to_string(),
+            TokenKind::CATCH => "CATCH".to_string(),
+            TokenKind::FINALLY => "FINALLY".to_string(),
+            TokenKind::THROW => "THROW".to_string(),
+            TokenKind::MACRO => "MACRO".to_string(),
         };
 
         write!(f, "{}", msg)
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Location<'a> {
     row: usize,
     col: usize,
@@ -101,13 +172,42 @@ pub struct Location<'a> {
 }
 
 impl<'a> Location<'a> {
-    pub fn new(row: usize, col: usize, file: &Path) -> Location {
+    pub fn new(row: usize, col: usize, file: &'a Path) -> Location<'a> {
         Location { row, col, file }
     }
 
     pub fn get(&self) -> (&usize, &usize, &'a Path) {
         (&self.row, &self.col, self.file)
     }
+
+    pub fn span(&self) -> Span {
+        Span {
+            file: Rc::from(self.file),
+            row: self.row,
+            col: self.col,
+        }
+    }
+}
+
+impl Display for Location<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file.display(), self.row, self.col)
+    }
+}
+
+/// A `Location` that owns its file, so that compiled code can keep it
+/// around after the source is gone.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+    file: Rc<Path>,
+    row: usize,
+    col: usize,
+}
+
+impl Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file.display(), self.row, self.col)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -118,28 +218,49 @@ pub struct Token<'a> {
 }
 
 impl<'a> Token<'a> {
-    pub fn new(token_type: TokenKind, local: Option<Location>) -> Token {
+    pub fn new(token_type: TokenKind, local: Option<Location<'a>>) -> Token<'a> {
         let literal = match token_type {
             TokenKind::EOF => String::from(""),
             TokenKind::ILLEGAL => String::from("ILLEGAL"),
             TokenKind::IDENT(ref s) => s.clone(),
             TokenKind::INT(i) => i.to_string(),
+            TokenKind::STRING(ref s) => s.clone(),
             TokenKind::ASSIGN => String::from("="),
             TokenKind::PLUS => String::from("+"),
             TokenKind::MINUS => String::from("-"),
             TokenKind::BANG => String::from("!"),
             TokenKind::ASTERISK => String::from("*"),
             TokenKind::SLASH => String::from("/"),
+            TokenKind::PERCENT => String::from("%"),
+            TokenKind::PLUSEQ => String::from("+="),
+            TokenKind::MINUSEQ => String::from("-="),
+            TokenKind::ASTERISKEQ => String::from("*="),
+            TokenKind::SLASHEQ => String::from("/="),
             TokenKind::LT => String::from("<"),
             TokenKind::GT => String::from(">"),
+            TokenKind::LTE => String::from("<="),
+            TokenKind::GTE => String::from(">="),
             TokenKind::EQ => String::from("=="),
             TokenKind::NEQ => String::from("!="),
+            TokenKind::AND => String::from("&&"),
+            TokenKind::OR => String::from("||"),
+            TokenKind::AMPERSAND => String::from("&"),
+            TokenKind::PIPE => String::from("|"),
+            TokenKind::CARET => String::from("^"),
+            TokenKind::TILDE => String::from("~"),
+            TokenKind::SHL => String::from("<<"),
+            TokenKind::SHR => String::from(">>"),
             TokenKind::COMMA => String::from(","),
             TokenKind::SEMICOLON => String::from(";"),
+            TokenKind::DOT => String::from("."),
+            TokenKind::DOTDOT => String::from(".."),
+            TokenKind::COLON => String::from(":"),
             TokenKind::LPAREN => String::from("("),
             TokenKind::RPAREN => String::from(")"),
             TokenKind::LBRACE => String::from("{"),
             TokenKind::RBRACE => String::from("}"),
+            TokenKind::LBRACKET => String::from("["),
+            TokenKind::RBRACKET => String::from("]"),
             TokenKind::FUNCTION => String::from("FUNCTION"),
             TokenKind::LET => String::from("LET"),
             TokenKind::TRUE => String::from("TRUE"),
@@ -147,6 +268,17 @@ impl<'a> Token<'a> {
             TokenKind::IF => String::from("IF"),
             TokenKind::ELSE => String::from("ELSE"),
             TokenKind::RETURN => String::from("RETURN"),
+            TokenKind::IMPORT => String::from("IMPORT"),
+            TokenKind::WHILE => String::from("WHILE"),
+            TokenKind::FOR => String::from("FOR"),
+            TokenKind::IN => String::from("IN"),
+            TokenKind::BREAK => String::from("BREAK"),
+            TokenKind::CONTINUE => String::from("CONTINUE"),
+            TokenKind::TRY => String::from("TRY"),
+            TokenKind::CATCH => String::from("CATCH"),
+            TokenKind::FINALLY => String::from("FINALLY"),
+            TokenKind::THROW => String::from("THROW"),
+            TokenKind::MACRO => String::from("MACRO"),
         };
 
         Token {
@@ -157,7 +289,33 @@ impl<'a> Token<'a> {
     }
 
     pub fn local(&self) -> Option<&Location<'a>> {
-        self.local.as_ref().map(|i| i)
+        self.local.as_ref()
+    }
+
+    /// Length of the token in the source, which isn't always its literal.
+    pub fn source_len(&self) -> usize {
+        match self.ttype {
+            TokenKind::FUNCTION | TokenKind::IF | TokenKind::IN => 2,
+            TokenKind::LET | TokenKind::FOR | TokenKind::TRY => 3,
+            TokenKind::TRUE | TokenKind::ELSE => 4,
+            TokenKind::FALSE
+            | TokenKind::WHILE
+            | TokenKind::BREAK
+            | TokenKind::CATCH
+            | TokenKind::THROW
+            | TokenKind::MACRO => 5,
+            TokenKind::RETURN | TokenKind::IMPORT => 6,
+            TokenKind::FINALLY => 7,
+            TokenKind::CONTINUE => 8,
+            // the quotes aren't part of the literal
+            TokenKind::STRING(_) => self.literal.chars().count() + 2,
+            // an integer literal out of range keeps its digits
+            TokenKind::ILLEGAL if self.literal.starts_with(|c: char| c.is_ascii_digit()) => {
+                self.literal.len()
+            }
+            TokenKind::ILLEGAL | TokenKind::EOF => 1,
+            _ => self.literal.chars().count(),
+        }
     }
 }
 
@@ -171,6 +329,24 @@ impl<'a> Default for Token<'a> {
     }
 }
 
+/// A `//` line comment. The lexer skips these, but keeps them around so that
+/// tools like the formatter can put them back.
+#[derive(Debug, Clone)]
+pub struct Comment<'a> {
+    pub text: String,
+    local: Option<Location<'a>>,
+}
+
+impl<'a> Comment<'a> {
+    pub fn new(text: String, local: Option<Location<'a>>) -> Comment<'a> {
+        Comment { text, local }
+    }
+
+    pub fn local(&self) -> Option<&Location<'a>> {
+        self.local.as_ref()
+    }
+}
+
 static KEYWORDS: phf::Map<&'static str, TokenKind> = phf_map! {
     "let" => TokenKind::LET,
     "fn" => TokenKind::FUNCTION,
@@ -179,6 +355,17 @@ static KEYWORDS: phf::Map<&'static str, TokenKind> = phf_map! {
     "if" => TokenKind::IF,
     "else" => TokenKind::ELSE,
     "return" => TokenKind::RETURN,
+    "import" => TokenKind::IMPORT,
+    "while" => TokenKind::WHILE,
+    "for" => TokenKind::FOR,
+    "in" => TokenKind::IN,
+    "break" => TokenKind::BREAK,
+    "continue" => TokenKind::CONTINUE,
+    "try" => TokenKind::TRY,
+    "catch" => TokenKind::CATCH,
+    "finally" => TokenKind::FINALLY,
+    "throw" => TokenKind::THROW,
+    "macro" => TokenKind::MACRO,
 };
 
 pub fn lookup_ident(ident: &str) -> TokenKind {
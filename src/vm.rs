@@ -0,0 +1,1369 @@
+use crate::code::{self, Instructions, Opcode};
+use crate::compiler::Bytecode;
+use crate::error::{self, Error, Failure, Kind, TraceFrame};
+use crate::gc::Heap;
+use crate::object::{
+    self, Closure, CompiledFunction, Exception, GcConfig, Host, Object, Overflow, BUILTINS,
+};
+use std::cell::RefCell;
+use std::fmt;
+use std::io::Write;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Room for `MAX_FRAMES` calls with plenty of locals and temporaries each,
+/// so that how deep calls go is limited by frames, as it is in the other
+/// engines, and this only stops a single expression that never ends.
+pub const STACK_SIZE: usize = MAX_FRAMES * 256;
+pub const MAX_FRAMES: usize = 1024;
+
+/// Instructions between looks at the clock, when there's a timeout.
+const CLOCK_INTERVAL: u32 = 1024;
+
+/// The error a run stops with when it's interrupted.
+pub const INTERRUPTED: &str = "interrupted";
+
+/// Stops a running vm from another thread, or a signal handler. The vm
+/// notices before its next instruction and fails with `INTERRUPTED`.
+#[derive(Debug, Clone, Default)]
+pub struct InterruptHandle(Arc<AtomicBool>);
+
+impl InterruptHandle {
+    pub fn new() -> InterruptHandle {
+        InterruptHandle::default()
+    }
+
+    pub fn interrupt(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Takes back an interrupt that no run has noticed yet.
+    pub fn clear(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+
+    /// Whether there's an interrupt, clearing it if so. The vm asks before
+    /// every instruction, so the usual answer is only a load rather than
+    /// a read-modify-write.
+    fn take(&self) -> bool {
+        self.0.load(Ordering::Relaxed) && self.0.swap(false, Ordering::Relaxed)
+    }
+}
+
+/// Resources a single run may use, for code that can't be trusted. Every
+/// engine takes them. Nothing is limited by default, beyond `MAX_FRAMES`
+/// and `STACK_SIZE`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Limits {
+    /// Steps the run may take: instructions on the vms, and expressions
+    /// and loop iterations on the evaluator.
+    pub fuel: Option<u64>,
+    /// Function calls that may be in progress at once.
+    pub max_depth: Option<usize>,
+    /// Bytes the run may allocate for closures, all told. This budgets
+    /// allocation rather than capping what's live at once: what the run
+    /// frees isn't given back.
+    pub max_heap: Option<usize>,
+    /// How long the run may take. The clock is checked every
+    /// `CLOCK_INTERVAL` steps, so a run can go a little over.
+    pub timeout: Option<Duration>,
+}
+
+/// The limit a run went over, which its `error::Error` says in `limit`.
+/// Unlike the message, code can't fake one by throwing it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LimitExceeded {
+    Fuel,
+    Depth,
+    Heap,
+    Timeout,
+}
+
+impl LimitExceeded {
+    pub fn message(self) -> &'static str {
+        match self {
+            LimitExceeded::Fuel => "out of fuel",
+            LimitExceeded::Depth => "call depth limit exceeded",
+            LimitExceeded::Heap => "heap limit exceeded",
+            LimitExceeded::Timeout => "timed out",
+        }
+    }
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message())
+    }
+}
+
+/// What a run has used of its `Limits`, and whether it's been interrupted.
+/// Each engine keeps one and asks it as it goes, so they all stop the same
+/// way.
+#[derive(Default)]
+pub(crate) struct Meter {
+    limits: Limits,
+    interrupt: InterruptHandle,
+    fuel: Option<u64>,
+    heap: usize,
+    deadline: Option<Instant>,
+    until_clock: u32,
+    /// What stopped the run, if it wasn't the code.
+    exceeded: Option<LimitExceeded>,
+    interrupted: bool,
+}
+
+impl Meter {
+    pub(crate) fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    pub(crate) fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
+    }
+
+    pub(crate) fn set_interrupt_handle(&mut self, handle: InterruptHandle) {
+        self.interrupt = handle;
+    }
+
+    /// Fills the budget back up for a new run.
+    pub(crate) fn start(&mut self) {
+        self.fuel = self.limits.fuel;
+        self.heap = 0;
+        self.deadline = self.limits.timeout.map(|t| Instant::now() + t);
+        self.until_clock = CLOCK_INTERVAL;
+        self.exceeded = None;
+        self.interrupted = false;
+    }
+
+    /// Counts a step against the fuel and, now and then, checks the clock.
+    /// Interrupts are checked here too.
+    pub(crate) fn step(&mut self) -> Result<(), Failure> {
+        if self.interrupt.take() {
+            self.interrupted = true;
+            return Err(Failure::from(String::from(INTERRUPTED)));
+        }
+
+        if let Some(fuel) = &mut self.fuel {
+            if *fuel == 0 {
+                return Err(self.exceed(LimitExceeded::Fuel));
+            }
+            *fuel -= 1;
+        }
+
+        if let Some(deadline) = self.deadline {
+            self.until_clock -= 1;
+            if self.until_clock == 0 {
+                self.until_clock = CLOCK_INTERVAL;
+                if Instant::now() >= deadline {
+                    return Err(self.exceed(LimitExceeded::Timeout));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks there's room for another call when `depth` are in progress,
+    /// not counting the main program.
+    pub(crate) fn call(&mut self, depth: usize) -> Result<(), Failure> {
+        if self.limits.max_depth.is_some_and(|max| depth >= max) {
+            return Err(self.exceed(LimitExceeded::Depth));
+        }
+        Ok(())
+    }
+
+    /// Counts `bytes` of closure against the heap budget.
+    pub(crate) fn allocate(&mut self, bytes: usize) -> Result<(), Failure> {
+        self.heap += bytes;
+        if self.limits.max_heap.is_some_and(|max| self.heap > max) {
+            return Err(self.exceed(LimitExceeded::Heap));
+        }
+        Ok(())
+    }
+
+    fn exceed(&mut self, limit: LimitExceeded) -> Failure {
+        self.exceeded = Some(limit);
+        Failure::from(limit.to_string())
+    }
+
+    /// The limit the run went over, if that's what stopped it.
+    pub(crate) fn exceeded(&self) -> Option<LimitExceeded> {
+        self.exceeded
+    }
+
+    /// Whether `catch` may stop the error in flight. Going over a limit, or
+    /// being interrupted, stops the run whatever it's doing.
+    pub(crate) fn catchable(&self) -> bool {
+        self.exceeded.is_none() && !self.interrupted
+    }
+}
+
+struct Frame {
+    closure: Rc<Closure>,
+    ip: usize,
+    /// Where the frame's locals start on the stack.
+    base_pointer: usize,
+}
+
+impl Frame {
+    fn instructions(&self) -> &Instructions {
+        &self.closure.func.instructions
+    }
+}
+
+/// Where `OpSetHandler` said to go when something fails, and what to
+/// unwind to first.
+struct Handler {
+    frames: usize,
+    stack: usize,
+    catch: usize,
+}
+
+pub struct Vm {
+    constants: Vec<Object>,
+    globals: Vec<Option<Object>>,
+    global_names: Vec<String>,
+    members: Vec<String>,
+
+    stack: Vec<Object>,
+    last_popped: Object,
+
+    frames: Vec<Frame>,
+    handlers: Vec<Handler>,
+    /// What was thrown, when the error in flight came from `OpThrow`.
+    thrown: Option<Rc<Exception>>,
+
+    out: Box<dyn Write>,
+
+    meter: Meter,
+    heap: Heap<'static>,
+
+    overflow: Overflow,
+}
+
+impl Vm {
+    pub fn new(bytecode: Bytecode) -> Vm {
+        Vm::with_globals(bytecode, Vec::new())
+    }
+
+    /// Runs `bytecode` against globals left behind by an earlier run.
+    pub fn with_globals(bytecode: Bytecode, mut globals: Vec<Option<Object>>) -> Vm {
+        globals.resize(bytecode.globals.len().max(globals.len()), None);
+
+        let main = CompiledFunction {
+            instructions: bytecode.instructions,
+            num_locals: 0,
+            num_parameters: 0,
+            name: None,
+            sites: bytecode.sites,
+        };
+        let main = Frame {
+            closure: Rc::new(Closure {
+                func: Rc::new(main),
+                free: Vec::new(),
+            }),
+            ip: 0,
+            base_pointer: 0,
+        };
+
+        Vm {
+            constants: bytecode.constants,
+            globals,
+            global_names: bytecode.globals,
+            members: bytecode.members,
+            stack: Vec::new(),
+            last_popped: Object::Null,
+            frames: vec![main],
+            handlers: Vec::new(),
+            thrown: None,
+            out: Box::new(std::io::stdout()),
+            meter: Meter::default(),
+            heap: Heap::default(),
+            overflow: Overflow::default(),
+        }
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.meter.set_limits(limits);
+    }
+
+    /// Makes integer arithmetic wrap around instead of failing when it
+    /// overflows.
+    pub fn set_overflow(&mut self, overflow: Overflow) {
+        self.overflow = overflow;
+    }
+
+    /// A handle that interrupts this vm.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.meter.interrupt_handle()
+    }
+
+    /// Makes `handle` interrupt this vm, instead of its own.
+    pub fn set_interrupt_handle(&mut self, handle: InterruptHandle) {
+        self.meter.set_interrupt_handle(handle);
+    }
+
+    /// Sends what `puts` prints to `out` rather than stdout.
+    pub fn set_output(&mut self, out: Box<dyn Write>) {
+        self.out = out;
+    }
+
+    /// Hands back the writer given to `set_output`, leaving a sink in its
+    /// place.
+    pub fn take_output(&mut self) -> Box<dyn Write> {
+        std::mem::replace(&mut self.out, Box::new(std::io::sink()))
+    }
+
+    /// Changes when the collector runs.
+    pub fn set_gc(&mut self, config: GcConfig) {
+        self.heap.set_config(config);
+    }
+
+    /// Collects in `heap` from now on, which an earlier run over the same
+    /// globals left behind, so the cycles it made can still be freed.
+    pub fn set_heap(&mut self, heap: Heap<'static>) {
+        self.heap = heap;
+    }
+
+    pub fn take_heap(&mut self) -> Heap<'static> {
+        std::mem::take(&mut self.heap)
+    }
+
+    pub fn into_globals(self) -> Vec<Option<Object>> {
+        self.globals
+    }
+
+    /// The value of the last expression statement that ran.
+    pub fn last_popped(&self) -> &Object {
+        &self.last_popped
+    }
+
+    fn push(&mut self, obj: Object) -> Result<(), Failure> {
+        if self.stack.len() >= STACK_SIZE {
+            return Err(Failure::stack_overflow());
+        }
+        self.stack.push(obj);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Object {
+        self.stack
+            .pop()
+            .expect("the compiler keeps the stack balanced")
+    }
+
+    fn frame(&mut self) -> &mut Frame {
+        self.frames
+            .last_mut()
+            .expect("there is always a main frame")
+    }
+
+    fn read_u16(&mut self) -> usize {
+        let frame = self.frame();
+        let v = code::read_u16(&frame.instructions()[frame.ip..]);
+        frame.ip += 2;
+        v as usize
+    }
+
+    fn read_u8(&mut self) -> usize {
+        let frame = self.frame();
+        let v = code::read_u8(&frame.instructions()[frame.ip..]);
+        frame.ip += 1;
+        v as usize
+    }
+
+    /// Runs the bytecode to the end. An error comes with the calls that
+    /// were in progress, whose frames are left as they were.
+    pub fn run(&mut self) -> Result<(), Error> {
+        self.meter.start();
+        self.handlers.clear();
+        self.thrown = None;
+
+        loop {
+            let failure = match self.execute() {
+                Ok(()) => return Ok(()),
+                Err(failure) => failure,
+            };
+            let caught = self.thrown.is_some() || self.meter.catchable();
+            match self.handlers.pop() {
+                Some(handler) if caught => self.catch(failure, handler)?,
+                _ => {
+                    return Err(Error {
+                        message: failure.message,
+                        kind: failure.kind,
+                        trace: self.trace(),
+                        limit: self.meter.exceeded(),
+                    })
+                }
+            }
+        }
+    }
+
+    /// Unwinds to `handler` and goes to its `catch` block, with the error
+    /// on the stack.
+    fn catch(&mut self, failure: Failure, handler: Handler) -> Result<(), Error> {
+        let exception = match self.thrown.take() {
+            Some(exception) => exception,
+            None => {
+                let frame = self.frames.last().expect("there is always a main frame");
+                let site = frame.closure.func.site(frame.ip);
+                Rc::new(Exception {
+                    kind: failure.kind,
+                    message: failure.message,
+                    span: site.and_then(|s| s.span.clone()),
+                })
+            }
+        };
+
+        self.frames.truncate(handler.frames);
+        self.stack.truncate(handler.stack);
+        self.frame().ip = handler.catch;
+        self.push(Object::Error(exception)).map_err(Error::from)
+    }
+
+    /// The calls in progress, innermost first.
+    fn trace(&self) -> Vec<TraceFrame> {
+        self.frames
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(idx, frame)| {
+                let func = &frame.closure.func;
+                let name = match (idx, &func.name) {
+                    (0, _) => error::MAIN,
+                    (_, Some(name)) => name,
+                    (_, None) => error::ANONYMOUS,
+                };
+                TraceFrame {
+                    function: name.to_string(),
+                    site: func.site(frame.ip).cloned(),
+                }
+            })
+            .collect()
+    }
+
+    fn execute(&mut self) -> Result<(), Failure> {
+        loop {
+            self.meter.step()?;
+
+            let frame = self.frame();
+            if frame.ip >= frame.instructions().len() {
+                return Ok(());
+            }
+
+            let byte = frame.instructions()[frame.ip];
+            frame.ip += 1;
+
+            let op = match Opcode::from_byte(byte) {
+                Some(op) => op,
+                None => return Err(Failure::from(format!("unknown opcode {}", byte))),
+            };
+
+            match op {
+                Opcode::Constant => {
+                    let idx = self.read_u16();
+                    self.push(self.constants[idx].clone())?;
+                }
+                Opcode::Pop => {
+                    self.last_popped = self.pop();
+                }
+                Opcode::Add
+                | Opcode::Sub
+                | Opcode::Mul
+                | Opcode::Div
+                | Opcode::Mod
+                | Opcode::BitAnd
+                | Opcode::BitOr
+                | Opcode::BitXor
+                | Opcode::ShiftLeft
+                | Opcode::ShiftRight => {
+                    self.binary_operation(op)?;
+                }
+                Opcode::True => self.push(Object::Boolean(true))?,
+                Opcode::False => self.push(Object::Boolean(false))?,
+                Opcode::Null => self.push(Object::Null)?,
+                Opcode::Equal | Opcode::NotEqual | Opcode::GreaterThan | Opcode::GreaterEqual => {
+                    self.comparison(op)?;
+                }
+                Opcode::Minus => match self.pop() {
+                    Object::Integer(i) => {
+                        let negated =
+                            self.overflow
+                                .apply(0, i, i128::checked_sub, i128::wrapping_sub);
+                        let negated = negated.map_err(|e| self.arithmetic_error(e))?;
+                        self.push(Object::Integer(negated))?
+                    }
+                    obj => {
+                        return Err(Failure::new(
+                            Kind::Type,
+                            format!("unsupported type for negation: {}", obj.type_name()),
+                        ))
+                    }
+                },
+                Opcode::Bang => {
+                    let value = self.pop();
+                    self.push(Object::Boolean(!value.is_truthy()))?;
+                }
+                Opcode::BitNot => match self.pop() {
+                    Object::Integer(i) => self.push(Object::Integer(!i))?,
+                    obj => {
+                        return Err(Failure::new(
+                            Kind::Type,
+                            format!("unsupported type for bitwise not: {}", obj.type_name()),
+                        ))
+                    }
+                },
+                Opcode::JumpNotTruthy => {
+                    let target = self.read_u16();
+                    if !self.pop().is_truthy() {
+                        self.frame().ip = target;
+                    }
+                }
+                Opcode::Jump => {
+                    let target = self.read_u16();
+                    self.frame().ip = target;
+                }
+                Opcode::SetGlobal => {
+                    let idx = self.read_u16();
+                    let value = self.pop();
+                    if idx >= self.globals.len() {
+                        self.globals.resize(idx + 1, None);
+                    }
+                    self.globals[idx] = Some(value);
+                }
+                Opcode::GetGlobal => {
+                    let idx = self.read_u16();
+                    match self.globals.get(idx).cloned().flatten() {
+                        Some(value) => self.push(value)?,
+                        None => {
+                            let name = self.global_names.get(idx).cloned().unwrap_or_default();
+                            return Err(Failure::not_found(&name));
+                        }
+                    }
+                }
+                Opcode::SetLocal => {
+                    let idx = self.read_u8();
+                    let base = self.frame().base_pointer;
+                    self.stack[base + idx] = self.pop();
+                }
+                Opcode::GetLocal => {
+                    let idx = self.read_u8();
+                    let base = self.frame().base_pointer;
+                    self.push(self.stack[base + idx].clone())?;
+                }
+                Opcode::GetMember => {
+                    let idx = self.read_u16();
+                    let slot = match self.pop() {
+                        Object::Module(m) => m.slot(&self.members[idx])?,
+                        Object::Error(e) => {
+                            let member = e.member(&self.members[idx])?;
+                            self.push(member)?;
+                            continue;
+                        }
+                        Object::GcStats(s) => {
+                            let member = s.member(&self.members[idx])?;
+                            self.push(member)?;
+                            continue;
+                        }
+                        obj => {
+                            return Err(Failure::new(
+                                Kind::Type,
+                                format!("unsupported type for member access: {}", obj.type_name()),
+                            ))
+                        }
+                    };
+                    match self.globals.get(slot).cloned().flatten() {
+                        Some(value) => self.push(value)?,
+                        None => {
+                            return Err(Failure::not_found(&self.members[idx]));
+                        }
+                    }
+                }
+                Opcode::CheckRange => {
+                    let bounds = &self.stack[self.stack.len() - 2..];
+                    if let [start, end] = bounds {
+                        if !matches!((start, end), (Object::Integer(_), Object::Integer(_))) {
+                            return Err(Failure::new(
+                                Kind::Type,
+                                format!(
+                                    "unsupported types for range: {} {}",
+                                    start.type_name(),
+                                    end.type_name()
+                                ),
+                            ));
+                        }
+                    }
+                }
+                Opcode::Array => {
+                    let n = self.read_u16();
+                    self.meter.allocate(n * std::mem::size_of::<Object>())?;
+                    let elements = self.stack.split_off(self.stack.len() - n);
+                    let array = Rc::new(RefCell::new(elements));
+                    self.heap.track(&array);
+                    self.push(Object::Array(array))?;
+                }
+                Opcode::Hash => {
+                    let n = self.read_u16();
+                    self.meter.allocate(2 * n * std::mem::size_of::<Object>())?;
+                    let mut flat = self.stack.split_off(self.stack.len() - 2 * n).into_iter();
+                    let pairs = std::iter::from_fn(|| Some((flat.next()?, flat.next()?)));
+                    let hash = object::make_hash(pairs)?;
+                    self.heap.track(&hash);
+                    self.push(Object::Hash(hash))?;
+                }
+                Opcode::Index => {
+                    let index = self.pop();
+                    let left = self.pop();
+                    self.push(object::index(&left, &index)?)?;
+                }
+                Opcode::DupPair => {
+                    let top = self.stack.len();
+                    let pair = self.stack[top - 2..].to_vec();
+                    for value in pair {
+                        self.push(value)?;
+                    }
+                }
+                Opcode::SetIndex => {
+                    let value = self.pop();
+                    let index = self.pop();
+                    let left = self.pop();
+                    object::set_index(&mut self.meter, &left, &index, value.clone())?;
+                    self.push(value)?;
+                }
+                Opcode::Next => {
+                    let target = self.read_u16();
+                    let position = match self.pop() {
+                        Object::Integer(i) => i as usize,
+                        _ => unreachable!("the compiler counts with integers"),
+                    };
+                    let items = self.pop();
+                    match object::nth(&items, position)? {
+                        Some(item) => self.push(item)?,
+                        None => self.frame().ip = target,
+                    }
+                }
+                Opcode::SetHandler => {
+                    let catch = self.read_u16();
+                    self.handlers.push(Handler {
+                        frames: self.frames.len(),
+                        stack: self.stack.len(),
+                        catch,
+                    });
+                }
+                Opcode::PopHandler => {
+                    self.handlers.pop();
+                }
+                Opcode::Throw => {
+                    let exception = match self.pop() {
+                        Object::Error(e) => e,
+                        value => {
+                            let frame = self.frame();
+                            let site = frame.closure.func.site(frame.ip);
+                            Rc::new(Exception {
+                                message: value.to_string(),
+                                kind: Kind::Thrown,
+                                span: site.and_then(|s| s.span.clone()),
+                            })
+                        }
+                    };
+                    let failure = Failure::new(exception.kind, exception.message.clone());
+                    self.thrown = Some(exception);
+                    return Err(failure);
+                }
+                Opcode::GetBuiltin => {
+                    let idx = self.read_u8();
+                    self.push(Object::Builtin(&BUILTINS[idx]))?;
+                }
+                Opcode::GetFree => {
+                    let idx = self.read_u8();
+                    let value = self.frame().closure.free[idx].clone();
+                    self.push(value)?;
+                }
+                Opcode::MakeCell => {
+                    let idx = self.read_u8();
+                    let base = self.frame().base_pointer;
+                    self.meter
+                        .allocate(std::mem::size_of::<RefCell<Object>>())?;
+                    let value = std::mem::replace(&mut self.stack[base + idx], Object::Null);
+                    let cell = Rc::new(RefCell::new(value));
+                    self.heap.track(&cell);
+                    self.stack[base + idx] = Object::Cell(cell);
+                }
+                Opcode::GetCell => {
+                    let value = match self.pop() {
+                        Object::Cell(cell) => cell.borrow().clone(),
+                        _ => unreachable!("only cells are read through"),
+                    };
+                    self.push(value)?;
+                }
+                Opcode::SetCell => {
+                    let cell = self.pop();
+                    let value = self.pop();
+                    match cell {
+                        Object::Cell(cell) => *cell.borrow_mut() = value,
+                        _ => unreachable!("only cells are stored through"),
+                    }
+                }
+                Opcode::CurrentClosure => {
+                    let closure = self.frame().closure.clone();
+                    self.push(Object::Closure(closure))?;
+                }
+                Opcode::Closure => {
+                    let idx = self.read_u16();
+                    let num_free = self.read_u8();
+                    self.push_closure(idx, num_free)?;
+                }
+                Opcode::Call => {
+                    let num_args = self.read_u8();
+                    self.call(num_args)?;
+                }
+                Opcode::TailCall => {
+                    let num_args = self.read_u8();
+                    if !self.tail_call(num_args)? {
+                        // a builtin, which leaves its result on the stack
+                        self.call(num_args)?;
+                        let value = self.pop();
+                        if self.return_value(value)? {
+                            return Ok(());
+                        }
+                    }
+                }
+                Opcode::ReturnValue | Opcode::Return => {
+                    let value = if op == Opcode::ReturnValue {
+                        self.pop()
+                    } else {
+                        Object::Null
+                    };
+
+                    if self.return_value(value)? {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    fn binary_operation(&mut self, op: Opcode) -> Result<(), Failure> {
+        let right = self.pop();
+        let left = self.pop();
+
+        let (l, r) = match (&left, &right) {
+            (Object::Integer(l), Object::Integer(r)) => (*l, *r),
+            _ => {
+                return Err(Failure::new(
+                    Kind::Type,
+                    format!(
+                        "unsupported types for binary operation: {} {}",
+                        left.type_name(),
+                        right.type_name()
+                    ),
+                ))
+            }
+        };
+
+        let overflow = self.overflow;
+        let result = match op {
+            Opcode::Add => overflow.apply(l, r, i128::checked_add, i128::wrapping_add),
+            Opcode::Sub => overflow.apply(l, r, i128::checked_sub, i128::wrapping_sub),
+            Opcode::Mul => overflow.apply(l, r, i128::checked_mul, i128::wrapping_mul),
+            Opcode::Div | Opcode::Mod if r == 0 => Err(Failure::division_by_zero()),
+            Opcode::Div => overflow.apply(l, r, i128::checked_div, i128::wrapping_div),
+            Opcode::Mod => overflow.apply(l, r, i128::checked_rem, i128::wrapping_rem),
+            Opcode::BitAnd => Ok(l & r),
+            Opcode::BitOr => Ok(l | r),
+            Opcode::BitXor => Ok(l ^ r),
+            Opcode::ShiftLeft | Opcode::ShiftRight if !(0..128).contains(&r) => {
+                Err(Failure::shift_out_of_range(r))
+            }
+            Opcode::ShiftLeft => overflow.apply(l, r, object::checked_shl, |l, r| l << r),
+            Opcode::ShiftRight => Ok(l >> r),
+            _ => unreachable!("only called for arithmetic"),
+        };
+        let result = result.map_err(|e| self.arithmetic_error(e))?;
+
+        self.push(Object::Integer(result))
+    }
+
+    /// An error from the arithmetic of the instruction just read, with
+    /// where its operator is.
+    fn arithmetic_error(&self, failure: Failure) -> Failure {
+        let frame = self.frames.last().expect("there is always a main frame");
+        let site = frame.closure.func.site(frame.ip);
+        object::arithmetic_error(failure, site.and_then(|s| s.span.as_ref()))
+    }
+
+    fn comparison(&mut self, op: Opcode) -> Result<(), Failure> {
+        let right = self.pop();
+        let left = self.pop();
+
+        let result = match (op, &left, &right) {
+            (Opcode::GreaterThan, Object::Integer(l), Object::Integer(r)) => l > r,
+            (Opcode::GreaterEqual, Object::Integer(l), Object::Integer(r)) => l >= r,
+            (Opcode::GreaterThan | Opcode::GreaterEqual, _, _) => {
+                return Err(Failure::new(
+                    Kind::Type,
+                    format!(
+                        "unknown operator: {} {} {}",
+                        left.type_name(),
+                        if op == Opcode::GreaterThan { ">" } else { ">=" },
+                        right.type_name()
+                    ),
+                ))
+            }
+            (Opcode::Equal, _, _) => left == right,
+            (Opcode::NotEqual, _, _) => left != right,
+            _ => unreachable!("only called for comparisons"),
+        };
+
+        self.push(Object::Boolean(result))
+    }
+
+    fn push_closure(&mut self, idx: usize, num_free: usize) -> Result<(), Failure> {
+        let func = match &self.constants[idx] {
+            Object::CompiledFunction(f) => f.clone(),
+            obj => {
+                return Err(Failure::from(format!(
+                    "not a function: {}",
+                    obj.type_name()
+                )))
+            }
+        };
+
+        let size = std::mem::size_of::<Closure>() + num_free * std::mem::size_of::<Object>();
+        self.meter.allocate(size)?;
+
+        let free = self.stack.split_off(self.stack.len() - num_free);
+        self.push(Object::Closure(Rc::new(Closure { func, free })))
+    }
+
+    /// Returns `value` from the current frame, saying whether that ended
+    /// the program.
+    fn return_value(&mut self, value: Object) -> Result<bool, Failure> {
+        // a top level `return` ends the program
+        if self.frames.len() == 1 {
+            self.last_popped = value;
+            return Ok(true);
+        }
+
+        let frame = self.frames.pop().expect("checked there's a frame");
+        // drop the locals and the function itself
+        self.stack.truncate(frame.base_pointer - 1);
+        self.push(value)?;
+        Ok(false)
+    }
+
+    /// Calls a closure in the current frame's place, so a chain of tail
+    /// calls runs in constant space. Anything else is left for `call`.
+    fn tail_call(&mut self, num_args: usize) -> Result<bool, Failure> {
+        let callee_at = self.stack.len() - 1 - num_args;
+        let closure = match &self.stack[callee_at] {
+            Object::Closure(closure) if closure.func.num_parameters == num_args => closure.clone(),
+            _ => return Ok(false),
+        };
+
+        // the callee and its arguments take the place of the current
+        // function and its locals
+        let base_pointer = self.frame().base_pointer;
+        self.stack.drain(base_pointer - 1..callee_at);
+        let num_locals = closure.func.num_locals;
+        if base_pointer + num_locals >= STACK_SIZE {
+            return Err(Failure::stack_overflow());
+        }
+        self.stack.resize(base_pointer + num_locals, Object::Null);
+
+        let frame = self.frame();
+        frame.closure = closure;
+        frame.ip = 0;
+        Ok(true)
+    }
+
+    fn call(&mut self, num_args: usize) -> Result<(), Failure> {
+        let callee = self.stack[self.stack.len() - 1 - num_args].clone();
+
+        match callee {
+            Object::Closure(closure) => {
+                if num_args != closure.func.num_parameters {
+                    return Err(Failure::wrong_arguments(
+                        closure.func.num_parameters,
+                        num_args,
+                    ));
+                }
+
+                if self.frames.len() >= MAX_FRAMES {
+                    return Err(Failure::stack_overflow());
+                }
+                // the main frame isn't a call
+                self.meter.call(self.frames.len() - 1)?;
+
+                let base_pointer = self.stack.len() - num_args;
+                let num_locals = closure.func.num_locals;
+                if base_pointer + num_locals >= STACK_SIZE {
+                    return Err(Failure::stack_overflow());
+                }
+                self.stack.resize(base_pointer + num_locals, Object::Null);
+
+                self.frames.push(Frame {
+                    closure,
+                    ip: 0,
+                    base_pointer,
+                });
+            }
+            Object::Builtin(builtin) => {
+                let args = self.stack.split_off(self.stack.len() - num_args);
+                let mut host = Host {
+                    out: self.out.as_mut(),
+                    gc: self.heap.stats(),
+                    meter: &mut self.meter,
+                };
+                let result = (builtin.func)(&mut host, &args)?;
+                self.pop();
+                self.push(result)?;
+            }
+            Object::Native(native) => {
+                let args = self.stack.split_off(self.stack.len() - num_args);
+                let result = native.call(&args)?;
+                self.pop();
+                self.push(result)?;
+            }
+            obj => return Err(Failure::not_callable(obj.type_name())),
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use std::path::Path;
+
+    fn run(input: &str) -> Result<Object, String> {
+        run_with(input, Overflow::Error)
+    }
+
+    fn run_with(input: &str, overflow: Overflow) -> Result<Object, String> {
+        let l = Lexer::new(input, true, None);
+        let mut p = Parser::new(l);
+        let program = p.parse_program().expect("Program should be Some here");
+        assert_eq!(p.errors().len(), 0, "{:?}", p.errors());
+
+        let mut c = Compiler::new();
+        c.compile(&program)?;
+
+        let mut vm = Vm::new(c.bytecode());
+        vm.set_output(Box::new(std::io::sink()));
+        vm.set_overflow(overflow);
+        vm.run()?;
+        Ok(vm.last_popped().clone())
+    }
+
+    #[test]
+    fn test_expressions() {
+        let tests = [
+            ("1 + 2 * 3 - 4 / 2", Object::Integer(5)),
+            ("-(5 + 5) * 2", Object::Integer(-20)),
+            ("1 < 2 == !(2 < 1)", Object::Boolean(true)),
+            ("true != false", Object::Boolean(true)),
+            ("!!5", Object::Boolean(true)),
+            ("if (1 > 2) { 10 }", Object::Null),
+            ("if (false) { 10 } else { 20 }", Object::Integer(20)),
+            ("if (true) { let x = 1; }", Object::Null),
+            ("let a = 5; let b = a * 2; a + b", Object::Integer(15)),
+            ("puts(1, 2)", Object::Null),
+            // nothing's been made that could be in a cycle yet
+            ("gc_stats().collections", Object::Integer(0)),
+        ];
+
+        for (input, expected) in tests {
+            assert_eq!(run(input), Ok(expected), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_functions_and_closures() {
+        let tests = [
+            ("let add = fn(a, b) { a + b }; add(1, add(2, 3))", 6),
+            ("let early = fn() { return 1; 2 }; early()", 1),
+            (
+                "let adder = fn(a) { fn(b) { fn(c) { a + b + c } } }; adder(1)(2)(3)",
+                6,
+            ),
+            (
+                "let fib = fn(n) { if (n < 2) { n } else { fib(n - 1) + fib(n - 2) } }; fib(15)",
+                610,
+            ),
+            (
+                "let wrapper = fn() { let count = fn(x) { if (x == 0) { 0 } else { count(x - 1) } }; count(3) }; wrapper()",
+                0,
+            ),
+            ("let f = fn() { g() }; let g = fn() { 7 }; f()", 7),
+            ("return 3; 4", 3),
+        ];
+
+        for (input, expected) in tests {
+            assert_eq!(run(input), Ok(Object::Integer(expected)), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_garbage_collection() {
+        // every call to leak makes a cell that the closure stored in it
+        // refers back to, and an array that holds itself
+        let input = "let leak = fn() {\
+                         let h = fn() { 0 };\
+                         h = fn() { h() };\
+                         let xs = [0];\
+                         xs[0] = xs;\
+                     };\
+                     let keep = fn(n) {\
+                         let f = fn() { n };\
+                         let g = fn() { f() };\
+                         f = fn() { if (false) { g() } else { n } };\
+                         g\
+                     };\
+                     let kept = keep(7);\
+                     for (i in 0..1000) { leak(); }\
+                     let s = gc_stats();\
+                     if (s.collections > 0 && s.live < 100) { kept() } else { s }";
+        let l = Lexer::new(input, true, None);
+        let mut p = Parser::new(l);
+        let program = p.parse_program().expect("Program should be Some here");
+
+        let mut c = Compiler::new();
+        c.compile(&program).unwrap();
+
+        let mut vm = Vm::new(c.bytecode());
+        vm.set_gc(GcConfig {
+            threshold: 10,
+            growth: 2,
+        });
+        vm.run().unwrap();
+        assert_eq!(vm.last_popped(), &Object::Integer(7));
+
+        // the last few are only freed by the next collection
+        let stats = vm.heap.stats();
+        assert_eq!(stats.allocated, 2001);
+        assert_eq!(stats.freed, 1997);
+        assert_eq!(stats.live, 3);
+    }
+
+    #[test]
+    fn test_loops() {
+        let tests = [
+            ("let s = 0; for (i in 0..10) { let s = s + i; } s", 45),
+            (
+                "let n = 0; while (true) { let n = n + 1; puts(1, if (n > 3) { break; } else { n }); } n",
+                4,
+            ),
+            (
+                "let f = fn(n) { let odd = 0; for (i in 0..n) { if (i / 2 * 2 == i) { continue; } let odd = odd + 1; } odd }; f(9)",
+                4,
+            ),
+            // deeper than recursion could go
+            ("let n = 0; while (n < 100000) { let n = n + 1; } n", 100000),
+        ];
+
+        for (input, expected) in tests {
+            assert_eq!(run(input), Ok(Object::Integer(expected)), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_tail_calls() {
+        let tests = [
+            (
+                "let count = fn(n) { if (n == 0) { return 0; } count(n - 1) }; count(1000000)",
+                Object::Integer(0),
+            ),
+            (
+                "let even = fn(n) { if (n == 0) { true } else { odd(n - 1) } };\
+                 let odd = fn(n) { if (n == 0) { false } else { even(n - 1) } };\
+                 even(10001)",
+                Object::Boolean(false),
+            ),
+            (
+                "let sum = fn(n, total) { if (n > 0) { return sum(n - 1, total + n); } total }; sum(10000, 0)",
+                Object::Integer(50005000),
+            ),
+            // a builtin in tail position returns its value as usual
+            ("let f = fn() { if (true) { puts() } }; f()", Object::Null),
+        ];
+
+        for (input, expected) in tests {
+            assert_eq!(run(input), Ok(expected), "{}", input);
+        }
+
+        assert_eq!(
+            run("let f = fn(n) { if (n == 0) { 1 } else { f(n - 1, 2) } }; f(3)"),
+            Err(String::from("wrong number of arguments: want=1, got=2"))
+        );
+    }
+
+    #[test]
+    fn test_limits() {
+        let spin = "let spin = fn(n) { if (n == 0) { 0 } else { 1 + spin(n - 1) } };";
+        let tests = [
+            (
+                "spin(100)",
+                Limits {
+                    fuel: Some(100),
+                    ..Limits::default()
+                },
+                Some(LimitExceeded::Fuel),
+            ),
+            (
+                "spin(100)",
+                Limits {
+                    fuel: Some(10_000),
+                    ..Limits::default()
+                },
+                None,
+            ),
+            (
+                "spin(100)",
+                Limits {
+                    max_depth: Some(100),
+                    ..Limits::default()
+                },
+                Some(LimitExceeded::Depth),
+            ),
+            (
+                "spin(99)",
+                Limits {
+                    max_depth: Some(100),
+                    ..Limits::default()
+                },
+                None,
+            ),
+            // tail calls reuse their caller's frame
+            (
+                "let loop = fn(n) { if (n == 0) { 0 } else { loop(n - 1) } }; loop(1000)",
+                Limits {
+                    max_depth: Some(100),
+                    ..Limits::default()
+                },
+                None,
+            ),
+            (
+                "let f = fn(x) { fn() { x } }; f(1); f(2); f(3)",
+                Limits {
+                    max_heap: Some(100),
+                    ..Limits::default()
+                },
+                Some(LimitExceeded::Heap),
+            ),
+            (
+                "spin(500); spin(500); spin(500); spin(500)",
+                Limits {
+                    timeout: Some(Duration::ZERO),
+                    ..Limits::default()
+                },
+                Some(LimitExceeded::Timeout),
+            ),
+        ];
+
+        for (input, limits, expected) in tests {
+            let input = format!("{}{}", spin, input);
+            let l = Lexer::new(&input, true, None);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().expect("Program should be Some here");
+
+            let mut c = Compiler::new();
+            c.compile(&program).expect("should compile");
+            let mut vm = Vm::new(c.bytecode());
+            vm.set_limits(limits);
+
+            let got = vm.run().err().map(|e| e.limit);
+            assert_eq!(got, expected.map(Some), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_limits_cant_be_thrown() {
+        // an error that only says it's a limit is an ordinary error, which
+        // `catch` can stop
+        let input = "let caught = try { throw \"out of fuel\"; } catch (e) { e.message };\
+                     throw caught;";
+        let l = Lexer::new(input, true, None);
+        let mut p = Parser::new(l);
+        let program = p.parse_program().expect("Program should be Some here");
+        let mut c = Compiler::new();
+        c.compile(&program).expect("should compile");
+        let mut vm = Vm::new(c.bytecode());
+        vm.set_limits(Limits {
+            fuel: Some(100),
+            ..Limits::default()
+        });
+
+        let err = vm.run().expect_err("should throw");
+        assert_eq!(err.message, "out of fuel");
+        assert_eq!(err.limit, None);
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let input = "let spin = fn(n) { if (n == 0) { 0 } else { spin(n - 1) } }; spin(500)";
+        let l = Lexer::new(input, true, None);
+        let mut p = Parser::new(l);
+        let program = p.parse_program().expect("Program should be Some here");
+        let mut c = Compiler::new();
+        c.compile(&program).expect("should compile");
+
+        let mut vm = Vm::new(c.bytecode());
+        let handle = vm.interrupt_handle();
+        let other = handle.clone();
+        let interrupter = std::thread::spawn(move || other.interrupt());
+        interrupter.join().expect("should interrupt");
+        assert_eq!(
+            vm.run().map_err(|e| e.message),
+            Err(String::from(INTERRUPTED))
+        );
+
+        // noticing the interrupt used it up
+        let mut vm = Vm::new(c.bytecode());
+        vm.set_interrupt_handle(handle);
+        assert_eq!(vm.run(), Ok(()));
+    }
+
+    #[test]
+    fn test_stack_traces() {
+        let input = "let count = fn(n) {\n  \
+                     if (n == 0) { -true } else { 1 + count(n - 1) }\n\
+                     };\n\
+                     let twice = fn(f) { fn(x) { f(f(x)) } };\n\
+                     twice(count)(3)";
+        let l = Lexer::new(input, false, Some(Path::new("test.my")));
+        let mut p = Parser::new(l);
+        let program = p.parse_program().expect("Program should be Some here");
+        let mut c = Compiler::new();
+        c.compile(&program).expect("should compile");
+
+        let mut vm = Vm::new(c.bytecode());
+        let err = vm.run().expect_err("should fail to negate true");
+        assert_eq!(err.message, "unsupported type for negation: BOOLEAN");
+        assert_eq!(
+            err.trace_lines(),
+            [
+                "in count at test.my:2:17: -true",
+                "in count at test.my:2:41: count(n - 1)",
+                "... the same 2 more times",
+                "in <anonymous> at test.my:4:32: f(x)",
+                "in <main> at test.my:5:13: twice(count)(3)",
+            ]
+        );
+        assert_eq!(err.trace.len(), 6);
+        assert_eq!(err.trace[5].function, crate::error::MAIN);
+    }
+
+    #[test]
+    fn test_runtime_errors() {
+        let tests = [
+            (
+                "1 + true",
+                "unsupported types for binary operation: INTEGER BOOLEAN",
+            ),
+            ("fn(a) { a }()", "wrong number of arguments: want=1, got=0"),
+            ("1()", "calling non-function: INTEGER"),
+            ("10 / (5 - 5)", "division by zero"),
+            ("10 % 0", "division by zero"),
+            ("1 <= true", "unknown operator: BOOLEAN >= INTEGER"),
+            ("1 << 128", "shift amount out of range: 128"),
+            ("-1 >> -1", "shift amount out of range: -1"),
+            ("~true", "unsupported type for bitwise not: BOOLEAN"),
+            ("let f = fn(x) { 1 + f(x) }; f(1)", "stack overflow"),
+            ("f(); let f = fn() { 1 };", "identifier not found: f"),
+            (
+                "let n = 1; n.x",
+                "unsupported type for member access: INTEGER",
+            ),
+            (
+                "for (i in 0..true) { }",
+                "unsupported types for range: INTEGER BOOLEAN",
+            ),
+        ];
+
+        for (input, expected) in tests {
+            assert_eq!(run(input), Err(String::from(expected)), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_exceptions() {
+        let tests = [
+            ("try { 10 / 0 } catch (e) { 2 }", Object::Integer(2)),
+            ("try { 1 } catch (e) { 2 } finally { 3 }", Object::Integer(1)),
+            (
+                "let f = fn() { try { throw 1; } catch (e) { return 2; } finally { 3 } }; f()",
+                Object::Integer(2),
+            ),
+            (
+                "let f = fn(x) { x.y }; try { f(1) } catch (e) { e.kind == \"type\" }",
+                Object::Boolean(true),
+            ),
+            (
+                "let n = 0; for (i in 0..3) { try { continue; } catch (e) { } finally { n += 1; } } n",
+                Object::Integer(3),
+            ),
+        ];
+
+        for (input, expected) in tests {
+            assert_eq!(run(input), Ok(expected), "{}", input);
+        }
+        assert_eq!(run("throw 3;"), Err(String::from("3")));
+
+        // running out of a limit isn't something a script can recover from
+        let input = "let spin = fn(n) { if (n == 0) { 0 } else { spin(n - 1) } };\
+                     try { spin(100) } catch (e) { 0 }";
+        let l = Lexer::new(input, true, None);
+        let mut p = Parser::new(l);
+        let program = p.parse_program().expect("Program should be Some here");
+        let mut c = Compiler::new();
+        c.compile(&program).expect("should compile");
+        let mut vm = Vm::new(c.bytecode());
+        vm.set_limits(Limits {
+            fuel: Some(100),
+            ..Limits::default()
+        });
+        let err = vm.run().expect_err("should run out of fuel");
+        assert_eq!(err.limit, Some(LimitExceeded::Fuel));
+    }
+
+    #[test]
+    fn test_integer_overflow() {
+        let tests = [
+            ("let max = ~(-1 << 127); max + 1", i128::MIN),
+            ("let min = -1 << 127; min - 1", i128::MAX),
+            ("let f = fn(x) { x * 4 }; f(1 << 126)", 0),
+            ("let min = -1 << 127; -min", i128::MIN),
+            ("let min = -1 << 127; min / -1", i128::MIN),
+            ("let min = -1 << 127; min % -1", 0),
+            ("let x = 3; x << 127", i128::MIN),
+            ("let x = 5; x << 126", 1 << 126),
+            ("let x = -3; x << 126", 1 << 126),
+        ];
+
+        for (input, wrapped) in tests {
+            assert_eq!(
+                run(input),
+                Err(String::from("integer overflow")),
+                "{}",
+                input
+            );
+            assert_eq!(
+                run_with(input, Overflow::Wrap),
+                Ok(Object::Integer(wrapped)),
+                "{}",
+                input
+            );
+        }
+        assert_eq!(
+            run_with("1 % 0", Overflow::Wrap),
+            Err(String::from("division by zero"))
+        );
+    }
+}